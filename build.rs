@@ -0,0 +1,14 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/forge.proto").expect("Failed to compile protos");
+
+    /* the nixpacks builds are made with, recorded on each one */
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let version = lock.split("[[package]]")
+        .find(|package| package.lines().any(|line| line.trim() == "name = \"nixpacks\""))
+        .and_then(|package| package.lines().find_map(|line| line.trim().strip_prefix("version = ")))
+        .map(|version| version.trim_matches('"').to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=NIXPACKS_VERSION={}", version);
+}
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS build_data (
+    id STRING PRIMARY KEY,
+    start_time STRING NOT NULL,
+    end_time STRING,
+    status STRING NOT NULL
+);
@@ -0,0 +1,2 @@
+-- compact nixpacks plan summary (JSON) the build was produced from
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS plan STRING;
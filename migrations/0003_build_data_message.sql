@@ -0,0 +1,2 @@
+-- why a build ended up in its final status
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS message STRING;
@@ -0,0 +1,2 @@
+-- repository (url or local path) the build was requested for
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS repo STRING;
@@ -0,0 +1,2 @@
+-- per-step durations (JSON) of a finished build
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS timings STRING;
@@ -0,0 +1,3 @@
+-- the build request (JSON, env values stripped) and the commit that was built
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS options STRING;
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS commit_sha STRING;
@@ -0,0 +1,2 @@
+-- image reference a build produced, checked by the image reconciler
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS image STRING;
@@ -0,0 +1,3 @@
+-- /stats windows by start time, overall and per status
+CREATE INDEX IF NOT EXISTS build_data_start_time_idx ON build_data (start_time);
+CREATE INDEX IF NOT EXISTS build_data_status_start_time_idx ON build_data (status, start_time);
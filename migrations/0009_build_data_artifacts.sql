@@ -0,0 +1,2 @@
+-- files (JSON) nixpacks wrote to a build's out_dir
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS artifacts STRING;
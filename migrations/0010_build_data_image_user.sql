@@ -0,0 +1,2 @@
+-- USER the built image runs as, empty for root
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS image_user STRING;
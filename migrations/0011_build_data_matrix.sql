@@ -0,0 +1,4 @@
+-- the /build/matrix a build was submitted with and its variant's position
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS matrix_id STRING;
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS matrix_index INT;
+CREATE INDEX IF NOT EXISTS build_data_matrix_id_idx ON build_data (matrix_id);
@@ -0,0 +1,2 @@
+-- the Dockerfile nixpacks generated, gzipped when large
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS dockerfile BYTES;
@@ -0,0 +1,2 @@
+-- json list of the providers whose PROVIDER_DEFAULTS_FILE defaults were applied
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS provider_defaults STRING;
@@ -0,0 +1,2 @@
+-- <name>:failed-<build id> tag of an image kept by keep_failed_image
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS failed_image STRING;
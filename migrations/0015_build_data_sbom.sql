@@ -0,0 +1,2 @@
+-- the software bill of materials of the built image, gzipped
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS sbom BYTES;
@@ -0,0 +1,10 @@
+-- followed log collections and how far they got, resumed after a restart with LOG_RESUME
+CREATE TABLE IF NOT EXISTS log_collections (
+    container_id STRING PRIMARY KEY,
+    start_time STRING NOT NULL,
+    end_time STRING NOT NULL,
+    last_seen STRING,
+    build_id STRING,
+    log_topic STRING NOT NULL,
+    log_table STRING NOT NULL
+);
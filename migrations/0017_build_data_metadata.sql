@@ -0,0 +1,2 @@
+-- json object of the client's own strings, stored as given
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS metadata STRING;
@@ -0,0 +1,2 @@
+-- size of the built image in bytes
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS image_size INT8;
@@ -0,0 +1,2 @@
+-- the layer cache key the build used, after CACHE_SCOPE
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS cache_key STRING;
@@ -0,0 +1,2 @@
+-- the min_level a resumed collection keeps filtering with
+ALTER TABLE log_collections ADD COLUMN IF NOT EXISTS min_level STRING;
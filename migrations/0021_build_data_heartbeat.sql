@@ -0,0 +1,3 @@
+-- when a running build last checked in and what it was doing, for spotting dead builds
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS last_heartbeat STRING;
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS phase STRING;
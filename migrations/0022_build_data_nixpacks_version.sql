@@ -0,0 +1,2 @@
+-- the nixpacks version that planned and built the image
+ALTER TABLE build_data ADD COLUMN IF NOT EXISTS nixpacks_version STRING;
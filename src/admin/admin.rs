@@ -0,0 +1,181 @@
+use hyper::{Body, Request, Response, StatusCode, Method};
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use tracing::error;
+
+use crate::engine::queue::{dequeue, drain, resume};
+use crate::engine::reconcile::reconcile_orphans;
+use crate::engine::state::AppState;
+use crate::logs::sink;
+
+use std::sync::Arc;
+
+/// Every HTTP route forge serves, for monitoring to discover without the
+/// landing page.
+pub const ROUTES: &[&str] = &[
+    "GET /",
+    "GET /readyz",
+    "POST /webhook",
+    "POST /build",
+    "POST /build/warm",
+    "POST /plan",
+    "POST /builds/batch",
+    "POST /build/matrix",
+    "POST /build/refs",
+    "GET /build/matrix/{id}",
+    "GET /build/{id}/artifacts",
+    "GET /build/{id}/dockerfile",
+    "GET /build/{id}/log",
+    "GET /build/{id}/sbom",
+    "GET /builds/{a}/diff/{b}",
+    "GET /stats",
+    "GET /builds",
+    "GET /events",
+    "GET /logs",
+    "GET /logs/stream",
+    "POST /admin/reconcile",
+    "POST /admin/drain",
+    "POST /admin/resume",
+    "GET /admin/queue",
+    "POST /admin/queue/{id}/promote",
+    "DELETE /admin/queue/{id}",
+    "GET /admin/config",
+    "GET /admin/status",
+    "GET /admin/routes",
+];
+
+/// Admin routes need `Authorization: Bearer <ADMIN_TOKEN>`, and are off
+/// entirely when no token is configured.
+fn authorized(req: &Request<Body>, state: &AppState) -> bool {
+    let config = state.config();
+    let token = match &config.admin_token {
+        Some(token) => token,
+        None => return false,
+    };
+
+    req.headers().get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| same_token(provided, token))
+}
+
+/// Compares HMACs of the two in constant time, so how long the check takes
+/// doesn't give away how much of the token a guess got right.
+fn same_token(provided: &str, token: &str) -> bool {
+    let mac = || <Hmac<Sha256> as Mac>::new_from_slice(token.as_bytes()).expect("HMAC takes keys of any length");
+
+    let mut expected = mac();
+    expected.update(token.as_bytes());
+    let mut guess = mac();
+    guess.update(provided.as_bytes());
+    guess.verify_slice(&expected.finalize().into_bytes()).is_ok()
+}
+
+/// The build id in `/admin/queue/{id}` followed by `suffix`.
+fn queued_id<'a>(path: &'a str, suffix: &str) -> Option<&'a str> {
+    path.strip_prefix("/admin/queue/")
+        .and_then(|rest| rest.strip_suffix(suffix))
+        .filter(|id| !id.is_empty() && !id.contains('/'))
+}
+
+fn not_queued(id: &str) -> Response<Body> {
+    json_response(StatusCode::NOT_FOUND, json!({ "error": format!("Build {} isn't queued", id) }))
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+pub async fn handle_request(req: Request<Body>, state: Arc<AppState>) -> Result<Response<Body>, hyper::Error> {
+    if !authorized(&req, &state) {
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::from("Unauthorized"))
+            .unwrap());
+    }
+    let config = state.config();
+
+    let path = req.uri().path();
+    match (req.method(), path) {
+        (&Method::POST, "/admin/reconcile") => {
+            match reconcile_orphans(&state, config.orphan_threshold).await {
+                Ok(count) => Ok(json_response(StatusCode::OK, json!({ "orphaned": count }))),
+                Err(e) => {
+                    error!("Failed to reconcile builds: {}", e);
+                    Ok(json_response(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": "Failed to reconcile builds" })))
+                }
+            }
+        },
+        (&Method::POST, "/admin/drain") => {
+            let abort_running = req.uri().query()
+                .is_some_and(|query| query.split('&').any(|param| param == "abort_running=true"));
+
+            let (cancelled, interrupted) = drain(&state, abort_running).await;
+            Ok(json_response(StatusCode::OK, json!({ "cancelled": cancelled, "interrupted": interrupted })))
+        },
+        (&Method::GET, "/admin/config") => {
+            /* secret fields are redacted by Config's Serialize */
+            Ok(json_response(StatusCode::OK, json!({ "config": *config })))
+        },
+        (&Method::GET, "/admin/status") => {
+            Ok(json_response(StatusCode::OK, json!({
+                "queued": state.queue.len(),
+                "accepting": state.queue.is_accepting(),
+                "pushes": {
+                    "limit": state.pushes.limit(),
+                    "active": state.pushes.active(),
+                    "waiting": state.pushes.waiting(),
+                },
+                "log_flushes": sink::in_flight(),
+            })))
+        },
+        (&Method::GET, "/admin/routes") => {
+            Ok(json_response(StatusCode::OK, json!({ "routes": ROUTES, "landing_page": config.enable_landing_page })))
+        },
+        (&Method::POST, "/admin/resume") => {
+            resume(&state);
+            Ok(json_response(StatusCode::OK, json!({ "accepting": true })))
+        },
+        (&Method::GET, "/admin/queue") => {
+            Ok(json_response(StatusCode::OK, json!({ "queue": state.queue.entries() })))
+        },
+        (&Method::POST, _) if queued_id(path, "/promote").is_some() => {
+            let id = queued_id(path, "/promote").unwrap();
+            if !state.queue.promote(id) {
+                return Ok(not_queued(id));
+            }
+            Ok(json_response(StatusCode::OK, json!({ "queue": state.queue.entries() })))
+        },
+        (&Method::DELETE, _) if queued_id(path, "").is_some() => {
+            let id = queued_id(path, "").unwrap();
+            if !dequeue(&state, id).await {
+                return Ok(not_queued(id));
+            }
+            Ok(json_response(StatusCode::OK, json!({ "removed": id })))
+        },
+        _ => {
+            Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Not found"))
+                .unwrap())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_exact_token_matches() {
+        assert!(same_token("s3cret-token", "s3cret-token"));
+        assert!(!same_token("s3cret-toke", "s3cret-token"));
+        assert!(!same_token("s3cret-token!", "s3cret-token"));
+        assert!(!same_token("", "s3cret-token"));
+    }
+}
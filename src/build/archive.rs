@@ -0,0 +1,223 @@
+use reqwest::Url;
+use zip::ZipArchive;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Why an archive couldn't be fetched or unpacked.
+#[derive(Debug)]
+pub enum ArchiveError {
+    /* bigger than allowed, downloaded or unpacked */
+    TooLarge(String),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ArchiveError::TooLarge(message) | ArchiveError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Archive sources have to be fetched over http(s).
+pub fn check_url(url: &str) -> Result<(), String> {
+    match Url::parse(url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+        Ok(url) => Err(format!("Archive urls must be http or https, not {}", url.scheme())),
+        Err(e) => Err(format!("Invalid archive url {}: {}", url, e)),
+    }
+}
+
+/// Downloads `url` into `dest`, giving up past `max_bytes` or when a chunk
+/// takes longer than `idle_timeout`.
+pub async fn download(url: &str, dest: &Path, max_bytes: u64, idle_timeout: Duration) -> Result<(), ArchiveError> {
+    let invalid = |e: String| ArchiveError::Invalid(format!("Failed to download {}: {}", url, e));
+
+    let mut response = reqwest::get(url).await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| invalid(e.to_string()))?;
+
+    if response.content_length().is_some_and(|length| length > max_bytes) {
+        return Err(ArchiveError::TooLarge(format!("Archive is larger than {} bytes", max_bytes)));
+    }
+
+    let mut file = File::create(dest).map_err(|e| invalid(e.to_string()))?;
+    let mut received: u64 = 0;
+    loop {
+        let chunk = match tokio::time::timeout(idle_timeout, response.chunk()).await {
+            Ok(Ok(Some(chunk))) => chunk,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => return Err(invalid(e.to_string())),
+            Err(_) => return Err(invalid("timed out".to_string())),
+        };
+
+        received += chunk.len() as u64;
+        if received > max_bytes {
+            return Err(ArchiveError::TooLarge(format!("Archive is larger than {} bytes", max_bytes)));
+        }
+        file.write_all(&chunk).map_err(|e| invalid(e.to_string()))?;
+    }
+
+    Ok(())
+}
+
+/// Unpacks the zip at `archive` into `dir` and returns the directory to build:
+/// the archive's only top-level directory when it has just one, as GitHub's
+/// codeload zips do, otherwise `dir` itself. Entries escaping `dir` and
+/// symlinks are refused, and so is anything unpacking past `max_bytes`.
+pub fn extract_zip(archive: &Path, dir: &Path, max_bytes: u64) -> Result<PathBuf, ArchiveError> {
+    let invalid = |e: String| ArchiveError::Invalid(format!("Invalid zip archive: {}", e));
+
+    fs::create_dir_all(dir).map_err(|e| invalid(e.to_string()))?;
+    let file = File::open(archive).map_err(|e| invalid(e.to_string()))?;
+    let mut zip = ZipArchive::new(file).map_err(|e| invalid(e.to_string()))?;
+    let mut unpacked: u64 = 0;
+
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index).map_err(|e| invalid(e.to_string()))?;
+        let relative = entry.enclosed_name()
+            .map(Path::to_path_buf)
+            .ok_or_else(|| invalid(format!("entry {} escapes the archive directory", entry.name())))?;
+
+        /* S_IFLNK */
+        if entry.unix_mode().is_some_and(|mode| mode & 0o170000 == 0o120000) {
+            return Err(invalid(format!("entry {} is a symlink", entry.name())));
+        }
+
+        let path = dir.join(&relative);
+        if entry.is_dir() {
+            fs::create_dir_all(&path).map_err(|e| invalid(e.to_string()))?;
+            continue;
+        }
+
+        let size = entry.size();
+        unpacked += size;
+        if unpacked > max_bytes {
+            return Err(ArchiveError::TooLarge(format!("Archive unpacks to more than {} bytes", max_bytes)));
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| invalid(e.to_string()))?;
+        }
+        let mut out = File::create(&path).map_err(|e| invalid(e.to_string()))?;
+        /* the size in the header can lie, so the copy is capped too */
+        let copied = io::copy(&mut (&mut entry).take(size + 1), &mut out).map_err(|e| invalid(e.to_string()))?;
+        if copied > size {
+            return Err(invalid(format!("entry {} is larger than its header says", relative.display())));
+        }
+    }
+
+    let top_level: Vec<PathBuf> = fs::read_dir(dir)
+        .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect())
+        .map_err(|e| invalid(e.to_string()))?;
+    match top_level.as_slice() {
+        [only] if only.is_dir() => Ok(only.clone()),
+        _ => Ok(dir.to_path_buf()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use std::convert::Infallible;
+    use std::io::Cursor;
+
+    /// A zip of `files`, (name, contents) pairs.
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// Serves `bytes` at every path, returning the server's base url.
+    fn serve(bytes: Vec<u8>) -> String {
+        let make_svc = make_service_fn(move |_conn| {
+            let bytes = bytes.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_req| {
+                    let bytes = bytes.clone();
+                    async move { Ok::<_, Infallible>(Response::new(Body::from(bytes))) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    fn unpack(bytes: &[u8], max_bytes: u64) -> (tempfile::TempDir, Result<PathBuf, ArchiveError>) {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("source.zip");
+        fs::write(&archive, bytes).unwrap();
+        let unpacked = extract_zip(&archive, &dir.path().join("source"), max_bytes);
+        (dir, unpacked)
+    }
+
+    #[tokio::test]
+    async fn zip_served_locally_is_downloaded_and_unpacked() {
+        let url = serve(zip(&[("app-main/package.json", "{}"), ("app-main/src/index.js", "console.log(1)")]));
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("source.zip");
+
+        download(&format!("{}/app/archive/main.zip", url), &archive, 1024 * 1024, Duration::from_secs(5)).await.unwrap();
+        let source = extract_zip(&archive, &dir.path().join("source"), 1024 * 1024).unwrap();
+
+        /* codeload's single top-level directory is what gets built */
+        assert_eq!(source, dir.path().join("source/app-main"));
+        assert_eq!(fs::read_to_string(source.join("src/index.js")).unwrap(), "console.log(1)");
+    }
+
+    #[tokio::test]
+    async fn download_stops_past_the_limit() {
+        let url = serve(vec![0; 4096]);
+        let dir = tempfile::tempdir().unwrap();
+
+        let result = download(&url, &dir.path().join("source.zip"), 1024, Duration::from_secs(5)).await;
+        assert!(matches!(result, Err(ArchiveError::TooLarge(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn several_top_level_entries_build_the_whole_dir() {
+        let (dir, source) = unpack(&zip(&[("package.json", "{}"), ("src/index.js", "")]), 1024);
+        assert_eq!(source.unwrap(), dir.path().join("source"));
+    }
+
+    #[test]
+    fn entries_escaping_the_dir_are_refused() {
+        let (dir, source) = unpack(&zip(&[("../escaped", "gotcha")]), 1024);
+        assert!(matches!(source, Err(ArchiveError::Invalid(_))), "{:?}", source);
+        assert!(!dir.path().join("escaped").exists());
+    }
+
+    #[test]
+    fn symlinks_are_refused() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.add_symlink("passwd", "/etc/passwd", FileOptions::default()).unwrap();
+        let bytes = zip.finish().unwrap().into_inner();
+
+        let (_dir, source) = unpack(&bytes, 1024);
+        match source {
+            Err(ArchiveError::Invalid(message)) => assert!(message.contains("symlink"), "{}", message),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn size_limit_applies_while_unpacking() {
+        let (_dir, source) = unpack(&zip(&[("big", &"a".repeat(2048))]), 1024);
+        assert!(matches!(source, Err(ArchiveError::TooLarge(_))), "{:?}", source);
+    }
+}
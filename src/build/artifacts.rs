@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// A file nixpacks wrote to a build's `out_dir`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Artifact {
+    /* relative to the build's artifact dir */
+    pub path: String,
+    pub size: u64,
+}
+
+/// Checks a requested `out_dir` is a plain relative path, it's placed
+/// under the build's own artifact dir.
+pub fn validate_out_dir(out_dir: &str) -> Result<(), String> {
+    let path = Path::new(out_dir);
+    if out_dir.is_empty() || !path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("out_dir must be a relative path inside the artifact dir, got {}", out_dir));
+    }
+    Ok(())
+}
+
+/// `<base>/<build id>`, where everything a build produced is kept.
+pub fn build_dir(base: &Path, build_id: &str) -> PathBuf {
+    base.join(build_id)
+}
+
+/// Every file under `dir`, sorted by path.
+pub fn list(dir: &Path) -> std::io::Result<Vec<Artifact>> {
+    let mut artifacts = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                let path = entry.path();
+                artifacts.push(Artifact {
+                    path: path.strip_prefix(dir).unwrap_or(&path).display().to_string(),
+                    size: entry.metadata()?.len(),
+                });
+            }
+        }
+    }
+
+    artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(artifacts)
+}
+
+/// Removes the artifact dirs under `base` that `keep` says to drop, returning
+/// the build ids that were pruned.
+pub fn prune(base: &Path, keep: impl Fn(&str) -> bool) -> std::io::Result<Vec<String>> {
+    let mut pruned = Vec::new();
+
+    for entry in fs::read_dir(base)? {
+        let entry = entry?;
+        let build_id = entry.file_name().to_string_lossy().to_string();
+        if !entry.file_type()?.is_dir() || keep(&build_id) {
+            continue;
+        }
+
+        fs::remove_dir_all(entry.path())?;
+        pruned.push(build_id);
+    }
+
+    Ok(pruned)
+}
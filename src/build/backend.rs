@@ -0,0 +1,310 @@
+use async_trait::async_trait;
+use nixpacks::create_docker_image;
+use serde::Serialize;
+use nixpacks::nixpacks::builder::docker::DockerBuilderOptions as NixpacksOptions;
+use nixpacks::nixpacks::plan::generator::GeneratePlanOptions;
+
+use crate::build::dockerfile;
+use crate::build::sandbox::{cli_build, SandboxLimits};
+use crate::build::secrets::{BuildSecrets, SecretFiles};
+use crate::build::workdir;
+
+use tokio::process::Command;
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+/// What turns a planned repo into an image.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Docker,
+    Podman,
+    Buildah,
+}
+
+impl Backend {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "docker" => Some(Backend::Docker),
+            "podman" => Some(Backend::Podman),
+            "buildah" => Some(Backend::Buildah),
+            _ => None,
+        }
+    }
+
+    /// The cli used to build and push images.
+    pub fn cli(&self) -> &'static str {
+        match self {
+            Backend::Docker => "docker",
+            Backend::Podman => "podman",
+            Backend::Buildah => "buildah",
+        }
+    }
+}
+
+/// Everything the image build step gets from the pipeline.
+pub struct BuildJob<'a> {
+    pub repo_dir: &'a str,
+    pub envs: Vec<&'a str>,
+    pub plan_options: &'a GeneratePlanOptions,
+    pub nixpack_options: NixpacksOptions,
+    pub tags: &'a [String],
+    pub limits: &'a SandboxLimits,
+    pub secrets: &'a BuildSecrets,
+    /* docker network the build's RUN steps are attached to */
+    pub network: Option<&'a str>,
+    /* build contexts (and secrets, without /dev/shm) are written under here */
+    pub workdir_base: &'a Path,
+    /* remote daemon the image is built on, docker only, it's loaded back here after */
+    pub docker_host: Option<&'a str>,
+    /* a failure has to say which step failed, e.g. to tell failing tests apart */
+    pub step_errors: bool,
+}
+
+#[async_trait]
+pub trait ImageBuilder: Send + Sync {
+    fn backend(&self) -> Backend;
+
+    /// Builds the image, returning the Dockerfile nixpacks generated for it
+    /// when it could be captured.
+    async fn build(&self, job: BuildJob<'_>) -> Result<Option<String>, String>;
+}
+
+/// nixpacks building through the docker daemon, the default.
+pub struct DockerBuilder;
+
+#[async_trait]
+impl ImageBuilder for DockerBuilder {
+    fn backend(&self) -> Backend {
+        Backend::Docker
+    }
+
+    async fn build(&self, job: BuildJob<'_>) -> Result<Option<String>, String> {
+        if let Some(docker_host) = job.docker_host {
+            let tags = job.tags;
+            let dockerfile = build_from_context(Backend::Docker, job).await?;
+            load_from_host(docker_host, tags).await?;
+            return Ok(dockerfile);
+        }
+
+        /* nixpacks can do plain builds itself, limits, secrets, networks and step errors need our own docker build */
+        if job.limits.is_empty() && job.secrets.is_empty() && job.network.is_none() && !job.step_errors {
+            let dockerfile = generate_dockerfile(&job).await;
+            create_docker_image(job.repo_dir, job.envs, job.plan_options, &job.nixpack_options)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(dockerfile)
+        } else {
+            build_from_context(Backend::Docker, job).await
+        }
+    }
+}
+
+/// Daemonless builds for rootless hosts: nixpacks only writes the build
+/// context and podman or buildah builds it.
+pub struct CliBuilder {
+    backend: Backend,
+}
+
+#[async_trait]
+impl ImageBuilder for CliBuilder {
+    fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    async fn build(&self, job: BuildJob<'_>) -> Result<Option<String>, String> {
+        build_from_context(self.backend, job).await
+    }
+}
+
+pub fn image_builder(backend: Backend) -> Box<dyn ImageBuilder> {
+    match backend {
+        Backend::Docker => Box::new(DockerBuilder),
+        Backend::Podman | Backend::Buildah => Box::new(CliBuilder { backend }),
+    }
+}
+
+/// The Dockerfile nixpacks would build `job` from, by having it write out the
+/// build context on the side. Only used where nixpacks builds itself and
+/// throws the context away, `None` if that failed.
+async fn generate_dockerfile(job: &BuildJob<'_>) -> Option<String> {
+    let temp_dir = workdir::create(job.workdir_base, "dockerfile").ok()?;
+    let mut options = job.nixpack_options.clone();
+    options.out_dir = Some(temp_dir.path().display().to_string());
+
+    match create_docker_image(job.repo_dir, job.envs.clone(), job.plan_options, &options).await {
+        Ok(_) => dockerfile::read_context(temp_dir.path()),
+        Err(e) => {
+            eprintln!("Failed to capture the Dockerfile: {}", e);
+            None
+        }
+    }
+}
+
+/// Has nixpacks write out the build context and runs the backend's build
+/// on it ourselves, so the sandbox limits, secrets and network can be passed
+/// along.
+async fn build_from_context(backend: Backend, mut job: BuildJob<'_>) -> Result<Option<String>, String> {
+    /* a requested out_dir keeps the context as the build's artifacts, otherwise it's thrown away */
+    let _temp_dir;
+    let context_dir = match &job.nixpack_options.out_dir {
+        Some(out_dir) => PathBuf::from(out_dir),
+        None => {
+            let temp_dir = workdir::create(job.workdir_base, "context")
+                .map_err(|e| format!("Failed to create build context dir: {}", e))?;
+            let path = temp_dir.path().to_path_buf();
+            _temp_dir = temp_dir;
+            path
+        }
+    };
+    job.nixpack_options.out_dir = Some(context_dir.display().to_string());
+
+    create_docker_image(job.repo_dir, job.envs, job.plan_options, &job.nixpack_options)
+        .await
+        .map_err(|e| e.to_string())?;
+    /* read before secret mounts are added, as nixpacks generated it */
+    let dockerfile = dockerfile::read_context(&context_dir);
+
+    /* only around for the build itself */
+    let secrets = if job.secrets.is_empty() {
+        None
+    } else {
+        let secrets = SecretFiles::write(job.secrets, job.workdir_base)
+            .map_err(|e| format!("Failed to write build secrets: {}", e))?;
+        secrets.mount_in(&context_dir.join(".nixpacks").join("Dockerfile"))
+            .map_err(|e| format!("Failed to mount build secrets: {}", e))?;
+        Some(secrets)
+    };
+
+    cli_build(
+        backend.cli(),
+        &context_dir,
+        job.tags,
+        &job.nixpack_options.labels,
+        &job.nixpack_options.platform,
+        job.nixpack_options.no_cache,
+        job.limits,
+        job.network,
+        secrets.as_ref(),
+        job.docker_host,
+    ).await?;
+
+    Ok(dockerfile)
+}
+
+/// Copies `tags`, built on the daemon at `docker_host`, to the local one with
+/// `docker save | docker load`, where everything after the build expects the
+/// image. The remote copy is removed again, its layers stay in the remote's
+/// build cache.
+async fn load_from_host(docker_host: &str, tags: &[String]) -> Result<(), String> {
+    let mut save = Command::new("docker")
+        .env("DOCKER_HOST", docker_host)
+        .arg("save")
+        .args(tags)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to run docker save on {}: {}", docker_host, e))?;
+    /* piped above */
+    let image: Stdio = save.stdout.take().unwrap().try_into()
+        .map_err(|e| format!("Failed to read the image from {}: {}", docker_host, e))?;
+
+    let loaded = Command::new("docker")
+        .arg("load")
+        .stdin(image)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run docker load: {}", e))?;
+    let saved = save.wait_with_output()
+        .await
+        .map_err(|e| format!("Failed to run docker save on {}: {}", docker_host, e))?;
+
+    if !saved.status.success() {
+        return Err(format!("Failed to copy the image from {}: {}", docker_host, String::from_utf8_lossy(&saved.stderr).trim()));
+    }
+    if !loaded.status.success() {
+        return Err(format!("Failed to load the image built on {}: {}", docker_host, String::from_utf8_lossy(&loaded.stderr).trim()));
+    }
+
+    let removed = Command::new("docker")
+        .env("DOCKER_HOST", docker_host)
+        .arg("rmi")
+        .args(tags)
+        .output()
+        .await;
+    if !removed.is_ok_and(|output| output.status.success()) {
+        eprintln!("Failed to remove {} from {}", tags.join(", "), docker_host);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    /* records what it was asked to build instead of building */
+    struct StubBuilder {
+        backend: Backend,
+        built: Mutex<Vec<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl ImageBuilder for StubBuilder {
+        fn backend(&self) -> Backend {
+            self.backend
+        }
+
+        async fn build(&self, job: BuildJob<'_>) -> Result<Option<String>, String> {
+            self.built.lock().unwrap().push(job.tags.to_vec());
+            Ok(Some("FROM scratch".to_string()))
+        }
+    }
+
+    #[test]
+    fn backends_parse() {
+        assert_eq!(Backend::parse("Docker"), Some(Backend::Docker));
+        assert_eq!(Backend::parse("podman"), Some(Backend::Podman));
+        assert_eq!(Backend::parse("buildah"), Some(Backend::Buildah));
+        assert_eq!(Backend::parse("kaniko"), None);
+    }
+
+    #[test]
+    fn each_backend_gets_its_builder_and_cli() {
+        for backend in [Backend::Docker, Backend::Podman, Backend::Buildah] {
+            assert_eq!(image_builder(backend).backend(), backend);
+        }
+        assert_eq!(Backend::Docker.cli(), "docker");
+        assert_eq!(Backend::Podman.cli(), "podman");
+        assert_eq!(Backend::Buildah.cli(), "buildah");
+    }
+
+    #[tokio::test]
+    async fn builds_go_through_the_selected_builder() {
+        let stub = StubBuilder { backend: Backend::Podman, built: Mutex::new(Vec::new()) };
+        let builder: &dyn ImageBuilder = &stub;
+
+        let tags = vec!["app:latest".to_string()];
+        let job = BuildJob {
+            repo_dir: ".",
+            envs: Vec::new(),
+            plan_options: &GeneratePlanOptions::default(),
+            nixpack_options: NixpacksOptions::default(),
+            tags: &tags,
+            limits: &SandboxLimits::default(),
+            secrets: &BuildSecrets::default(),
+            network: None,
+            workdir_base: Path::new("."),
+            docker_host: None,
+            step_errors: false,
+        };
+
+        assert_eq!(builder.build(job).await, Ok(Some("FROM scratch".to_string())));
+        assert_eq!(builder.backend().cli(), "podman");
+        assert_eq!(*stub.built.lock().unwrap(), [tags]);
+    }
+}
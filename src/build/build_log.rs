@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+
+use crate::logs::redact::Redactor;
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// How much of a build's output is captured, each level including the ones
+/// before it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /* failures and the final status */
+    Quiet,
+    /* plus each step and post-build output */
+    #[default]
+    Normal,
+    /* plus the plan and the generated Dockerfile */
+    Verbose,
+}
+
+impl LogLevel {
+    /// The level `build_options.quiet`/`verbose` ask for, verbose winning.
+    pub fn from_options(quiet: bool, verbose: bool) -> Self {
+        match (quiet, verbose) {
+            (_, true) => LogLevel::Verbose,
+            (true, false) => LogLevel::Quiet,
+            (false, false) => LogLevel::Normal,
+        }
+    }
+}
+
+/// Output forge captures for one build, appended to `<dir>/<build id>.log`.
+#[derive(Debug, Clone)]
+pub struct BuildLog {
+    path: PathBuf,
+    capture: bool,
+    level: LogLevel,
+    redactor: Redactor,
+}
+
+impl BuildLog {
+    pub fn new(dir: &Path, build_id: &str) -> Self {
+        BuildLog {
+            path: dir.join(format!("{}.log", build_id)),
+            capture: true,
+            level: LogLevel::default(),
+            redactor: Redactor::default(),
+        }
+    }
+
+    /// Only captures text at or below `level`, and nothing at all without
+    /// `capture`.
+    pub fn with_level(self, capture: bool, level: LogLevel) -> Self {
+        BuildLog { capture, level, ..self }
+    }
+
+    /// Masks what `redactor` finds before anything is written.
+    pub fn with_redactor(self, redactor: Redactor) -> Self {
+        BuildLog { redactor, ..self }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `text` if this log captures `level`.
+    pub fn append(&self, level: LogLevel, text: &str) -> std::io::Result<()> {
+        if !self.capture || level > self.level {
+            return Ok(());
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let text = self.redactor.redact(text);
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(text.as_bytes())?;
+        if !text.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    /// Bytes captured so far, 0 before anything was written.
+    pub fn length(&self) -> std::io::Result<u64> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// What was captured from byte `offset` on, with the log's total length
+    /// so far. `None` when `offset` is past the end. A log nothing was
+    /// written to yet reads as empty.
+    pub fn read_from(&self, offset: u64) -> std::io::Result<Option<(Vec<u8>, u64)>> {
+        let mut file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok((offset == 0).then_some((Vec::new(), 0))),
+            Err(e) => return Err(e),
+        };
+
+        /* read up to the length seen now, the build may still be appending */
+        let length = file.metadata()?.len();
+        if offset > length {
+            return Ok(None);
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = Vec::with_capacity((length - offset) as usize);
+        file.take(length - offset).read_to_end(&mut bytes)?;
+        Ok(Some((bytes, length)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* one line at each level, captured by a log at `level` */
+    fn captured(capture: bool, level: LogLevel) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let log = BuildLog::new(dir.path(), "build").with_level(capture, level);
+        log.append(LogLevel::Quiet, "Build failed").unwrap();
+        log.append(LogLevel::Normal, "Cloned").unwrap();
+        log.append(LogLevel::Verbose, "Plan").unwrap();
+        String::from_utf8(log.read_from(0).unwrap().unwrap().0).unwrap()
+    }
+
+    #[test]
+    fn quiet_captures_only_failures() {
+        assert_eq!(captured(true, LogLevel::Quiet), "Build failed\n");
+    }
+
+    #[test]
+    fn normal_captures_steps() {
+        assert_eq!(captured(true, LogLevel::Normal), "Build failed\nCloned\n");
+    }
+
+    #[test]
+    fn verbose_captures_everything() {
+        assert_eq!(captured(true, LogLevel::Verbose), "Build failed\nCloned\nPlan\n");
+    }
+
+    #[test]
+    fn nothing_is_captured_when_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = BuildLog::new(dir.path(), "build").with_level(false, LogLevel::Verbose);
+        log.append(LogLevel::Quiet, "Build failed").unwrap();
+        assert!(!log.path().exists());
+        assert_eq!(log.length().unwrap(), 0);
+    }
+
+    #[test]
+    fn build_options_map_to_a_level() {
+        assert_eq!(LogLevel::from_options(false, false), LogLevel::Normal);
+        assert_eq!(LogLevel::from_options(true, false), LogLevel::Quiet);
+        assert_eq!(LogLevel::from_options(false, true), LogLevel::Verbose);
+        assert_eq!(LogLevel::from_options(true, true), LogLevel::Verbose);
+    }
+
+    #[test]
+    fn known_secret_is_masked_in_the_stored_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let redactor = crate::logs::redact::RedactConfig { patterns: Vec::new(), env_names: vec!["TOKEN".to_string()] }
+            .redactor(["NPM_TOKEN=npm_s3cr3t"]);
+        let log = BuildLog::new(dir.path(), "build").with_redactor(redactor);
+
+        log.append(LogLevel::Normal, "$ npm publish --token npm_s3cr3t").unwrap();
+
+        let stored = std::fs::read_to_string(log.path()).unwrap();
+        assert_eq!(stored, "$ npm publish --token ***\n");
+    }
+}
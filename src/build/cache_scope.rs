@@ -0,0 +1,29 @@
+use serde::Serialize;
+
+/// Which builds share the layer cache of a cache key.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheScope {
+    /* every build of the key, whatever branch it's from */
+    Shared,
+    /* builds of the same branch, so feature branches don't poison main's cache */
+    Branch,
+}
+
+impl CacheScope {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "shared" | "none" => Some(CacheScope::Shared),
+            "branch" => Some(CacheScope::Branch),
+            _ => None,
+        }
+    }
+}
+
+/// `base` scoped to `branch`, characters a cache id can't have become `-`.
+pub fn scoped_key(base: &str, branch: &str) -> String {
+    let branch: String = branch.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') { c } else { '-' })
+        .collect();
+    format!("{}-{}", base, branch)
+}
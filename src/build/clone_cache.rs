@@ -0,0 +1,130 @@
+use git2::build::RepoBuilder;
+use git2::{ErrorClass, FetchPrune, Repository};
+use sha1::{Digest, Sha1};
+use tracing::warn;
+
+use crate::build::disk::dir_size;
+use crate::build::git::{self, fetch_options};
+use crate::build::lock::KeyedLock;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/* touched on every use, its mtime orders mirrors for eviction */
+const LAST_USED: &str = "forge-last-used";
+
+/// Bare mirrors of the repos we build, so repeat builds fetch what changed
+/// instead of cloning from scratch.
+pub struct CloneCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /* keyed by mirror, held while it's fetched, checked out or evicted */
+    locks: KeyedLock,
+}
+
+impl CloneCache {
+    pub fn new(dir: PathBuf, max_mb: u64) -> Self {
+        CloneCache {
+            dir,
+            max_bytes: max_mb * 1024 * 1024,
+            locks: KeyedLock::new(),
+        }
+    }
+
+    /// Brings the mirror of `url` up to date and checks it out into `dir`.
+    /// A mirror that can't be used is dropped and `url` cloned directly.
+    pub async fn checkout(&self, url: &str, dir: &str) -> Result<Repository, git2::Error> {
+        let key = hex::encode(Sha1::digest(url.as_bytes()));
+        let mirror = self.dir.join(format!("{}.git", key));
+
+        let result = {
+            let _guard = self.locks.lock(&key).await;
+
+            match update_mirror(url, &mirror).and_then(|_| checkout_from(&mirror, url, dir)) {
+                Ok(repo) => Ok(repo),
+                /* the remote itself is the problem, a fresh clone won't do better */
+                Err(e) if matches!(e.class(), ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssh) => Err(e),
+                Err(e) => {
+                    warn!("Clone cache for {} is unusable, cloning from scratch: {}", url, e);
+                    let _ = fs::remove_dir_all(&mirror);
+                    let _ = fs::remove_dir_all(dir);
+                    fs::create_dir_all(dir).map_err(|e| git2::Error::from_str(&e.to_string()))?;
+                    git::clone(url, dir)
+                }
+            }
+        };
+
+        self.evict().await;
+        result
+    }
+
+    /// Removes the least recently used mirrors until the cache fits in its
+    /// size limit.
+    async fn evict(&self) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read clone cache {}: {}", self.dir.display(), e);
+                return;
+            }
+        };
+
+        let mut mirrors: Vec<(SystemTime, String, PathBuf, u64)> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .map(|path| {
+                let key = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+                let last_used = fs::metadata(path.join(LAST_USED)).and_then(|m| m.modified()).unwrap_or(UNIX_EPOCH);
+                let size = dir_size(&path);
+                (last_used, key, path, size)
+            })
+            .collect();
+
+        let mut total: u64 = mirrors.iter().map(|(_, _, _, size)| size).sum();
+        mirrors.sort_by_key(|(last_used, _, _, _)| *last_used);
+
+        for (_, key, path, size) in mirrors {
+            if total <= self.max_bytes {
+                break;
+            }
+
+            let _guard = self.locks.lock(&key).await;
+            match fs::remove_dir_all(&path) {
+                Ok(_) => total -= size,
+                Err(e) => warn!("Failed to evict {} from the clone cache: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+/// Creates the mirror on first use, then fetches every branch and tag into it.
+fn update_mirror(url: &str, mirror: &Path) -> Result<(), git2::Error> {
+    let repo = if mirror.exists() {
+        Repository::open_bare(mirror)?
+    } else {
+        Repository::init_bare(mirror)?
+    };
+
+    let mut options = fetch_options();
+    options.prune(FetchPrune::On);
+
+    let mut remote = repo.remote_anonymous(url)?;
+    remote.fetch(&["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"], Some(&mut options), None)?;
+
+    /* so the checkout lands on the remote's default branch, like a clone would */
+    let head = remote.default_branch()?;
+    repo.set_head(head.as_str().ok_or_else(|| git2::Error::from_str("Default branch name isn't utf-8"))?)?;
+
+    fs::write(mirror.join(LAST_USED), b"").map_err(|e| git2::Error::from_str(&e.to_string()))
+}
+
+fn checkout_from(mirror: &Path, url: &str, dir: &str) -> Result<Repository, git2::Error> {
+    let mirror = mirror.to_str().ok_or_else(|| git2::Error::from_str("Clone cache path isn't utf-8"))?;
+    let repo = RepoBuilder::new().clone(mirror, Path::new(dir))?;
+
+    /* point origin back at the real remote, relative submodule urls resolve against it */
+    repo.remote_set_url("origin", url)?;
+    Ok(repo)
+}
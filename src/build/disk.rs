@@ -0,0 +1,36 @@
+use tracing::info;
+
+use std::fs;
+use std::path::Path;
+
+/// Free space on the filesystem holding `path`, in megabytes.
+pub fn free_space_mb(path: &Path) -> std::io::Result<u64> {
+    Ok(fs2::available_space(path)? / 1024 / 1024)
+}
+
+/// Refuses to start a build when the working directory filesystem is lower
+/// on space than `min_free_mb`, rather than letting it fail halfway through.
+pub fn preflight(workdir: &Path, min_free_mb: u64) -> Result<(), String> {
+    let free_mb = free_space_mb(workdir).map_err(|e| format!("Failed to check free space on {}: {}", workdir.display(), e))?;
+    info!("{} MB free on {}", free_mb, workdir.display());
+
+    if free_mb < min_free_mb {
+        return Err(format!("Only {} MB free on {}, at least {} MB is required to build", free_mb, workdir.display(), min_free_mb));
+    }
+
+    Ok(())
+}
+
+/// Bytes used by the files under `path`, symlinks not followed.
+pub fn dir_size(path: &Path) -> u64 {
+    fs::read_dir(path)
+        .map(|entries| entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            })
+            .sum())
+        .unwrap_or(0)
+}
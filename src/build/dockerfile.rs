@@ -0,0 +1,40 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Dockerfiles larger than this are stored gzipped.
+pub const COMPRESS_OVER: usize = 16 * 1024;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The Dockerfile nixpacks wrote into the build context at `context_dir`.
+pub fn read_context(context_dir: &Path) -> Option<String> {
+    std::fs::read_to_string(context_dir.join(".nixpacks").join("Dockerfile")).ok()
+}
+
+/// `dockerfile` as stored on the build, gzipped past `COMPRESS_OVER`.
+pub fn encode(dockerfile: &str) -> Vec<u8> {
+    if dockerfile.len() <= COMPRESS_OVER {
+        return dockerfile.as_bytes().to_vec();
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    /* writes to a Vec don't fail */
+    encoder.write_all(dockerfile.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Reverses `encode`. A Dockerfile is text, so it can't start with the gzip
+/// magic bytes by accident.
+pub fn decode(stored: &[u8]) -> Result<String, String> {
+    if !stored.starts_with(&GZIP_MAGIC) {
+        return String::from_utf8(stored.to_vec()).map_err(|e| e.to_string());
+    }
+
+    let mut dockerfile = String::new();
+    GzDecoder::new(stored).read_to_string(&mut dockerfile).map_err(|e| e.to_string())?;
+    Ok(dockerfile)
+}
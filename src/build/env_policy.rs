@@ -0,0 +1,88 @@
+use serde::Serialize;
+use tracing::warn;
+
+/// Variables that change how the build's programs are found, loaded or
+/// run. They're refused unless `ALLOWED_BUILD_ENVS` names them.
+pub const DANGEROUS_ENVS: [&str; 9] = ["PATH", "LD_PRELOAD", "LD_LIBRARY_PATH", "LD_AUDIT", "BASH_ENV", "ENV", "IFS", "PS4", "SHELLOPTS"];
+
+/// Which env var names a build may set.
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct EnvPolicy {
+    /* only these may be set, any name when empty */
+    pub allowed: Vec<String>,
+    pub denied: Vec<String>,
+    /* leave refused envs out of the build instead of refusing the build */
+    pub drop_denied: bool,
+}
+
+impl EnvPolicy {
+    /// Why `name` may not be set, `None` when it may.
+    fn refusal(&self, name: &str) -> Option<&'static str> {
+        let allowed = self.allowed.iter().any(|allowed| allowed == name);
+
+        if self.denied.iter().any(|denied| denied == name) {
+            Some("is denied by DENIED_BUILD_ENVS")
+        } else if !self.allowed.is_empty() && !allowed {
+            Some("isn't in ALLOWED_BUILD_ENVS")
+        } else if DANGEROUS_ENVS.contains(&name) && !allowed {
+            Some("can change how the build runs, it needs to be named in ALLOWED_BUILD_ENVS")
+        } else {
+            None
+        }
+    }
+
+    /// `envs` (`NAME=value`) the policy lets through. A refused variable is
+    /// an error naming it, or with `drop_denied` left out.
+    pub fn apply(&self, envs: Vec<String>) -> Result<Vec<String>, String> {
+        let mut kept = Vec::with_capacity(envs.len());
+
+        for env in envs {
+            let name = env.split('=').next().unwrap_or_default();
+            match self.refusal(name) {
+                None => kept.push(env),
+                Some(reason) if self.drop_denied => warn!("Dropped env {} from a build, it {}", name, reason),
+                Some(reason) => return Err(format!("Env {} {}", name, reason)),
+            }
+        }
+
+        Ok(kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envs(envs: &[&str]) -> Vec<String> {
+        envs.iter().map(|env| env.to_string()).collect()
+    }
+
+    #[test]
+    fn ordinary_envs_are_allowed() {
+        let envs = envs(&["NODE_ENV=production", "EMPTY="]);
+        assert_eq!(EnvPolicy::default().apply(envs.clone()).unwrap(), envs);
+    }
+
+    #[test]
+    fn denied_and_unlisted_envs_are_refused_by_name() {
+        let policy = EnvPolicy { denied: envs(&["DEBUG"]), ..Default::default() };
+        assert_eq!(policy.apply(envs(&["DEBUG=1"])).unwrap_err(), "Env DEBUG is denied by DENIED_BUILD_ENVS");
+
+        let policy = EnvPolicy { allowed: envs(&["NODE_ENV"]), ..Default::default() };
+        assert_eq!(policy.apply(envs(&["NODE_ENV=production", "PORT=80"])).unwrap_err(), "Env PORT isn't in ALLOWED_BUILD_ENVS");
+    }
+
+    #[test]
+    fn dangerous_envs_need_to_be_allowed() {
+        assert!(EnvPolicy::default().apply(envs(&["LD_PRELOAD=/tmp/evil.so"])).unwrap_err().starts_with("Env LD_PRELOAD can change how the build runs"));
+
+        let policy = EnvPolicy { allowed: envs(&["PATH"]), ..Default::default() };
+        assert_eq!(policy.apply(envs(&["PATH=/opt/bin"])).unwrap(), envs(&["PATH=/opt/bin"]));
+    }
+
+    #[test]
+    fn refused_envs_can_be_dropped() {
+        let policy = EnvPolicy { drop_denied: true, ..Default::default() };
+        assert_eq!(policy.apply(envs(&["PATH=/tmp", "NODE_ENV=production"])).unwrap(), envs(&["NODE_ENV=production"]));
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path};
+
+/* per build, they're meant for small config files and stamps */
+const MAX_FILES: usize = 16;
+const MAX_CONTENT_BYTES: usize = 64 * 1024;
+
+/// A file written into the checkout before it's planned, e.g. a version stamp.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeneratedFile {
+    /* relative to the repo root */
+    pub path: String,
+    /* {sha}, {branch} and the like are filled in, other braces are left alone */
+    pub content: String,
+    /* replace a file the repo already has */
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Checks `files` can be written without leaving the repo, for validating
+/// the request.
+pub fn check(files: &[GeneratedFile]) -> Result<(), String> {
+    if files.len() > MAX_FILES {
+        return Err(format!("generated_files may have at most {} entries", MAX_FILES));
+    }
+
+    for (i, file) in files.iter().enumerate() {
+        let path = Path::new(&file.path);
+        let relative = !file.path.is_empty() && path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !relative {
+            return Err(format!("generated_files paths must be relative paths inside the repo, got {}", file.path));
+        }
+        if path.components().any(|component| component.as_os_str() == ".git") {
+            return Err(format!("generated_files can't write into .git, got {}", file.path));
+        }
+        if file.content.len() > MAX_CONTENT_BYTES {
+            return Err(format!("generated file {} is longer than {} bytes", file.path, MAX_CONTENT_BYTES));
+        }
+        if files[..i].iter().any(|other| Path::new(&other.path) == path) {
+            return Err(format!("generated file {} is listed twice", file.path));
+        }
+    }
+
+    Ok(())
+}
+
+/// `content` with every `{name}` of `vars` replaced by its value.
+pub fn render(content: &str, vars: &BTreeMap<String, String>) -> String {
+    vars.iter().fold(content.to_string(), |content, (name, value)| content.replace(&format!("{{{}}}", name), value))
+}
+
+/// Writes `files` into `repo_dir`, returning their paths. A file the repo
+/// already has is only replaced with `force`, and nothing is written through
+/// a symlink that leads out of the repo.
+pub fn write(repo_dir: &Path, files: &[GeneratedFile], vars: &BTreeMap<String, String>) -> Result<Vec<String>, String> {
+    let root = repo_dir.canonicalize().map_err(|e| format!("Can't resolve {}: {}", repo_dir.display(), e))?;
+    let mut written = Vec::with_capacity(files.len());
+
+    for file in files {
+        let target = root.join(&file.path);
+        create_parents(&root, &file.path)?;
+
+        if let Ok(existing) = fs::symlink_metadata(&target) {
+            if existing.is_dir() {
+                return Err(format!("Can't generate {}, it's a directory in the repo", file.path));
+            }
+            if !file.force {
+                return Err(format!("Can't generate {}, the repo already has it, set force to replace it", file.path));
+            }
+            /* a symlink is replaced, not written through */
+            fs::remove_file(&target).map_err(|e| format!("Failed to replace {}: {}", file.path, e))?;
+        }
+
+        fs::write(&target, render(&file.content, vars)).map_err(|e| format!("Failed to write {}: {}", file.path, e))?;
+        written.push(file.path.clone());
+    }
+
+    Ok(written)
+}
+
+/// Creates the directories `path` sits in under `root`, one at a time so a
+/// symlink among them is caught before anything is created outside the repo.
+fn create_parents(root: &Path, path: &str) -> Result<(), String> {
+    let parents = match Path::new(path).parent() {
+        Some(parents) => parents,
+        None => return Ok(()),
+    };
+
+    let mut dir = root.to_path_buf();
+    for component in parents.components() {
+        dir.push(component);
+        match fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                if !dir.canonicalize().is_ok_and(|target| target.starts_with(root) && target.is_dir()) {
+                    return Err(format!("Can't generate {}, {} leads out of the repo", path, dir.strip_prefix(root).unwrap_or(&dir).display()));
+                }
+            },
+            Ok(metadata) if !metadata.is_dir() => {
+                return Err(format!("Can't generate {}, {} isn't a directory", path, dir.strip_prefix(root).unwrap_or(&dir).display()));
+            },
+            Ok(_) => {},
+            Err(_) => fs::create_dir(&dir).map_err(|e| format!("Failed to create the directory of {}: {}", path, e))?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, content: &str, force: bool) -> GeneratedFile {
+        GeneratedFile { path: path.to_string(), content: content.to_string(), force }
+    }
+
+    fn vars() -> BTreeMap<String, String> {
+        BTreeMap::from([("sha".to_string(), "abc123".to_string()), ("branch".to_string(), "main".to_string())])
+    }
+
+    #[test]
+    fn file_appears_in_the_build_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [file("config/version.json", r#"{ "sha": "{sha}", "branch": "{branch}", "other": "{unknown}" }"#, false)];
+        check(&files).unwrap();
+
+        let written = write(dir.path(), &files, &vars()).unwrap();
+
+        assert_eq!(written, ["config/version.json"]);
+        let content = fs::read_to_string(dir.path().join("config/version.json")).unwrap();
+        assert_eq!(content, r#"{ "sha": "abc123", "branch": "main", "other": "{unknown}" }"#);
+    }
+
+    #[test]
+    fn paths_outside_the_repo_are_refused() {
+        for path in ["../escape", "/etc/passwd", "", ".git/hooks/pre-commit", "a/../../b"] {
+            assert!(check(&[file(path, "x", false)]).is_err(), "{} was allowed", path);
+        }
+        assert!(check(&[file("VERSION", "1", false), file("VERSION", "2", false)]).is_err());
+    }
+
+    #[test]
+    fn existing_files_need_force() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("package.json"), "{}").unwrap();
+
+        let error = write(dir.path(), &[file("package.json", "replaced", false)], &vars()).unwrap_err();
+        assert!(error.contains("set force to replace it"), "{}", error);
+        assert_eq!(fs::read_to_string(dir.path().join("package.json")).unwrap(), "{}");
+
+        write(dir.path(), &[file("package.json", "replaced", true)], &vars()).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("package.json")).unwrap(), "replaced");
+    }
+
+    #[test]
+    fn symlinks_out_of_the_repo_arent_followed() {
+        let outside = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("linked")).unwrap();
+
+        let error = write(dir.path(), &[file("linked/stamp", "x", true)], &vars()).unwrap_err();
+        assert!(error.contains("leads out of the repo"), "{}", error);
+        assert!(!outside.path().join("stamp").exists());
+    }
+}
@@ -0,0 +1,203 @@
+use git2::build::RepoBuilder;
+use git2::{Cred, CredentialType, FetchOptions, Oid, RemoteCallbacks, Repository, SubmoduleUpdateOptions};
+
+/// Fetch options shared by clones and submodule updates, so private
+/// submodules authenticate the same way as the repository itself: ssh-agent
+/// for ssh remotes, the git credential helper for https.
+pub fn fetch_options<'a>() -> FetchOptions<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(|url, username, allowed| {
+        if allowed.contains(CredentialType::SSH_KEY) {
+            Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            Cred::credential_helper(&git2::Config::open_default()?, url, username)
+        } else {
+            Cred::default()
+        }
+    });
+
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks);
+    options
+}
+
+pub fn clone(url: &str, dir: &str) -> Result<Repository, git2::Error> {
+    RepoBuilder::new()
+        .fetch_options(fetch_options())
+        .clone(url, std::path::Path::new(dir))
+}
+
+/// Initializes and checks out every submodule, recursing into nested ones.
+pub fn update_submodules(repo: &Repository) -> Result<(), String> {
+    for mut submodule in repo.submodules().map_err(|e| format!("Failed to list submodules: {}", e))? {
+        let name = submodule.name().unwrap_or("<unnamed>").to_string();
+
+        let mut options = SubmoduleUpdateOptions::new();
+        options.fetch(fetch_options());
+
+        submodule.update(true, Some(&mut options))
+            .map_err(|e| format!("Failed to fetch submodule {}: {}", name, e))?;
+
+        let nested = submodule.open().map_err(|e| format!("Failed to open submodule {}: {}", name, e))?;
+        update_submodules(&nested)?;
+    }
+
+    Ok(())
+}
+
+/// Checks out `sha` with a detached HEAD.
+pub fn checkout_commit(repo: &Repository, sha: &str) -> Result<(), String> {
+    let commit = Oid::from_str(sha)
+        .and_then(|oid| repo.find_commit(oid))
+        .map_err(|e| format!("Commit {} not found: {}", sha, e))?;
+
+    repo.checkout_tree(commit.as_object(), Some(git2::build::CheckoutBuilder::new().force()))
+        .and_then(|_| repo.set_head_detached(commit.id()))
+        .map_err(|e| format!("Failed to check out {}: {}", sha, e))
+}
+
+/// Checks out branch or tag `name` of the cloned remote with a detached
+/// HEAD. Branches are looked up on origin, as a clone only has the default
+/// one locally.
+pub fn checkout_ref(repo: &Repository, name: &str) -> Result<(), String> {
+    let reference = [format!("refs/remotes/origin/{}", name), format!("refs/tags/{}", name), format!("refs/heads/{}", name)]
+        .iter()
+        .find_map(|candidate| repo.find_reference(candidate).ok())
+        .ok_or_else(|| format!("Ref {} not found", name))?;
+    let commit = reference.peel_to_commit().map_err(|e| format!("Ref {} isn't a commit: {}", name, e))?;
+
+    repo.checkout_tree(commit.as_object(), Some(git2::build::CheckoutBuilder::new().force()))
+        .and_then(|_| repo.set_head_detached(commit.id()))
+        .map_err(|e| format!("Failed to check out {}: {}", name, e))
+}
+
+/// Paths added, removed or modified between two commits.
+pub fn changed_files(repo: &Repository, before: &str, after: &str) -> Result<Vec<String>, git2::Error> {
+    let tree = |sha: &str| Oid::from_str(sha).and_then(|oid| repo.find_commit(oid)).and_then(|commit| commit.tree());
+    let diff = repo.diff_tree_to_tree(Some(&tree(before)?), Some(&tree(after)?), None)?;
+
+    let mut files = Vec::new();
+    for delta in diff.deltas() {
+        /* a rename counts for both where it came from and where it went */
+        for file in [delta.old_file(), delta.new_file()] {
+            if let Some(path) = file.path().and_then(|path| path.to_str()) {
+                if !files.iter().any(|seen| seen == path) {
+                    files.push(path.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// The commit checked out in `repo_dir`, if it's a git repository at all.
+pub fn head_commit(repo_dir: &str) -> Option<String> {
+    let repo = Repository::open(repo_dir).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+/// The branch checked out in `repo_dir`, none when HEAD is detached.
+pub fn head_branch(repo_dir: &str) -> Option<String> {
+    let repo = Repository::open(repo_dir).ok()?;
+    let head = repo.head().ok()?;
+    if !head.is_branch() {
+        return None;
+    }
+    head.shorthand().map(str::to_string)
+}
+
+/// The url of the repo's `origin` remote, if it has one.
+pub fn origin_url(repo_dir: &str) -> Option<String> {
+    let repo = Repository::open(repo_dir).ok()?;
+    let remote = repo.find_remote("origin").ok()?;
+    remote.url().map(str::to_string)
+}
+
+/// Modified, staged and untracked (but not ignored) files in `repo_dir`'s
+/// working tree, empty when it's clean.
+pub fn dirty_files(repo_dir: &str) -> Result<Vec<String>, git2::Error> {
+    let repo = Repository::open(repo_dir)?;
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true).include_ignored(false);
+
+    let statuses = repo.statuses(Some(&mut options))?;
+    Ok(statuses
+        .iter()
+        .filter(|entry| entry.status() != git2::Status::CURRENT)
+        .filter_map(|entry| entry.path().map(str::to_string))
+        .collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    /// Commits `files` (path, contents) on top of HEAD, returning the commit.
+    pub(crate) fn commit(repo: &Repository, files: &[(&str, &str)]) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (path, contents) in files {
+            let full_path = workdir.join(path);
+            std::fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+            std::fs::write(&full_path, contents).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("forge", "forge@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, "commit", &tree, &parents).unwrap()
+    }
+
+    fn file_url(path: &Path) -> String {
+        format!("file://{}", path.display())
+    }
+
+    /* a repo with `vendor/lib` a submodule of another, both under `dir` */
+    fn repo_with_submodule(dir: &Path) -> String {
+        let lib = Repository::init(dir.join("lib")).unwrap();
+        commit(&lib, &[("lib.txt", "from the submodule")]);
+
+        let app = Repository::init(dir.join("app")).unwrap();
+        commit(&app, &[("main.txt", "from the app")]);
+        let mut submodule = app.submodule(&file_url(&dir.join("lib")), Path::new("vendor/lib"), true).unwrap();
+        submodule.clone(None).unwrap();
+        submodule.add_finalize().unwrap();
+        commit(&app, &[]);
+
+        file_url(&dir.join("app"))
+    }
+
+    #[test]
+    fn submodules_are_checked_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = repo_with_submodule(dir.path());
+
+        let checkout = dir.path().join("checkout");
+        let repo = clone(&url, checkout.to_str().unwrap()).unwrap();
+        assert!(checkout.join("main.txt").exists());
+        assert!(!checkout.join("vendor/lib/lib.txt").exists());
+
+        update_submodules(&repo).unwrap();
+        assert_eq!(std::fs::read_to_string(checkout.join("vendor/lib/lib.txt")).unwrap(), "from the submodule");
+    }
+
+    #[test]
+    fn unreachable_submodule_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = repo_with_submodule(dir.path());
+        std::fs::remove_dir_all(dir.path().join("lib")).unwrap();
+
+        let checkout = dir.path().join("checkout");
+        let repo = clone(&url, checkout.to_str().unwrap()).unwrap();
+
+        let error = update_submodules(&repo).unwrap_err();
+        assert!(error.starts_with("Failed to fetch submodule vendor/lib"), "{}", error);
+    }
+}
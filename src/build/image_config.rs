@@ -0,0 +1,104 @@
+use tokio::process::Command;
+
+use crate::build::workdir;
+
+use std::path::Path;
+
+/// Refuses an `entrypoint` or `cmd` override that's given but empty.
+pub fn check(name: &str, value: &Option<Vec<String>>) -> Result<(), String> {
+    match value {
+        Some(value) if value.is_empty() || value[0].trim().is_empty() => Err(format!("{} can't be empty", name)),
+        _ => Ok(()),
+    }
+}
+
+/// The Dockerfile of a metadata-only layer over `image`. As in any
+/// Dockerfile, setting ENTRYPOINT alone clears the CMD `image` had.
+fn dockerfile(image: &str, entrypoint: Option<&[String]>, cmd: Option<&[String]>) -> String {
+    /* exec form, so arguments aren't run through a shell */
+    let exec_form = |args: &[String]| serde_json::to_string(args).unwrap();
+
+    let mut dockerfile = format!("FROM {}\n", image);
+    if let Some(entrypoint) = entrypoint {
+        dockerfile.push_str(&format!("ENTRYPOINT {}\n", exec_form(entrypoint)));
+    }
+    if let Some(cmd) = cmd {
+        dockerfile.push_str(&format!("CMD {}\n", exec_form(cmd)));
+    }
+    dockerfile
+}
+
+/// Replaces the entrypoint and/or cmd of the image built as `tags[0]`, by
+/// building a layer with only that config on top and moving every tag to
+/// it.
+pub async fn override_config(cli: &str, tags: &[String], entrypoint: Option<&[String]>, cmd: Option<&[String]>, workdir_base: &Path) -> Result<(), String> {
+    let context = workdir::create(workdir_base, "config")
+        .map_err(|e| format!("Failed to create build context dir: {}", e))?;
+    std::fs::write(context.path().join("Dockerfile"), dockerfile(&tags[0], entrypoint, cmd))
+        .map_err(|e| format!("Failed to write the config override: {}", e))?;
+
+    let mut command = Command::new(cli);
+    command.kill_on_drop(true).arg("build").arg(context.path());
+    for tag in tags {
+        command.arg("-t").arg(tag);
+    }
+
+    let output = command.output().await.map_err(|e| format!("Failed to run {} build: {}", cli, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("Failed to override the image config: {}", String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn cmd_override_is_exec_form() {
+        let cmd = args(&["node", "server.js", "--port", "8080"]);
+        assert_eq!(dockerfile("app:1", None, Some(&cmd)), "FROM app:1\nCMD [\"node\",\"server.js\",\"--port\",\"8080\"]\n");
+    }
+
+    #[test]
+    fn entrypoint_and_cmd_together() {
+        let entrypoint = args(&["/bin/tini", "--"]);
+        let cmd = args(&["npm", "start"]);
+        assert_eq!(
+            dockerfile("app:1", Some(&entrypoint), Some(&cmd)),
+            "FROM app:1\nENTRYPOINT [\"/bin/tini\",\"--\"]\nCMD [\"npm\",\"start\"]\n",
+        );
+    }
+
+    #[test]
+    fn empty_overrides_are_refused() {
+        assert!(check("cmd", &None).is_ok());
+        assert!(check("cmd", &Some(args(&["npm", "start"]))).is_ok());
+        assert_eq!(check("cmd", &Some(Vec::new())), Err("cmd can't be empty".to_string()));
+        assert_eq!(check("entrypoint", &Some(args(&[" "]))), Err("entrypoint can't be empty".to_string()));
+    }
+
+    #[tokio::test]
+    async fn produced_image_gets_the_override_and_every_tag() {
+        use std::os::unix::fs::PermissionsExt;
+
+        /* stands in for docker, keeping the Dockerfile it was asked to build and its arguments */
+        let dir = tempfile::tempdir().unwrap();
+        let cli = dir.path().join("docker");
+        let record = dir.path().join("record");
+        std::fs::write(&cli, format!("#!/bin/sh\ncat \"$2/Dockerfile\" > {0}\necho \"$1 $3 $4 $5 $6\" >> {0}\n", record.display())).unwrap();
+        std::fs::set_permissions(&cli, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let tags = args(&["app:1", "app:latest"]);
+        let cmd = args(&["./start.sh"]);
+        override_config(cli.to_str().unwrap(), &tags, None, Some(&cmd), dir.path()).await.unwrap();
+
+        let recorded = std::fs::read_to_string(&record).unwrap();
+        assert_eq!(recorded, "FROM app:1\nCMD [\"./start.sh\"]\nbuild -t app:1 -t app:latest\n");
+    }
+}
@@ -0,0 +1,41 @@
+use shiplift::Docker;
+
+/// The size of `image` in bytes, as `docker images` shows it.
+pub async fn image_size(docker: &Docker, image: &str) -> Result<u64, String> {
+    let details = docker.images().get(image).inspect().await
+        .map_err(|e| format!("Failed to inspect {}: {}", image, e))?;
+
+    Ok(details.size)
+}
+
+/// Refuses an image of `size` bytes over `max_mb` (0 for no limit).
+pub fn check(size: u64, max_mb: u64) -> Result<(), String> {
+    let size_mb = size as f64 / (1024.0 * 1024.0);
+    if max_mb > 0 && size > max_mb * 1024 * 1024 {
+        return Err(format!("image too large: {:.1} MB, the limit is {} MB", size_mb, max_mb));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn image_under_the_limit_passes() {
+        assert!(check(120 * MB, 500).is_ok());
+        assert!(check(500 * MB, 500).is_ok());
+    }
+
+    #[test]
+    fn image_over_the_limit_is_too_large() {
+        assert_eq!(check(600 * MB + MB / 2, 500).unwrap_err(), "image too large: 600.5 MB, the limit is 500 MB");
+    }
+
+    #[test]
+    fn no_limit_lets_any_size_through() {
+        assert!(check(10_000 * MB, 0).is_ok());
+    }
+}
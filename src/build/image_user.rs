@@ -0,0 +1,53 @@
+use shiplift::Docker;
+
+/// The user containers of `image` run as, empty when the image doesn't set
+/// one (i.e. root).
+pub async fn image_user(docker: &Docker, image: &str) -> Result<String, String> {
+    let details = docker.images().get(image).inspect().await
+        .map_err(|e| format!("Failed to inspect {}: {}", image, e))?;
+
+    Ok(details.config.user)
+}
+
+/// Whether a USER value (`name`, `uid`, or either with `:group`) means root.
+pub fn runs_as_root(user: &str) -> bool {
+    let user = user.split(':').next().unwrap_or_default().trim();
+    user.is_empty() || user == "root" || user == "0"
+}
+
+/// Why a build requiring a non-root image fails, given the user read from its
+/// image (None when it couldn't be read).
+pub fn root_refusal(user: Option<&str>) -> Option<&'static str> {
+    match user {
+        Some(user) if !runs_as_root(user) => None,
+        Some(_) => Some("image runs as root"),
+        None => Some("image user couldn't be verified"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_images_are_refused() {
+        /* an image without USER, and the ways USER can still mean root */
+        for user in ["", "root", "0", "root:root", "0:1000", " root "] {
+            assert!(runs_as_root(user), "{:?}", user);
+            assert_eq!(root_refusal(Some(user)), Some("image runs as root"));
+        }
+    }
+
+    #[test]
+    fn non_root_images_pass() {
+        for user in ["app", "1000", "1000:1000", "node:node", "rootless"] {
+            assert!(!runs_as_root(user), "{:?}", user);
+            assert_eq!(root_refusal(Some(user)), None);
+        }
+    }
+
+    #[test]
+    fn unreadable_user_is_refused() {
+        assert_eq!(root_refusal(None), Some("image user couldn't be verified"));
+    }
+}
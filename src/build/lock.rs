@@ -0,0 +1,32 @@
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Async lock keyed by an arbitrary string, so work sharing a key runs one at
+/// a time while work on different keys runs in parallel.
+#[derive(Default)]
+pub struct KeyedLock {
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl KeyedLock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits until no one else holds `key`, the lock is released when the
+    /// returned guard is dropped.
+    pub async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let entry = {
+            let mut locks = self.locks.lock().unwrap();
+
+            // drop entries nobody is holding or waiting on
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+
+            locks.entry(key.to_string()).or_default().clone()
+        };
+
+        entry.lock_owned().await
+    }
+}
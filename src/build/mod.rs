@@ -0,0 +1,28 @@
+pub mod archive;
+pub mod artifacts;
+pub mod backend;
+pub mod build_log;
+pub mod cache_scope;
+pub mod clone_cache;
+pub mod disk;
+pub mod dockerfile;
+pub mod env_policy;
+pub mod generated_files;
+pub mod git;
+pub mod image_config;
+pub mod image_size;
+pub mod image_user;
+pub mod lock;
+pub mod oci;
+pub mod paths_filter;
+pub mod platform;
+pub mod plan;
+pub mod plan_cache;
+pub mod post_build;
+pub mod pull;
+pub mod registry;
+pub mod sandbox;
+pub mod sbom;
+pub mod secrets;
+pub mod verify;
+pub mod workdir;
@@ -0,0 +1,40 @@
+/// Whether any of `files` matches one of `patterns`. A pattern is a path
+/// relative to the repo root, either a directory prefix (`services/api`) or
+/// a glob where `*` stays within a path segment and `**` spans any number
+/// of them (`services/*/src/**`, `**/*.rs`).
+pub fn any_match(patterns: &[String], files: &[String]) -> bool {
+    files.iter().any(|file| patterns.iter().any(|pattern| matches(pattern, file)))
+}
+
+fn matches(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.trim_start_matches("./").trim_end_matches('/');
+
+    if !pattern.contains('*') {
+        return path == pattern || path.strip_prefix(pattern).is_some_and(|rest| rest.starts_with('/'));
+    }
+
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    match_segments(&pattern, &path)
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (Some(&"**"), _) => {
+            match_segments(&pattern[1..], path) || (!path.is_empty() && match_segments(pattern, &path[1..]))
+        }
+        (Some(segment), Some(name)) => match_segment(segment.as_bytes(), name.as_bytes()) && match_segments(&pattern[1..], &path[1..]),
+        _ => false,
+    }
+}
+
+fn match_segment(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => match_segment(&pattern[1..], name) || (!name.is_empty() && match_segment(pattern, &name[1..])),
+        (Some(b'?'), Some(_)) => match_segment(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) => p == n && match_segment(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
@@ -0,0 +1,175 @@
+use nixpacks::nixpacks::plan::phase::Phase;
+use nixpacks::nixpacks::plan::BuildPlan;
+use serde::Serialize;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The nixpacks forge was built with, which plans and builds every image.
+/// It's linked in, so it only changes with a new forge build.
+pub const NIXPACKS_VERSION: &str = env!("NIXPACKS_VERSION");
+
+/// Compact view of a nixpacks plan. Everything is kept in sorted maps so two
+/// summaries of the same plan always serialize identically and diff cleanly.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PlanSummary {
+    pub providers: Vec<String>,
+    pub build_image: Option<String>,
+    pub phases: BTreeMap<String, PhaseSummary>,
+    pub start_cmd: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PhaseSummary {
+    pub depends_on: Vec<String>,
+    pub nix_pkgs: Vec<String>,
+    pub apt_pkgs: Vec<String>,
+    pub cmds: Vec<String>,
+}
+
+impl PlanSummary {
+    pub fn from_plan(plan: &BuildPlan) -> Self {
+        let phases = plan.phases.clone().unwrap_or_default().into_iter()
+            .map(|(name, phase)| (name, PhaseSummary {
+                depends_on: phase.depends_on.unwrap_or_default(),
+                nix_pkgs: phase.nix_pkgs.unwrap_or_default(),
+                apt_pkgs: phase.apt_pkgs.unwrap_or_default(),
+                cmds: phase.cmds.unwrap_or_default(),
+            }))
+            .collect();
+
+        PlanSummary {
+            providers: plan.providers.clone().unwrap_or_default(),
+            build_image: plan.build_image.clone(),
+            phases,
+            start_cmd: plan.start_phase.as_ref().and_then(|start| start.cmd.clone()),
+        }
+    }
+}
+
+/// `plan` cut down to the `install` phase and the phases it depends on, with
+/// no start command. Building it fills the layer cache with a repo's
+/// dependencies without building its code.
+pub fn dependency_plan(plan: &BuildPlan) -> BuildPlan {
+    let mut plan = plan.clone();
+
+    if let Some(phases) = &mut plan.phases {
+        let mut keep = BTreeSet::new();
+        let mut pending = vec!["install".to_string()];
+        while let Some(name) = pending.pop() {
+            if let Some(phase) = phases.get(&name) {
+                if keep.insert(name) {
+                    pending.extend(phase.depends_on.clone().unwrap_or_default());
+                }
+            }
+        }
+        phases.retain(|name, _| keep.contains(name));
+    }
+    plan.start_phase = None;
+
+    plan
+}
+
+/// Part of a test phase's command and printed when it fails, so a failed
+/// build's error tells failing tests apart from any other step.
+pub const TESTS_FAILED_MARKER: &str = "forge: test_command failed";
+
+/// The shell command of a test phase running `command`.
+fn test_cmd(command: &str) -> String {
+    format!("( {} ) || {{ echo '{}' >&2; exit 1; }}", command, TESTS_FAILED_MARKER)
+}
+
+/// `plan` with a `test` phase running `command` right after `install` (or
+/// `setup` without one). The phases that waited for it wait for the tests
+/// too, so failing tests fail the image build before there's an image.
+pub fn with_test_phase(plan: &BuildPlan, command: &str) -> BuildPlan {
+    let mut plan = plan.clone();
+    let phases = plan.phases.get_or_insert_with(BTreeMap::new);
+
+    let after = ["install", "setup"].into_iter().find(|name| phases.contains_key(*name));
+    if let Some(after) = after {
+        for phase in phases.values_mut() {
+            for name in phase.depends_on.iter_mut().flatten().filter(|name| name.as_str() == after) {
+                *name = "test".to_string();
+            }
+        }
+    }
+
+    let mut test = Phase::new("test");
+    test.depends_on = after.map(|after| vec![after.to_string()]);
+    test.cmds = Some(vec![test_cmd(command)]);
+    phases.insert("test".to_string(), test);
+
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nixpacks::nixpacks::plan::phase::StartPhase;
+
+    fn phase(name: &str, depends_on: &[&str]) -> Phase {
+        let mut phase = Phase::new(name);
+        phase.depends_on = Some(depends_on.iter().map(|name| name.to_string()).collect());
+        phase
+    }
+
+    #[test]
+    fn dependency_plan_keeps_install_and_what_it_depends_on() {
+        let plan = BuildPlan::new(
+            &[phase("setup", &[]), phase("install", &["setup"]), phase("build", &["install"])],
+            Some(StartPhase::new("npm start")),
+        );
+
+        let warm = dependency_plan(&plan);
+
+        let phases: Vec<&String> = warm.phases.as_ref().unwrap().keys().collect();
+        assert_eq!(phases, ["install", "setup"]);
+        assert!(warm.start_phase.is_none());
+        /* the real build still builds everything */
+        assert_eq!(plan.phases.as_ref().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn dependency_plan_without_install_is_empty() {
+        let plan = BuildPlan::new(&[phase("build", &[])], None);
+        assert!(dependency_plan(&plan).phases.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_phase_runs_between_install_and_build() {
+        let plan = BuildPlan::new(
+            &[phase("setup", &[]), phase("install", &["setup"]), phase("build", &["install"])],
+            Some(StartPhase::new("npm start")),
+        );
+
+        let tested = with_test_phase(&plan, "npm test");
+        let phases = tested.phases.as_ref().unwrap();
+
+        assert_eq!(phases["test"].depends_on, Some(vec!["install".to_string()]));
+        assert_eq!(phases["build"].depends_on, Some(vec!["test".to_string()]));
+        assert_eq!(phases["install"].depends_on, Some(vec!["setup".to_string()]));
+        assert_eq!(phases["test"].cmds.as_ref().unwrap()[0], test_cmd("npm test"));
+        assert!(tested.start_phase.is_some());
+    }
+
+    /* the step's exit code and output, as a RUN step runs it */
+    fn run(command: &str) -> (i32, String) {
+        let output = std::process::Command::new("sh").arg("-c").arg(test_cmd(command)).output().unwrap();
+        (output.status.code().unwrap(), String::from_utf8_lossy(&output.stderr).into_owned())
+    }
+
+    #[test]
+    fn passing_tests_let_the_build_go_on() {
+        assert_eq!(run("true; echo ok"), (0, String::new()));
+    }
+
+    #[test]
+    fn failing_tests_fail_the_step_and_say_so() {
+        let (code, stderr) = run("echo 1 failing >&2; exit 3");
+        assert_eq!(code, 1);
+        assert_eq!(stderr, format!("1 failing\n{}\n", TESTS_FAILED_MARKER));
+
+        /* the whole command is checked, not only its last part */
+        assert_eq!(run("exit 3; true").0, 1);
+    }
+}
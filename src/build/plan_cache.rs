@@ -0,0 +1,78 @@
+use nixpacks::nixpacks::plan::BuildPlan;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Plans recently generated by /plan, so a /build of the same commit right
+/// after builds exactly what was shown instead of planning again.
+pub struct PlanCache {
+    entries: Mutex<HashMap<String, (Instant, BuildPlan)>>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl PlanCache {
+    /// A zero `ttl` or `max_entries` caches nothing.
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        PlanCache {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+            max_entries,
+        }
+    }
+
+    /// The key of `repo` at `commit`. Envs change what nixpacks plans, so
+    /// they're part of it.
+    pub fn key(repo: &str, commit: &str, envs: &[&str]) -> String {
+        format!("{}@{}#{}", repo, commit, envs.join("\n"))
+    }
+
+    pub fn get(&self, key: &str) -> Option<BuildPlan> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (stored, _)| stored.elapsed() < self.ttl);
+        entries.get(key).map(|(_, plan)| plan.clone())
+    }
+
+    pub fn insert(&self, key: String, plan: BuildPlan) {
+        if self.ttl.is_zero() || self.max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (stored, _)| stored.elapsed() < self.ttl);
+
+        /* full, make room by dropping the oldest */
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries.iter().min_by_key(|(_, (stored, _))| *stored).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(key, (Instant::now(), plan));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_plans_expire_and_are_bounded() {
+        let cache = PlanCache::new(Duration::from_secs(60), 1);
+        cache.insert("a".to_string(), BuildPlan::default());
+        cache.insert("b".to_string(), BuildPlan::default());
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+
+        let expired = PlanCache::new(Duration::ZERO, 10);
+        expired.insert("a".to_string(), BuildPlan::default());
+        assert!(expired.get("a").is_none());
+    }
+
+    #[test]
+    fn envs_are_part_of_the_key() {
+        assert_ne!(PlanCache::key("app", "abc", &["NODE_ENV=production"]), PlanCache::key("app", "abc", &[]));
+    }
+}
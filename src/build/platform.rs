@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+
+/// Platforms builds may target when `ALLOWED_PLATFORMS` isn't set.
+pub const DEFAULT_PLATFORMS: [&str; 2] = ["linux/amd64", "linux/arm64"];
+
+/// `platform` spelled the way docker reports it, e.g. `arm64` or
+/// `linux/aarch64` become `linux/arm64`. A bare architecture is taken to be
+/// linux.
+pub fn normalize(platform: &str) -> String {
+    let platform = platform.trim().to_lowercase();
+    let (os, arch) = match platform.split_once('/') {
+        Some((os, arch)) => (os.to_string(), arch.to_string()),
+        None => ("linux".to_string(), platform),
+    };
+
+    let arch = match arch.as_str() {
+        "x86_64" | "x86-64" | "x64" => "amd64",
+        "aarch64" | "arm64/v8" | "aarch64/v8" => "arm64",
+        "armv7" | "armhf" | "armv7l" => "arm/v7",
+        "armv6" | "armv6l" => "arm/v6",
+        "i386" | "i686" | "x86" => "386",
+        arch => arch,
+    };
+
+    format!("{}/{}", os, arch)
+}
+
+/// Refuses platforms not in `allowed`, both already normalized.
+pub fn check(platforms: &[String], allowed: &[String]) -> Result<(), String> {
+    match platforms.iter().find(|platform| !allowed.contains(platform)) {
+        Some(platform) => Err(format!("Unsupported platform {}, allowed platforms are: {}", platform, allowed.join(", "))),
+        None => Ok(()),
+    }
+}
+
+/// The daemon (a `DOCKER_HOST`) a build for `platforms` is sent to, from
+/// `hosts` keyed by normalized platform. Only when every platform maps to the
+/// same one, anything else builds locally and emulates what the host can't run.
+pub fn docker_host<'a>(platforms: &[String], hosts: &'a BTreeMap<String, String>) -> Option<&'a str> {
+    let (first, rest) = platforms.split_first()?;
+    let host = hosts.get(first)?;
+
+    if rest.iter().all(|platform| hosts.get(platform) == Some(host)) {
+        Some(host)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed() -> Vec<String> {
+        DEFAULT_PLATFORMS.iter().map(|platform| platform.to_string()).collect()
+    }
+
+    #[test]
+    fn valid_platforms_are_unchanged() {
+        assert_eq!(normalize("linux/amd64"), "linux/amd64");
+        assert_eq!(normalize("linux/arm/v7"), "linux/arm/v7");
+        assert_eq!(check(&["linux/amd64".to_string(), "linux/arm64".to_string()], &allowed()), Ok(()));
+    }
+
+    #[test]
+    fn aliases_are_normalized() {
+        assert_eq!(normalize("arm64"), "linux/arm64");
+        assert_eq!(normalize("linux/aarch64"), "linux/arm64");
+        assert_eq!(normalize(" Linux/X86_64 "), "linux/amd64");
+        assert_eq!(normalize("armhf"), "linux/arm/v7");
+        assert_eq!(check(&[normalize("aarch64")], &allowed()), Ok(()));
+    }
+
+    #[test]
+    fn invalid_platforms_are_rejected() {
+        let error = check(&[normalize("linux/amd46")], &allowed()).unwrap_err();
+        assert_eq!(error, "Unsupported platform linux/amd46, allowed platforms are: linux/amd64, linux/arm64");
+        assert!(check(&[normalize("armv7")], &allowed()).is_err());
+    }
+
+    fn hosts() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("linux/arm64".to_string(), "tcp://arm-builder:2375".to_string()),
+            ("linux/arm/v7".to_string(), "tcp://arm-builder:2375".to_string()),
+        ])
+    }
+
+    fn platforms(platforms: &[&str]) -> Vec<String> {
+        platforms.iter().map(|platform| normalize(platform)).collect()
+    }
+
+    #[test]
+    fn mapped_platforms_go_to_their_daemon() {
+        assert_eq!(docker_host(&platforms(&["arm64"]), &hosts()), Some("tcp://arm-builder:2375"));
+        assert_eq!(docker_host(&platforms(&["linux/arm64", "armv7"]), &hosts()), Some("tcp://arm-builder:2375"));
+    }
+
+    #[test]
+    fn unmapped_platforms_build_locally() {
+        assert_eq!(docker_host(&platforms(&["linux/amd64"]), &hosts()), None);
+        /* no one daemon runs both natively */
+        assert_eq!(docker_host(&platforms(&["linux/amd64", "linux/arm64"]), &hosts()), None);
+        /* the host's own platform */
+        assert_eq!(docker_host(&[], &hosts()), None);
+    }
+}
@@ -0,0 +1,54 @@
+use shiplift::{ContainerOptions, Docker, LogsOptions, RmContainerOptions};
+use futures::StreamExt;
+use tracing::error;
+
+use std::time::Duration;
+
+pub struct PostBuildResult {
+    pub exit_code: u64,
+    pub output: String,
+}
+
+/// Runs `command` in a throwaway container of `image`, returning its exit
+/// code and combined output. The container is removed whatever happens.
+pub async fn run_post_build(docker: &Docker, image: &str, command: &str, timeout: Duration) -> Result<PostBuildResult, String> {
+    let options = ContainerOptions::builder(image)
+        .cmd(vec!["sh", "-c", command])
+        .build();
+
+    let info = docker.containers().create(&options).await
+        .map_err(|e| format!("Failed to create post-build container: {}", e))?;
+    let container = docker.containers().get(&info.id);
+
+    let result = async {
+        container.start().await.map_err(|e| format!("Failed to start post-build container: {}", e))?;
+
+        let exit = match tokio::time::timeout(timeout, container.wait()).await {
+            Ok(exit) => exit.map_err(|e| format!("Failed waiting on post-build container: {}", e))?,
+            Err(_) => return Err(format!("Post-build command timed out after {}s", timeout.as_secs())),
+        };
+
+        let mut output = String::new();
+        let mut logs = container.logs(&LogsOptions::builder().stdout(true).stderr(true).build());
+        while let Some(chunk) = logs.next().await {
+            match chunk {
+                Ok(chunk) => output.push_str(&String::from_utf8_lossy(&chunk)),
+                Err(e) => {
+                    error!("Error reading post-build output: {}", e);
+                    break;
+                }
+            }
+        }
+
+        Ok(PostBuildResult {
+            exit_code: exit.status_code,
+            output,
+        })
+    }.await;
+
+    if let Err(e) = container.remove(RmContainerOptions::builder().force(true).build()).await {
+        error!("Failed to remove post-build container {}: {}", info.id, e);
+    }
+
+    result
+}
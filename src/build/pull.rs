@@ -0,0 +1,44 @@
+use shiplift::{Docker, PullOptions};
+use futures::StreamExt;
+use tracing::info;
+
+use nixpacks::nixpacks::images::DEFAULT_BASE_IMAGE;
+use nixpacks::nixpacks::plan::BuildPlan;
+
+use crate::build::lock::KeyedLock;
+use crate::config::config::PullPolicy;
+
+/// Base images a plan will build from.
+pub fn base_images(plan: &BuildPlan) -> Vec<String> {
+    vec![plan.build_image.clone().unwrap_or_else(|| DEFAULT_BASE_IMAGE.to_string())]
+}
+
+/// Pulls `images` ahead of the build. Each image is pulled behind its own lock
+/// so concurrent builds needing the same base share a single pull.
+pub async fn pull_base_images(docker: &Docker, images: &[String], policy: PullPolicy, locks: &KeyedLock) -> Result<(), shiplift::Error> {
+    if policy == PullPolicy::Never {
+        return Ok(());
+    }
+
+    for image in images {
+        let _guard = locks.lock(image).await;
+
+        /* checked under the lock, so builds that waited on a pull see it as a hit */
+        if policy == PullPolicy::IfMissing && docker.images().get(image).inspect().await.is_ok() {
+            info!("Base image {} already present, skipping pull", image);
+            continue;
+        }
+
+        info!("Pulling base image {}", image);
+        let options = PullOptions::builder().image(image).build();
+        let mut pull = docker.images().pull(&options);
+
+        while let Some(progress) = pull.next().await {
+            progress?;
+        }
+
+        info!("Pulled base image {}", image);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,538 @@
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::config::redact;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Whether the first path component of `name` is a registry host, the same
+/// rule docker uses: it has a `.` or `:`, or is `localhost`.
+pub fn has_registry_host(name: &str) -> bool {
+    match name.split_once('/') {
+        Some((first, _)) => first.contains('.') || first.contains(':') || first == "localhost",
+        None => false,
+    }
+}
+
+/// The registry `reference` is pushed to, docker hub when it names none.
+pub fn registry_host(reference: &str) -> &str {
+    match reference.split_once('/') {
+        Some((host, _)) if has_registry_host(reference) => host,
+        _ => "docker.io",
+    }
+}
+
+/// Prepends `prefix` (e.g. `registry.example.com/team`) to `name` unless the
+/// name already points at a registry.
+pub fn qualify(name: &str, prefix: Option<&str>) -> Result<String, String> {
+    let reference = match prefix.map(|prefix| prefix.trim_end_matches('/')) {
+        Some(prefix) if !prefix.is_empty() && !has_registry_host(name) => format!("{}/{}", prefix, name),
+        _ => name.to_string(),
+    };
+
+    validate_reference(&reference)?;
+    Ok(reference)
+}
+
+/// Which registry a build is pushed to by its target environment, e.g.
+/// `production` to the prod registry and `staging` to a dev one.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RegistryRoutes {
+    /* environment -> image prefix, e.g. production -> registry.example.com/team */
+    pub environments: BTreeMap<String, String>,
+    /* branch -> environment, for builds that don't name one */
+    pub branches: BTreeMap<String, String>,
+    /* pushes without a route are refused instead of using IMAGE_PREFIX */
+    pub required: bool,
+}
+
+impl RegistryRoutes {
+    /// The image prefix for a build targeting `environment`, or `branch`'s
+    /// environment when it names none. `None` leaves it to the default.
+    pub fn prefix(&self, environment: Option<&str>, branch: Option<&str>, push: bool) -> Result<Option<&str>, String> {
+        let environment = environment.or_else(|| branch.and_then(|branch| self.branches.get(branch)).map(String::as_str));
+
+        match environment.map(|environment| (environment, self.environments.get(environment))) {
+            Some((_, Some(prefix))) => Ok(Some(prefix)),
+            Some((environment, None)) if self.required && push => Err(format!("No registry is configured for environment {}", environment)),
+            None if self.required && push => Err(match branch {
+                Some(branch) => format!("Branch {} isn't mapped to an environment, set environment to pick a registry", branch),
+                None => "Pushing needs an environment to pick a registry".to_string(),
+            }),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Checks `[host[:port]/]path[:tag]` against docker's reference grammar.
+pub fn validate_reference(reference: &str) -> Result<(), String> {
+    let invalid = |reason: &str| Err(format!("Invalid image reference {}: {}", reference, reason));
+
+    let (remainder, tag) = match reference.rsplit_once(':') {
+        Some((remainder, tag)) if !tag.contains('/') => (remainder, Some(tag)),
+        _ => (reference, None),
+    };
+
+    if let Some(tag) = tag {
+        let valid_tag = !tag.is_empty()
+            && tag.len() <= 128
+            && !tag.starts_with('.')
+            && !tag.starts_with('-')
+            && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+        if !valid_tag {
+            return invalid("bad tag");
+        }
+    }
+
+    let path = if has_registry_host(remainder) {
+        remainder.split_once('/').map(|(_, path)| path).unwrap_or_default()
+    } else {
+        remainder
+    };
+
+    if path.is_empty() {
+        return invalid("missing name");
+    }
+
+    for component in path.split('/') {
+        let valid_component = !component.is_empty()
+            && component.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && component.ends_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && component.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '_' || c == '-');
+        if !valid_component {
+            return invalid("name components must be lowercase letters, digits and separators");
+        }
+    }
+
+    Ok(())
+}
+
+/// The tag of `reference`, `latest` when it has none, as docker assumes.
+pub fn tag_of(reference: &str) -> &str {
+    match reference.rsplit_once(':') {
+        Some((_, tag)) if !tag.contains('/') => tag,
+        _ => "latest",
+    }
+}
+
+/// `reference` without its tag.
+pub fn repository_of(reference: &str) -> &str {
+    match reference.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => reference,
+    }
+}
+
+/// What a push does with a tag the registry already has.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExistingTagPolicy {
+    /* push over it, as docker push does */
+    Overwrite,
+    /* leave it be and push the build's other tags */
+    Skip,
+    /* refuse the build with a 409, nothing is pushed */
+    Fail,
+}
+
+impl ExistingTagPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "overwrite" => Some(ExistingTagPolicy::Overwrite),
+            "skip" => Some(ExistingTagPolicy::Skip),
+            "fail" => Some(ExistingTagPolicy::Fail),
+            _ => None,
+        }
+    }
+
+    /// Why the build is refused with `existing` already in the registry,
+    /// only with `Fail`.
+    pub fn conflict(&self, existing: &[String]) -> Option<String> {
+        match self {
+            ExistingTagPolicy::Fail if !existing.is_empty() => Some(format!("{} already exists in the registry", existing.join(", "))),
+            _ => None,
+        }
+    }
+}
+
+/// The `references` the registry already has, none with `Overwrite`,
+/// which pushes over them without asking.
+pub async fn existing_tags<'a>(cli: &str, references: impl Iterator<Item = &'a String>, policy: ExistingTagPolicy) -> Result<Vec<String>, String> {
+    let mut existing = Vec::new();
+    if policy == ExistingTagPolicy::Overwrite {
+        return Ok(existing);
+    }
+
+    for reference in references {
+        if exists(cli, reference).await? {
+            existing.push(reference.clone());
+        }
+    }
+    Ok(existing)
+}
+
+/// Whether the registry still has `reference`, asked with `<cli> manifest
+/// inspect`. Errors other than a missing manifest are returned, so an
+/// unreachable registry isn't taken for a missing image.
+pub async fn exists(cli: &str, reference: &str) -> Result<bool, String> {
+    let output = Command::new(cli)
+        .args(["manifest", "inspect", reference])
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run {} manifest inspect: {}", cli, e))?;
+
+    if output.status.success() {
+        return Ok(true);
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr).to_lowercase();
+    if stderr.contains("no such manifest") || stderr.contains("manifest unknown") || stderr.contains("not found") {
+        Ok(false)
+    } else {
+        Err(format!("Failed to inspect {}: {}", reference, stderr.trim()))
+    }
+}
+
+/// Registry credentials pushes log in with, set by `REGISTRY_USERNAME` and
+/// `REGISTRY_PASSWORD`. Without them the host's registry login is used.
+#[derive(Serialize, Clone, PartialEq)]
+pub struct RegistryCredentials {
+    pub username: String,
+    #[serde(serialize_with = "redact::secret")]
+    pub password: String,
+}
+
+impl fmt::Debug for RegistryCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RegistryCredentials")
+            .field("username", &self.username)
+            .field("password", &redact::MASK)
+            .finish()
+    }
+}
+
+/// Logs `cli` into `host`, the password going over stdin so it never shows
+/// up in the process list.
+pub async fn login(cli: &str, host: &str, credentials: &RegistryCredentials) -> Result<(), String> {
+    let mut child = Command::new(cli)
+        .args(["login", host, "-u", &credentials.username, "--password-stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {} login: {}", cli, e))?;
+
+    /* dropped once written, closing stdin so the cli stops reading */
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(credentials.password.as_bytes()).await
+            .map_err(|e| format!("Failed to run {} login: {}", cli, e))?;
+    }
+
+    let output = child.wait_with_output().await
+        .map_err(|e| format!("Failed to run {} login: {}", cli, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("Failed to log in to {}: {}", host, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+/// Pushes `reference` with `cli` (docker, podman or buildah), which picks up
+/// the registry credentials from its login.
+pub async fn push(cli: &str, reference: &str) -> Result<(), String> {
+    let output = Command::new(cli)
+        .arg("push")
+        .arg(reference)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run {} push: {}", cli, e))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("Failed to push {}: {}", reference, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+/// Adds the tag `target` to the local image `source`.
+pub async fn tag(cli: &str, source: &str, target: &str) -> Result<(), String> {
+    let output = Command::new(cli)
+        .args(["tag", source, target])
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run {} tag: {}", cli, e))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("Failed to tag {} as {}: {}", source, target, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+/// Removes the local tag `reference`, and the image once nothing else tags
+/// it. A tag that's already gone counts as removed.
+pub async fn remove(cli: &str, reference: &str) -> Result<(), String> {
+    let output = Command::new(cli)
+        .args(["rmi", reference])
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run {} rmi: {}", cli, e))?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr).to_lowercase();
+    if output.status.success() || stderr.contains("no such image") || stderr.contains("image not known") {
+        Ok(())
+    } else {
+        Err(format!("Failed to remove {}: {}", reference, stderr.trim()))
+    }
+}
+
+/// Caps how many pushes run at once across every build, so a burst of
+/// builds finishing together doesn't saturate the uplink or trip registry
+/// rate limits.
+pub struct PushLimiter {
+    permits: Semaphore,
+    limit: usize,
+    waiting: AtomicUsize,
+}
+
+/* counts a push as waiting until it gets its permit or gives up */
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl PushLimiter {
+    pub fn new(limit: usize) -> Self {
+        PushLimiter {
+            permits: Semaphore::new(limit),
+            limit,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// A permit if one is free right now.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.permits.try_acquire().ok()
+    }
+
+    /// Waits for a permit, the push may start once it's returned and ends
+    /// when it's dropped.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        let _waiting = Waiting(&self.waiting);
+        /* the semaphore is never closed */
+        self.permits.acquire().await.unwrap()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn active(&self) -> usize {
+        self.limit - self.permits.available_permits()
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn push_limit_is_respected() {
+        let limiter = Arc::new(PushLimiter::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        let pushes: Vec<_> = (0..6).map(|_| {
+            let (limiter, running, most) = (Arc::clone(&limiter), Arc::clone(&running), Arc::clone(&most));
+            tokio::spawn(async move {
+                let _permit = limiter.acquire().await;
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            })
+        }).collect();
+        for push in pushes {
+            push.await.unwrap();
+        }
+
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        assert_eq!(limiter.active(), 0);
+        assert_eq!(limiter.waiting(), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_pushes_are_counted_until_they_start() {
+        let limiter = Arc::new(PushLimiter::new(1));
+        let permit = limiter.acquire().await;
+        assert_eq!(limiter.active(), 1);
+        assert!(limiter.try_acquire().is_none());
+
+        let queued = tokio::spawn({
+            let limiter = Arc::clone(&limiter);
+            async move {
+                let _permit = limiter.acquire().await;
+            }
+        });
+        while limiter.waiting() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(limiter.waiting(), 1);
+
+        drop(permit);
+        queued.await.unwrap();
+        assert_eq!(limiter.waiting(), 0);
+        assert_eq!(limiter.active(), 0);
+    }
+
+    fn routes(required: bool) -> RegistryRoutes {
+        RegistryRoutes {
+            environments: [
+                ("production".to_string(), "registry.example.com/prod".to_string()),
+                ("staging".to_string(), "dev-registry.example.com/staging".to_string()),
+            ].into_iter().collect(),
+            branches: [
+                ("main".to_string(), "production".to_string()),
+                ("develop".to_string(), "staging".to_string()),
+            ].into_iter().collect(),
+            required,
+        }
+    }
+
+    #[test]
+    fn main_pushes_to_the_prod_registry() {
+        assert_eq!(routes(false).prefix(None, Some("main"), true), Ok(Some("registry.example.com/prod")));
+        assert_eq!(routes(false).prefix(None, Some("develop"), true), Ok(Some("dev-registry.example.com/staging")));
+    }
+
+    #[test]
+    fn explicit_environment_wins_over_the_branch() {
+        assert_eq!(routes(false).prefix(Some("staging"), Some("main"), true), Ok(Some("dev-registry.example.com/staging")));
+    }
+
+    #[test]
+    fn unmapped_builds_use_the_default_unless_routes_are_required() {
+        assert_eq!(routes(false).prefix(None, Some("feature"), true), Ok(None));
+        assert_eq!(routes(false).prefix(Some("qa"), None, true), Ok(None));
+
+        assert!(routes(true).prefix(None, Some("feature"), true).unwrap_err().contains("Branch feature isn't mapped"));
+        assert_eq!(routes(true).prefix(Some("qa"), None, true), Err("No registry is configured for environment qa".to_string()));
+        /* nothing is pushed, so nothing needs a registry */
+        assert_eq!(routes(true).prefix(None, Some("feature"), false), Ok(None));
+    }
+
+    #[test]
+    fn pushes_log_in_to_the_reference_registry() {
+        assert_eq!(registry_host("registry.example.com/team/app:1.0"), "registry.example.com");
+        assert_eq!(registry_host("localhost:5000/app"), "localhost:5000");
+        assert_eq!(registry_host("team/app"), "docker.io");
+        assert_eq!(registry_host("app"), "docker.io");
+    }
+
+    fn credentials() -> RegistryCredentials {
+        RegistryCredentials { username: "forge".to_string(), password: "hunter2".to_string() }
+    }
+
+    #[test]
+    fn registry_password_is_never_shown() {
+        assert!(!format!("{:?}", credentials()).contains("hunter2"));
+        assert_eq!(serde_json::to_value(credentials()).unwrap(), serde_json::json!({ "username": "forge", "password": redact::MASK }));
+    }
+
+    #[tokio::test]
+    async fn login_sends_the_password_on_stdin() {
+        use std::os::unix::fs::PermissionsExt;
+
+        /* stands in for docker, recording how it was run */
+        let dir = tempfile::tempdir().unwrap();
+        let cli = dir.path().join("docker");
+        let record = dir.path().join("record");
+        std::fs::write(&cli, format!("#!/bin/sh\necho \"$@\" > {0}\ncat >> {0}\n", record.display())).unwrap();
+        std::fs::set_permissions(&cli, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        login(cli.to_str().unwrap(), "registry.example.com", &credentials()).await.unwrap();
+
+        let recorded = std::fs::read_to_string(&record).unwrap();
+        assert_eq!(recorded, "login registry.example.com -u forge --password-stdin\nhunter2");
+    }
+
+    #[tokio::test]
+    async fn failed_login_is_an_error() {
+        let error = login("false", "registry.example.com", &credentials()).await.unwrap_err();
+        assert!(error.starts_with("Failed to log in to registry.example.com"), "{}", error);
+    }
+
+    /* a docker whose registry has app:1.0 and nothing else, and can't reach down.example.com */
+    fn registry_cli(dir: &std::path::Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let cli = dir.join("docker");
+        std::fs::write(&cli, r#"#!/bin/sh
+case "$3" in
+    registry.example.com/app:1.0) echo '{"schemaVersion": 2}' ;;
+    down.example.com/*) echo "dial tcp: connection refused" >&2; exit 1 ;;
+    *) echo "no such manifest: $3" >&2; exit 1 ;;
+esac
+"#).unwrap();
+        std::fs::set_permissions(&cli, std::fs::Permissions::from_mode(0o755)).unwrap();
+        cli.display().to_string()
+    }
+
+    fn references() -> Vec<String> {
+        vec!["registry.example.com/app:1.0".to_string(), "registry.example.com/app:1.1".to_string()]
+    }
+
+    #[tokio::test]
+    async fn overwrite_pushes_without_asking() {
+        /* false would fail any question */
+        assert_eq!(existing_tags("false", references().iter(), ExistingTagPolicy::Overwrite).await, Ok(Vec::new()));
+        assert_eq!(ExistingTagPolicy::Overwrite.conflict(&references()), None);
+    }
+
+    #[tokio::test]
+    async fn skip_leaves_existing_tags_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = existing_tags(&registry_cli(dir.path()), references().iter(), ExistingTagPolicy::Skip).await.unwrap();
+
+        assert_eq!(existing, ["registry.example.com/app:1.0"]);
+        assert_eq!(ExistingTagPolicy::Skip.conflict(&existing), None);
+    }
+
+    #[tokio::test]
+    async fn fail_refuses_a_build_with_existing_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let cli = registry_cli(dir.path());
+
+        let existing = existing_tags(&cli, references().iter(), ExistingTagPolicy::Fail).await.unwrap();
+        assert_eq!(ExistingTagPolicy::Fail.conflict(&existing).as_deref(), Some("registry.example.com/app:1.0 already exists in the registry"));
+
+        let new = ["registry.example.com/app:2.0".to_string()];
+        let existing = existing_tags(&cli, new.iter(), ExistingTagPolicy::Fail).await.unwrap();
+        assert_eq!(ExistingTagPolicy::Fail.conflict(&existing), None);
+    }
+
+    #[tokio::test]
+    async fn unreachable_registry_isnt_taken_for_a_missing_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let down = ["down.example.com/app:1.0".to_string()];
+
+        let error = existing_tags(&registry_cli(dir.path()), down.iter(), ExistingTagPolicy::Skip).await.unwrap_err();
+        assert!(error.contains("connection refused"), "{}", error);
+    }
+}
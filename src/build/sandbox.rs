@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::build::secrets::SecretFiles;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Resource limits a request asks for on top of the server defaults.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct SandboxRequest {
+    /* docker style, e.g. "nofile=1024:2048" */
+    #[serde(default)]
+    pub ulimits: Vec<String>,
+    /* size of the build container's /dev/shm tmpfs */
+    pub shm_size_mb: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ulimit {
+    pub soft: i64,
+    pub hard: i64,
+}
+
+impl Ulimit {
+    /// Parses `name=soft[:hard]`, -1 meaning unlimited.
+    pub fn parse(value: &str) -> Result<(String, Ulimit), String> {
+        let (name, limits) = value.split_once('=').ok_or_else(|| format!("Invalid ulimit {}, expected name=soft[:hard]", value))?;
+        let parse = |limit: &str| limit.parse::<i64>().map_err(|_| format!("Invalid ulimit value in {}", value));
+
+        let (soft, hard) = match limits.split_once(':') {
+            Some((soft, hard)) => (parse(soft)?, parse(hard)?),
+            None => (parse(limits)?, parse(limits)?),
+        };
+
+        Ok((name.to_string(), Ulimit { soft, hard }))
+    }
+
+    fn exceeds(&self, max: i64) -> bool {
+        if max == -1 {
+            return false;
+        }
+        self.hard == -1 || self.hard > max || self.soft > max
+    }
+}
+
+/// Effective limits for one build, after merging and validation.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SandboxLimits {
+    pub ulimits: BTreeMap<String, Ulimit>,
+    pub shm_size_mb: Option<u64>,
+}
+
+impl SandboxLimits {
+    pub fn is_empty(&self) -> bool {
+        self.ulimits.is_empty() && self.shm_size_mb.is_none()
+    }
+
+    /// Layers `request` over the server `defaults` and checks the result
+    /// against the server maximums.
+    pub fn resolve(
+        request: Option<&SandboxRequest>,
+        defaults: &SandboxLimits,
+        max_ulimits: &BTreeMap<String, i64>,
+        max_shm_size_mb: u64,
+    ) -> Result<SandboxLimits, String> {
+        let mut limits = defaults.clone();
+
+        if let Some(request) = request {
+            for ulimit in &request.ulimits {
+                let (name, ulimit) = Ulimit::parse(ulimit)?;
+                limits.ulimits.insert(name, ulimit);
+            }
+            if request.shm_size_mb.is_some() {
+                limits.shm_size_mb = request.shm_size_mb;
+            }
+        }
+
+        for (name, ulimit) in &limits.ulimits {
+            match max_ulimits.get(name) {
+                Some(max) if ulimit.exceeds(*max) => return Err(format!("ulimit {} exceeds the server maximum of {}", name, max)),
+                Some(_) => {}
+                None => return Err(format!("ulimit {} is not allowed", name)),
+            }
+        }
+
+        if let Some(shm_size_mb) = limits.shm_size_mb {
+            if shm_size_mb > max_shm_size_mb {
+                return Err(format!("shm_size_mb exceeds the server maximum of {}", max_shm_size_mb));
+            }
+        }
+
+        Ok(limits)
+    }
+}
+
+/// Runs `<cli> build` (docker, podman and buildah take the same flags) over
+/// a context nixpacks wrote out, for the limits, secrets and network nixpacks
+/// has no options for.
+#[allow(clippy::too_many_arguments)]
+pub async fn cli_build(cli: &str, context_dir: &Path, tags: &[String], labels: &[String], platform: &[String], no_cache: bool, limits: &SandboxLimits, network: Option<&str>, secrets: Option<&SecretFiles>, docker_host: Option<&str>) -> Result<(), String> {
+    let mut cmd = Command::new(cli);
+    if let Some(docker_host) = docker_host {
+        cmd.env("DOCKER_HOST", docker_host);
+    }
+    /* so an aborted build doesn't leave the cli building */
+    cmd.kill_on_drop(true);
+    cmd.arg("build")
+        .arg(context_dir)
+        .arg("-f")
+        .arg(context_dir.join(".nixpacks").join("Dockerfile"));
+
+    for tag in tags {
+        cmd.arg("-t").arg(tag);
+    }
+    for label in labels {
+        cmd.arg("--label").arg(label);
+    }
+    for platform in platform {
+        cmd.arg("--platform").arg(platform);
+    }
+    if no_cache {
+        cmd.arg("--no-cache");
+    }
+    for (name, ulimit) in &limits.ulimits {
+        cmd.arg("--ulimit").arg(format!("{}={}:{}", name, ulimit.soft, ulimit.hard));
+    }
+    if let Some(shm_size_mb) = limits.shm_size_mb {
+        cmd.arg("--shm-size").arg(format!("{}m", shm_size_mb));
+    }
+    if let Some(network) = network {
+        cmd.arg("--network").arg(network);
+    }
+    if let Some(secrets) = secrets {
+        /* secret mounts are BuildKit only */
+        cmd.env("DOCKER_BUILDKIT", "1").args(secrets.docker_args());
+    }
+
+    let output = cmd.output().await.map_err(|e| format!("Failed to run {} build: {}", cli, e))?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    /* the end of the output is where the build says what went wrong */
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let tail: Vec<&str> = stderr.lines().rev().take(20).collect();
+    Err(tail.into_iter().rev().collect::<Vec<_>>().join("\n"))
+}
+
+/// Points at the sandbox settings when a build failure looks like it ran
+/// into a resource limit.
+pub fn limit_hint(error: &str) -> Option<&'static str> {
+    let error = error.to_lowercase();
+
+    if error.contains("no space left on device") {
+        Some("the build ran out of disk or tmpfs space, try a larger shm_size_mb or free up space on the host")
+    } else if error.contains("too many open files") {
+        Some("the build hit the open file limit, try raising the nofile ulimit")
+    } else if error.contains("file size limit exceeded") {
+        Some("the build hit the file size limit, try raising the fsize ulimit")
+    } else if error.contains("resource temporarily unavailable") || error.contains("cannot fork") {
+        Some("the build hit the process limit, try raising the nproc ulimit")
+    } else {
+        None
+    }
+}
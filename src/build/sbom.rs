@@ -0,0 +1,117 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+/// Generating a software bill of materials for built images.
+#[derive(Serialize, Debug, Clone)]
+pub struct SbomConfig {
+    /* for every build, else only those that ask with "sbom" */
+    pub enabled: bool,
+    /* a syft compatible command, builds go on without an SBOM when it's missing */
+    pub generator: String,
+    pub format: SbomFormat,
+    pub timeout_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SbomFormat {
+    Spdx,
+    Cyclonedx,
+}
+
+impl SbomFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "spdx" => Some(SbomFormat::Spdx),
+            "cyclonedx" => Some(SbomFormat::Cyclonedx),
+            _ => None,
+        }
+    }
+
+    /// The generator's `-o` value for this format, both being JSON.
+    fn output(&self) -> &'static str {
+        match self {
+            SbomFormat::Spdx => "spdx-json",
+            SbomFormat::Cyclonedx => "cyclonedx-json",
+        }
+    }
+}
+
+/// The SBOM of `image` as the configured generator writes it, `None` when
+/// the generator isn't installed.
+pub async fn generate(config: &SbomConfig, image: &str) -> Result<Option<Vec<u8>>, String> {
+    let mut command = Command::new(&config.generator);
+    command.kill_on_drop(true).arg(image).arg("-o").arg(config.format.output()).arg("-q");
+
+    let output = match tokio::time::timeout(Duration::from_secs(config.timeout_secs), command.output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Ok(Err(e)) => return Err(format!("Failed to run {}: {}", config.generator, e)),
+        Err(_) => return Err(format!("{} timed out after {}s", config.generator, config.timeout_secs)),
+    };
+
+    if !output.status.success() {
+        return Err(format!("{} failed: {}", config.generator, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    serde_json::from_slice::<serde_json::Value>(&output.stdout)
+        .map_err(|e| format!("{} didn't write a JSON document: {}", config.generator, e))?;
+
+    Ok(Some(output.stdout))
+}
+
+/// `sbom` as stored on the build, always gzipped as SBOMs of whole images
+/// run to megabytes.
+pub fn encode(sbom: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    /* writes to a Vec don't fail */
+    encoder.write_all(sbom).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn decode(stored: &[u8]) -> Result<Vec<u8>, String> {
+    let mut sbom = Vec::new();
+    GzDecoder::new(stored).read_to_end(&mut sbom).map_err(|e| e.to_string())?;
+    Ok(sbom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(generator: &str) -> SbomConfig {
+        SbomConfig { enabled: true, generator: generator.to_string(), format: SbomFormat::Spdx, timeout_secs: 10 }
+    }
+
+    #[tokio::test]
+    async fn generated_document_is_kept() {
+        use std::os::unix::fs::PermissionsExt;
+
+        /* stands in for syft, answering with a document naming how it was run */
+        let dir = tempfile::tempdir().unwrap();
+        let syft = dir.path().join("syft");
+        std::fs::write(&syft, "#!/bin/sh\necho \"{\\\"args\\\": \\\"$*\\\"}\"\n").unwrap();
+        std::fs::set_permissions(&syft, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let document = generate(&config(syft.to_str().unwrap()), "app:1").await.unwrap().unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&document).unwrap();
+        assert_eq!(document, serde_json::json!({ "args": "app:1 -o spdx-json -q" }));
+    }
+
+    #[tokio::test]
+    async fn missing_generator_is_skipped() {
+        assert_eq!(generate(&config("forge-no-such-sbom-generator"), "app:1").await, Ok(None));
+    }
+
+    #[test]
+    fn stored_sboms_round_trip() {
+        let document = br#"{"bomFormat":"CycloneDX"}"#;
+        assert_eq!(decode(&encode(document)).unwrap(), document);
+        assert!(decode(b"not gzip").is_err());
+    }
+}
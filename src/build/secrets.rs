@@ -0,0 +1,106 @@
+use serde::Deserialize;
+use tempfile::TempDir;
+
+use crate::build::workdir;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Secrets a request wants available to the build as files, by name. Only
+/// the names are ever printed.
+#[derive(Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct BuildSecrets(BTreeMap<String, String>);
+
+impl BuildSecrets {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.0.keys()
+    }
+
+    /// Only for masking them in captured output.
+    pub fn values(&self) -> impl Iterator<Item = &String> {
+        self.0.values()
+    }
+
+    /// Every secret has to be on the server's allow list.
+    pub fn check(&self, allowed: &[String]) -> Result<(), String> {
+        for name in self.names() {
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') || name.starts_with('.') {
+                return Err(format!("Invalid secret name {}", name));
+            }
+            if !allowed.contains(name) {
+                return Err(format!("Secret {} is not allowed", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BuildSecrets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// Secrets written out for one docker build, removed again when dropped.
+pub struct SecretFiles {
+    dir: TempDir,
+    names: Vec<String>,
+}
+
+impl SecretFiles {
+    /// Writes each secret to its own owner-only file, on tmpfs when the host
+    /// has /dev/shm so they never touch disk.
+    pub fn write(secrets: &BuildSecrets, fallback_dir: &Path) -> std::io::Result<Self> {
+        let shm = Path::new("/dev/shm");
+        let base = if shm.is_dir() { shm } else { fallback_dir };
+        let dir = workdir::create(base, "secrets")?;
+
+        for (name, value) in &secrets.0 {
+            let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(dir.path().join(name))?;
+            file.write_all(value.as_bytes())?;
+        }
+
+        Ok(SecretFiles {
+            dir,
+            names: secrets.names().cloned().collect(),
+        })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// `docker build` flags handing the files to BuildKit.
+    pub fn docker_args(&self) -> Vec<String> {
+        self.names.iter()
+            .flat_map(|name| ["--secret".to_string(), format!("id={},src={}", name, self.path(name).display())])
+            .collect()
+    }
+
+    /// Mounts every secret at /run/secrets/<name> for each RUN step of
+    /// `dockerfile`, nixpacks doesn't know about them.
+    pub fn mount_in(&self, dockerfile: &Path) -> std::io::Result<()> {
+        let mounts: String = self.names.iter()
+            .map(|name| format!("--mount=type=secret,id={},target=/run/secrets/{} ", name, name))
+            .collect();
+
+        let contents = fs::read_to_string(dockerfile)?;
+        let rewritten: Vec<String> = contents.lines()
+            .map(|line| match line.trim_start().strip_prefix("RUN ") {
+                Some(command) => format!("RUN {}{}", mounts, command),
+                None => line.to_string(),
+            })
+            .collect();
+
+        fs::write(dockerfile, rewritten.join("\n") + "\n")
+    }
+}
@@ -0,0 +1,75 @@
+use git2::Repository;
+
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Unsigned,
+    Untrusted(String),
+    Git(git2::Error),
+    Gpg(std::io::Error),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Unsigned => write!(f, "HEAD commit is not signed"),
+            SignatureError::Untrusted(reason) => write!(f, "HEAD commit signature is not trusted: {}", reason),
+            SignatureError::Git(e) => write!(f, "failed to read HEAD commit: {}", e),
+            SignatureError::Gpg(e) => write!(f, "failed to run gpg: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Checks that the HEAD commit of `repo_dir` carries a GPG signature made by
+/// one of the keys in `keyring`.
+pub fn verify_head_signature(repo_dir: &str, keyring: &Path) -> Result<(), SignatureError> {
+    let repo = Repository::open(repo_dir).map_err(SignatureError::Git)?;
+    let head = repo.head().and_then(|head| head.peel_to_commit()).map_err(SignatureError::Git)?;
+
+    let (signature, signed_data) = match repo.extract_signature(&head.id(), None) {
+        Ok(parts) => parts,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Err(SignatureError::Unsigned),
+        Err(e) => return Err(SignatureError::Git(e)),
+    };
+
+    let signature_file = tempfile::NamedTempFile::new().map_err(SignatureError::Gpg)?;
+    std::fs::write(signature_file.path(), &*signature).map_err(SignatureError::Gpg)?;
+
+    /* only the configured keyring is consulted, never the user's default one */
+    let mut gpg = Command::new("gpg")
+        .arg("--batch")
+        .arg("--no-default-keyring")
+        .arg("--keyring")
+        .arg(keyring)
+        .arg("--status-fd")
+        .arg("1")
+        .arg("--verify")
+        .arg(signature_file.path())
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(SignatureError::Gpg)?;
+
+    gpg.stdin.take().unwrap().write_all(&signed_data).map_err(SignatureError::Gpg)?;
+    let output = gpg.wait_with_output().map_err(SignatureError::Gpg)?;
+    let status = String::from_utf8_lossy(&output.stdout);
+
+    if output.status.success() && status.lines().any(|line| line.starts_with("[GNUPG:] VALIDSIG")) {
+        return Ok(());
+    }
+
+    let reason = status.lines()
+        .find(|line| line.starts_with("[GNUPG:] NO_PUBKEY") || line.starts_with("[GNUPG:] BADSIG") || line.starts_with("[GNUPG:] ERRSIG"))
+        .map(|line| line.trim_start_matches("[GNUPG:] ").to_string())
+        .unwrap_or_else(|| "signature could not be verified".to_string());
+
+    Err(SignatureError::Untrusted(reason))
+}
@@ -0,0 +1,81 @@
+use tempfile::TempDir;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::build::disk::dir_size;
+
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+const PREFIX: &str = "forge-";
+
+/* random per process, so a sweep can tell our live dirs from ones a crashed run left */
+fn instance() -> &'static str {
+    static INSTANCE: OnceLock<String> = OnceLock::new();
+    INSTANCE.get_or_init(|| Uuid::new_v4().simple().to_string()[..8].to_string())
+}
+
+/// A working directory of `kind` (clone, upload, context, ...) under `base`,
+/// removed on drop. It's named `forge-<instance>-<kind>-<random>`, which is
+/// how `sweep` recognizes directories forge created.
+pub fn create(base: &Path, kind: &str) -> std::io::Result<TempDir> {
+    tempfile::Builder::new()
+        .prefix(&format!("{}{}-{}-", PREFIX, instance(), kind))
+        .tempdir_in(base)
+}
+
+/* the instance that created the dir called `name`, None for anything not ours */
+fn owner(name: &str) -> Option<&str> {
+    let (instance, rest) = name.strip_prefix(PREFIX)?.split_once('-')?;
+    let (kind, random) = rest.split_once('-')?;
+
+    let valid = instance.len() == 8
+        && instance.chars().all(|c| c.is_ascii_hexdigit())
+        && !kind.is_empty()
+        && !random.is_empty();
+    valid.then_some(instance)
+}
+
+/// Removes working directories under `base` that an earlier (crashed) forge
+/// left behind and haven't been touched for `min_age`. Only directories
+/// named by `create` are considered, never those of this process. Returns
+/// how many were removed and the bytes reclaimed.
+pub fn sweep(base: &Path, min_age: Duration) -> std::io::Result<(usize, u64)> {
+    let now = SystemTime::now();
+    let mut removed = 0;
+    let mut reclaimed = 0;
+
+    for entry in fs::read_dir(base)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let owned_by_other = name.to_str().and_then(owner).is_some_and(|owner| owner != instance());
+        if !owned_by_other {
+            continue;
+        }
+
+        /* symlinks aren't followed, forge only creates real dirs */
+        let metadata = match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => metadata,
+            _ => continue,
+        };
+        let age = metadata.modified().ok().and_then(|modified| now.duration_since(modified).ok());
+        if age.is_none_or(|age| age < min_age) {
+            continue;
+        }
+
+        let path = entry.path();
+        let size = dir_size(&path);
+        match fs::remove_dir_all(&path) {
+            Ok(_) => {
+                info!("Removed leftover working dir {} ({} bytes)", path.display(), size);
+                removed += 1;
+                reclaimed += size;
+            },
+            Err(e) => warn!("Failed to remove leftover working dir {}: {}", path.display(), e),
+        }
+    }
+
+    Ok((removed, reclaimed))
+}
@@ -0,0 +1,712 @@
+use serde::{Serialize, Serializer};
+use sqlx::postgres::PgPoolOptions;
+
+use crate::build::backend::Backend;
+use crate::build::cache_scope::CacheScope;
+use crate::build::env_policy::EnvPolicy;
+use crate::build::platform::{self, DEFAULT_PLATFORMS};
+use crate::build::registry::{ExistingTagPolicy, RegistryCredentials, RegistryRoutes};
+use crate::build::sandbox::{SandboxLimits, Ulimit};
+use crate::build::sbom::{SbomConfig, SbomFormat};
+use crate::config::redact;
+use crate::engine::profiles::{parse_profiles, parse_provider_defaults};
+use crate::engine::reconcile::ImageCheck;
+use crate::logs::clickhouse::ClickHouseConfig;
+use crate::logs::dead_letter::DeadLetterConfig;
+use crate::logs::kafka::KafkaConfig;
+use crate::logs::level::DetectorKind;
+use crate::logs::logs::LogConfig;
+use crate::logs::redact::{check_patterns, RedactConfig};
+use crate::logs::s3::S3Config;
+use crate::logs::sink::SinkKind;
+use crate::server::compression::CompressionConfig;
+use crate::server::cors::CorsConfig;
+use crate::server::server::HttpConfig;
+use crate::webhook::apps::parse_apps;
+use crate::webhook::commit_status::CommitStatusConfig;
+use crate::webhook::limits::JsonLimits;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PullPolicy {
+    /* leave base images to the docker build itself */
+    Never,
+    /* pull only when the image isn't on the host yet */
+    IfMissing,
+    Always,
+}
+
+impl PullPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "never" | "false" | "0" => Some(PullPolicy::Never),
+            "missing" | "if_missing" => Some(PullPolicy::IfMissing),
+            "always" | "true" | "1" => Some(PullPolicy::Always),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DbPoolConfig {
+    /* COCKROACH_DB_URL */
+    #[serde(serialize_with = "redact::url")]
+    pub url: String,
+    pub max_connections: u32,
+    /* how long a query waits for a free connection before failing */
+    pub acquire_timeout: Duration,
+    /* none keeps connections around forever */
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+}
+
+impl DbPoolConfig {
+    fn from_env() -> Result<Self, String> {
+        /* 0 turns these off */
+        let optional_secs = |name: &str, default: u64| -> Result<Option<Duration>, String> {
+            Ok(Some(number_var(name, default)?).filter(|secs| *secs > 0).map(Duration::from_secs))
+        };
+
+        let max_connections = number_var("DB_MAX_CONNECTIONS", 5)?;
+        if max_connections == 0 {
+            return Err("DB_MAX_CONNECTIONS must be at least 1".to_string());
+        }
+
+        let acquire_timeout = number_var("DB_ACQUIRE_TIMEOUT_SECS", 10)?;
+        if acquire_timeout == 0 {
+            return Err("DB_ACQUIRE_TIMEOUT_SECS must be at least 1".to_string());
+        }
+
+        Ok(DbPoolConfig {
+            url: secret_var("COCKROACH_DB_URL")?.ok_or("COCKROACH_DB_URL or COCKROACH_DB_URL_FILE must be set")?,
+            max_connections,
+            acquire_timeout: Duration::from_secs(acquire_timeout),
+            idle_timeout: optional_secs("DB_IDLE_TIMEOUT_SECS", 600)?,
+            max_lifetime: optional_secs("DB_MAX_LIFETIME_SECS", 1800)?,
+        })
+    }
+
+    /// Pool options with these limits, connecting is left to the caller.
+    pub fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new()
+            .max_connections(self.max_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .idle_timeout(self.idle_timeout)
+            .max_lifetime(self.max_lifetime)
+    }
+}
+
+/// Server configuration, read once from the environment at startup. Shown
+/// by /admin/config, so secret fields are marked to be redacted.
+#[derive(Serialize, Debug, Clone)]
+pub struct Config {
+    pub db: DbPoolConfig,
+    pub http: HttpConfig,
+    /* browser origins allowed to call the api, no CORS headers when empty */
+    pub cors: CorsConfig,
+    pub compression: CompressionConfig,
+    pub pull_policy: PullPolicy,
+    /* which builds share a cache key, applied to builds that don't set one */
+    pub cache_scope: CacheScope,
+    /* what pushes do with tags the registry already has, for builds that don't say */
+    pub on_existing_tag: ExistingTagPolicy,
+    /* gpg keyring holding the keys allowed to sign commits we build */
+    pub trusted_keyring: Option<PathBuf>,
+    /* only used when built with the grpc feature */
+    pub grpc_addr: SocketAddr,
+    /* limits every build runs with unless the request asks otherwise */
+    pub build_limits: SandboxLimits,
+    pub max_build_ulimits: BTreeMap<String, i64>,
+    pub max_build_shm_size_mb: u64,
+    pub logs: LogConfig,
+    /* record followed collections so they resume after a restart, read once at startup */
+    pub log_resume: bool,
+    /* where per-build captured output is kept */
+    pub build_log_dir: PathBuf,
+    /* whether builds write a log unless they say otherwise */
+    pub capture_build_logs: bool,
+    /* builds' out_dir contents, kept for the last artifact_retention completed builds */
+    pub artifact_dir: PathBuf,
+    pub artifact_retention: usize,
+    /* how many keep_failed_image tags are kept, the oldest are removed */
+    pub failed_image_retention: usize,
+    pub post_build_timeout_secs: u64,
+    /* GITHUB_WEBHOOK_SECRET, deliveries are signed with it */
+    #[serde(serialize_with = "redact::optional_secret")]
+    pub webhook_secret: Option<String>,
+    /* accept X-Hub-Signature (sha1) when X-Hub-Signature-256 is missing */
+    pub webhook_allow_sha1: bool,
+    /* checked before a webhook payload is deserialized */
+    pub webhook_json_limits: JsonLimits,
+    /* X-GitHub-Event types the webhook acts on, others are answered and dropped */
+    pub webhook_events: Vec<String>,
+    /* profile webhook triggered builds are layered over */
+    pub webhook_profile: Option<String>,
+    /* owner/name -> the apps a push to that repo builds, one build each */
+    pub webhook_apps: BTreeMap<String, Vec<serde_json::Value>>,
+    /* clones and build contexts are created under here */
+    pub workdir_base: PathBuf,
+    pub min_free_disk_mb: u64,
+    /* largest tarball /build accepts, both as sent and unpacked */
+    pub max_upload_mb: u64,
+    /* named partial build requests selectable with "profile" */
+    #[serde(serialize_with = "redact_profile_secrets")]
+    pub profiles: BTreeMap<String, serde_json::Value>,
+    /* detected nixpacks provider -> build_options defaults */
+    pub provider_defaults: BTreeMap<String, serde_json::Value>,
+    /* number of build workers */
+    pub max_concurrent_builds: usize,
+    pub max_concurrent_pushes: usize,
+    /* GitHub commit statuses for webhook builds */
+    pub commit_status: CommitStatusConfig,
+    /* bearer token for /admin routes, which are disabled without one */
+    #[serde(serialize_with = "redact::optional_secret")]
+    pub admin_token: Option<String>,
+    /* the informational HTML at GET / */
+    pub enable_landing_page: bool,
+    /* non-terminal builds older than this with no worker are orphaned */
+    #[serde(serialize_with = "seconds")]
+    pub orphan_threshold: chrono::Duration,
+    /* how often running builds record last_heartbeat, 0 for never */
+    pub heartbeat_interval_secs: u64,
+    /* registry/namespace prepended to image names without a registry host */
+    pub image_prefix: Option<String>,
+    /* per environment registries, chosen over image_prefix */
+    pub registry_routes: RegistryRoutes,
+    /* logged in with before pushing, otherwise the host's login is used */
+    pub registry_credentials: Option<RegistryCredentials>,
+    /* bare mirrors of built repos, fetched into instead of cloning every build */
+    pub clone_cache_dir: Option<PathBuf>,
+    pub clone_cache_max_mb: u64,
+    /* how long /plan results are kept for a /build of the same commit, 0 for not at all */
+    pub plan_cache_ttl_secs: u64,
+    pub plan_cache_max_entries: usize,
+    /* planning that takes longer fails the build, 0 for no limit */
+    pub plan_timeout_secs: u64,
+    /* secret names requests may pass with "secrets" */
+    pub allowed_build_secrets: Vec<String>,
+    /* normalized, build_options.platform has to be one of these */
+    pub allowed_platforms: Vec<String>,
+    /* normalized platform -> DOCKER_HOST of a daemon that builds it natively */
+    pub platform_docker_hosts: BTreeMap<String, String>,
+    /* docker networks build_network may name, none when empty */
+    pub allowed_build_networks: Vec<String>,
+    /* env var names requests may set */
+    pub build_envs: EnvPolicy,
+    /* how often completed builds are checked for pruned images, 0 for never */
+    pub image_reconcile_interval_secs: u64,
+    pub image_reconcile_check: ImageCheck,
+    /* how often working dirs left by crashed runs are swept, 0 for only at startup */
+    pub workdir_sweep_interval_secs: u64,
+    /* leftover working dirs younger than this are kept */
+    pub workdir_sweep_min_age_secs: u64,
+    /* /stats window when the request doesn't give one */
+    pub stats_window_days: i64,
+    /* tags builds may only apply with allow_protected_tags */
+    pub protected_tags: Vec<String>,
+    pub build_backend: Backend,
+    /* cancel a build whose synchronous /build caller hung up */
+    pub cancel_on_disconnect: bool,
+    /* fail builds whose image runs as root, unless they set allow_root */
+    pub require_non_root: bool,
+    /* fail builds whose image is bigger, 0 for no limit */
+    pub max_image_size_mb: u64,
+    /* label images with their source, revision and build time */
+    pub oci_labels: bool,
+    /* build under a tag of the build's own, the real tags are applied once it went through */
+    pub isolate_build_tags: bool,
+    pub sbom: SbomConfig,
+}
+
+impl Config {
+    /// Puts back the settings of `running` that only take effect at startup
+    /// (listeners, the db pool, the build backend and clone cache), returning
+    /// the names of those this config tried to change.
+    pub fn keep_startup_settings(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field != running.$field {
+                        ignored.push(stringify!($field));
+                        self.$field = running.$field.clone();
+                    }
+                )*
+            };
+        }
+        keep!(db, http, grpc_addr, build_backend, clone_cache_dir, clone_cache_max_mb, plan_cache_ttl_secs, plan_cache_max_entries, image_reconcile_interval_secs, max_concurrent_pushes, workdir_sweep_interval_secs, log_resume);
+        /* the dead-letter replayer's interval is fixed when it's spawned */
+        if self.logs.dead_letter.retry_interval_secs != running.logs.dead_letter.retry_interval_secs {
+            ignored.push("logs.dead_letter.retry_interval_secs");
+            self.logs.dead_letter.retry_interval_secs = running.logs.dead_letter.retry_interval_secs;
+        }
+        /* the sinks' flush limiters are sized once, by the first collection */
+        if self.logs.sink_concurrency != running.logs.sink_concurrency {
+            ignored.push("logs.sink_concurrency");
+            self.logs.sink_concurrency = running.logs.sink_concurrency;
+        }
+
+        ignored
+    }
+
+    /// Reads the config from the environment, or says which setting is
+    /// missing or invalid.
+    pub fn from_env() -> Result<Self, String> {
+        let pull_policy = match var("PULL_BASE_IMAGES") {
+            Some(value) => PullPolicy::parse(&value)
+                .ok_or_else(|| format!("PULL_BASE_IMAGES must be one of never, missing, always (got {})", value))?,
+            None => PullPolicy::Never,
+        };
+
+        let mut build_limits = SandboxLimits::default();
+        for ulimit in list_var("BUILD_ULIMITS") {
+            let (name, ulimit) = Ulimit::parse(&ulimit).map_err(|e| format!("BUILD_ULIMITS: {}", e))?;
+            build_limits.ulimits.insert(name, ulimit);
+        }
+        build_limits.shm_size_mb = var("BUILD_SHM_SIZE_MB")
+            .map(|value| value.parse().map_err(|_| format!("BUILD_SHM_SIZE_MB must be a number (got {})", value)))
+            .transpose()?;
+
+        let max_build_ulimits = var("MAX_BUILD_ULIMITS")
+            .map(|_| list_var("MAX_BUILD_ULIMITS"))
+            .unwrap_or_else(|| vec!["nofile=1048576".to_string(), "nproc=65536".to_string(), "fsize=-1".to_string()])
+            .into_iter()
+            .map(|entry| {
+                let (name, max) = entry.split_once('=').ok_or("MAX_BUILD_ULIMITS entries must be name=max")?;
+                let max = max.parse().map_err(|_| format!("MAX_BUILD_ULIMITS maximums must be numbers (got {})", max))?;
+                Ok((name.to_string(), max))
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Config {
+            db: DbPoolConfig::from_env()?,
+            http: HttpConfig {
+                keepalive: var("HTTP_KEEPALIVE").is_none_or(|_| bool_var("HTTP_KEEPALIVE")),
+                tcp_keepalive: Some(number_var("HTTP_TCP_KEEPALIVE_SECS", 60)?)
+                    .filter(|secs| *secs > 0)
+                    .map(Duration::from_secs),
+                header_read_timeout: Duration::from_secs(number_var("HTTP_HEADER_READ_TIMEOUT_SECS", 30)?),
+                body_read_timeout: Duration::from_secs(number_var("HTTP_BODY_READ_TIMEOUT_SECS", 60)?),
+                max_connections: number_var("HTTP_MAX_CONNECTIONS", 1024)?,
+            },
+            cors: CorsConfig {
+                allowed_origins: list_var("CORS_ALLOWED_ORIGINS"),
+                allowed_methods: var("CORS_ALLOWED_METHODS")
+                    .map_or_else(|| vec!["GET".to_string(), "POST".to_string()], |_| list_var("CORS_ALLOWED_METHODS")),
+                allowed_headers: var("CORS_ALLOWED_HEADERS")
+                    .map_or_else(|| vec!["Content-Type".to_string(), "Authorization".to_string()], |_| list_var("CORS_ALLOWED_HEADERS")),
+                max_age_secs: number_var("CORS_MAX_AGE_SECS", 600)?,
+            },
+            compression: CompressionConfig {
+                enabled: var("HTTP_COMPRESSION").is_none_or(|_| bool_var("HTTP_COMPRESSION")),
+                min_bytes: number_var("HTTP_COMPRESSION_MIN_BYTES", 4096)?,
+            },
+            pull_policy,
+            cache_scope: match var("CACHE_SCOPE") {
+                Some(value) => CacheScope::parse(&value)
+                    .ok_or_else(|| format!("CACHE_SCOPE must be shared or branch (got {})", value))?,
+                None => CacheScope::Shared,
+            },
+            on_existing_tag: match var("ON_EXISTING_TAG") {
+                Some(value) => ExistingTagPolicy::parse(&value)
+                    .ok_or_else(|| format!("ON_EXISTING_TAG must be overwrite, skip or fail (got {})", value))?,
+                None => ExistingTagPolicy::Overwrite,
+            },
+            trusted_keyring: var("TRUSTED_GPG_KEYRING").map(PathBuf::from),
+            grpc_addr: var("GRPC_ADDR")
+                .unwrap_or_else(|| "0.0.0.0:50051".to_string())
+                .parse()
+                .map_err(|_| "GRPC_ADDR must be a socket address")?,
+            build_limits,
+            max_build_ulimits,
+            max_build_shm_size_mb: number_var("MAX_BUILD_SHM_SIZE_MB", 2048)?,
+            logs: LogConfig {
+                reconnect_attempts: number_var("LOG_RECONNECT_ATTEMPTS", 3)?,
+                reconnect_delay: Duration::from_millis(number_var("LOG_RECONNECT_DELAY_MS", 1000)?),
+                collection_timeout: Duration::from_secs(number_var("LOG_COLLECTION_TIMEOUT_SECS", 3600)?),
+                max_line_bytes: number_var("LOG_MAX_LINE_BYTES", 256 * 1024)?,
+                redact: RedactConfig {
+                    patterns: var("LOG_REDACT_PATTERNS_FILE")
+                        .map(|path| -> Result<Vec<String>, String> {
+                            let contents = read_file(&path)?;
+                            /* one regex per line, commas being common in them */
+                            let patterns: Vec<String> = contents.lines()
+                                .map(str::trim)
+                                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                                .map(String::from)
+                                .collect();
+                            check_patterns(&patterns).map_err(|e| format!("Invalid LOG_REDACT_PATTERNS_FILE {}: {}", path, e))?;
+                            Ok(patterns)
+                        })
+                        .transpose()?
+                        .unwrap_or_default(),
+                    env_names: var("LOG_REDACT_ENV_NAMES")
+                        .map_or_else(|| ["TOKEN", "SECRET", "PASSWORD", "KEY"].map(String::from).to_vec(), |_| list_var("LOG_REDACT_ENV_NAMES")),
+                },
+                sinks: list_var("LOG_SINKS").iter()
+                    .map(|sink| SinkKind::parse(sink).ok_or_else(|| format!("LOG_SINKS: unknown sink {}, expected clickhouse, kafka or s3", sink)))
+                    .collect::<Result<_, _>>()?,
+                sink_concurrency: number_var("LOG_SINK_CONCURRENCY", 4)?,
+                sink_retry_interval: Duration::from_secs(number_var("LOG_SINK_RETRY_SECS", 30)?),
+                clickhouse: ClickHouseConfig {
+                    collapse_repeats: bool_var("CLICKHOUSE_COLLAPSE_REPEATS"),
+                    ..ClickHouseConfig::new(
+                        secret_var("CLICKHOUSE_URL")?.unwrap_or_else(|| "tcp://clickhouse:9000".to_string()),
+                        var("CLICKHOUSE_LOGS_TABLE").unwrap_or_else(|| "logs".to_string()),
+                    )?
+                },
+                kafka: KafkaConfig {
+                    brokers: var("KAFKA_BROKERS").unwrap_or_else(|| "redpanda:18081".to_string()),
+                    topic: var("KAFKA_LOGS_TOPIC").unwrap_or_else(|| "logs_topic".to_string()),
+                    buffer_size: number_var("KAFKA_BUFFER_SIZE", 1000)?,
+                    retry_initial: Duration::from_millis(number_var("KAFKA_RETRY_INITIAL_MS", 500)?),
+                    retry_max: Duration::from_millis(number_var("KAFKA_RETRY_MAX_MS", 30000)?),
+                },
+                s3: match (var("S3_ENDPOINT"), var("S3_BUCKET")) {
+                    (Some(endpoint), Some(bucket)) => Some(S3Config {
+                        endpoint,
+                        bucket,
+                        region: var("S3_REGION").unwrap_or_else(|| "us-east-1".to_string()),
+                        access_key_id: var("S3_ACCESS_KEY_ID").unwrap_or_default(),
+                        secret_access_key: secret_var("S3_SECRET_ACCESS_KEY")?.unwrap_or_default(),
+                        batch_lines: number_var("S3_BATCH_LINES", 1000)?,
+                    }),
+                    _ => None,
+                },
+                dead_letter: DeadLetterConfig {
+                    dir: var("LOG_DEAD_LETTER_DIR").map(PathBuf::from),
+                    max_mb: number_var("LOG_DEAD_LETTER_MAX_MB", 1024)?,
+                    retry_interval_secs: number_var("LOG_DEAD_LETTER_RETRY_SECS", 60)?,
+                },
+                allowed_topics: list_var("LOG_ALLOWED_TOPICS"),
+                allowed_tables: list_var("LOG_ALLOWED_TABLES"),
+                level_detectors: var("LOG_LEVEL_DETECTORS")
+                    .map_or_else(|| vec!["json".to_string(), "prefix".to_string()], |_| list_var("LOG_LEVEL_DETECTORS"))
+                    .iter()
+                    .map(|name| DetectorKind::parse(name).ok_or_else(|| format!("LOG_LEVEL_DETECTORS: unknown detector {}, expected json or prefix", name)))
+                    .collect::<Result<_, _>>()?,
+            },
+            log_resume: bool_var("LOG_RESUME"),
+            build_log_dir: PathBuf::from(var("BUILD_LOG_DIR").unwrap_or_else(|| "build-logs".to_string())),
+            capture_build_logs: var("CAPTURE_BUILD_LOGS").is_none_or(|_| bool_var("CAPTURE_BUILD_LOGS")),
+            artifact_dir: PathBuf::from(var("ARTIFACT_DIR").unwrap_or_else(|| "build-artifacts".to_string())),
+            artifact_retention: number_var("ARTIFACT_RETENTION", 10)?,
+            failed_image_retention: number_var("FAILED_IMAGE_RETENTION", 5)?,
+            post_build_timeout_secs: number_var("POST_BUILD_TIMEOUT_SECS", 600)?,
+            webhook_secret: secret_var("GITHUB_WEBHOOK_SECRET")?,
+            webhook_allow_sha1: bool_var("WEBHOOK_ALLOW_SHA1"),
+            webhook_json_limits: JsonLimits {
+                max_depth: number_var("WEBHOOK_MAX_JSON_DEPTH", 32)?,
+                max_fields: number_var("WEBHOOK_MAX_JSON_FIELDS", 10000)?,
+            },
+            webhook_events: var("WEBHOOK_EVENTS")
+                .map_or_else(|| vec!["push".to_string()], |_| list_var("WEBHOOK_EVENTS")),
+            webhook_profile: var("WEBHOOK_PROFILE"),
+            webhook_apps: var("WEBHOOK_APPS_FILE")
+                .map(|path| parse_apps(&read_file(&path)?).map_err(|e| format!("Invalid WEBHOOK_APPS_FILE {}: {}", path, e)))
+                .transpose()?
+                .unwrap_or_default(),
+            workdir_base: var("WORKDIR_BASE").map(PathBuf::from).unwrap_or_else(env::temp_dir),
+            min_free_disk_mb: number_var("MIN_FREE_DISK_MB", 1024)?,
+            max_upload_mb: number_var("MAX_UPLOAD_MB", 1024)?,
+            profiles: var("BUILD_PROFILES_FILE")
+                .map(|path| parse_profiles(&read_file(&path)?).map_err(|e| format!("Invalid BUILD_PROFILES_FILE {}: {}", path, e)))
+                .transpose()?
+                .unwrap_or_default(),
+            provider_defaults: var("PROVIDER_DEFAULTS_FILE")
+                .map(|path| parse_provider_defaults(&read_file(&path)?).map_err(|e| format!("Invalid PROVIDER_DEFAULTS_FILE {}: {}", path, e)))
+                .transpose()?
+                .unwrap_or_default(),
+            max_concurrent_builds: number_var("MAX_CONCURRENT_BUILDS", 2)?,
+            max_concurrent_pushes: number_var("MAX_CONCURRENT_PUSHES", 2)?,
+            admin_token: secret_var("ADMIN_TOKEN")?,
+            commit_status: CommitStatusConfig {
+                token: secret_var("GITHUB_TOKEN")?,
+                context: var("COMMIT_STATUS_CONTEXT").unwrap_or_else(|| "forge".to_string()),
+                api_url: var("GITHUB_API_URL").unwrap_or_else(|| "https://api.github.com".to_string()),
+                target_url: var("COMMIT_STATUS_TARGET_URL"),
+            },
+            enable_landing_page: var("ENABLE_LANDING_PAGE").is_none_or(|_| bool_var("ENABLE_LANDING_PAGE")),
+            orphan_threshold: chrono::Duration::seconds(number_var("ORPHAN_THRESHOLD_SECS", 3600)?),
+            heartbeat_interval_secs: number_var("HEARTBEAT_INTERVAL_SECS", 30)?,
+            image_prefix: var("IMAGE_PREFIX"),
+            registry_routes: RegistryRoutes {
+                environments: map_var("REGISTRY_ROUTES")?,
+                branches: map_var("BRANCH_ENVIRONMENTS")?,
+                required: bool_var("REQUIRE_REGISTRY_ROUTE"),
+            },
+            registry_credentials: registry_credentials()?,
+            clone_cache_dir: var("CLONE_CACHE_DIR").map(PathBuf::from),
+            clone_cache_max_mb: number_var("CLONE_CACHE_MAX_MB", 10240)?,
+            plan_cache_ttl_secs: number_var("PLAN_CACHE_TTL_SECS", 600)?,
+            plan_cache_max_entries: number_var("PLAN_CACHE_MAX_ENTRIES", 100)?,
+            plan_timeout_secs: number_var("PLAN_TIMEOUT_SECS", 300)?,
+            allowed_build_secrets: list_var("ALLOWED_BUILD_SECRETS"),
+            allowed_platforms: var("ALLOWED_PLATFORMS")
+                .map_or_else(|| DEFAULT_PLATFORMS.map(String::from).to_vec(), |_| list_var("ALLOWED_PLATFORMS"))
+                .iter()
+                .map(|name| platform::normalize(name))
+                .collect(),
+            platform_docker_hosts: map_var("PLATFORM_DOCKER_HOSTS")?
+                .into_iter()
+                .map(|(name, host)| (platform::normalize(&name), host))
+                .collect(),
+            allowed_build_networks: list_var("ALLOWED_BUILD_NETWORKS"),
+            build_envs: EnvPolicy {
+                allowed: list_var("ALLOWED_BUILD_ENVS"),
+                denied: list_var("DENIED_BUILD_ENVS"),
+                drop_denied: bool_var("DROP_DENIED_BUILD_ENVS"),
+            },
+            protected_tags: list_var("PROTECTED_TAGS"),
+            stats_window_days: number_var("STATS_WINDOW_DAYS", 30)?,
+            image_reconcile_interval_secs: number_var("IMAGE_RECONCILE_INTERVAL_SECS", 0)?,
+            image_reconcile_check: match var("IMAGE_RECONCILE_CHECK") {
+                Some(value) => ImageCheck::parse(&value)
+                    .ok_or_else(|| format!("IMAGE_RECONCILE_CHECK must be one of local, registry (got {})", value))?,
+                None => ImageCheck::Local,
+            },
+            workdir_sweep_interval_secs: number_var("WORKDIR_SWEEP_INTERVAL_SECS", 3600)?,
+            workdir_sweep_min_age_secs: number_var("WORKDIR_SWEEP_MIN_AGE_SECS", 21600)?,
+            build_backend: match var("BUILD_BACKEND") {
+                Some(value) => Backend::parse(&value)
+                    .ok_or_else(|| format!("BUILD_BACKEND must be one of docker, podman, buildah (got {})", value))?,
+                None => Backend::Docker,
+            },
+            cancel_on_disconnect: var("CANCEL_ON_DISCONNECT").is_none_or(|_| bool_var("CANCEL_ON_DISCONNECT")),
+            require_non_root: bool_var("REQUIRE_NON_ROOT"),
+            max_image_size_mb: number_var("MAX_IMAGE_SIZE_MB", 0)?,
+            oci_labels: var("OCI_LABELS").is_none_or(|_| bool_var("OCI_LABELS")),
+            isolate_build_tags: bool_var("ISOLATE_BUILD_TAGS"),
+            sbom: SbomConfig {
+                enabled: bool_var("GENERATE_SBOM"),
+                generator: var("SBOM_GENERATOR").unwrap_or_else(|| "syft".to_string()),
+                format: match var("SBOM_FORMAT") {
+                    Some(value) => SbomFormat::parse(&value)
+                        .ok_or_else(|| format!("SBOM_FORMAT must be spdx or cyclonedx (got {})", value))?,
+                    None => SbomFormat::Spdx,
+                },
+                timeout_secs: number_var("SBOM_TIMEOUT_SECS", 300)?,
+            },
+        })
+    }
+}
+
+/// Profiles can carry build secrets, only their names are shown.
+fn redact_profile_secrets<S: Serializer>(profiles: &BTreeMap<String, serde_json::Value>, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut profiles = profiles.clone();
+
+    for profile in profiles.values_mut() {
+        if let Some(serde_json::Value::Object(secrets)) = profile.get_mut("secrets") {
+            for value in secrets.values_mut() {
+                *value = serde_json::Value::from(redact::MASK);
+            }
+        }
+    }
+
+    profiles.serialize(serializer)
+}
+
+fn seconds<S: Serializer>(duration: &chrono::Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_i64(duration.num_seconds())
+}
+
+/// Reads an env var, treating unset and empty the same.
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Reads a numeric env var, `default` when it's unset.
+fn number_var<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+    match var(name) {
+        Some(value) => value.parse().map_err(|_| format!("{} must be a number (got {})", name, value)),
+        None => Ok(default),
+    }
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))
+}
+
+/// Reads a secret from the file `<name>_FILE` points at when that's set, as
+/// docker and kubernetes secrets are mounted, otherwise from `name` itself.
+/// Trailing whitespace, like the file's final newline, is dropped.
+fn secret_var(name: &str) -> Result<Option<String>, String> {
+    let file_var = format!("{}_FILE", name);
+    match var(&file_var) {
+        Some(path) => {
+            let value = std::fs::read_to_string(&path).map_err(|e| format!("{}: can't read {}: {}", file_var, path, e))?;
+            Ok(Some(value.trim_end().to_string()).filter(|value| !value.is_empty()))
+        },
+        None => Ok(var(name)),
+    }
+}
+
+/// Reads a true/false env var, unset meaning false.
+fn bool_var(name: &str) -> bool {
+    var(name).is_some_and(|value| matches!(value.to_lowercase().as_str(), "true" | "1" | "yes"))
+}
+
+/// Reads a comma separated env var, unset meaning an empty list.
+fn list_var(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|value| value.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// `REGISTRY_USERNAME` with `REGISTRY_PASSWORD`, both or neither.
+fn registry_credentials() -> Result<Option<RegistryCredentials>, String> {
+    match (var("REGISTRY_USERNAME"), secret_var("REGISTRY_PASSWORD")?) {
+        (Some(username), Some(password)) => Ok(Some(RegistryCredentials { username, password })),
+        (None, None) => Ok(None),
+        (Some(_), None) => Err("REGISTRY_USERNAME needs REGISTRY_PASSWORD or REGISTRY_PASSWORD_FILE".to_string()),
+        (None, Some(_)) => Err("REGISTRY_PASSWORD needs REGISTRY_USERNAME".to_string()),
+    }
+}
+
+/// Reads a comma separated list of key=value pairs.
+fn map_var(name: &str) -> Result<BTreeMap<String, String>, String> {
+    list_var(name)
+        .into_iter()
+        .map(|entry| {
+            let (key, value) = entry.split_once('=').ok_or_else(|| format!("{} entries must be key=value (got {})", name, entry))?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::sync::{Mutex, MutexGuard};
+
+    /* held by tests reading the whole config from the environment, or setting variables it reads */
+    static ENV: Mutex<()> = Mutex::new(());
+
+    pub(crate) fn lock_env() -> MutexGuard<'static, ()> {
+        /* a failed test doesn't leave the environment unusable */
+        ENV.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The config an unconfigured forge runs with, plus `COCKROACH_DB_URL`.
+    pub(crate) fn config() -> Config {
+        let _env = lock_env();
+        env::set_var("COCKROACH_DB_URL", "postgres://forge@localhost:26257/forge");
+        Config::from_env().unwrap()
+    }
+
+    #[test]
+    fn sink_concurrency_keeps_its_startup_value() {
+        let running = config();
+        let mut reloaded = config();
+        reloaded.logs.sink_concurrency = running.logs.sink_concurrency + 1;
+
+        assert_eq!(reloaded.keep_startup_settings(&running), ["logs.sink_concurrency"]);
+        assert_eq!(reloaded.logs.sink_concurrency, running.logs.sink_concurrency);
+    }
+
+    #[tokio::test]
+    async fn exhausted_pool_times_out_instead_of_hanging() {
+        /* accepts connections but never answers, like a database that's stuck */
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                held.push(socket);
+            }
+        });
+
+        let db = DbPoolConfig {
+            url: format!("postgres://forge@{}/forge", addr),
+            max_connections: 1,
+            acquire_timeout: Duration::from_millis(200),
+            idle_timeout: None,
+            max_lifetime: None,
+        };
+        let pool = db.pool_options().connect_lazy(&db.url).unwrap();
+
+        let acquired = tokio::time::timeout(Duration::from_secs(5), pool.acquire()).await
+            .expect("acquire hung past its timeout");
+        assert!(matches!(acquired, Err(sqlx::Error::PoolTimedOut)), "{:?}", acquired.map(|_| ()));
+    }
+
+    #[test]
+    fn profile_secrets_are_redacted() {
+        let profiles = parse_profiles(r#"{ "production": { "environment": "production", "secrets": { "NPM_TOKEN": "hunter2" } } }"#).unwrap();
+
+        let shown = redact_profile_secrets(&profiles, serde_json::value::Serializer).unwrap();
+
+        assert_eq!(shown["production"]["secrets"]["NPM_TOKEN"], "****");
+        assert_eq!(shown["production"]["environment"], "production");
+        assert!(!shown.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn database_url_password_is_redacted() {
+        let db = DbPoolConfig {
+            url: "postgres://forge:hunter2@db:26257/forge".to_string(),
+            max_connections: 5,
+            acquire_timeout: Duration::from_secs(10),
+            idle_timeout: None,
+            max_lifetime: None,
+        };
+
+        let shown = serde_json::to_string(&db).unwrap();
+        assert!(!shown.contains("hunter2"), "{}", shown);
+        assert!(shown.contains("postgres://forge:****@db:26257/forge"), "{}", shown);
+    }
+
+    /* env var names are unique to each test, the environment being process wide */
+    #[test]
+    fn bad_numbers_are_errors() {
+        assert_eq!(number_var("FORGE_TEST_UNSET_NUMBER", 7u64), Ok(7));
+
+        env::set_var("FORGE_TEST_NUMBER", "12");
+        assert_eq!(number_var("FORGE_TEST_NUMBER", 7u64), Ok(12));
+
+        env::set_var("FORGE_TEST_BAD_NUMBER", "twelve");
+        assert_eq!(number_var("FORGE_TEST_BAD_NUMBER", 7u64), Err("FORGE_TEST_BAD_NUMBER must be a number (got twelve)".to_string()));
+    }
+
+    #[test]
+    fn secrets_are_read_from_their_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        std::fs::write(&path, "hunter2\n").unwrap();
+
+        env::set_var("FORGE_TEST_SECRET", "from the env");
+        env::set_var("FORGE_TEST_SECRET_FILE", &path);
+        assert_eq!(secret_var("FORGE_TEST_SECRET"), Ok(Some("hunter2".to_string())));
+
+        env::set_var("FORGE_TEST_MISSING_SECRET_FILE", dir.path().join("missing"));
+        let error = secret_var("FORGE_TEST_MISSING_SECRET").unwrap_err();
+        assert!(error.starts_with("FORGE_TEST_MISSING_SECRET_FILE: can't read"), "{}", error);
+    }
+
+    #[test]
+    fn registry_password_is_read_from_its_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry_password");
+        std::fs::write(&path, "hunter2\r\n").unwrap();
+
+        let _env = lock_env();
+        env::set_var("REGISTRY_USERNAME", "forge");
+        env::set_var("REGISTRY_PASSWORD_FILE", &path);
+        let credentials = registry_credentials();
+        env::remove_var("REGISTRY_PASSWORD_FILE");
+        let missing_password = registry_credentials();
+        env::remove_var("REGISTRY_USERNAME");
+
+        assert_eq!(credentials, Ok(Some(RegistryCredentials { username: "forge".to_string(), password: "hunter2".to_string() })));
+        assert_eq!(missing_password, Err("REGISTRY_USERNAME needs REGISTRY_PASSWORD or REGISTRY_PASSWORD_FILE".to_string()));
+        assert_eq!(registry_credentials(), Ok(None));
+    }
+}
@@ -0,0 +1,174 @@
+use clickhouse_rs::Pool;
+use colored::*;
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseProducer, Producer};
+use shiplift::Docker;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+
+use crate::config::config::Config;
+use crate::logs::clickhouse::ClickHouseConfig;
+use crate::logs::kafka::KafkaConfig;
+use crate::logs::sink::SinkKind;
+
+use std::time::Duration;
+
+/* every column the server reads or writes, i.e. all migrations applied */
+const BUILD_DATA_COLUMNS: &str = "id, start_time, end_time, status, plan, message, repo, timings, options, commit_sha, image, artifacts, image_user, matrix_id, matrix_index, dockerfile, provider_defaults, failed_image, sbom, metadata, image_size, cache_key, last_heartbeat, phase, nixpacks_version";
+
+/// What `forge doctor` checks, each one skippable by name.
+pub const CHECKS: [&str; 6] = ["config", "db", "schema", "clickhouse", "kafka", "docker"];
+
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skipped(String),
+}
+
+pub async fn check_db(config: &Config) -> Result<PgPool, String> {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(config.db.acquire_timeout)
+        .connect(&config.db.url)
+        .await
+        .map_err(|e| format!("can't connect: {}", e))?;
+
+    sqlx::query("SELECT 1").execute(&pool).await.map_err(|e| e.to_string())?;
+    Ok(pool)
+}
+
+pub async fn check_schema(pool: &PgPool) -> Result<(), String> {
+    sqlx::query(&format!("SELECT {} FROM build_data LIMIT 0", BUILD_DATA_COLUMNS))
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("build_data is missing or not migrated: {}", e))
+}
+
+pub async fn check_clickhouse(config: &ClickHouseConfig) -> Result<(), String> {
+    let mut handle = Pool::new(config.url.as_str()).get_handle().await.map_err(|e| format!("can't connect: {}", e))?;
+    handle.ping().await.map_err(|e| format!("ping failed: {}", e))?;
+
+    let block = handle.query(format!("EXISTS TABLE {}", config.table)).fetch_all().await.map_err(|e| e.to_string())?;
+    let exists: u8 = block.get(0, "result").map_err(|e| e.to_string())?;
+    if exists == 1 {
+        Ok(())
+    } else {
+        Err(format!("table {} doesn't exist", config.table))
+    }
+}
+
+pub async fn check_kafka(config: &KafkaConfig) -> Result<(), String> {
+    let brokers = config.brokers.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let producer: BaseProducer = ClientConfig::new()
+            .set("bootstrap.servers", &brokers)
+            .create()
+            .map_err(|e| e.to_string())?;
+
+        producer.client().fetch_metadata(None, Duration::from_secs(5))
+            .map(|_| ())
+            .map_err(|e| format!("brokers unreachable: {}", e))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+pub async fn check_docker() -> Result<(), String> {
+    Docker::new().ping().await.map(|_| ()).map_err(|e| format!("daemon unreachable: {}", e))
+}
+
+fn report(name: &str, outcome: &Outcome) {
+    match outcome {
+        Outcome::Pass(detail) => println!("{} {:<10} {}", "PASS".green(), name, detail),
+        Outcome::Fail(detail) => println!("{} {:<10} {}", "FAIL".red(), name, detail),
+        Outcome::Skipped(detail) => println!("{} {:<10} {}", "SKIP".yellow(), name, detail),
+    }
+}
+
+/// Runs every check not in `skip` and prints a report. Returns the process
+/// exit code, non-zero when any check failed.
+pub async fn run(skip: &[String]) -> i32 {
+    let skipped = |name: &str| skip.iter().any(|skip| skip == name);
+    let mut failed = false;
+    let mut record = |name: &str, outcome: Outcome| {
+        failed |= matches!(outcome, Outcome::Fail(_));
+        report(name, &outcome);
+    };
+
+    /* the other checks need the config, so it's always loaded */
+    let config = match Config::from_env() {
+        Ok(config) => {
+            record("config", if skipped("config") { Outcome::Skipped("skipped".to_string()) } else { Outcome::Pass("loaded".to_string()) });
+            config
+        },
+        Err(e) => {
+            record("config", Outcome::Fail(e));
+            for name in &CHECKS[1..] {
+                record(name, Outcome::Skipped("no usable config".to_string()));
+            }
+            return 1;
+        }
+    };
+
+    let pool = if skipped("db") {
+        record("db", Outcome::Skipped("skipped".to_string()));
+        None
+    } else {
+        match check_db(&config).await {
+            Ok(pool) => {
+                record("db", Outcome::Pass("connected".to_string()));
+                Some(pool)
+            },
+            Err(e) => {
+                record("db", Outcome::Fail(e));
+                None
+            }
+        }
+    };
+
+    record("schema", match (&pool, skipped("schema")) {
+        (_, true) => Outcome::Skipped("skipped".to_string()),
+        (None, false) => Outcome::Skipped("no database connection".to_string()),
+        (Some(pool), false) => match check_schema(pool).await {
+            Ok(()) => Outcome::Pass("build_data is migrated".to_string()),
+            Err(e) => Outcome::Fail(e),
+        },
+    });
+
+    let in_use = |kind: SinkKind| config.logs.sinks.contains(&kind);
+
+    record("clickhouse", if skipped("clickhouse") {
+        Outcome::Skipped("skipped".to_string())
+    } else if !in_use(SinkKind::ClickHouse) {
+        Outcome::Skipped("not in LOG_SINKS".to_string())
+    } else {
+        match check_clickhouse(&config.logs.clickhouse).await {
+            Ok(()) => Outcome::Pass(format!("table {} exists", config.logs.clickhouse.table)),
+            Err(e) => Outcome::Fail(e),
+        }
+    });
+
+    record("kafka", if skipped("kafka") {
+        Outcome::Skipped("skipped".to_string())
+    } else if !in_use(SinkKind::Kafka) {
+        Outcome::Skipped("not in LOG_SINKS".to_string())
+    } else {
+        match check_kafka(&config.logs.kafka).await {
+            Ok(()) => Outcome::Pass(format!("brokers {} reachable", config.logs.kafka.brokers)),
+            Err(e) => Outcome::Fail(e),
+        }
+    });
+
+    record("docker", if skipped("docker") {
+        Outcome::Skipped("skipped".to_string())
+    } else {
+        match check_docker().await {
+            Ok(()) => Outcome::Pass("daemon reachable".to_string()),
+            Err(e) => Outcome::Fail(e),
+        }
+    });
+
+    if failed { 1 } else { 0 }
+}
@@ -0,0 +1,52 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::engine::pipeline::BuildRecord;
+
+/// One field that differs between two builds, `None` where a build doesn't
+/// have it at all.
+#[derive(Serialize, Debug, Clone)]
+pub struct FieldChange {
+    pub field: String,
+    pub a: Option<Value>,
+    pub b: Option<Value>,
+}
+
+/// Fields of the plans, options, commits and timings of `a` and `b` that
+/// differ, as dotted paths like `plan.phases.install.cmds`.
+pub fn diff_builds(a: &BuildRecord, b: &BuildRecord) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    diff_values("", Some(&comparable(a)), Some(&comparable(b)), &mut changes);
+    changes
+}
+
+fn comparable(record: &BuildRecord) -> Value {
+    serde_json::json!({
+        "commit": record.commit,
+        "plan": record.plan,
+        "options": record.options,
+        "timings": record.timings,
+    })
+}
+
+/* objects are compared key by key, anything else (lists included) as a whole */
+fn diff_values(path: &str, a: Option<&Value>, b: Option<&Value>, changes: &mut Vec<FieldChange>) {
+    match (a, b) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+
+            for key in keys {
+                let field = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                diff_values(&field, a.get(key), b.get(key), changes);
+            }
+        }
+        (a, b) if a != b => changes.push(FieldChange {
+            field: path.to_string(),
+            a: a.cloned(),
+            b: b.cloned(),
+        }),
+        _ => {}
+    }
+}
@@ -0,0 +1,129 @@
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::broadcast;
+
+/// A build lifecycle transition, broadcast to anyone following builds live.
+#[derive(Serialize, Debug, Clone)]
+pub struct BuildEvent {
+    pub id: String,
+    pub repo: String,
+    pub status: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl BuildEvent {
+    pub fn new(id: &str, repo: &str, status: &str) -> Self {
+        BuildEvent {
+            id: id.to_string(),
+            repo: repo.to_string(),
+            status: status.to_string(),
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+/// A built image waiting for a free MAX_CONCURRENT_PUSHES slot.
+pub const PUSH_QUEUED: &str = "Pushing (queued)";
+
+/// Whether `status` is one a build never leaves.
+pub fn is_terminal(status: &str) -> bool {
+    !matches!(status, "queued" | "running" | PUSH_QUEUED)
+}
+
+/// Resolves once build `id` reaches a terminal status. `events` has to be
+/// subscribed before the build's current status is looked up, so the
+/// transition can't slip through in between.
+pub async fn build_finished(mut events: broadcast::Receiver<BuildEvent>, id: String) {
+    loop {
+        match events.recv().await {
+            Ok(event) if event.id == id && is_terminal(&event.status) => return,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Every event of `repo` (or of all repos) from now on, one json object per
+/// line. A reader that falls behind misses events instead of holding up the
+/// builds, and gets a `{"gap": <missed>}` line where they went missing.
+pub fn event_lines(events: broadcast::Receiver<BuildEvent>, repo: Option<String>) -> impl Stream<Item = String> {
+    futures::stream::unfold(events, move |mut events| {
+        let repo = repo.clone();
+        async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) if repo.as_ref().is_none_or(|repo| &event.repo == repo) => return Some((json!(event).to_string() + "\n", events)),
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        return Some((json!({ "gap": missed, "timestamp": Utc::now() }).to_string() + "\n", events));
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::config::tests::config;
+    use crate::engine::state::tests::state;
+
+    use futures::StreamExt;
+
+    fn line(line: Option<String>) -> serde_json::Value {
+        serde_json::from_str(&line.expect("the stream ended")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn transitions_appear_on_the_stream() {
+        let state = state(config());
+        let lines = event_lines(state.events.subscribe(), Some("https://github.com/example/app".to_string()));
+        futures::pin_mut!(lines);
+
+        for status in ["queued", "running", "Completed"] {
+            state.emit(BuildEvent::new("42", "https://github.com/example/other", status));
+            state.emit(BuildEvent::new("41", "https://github.com/example/app", status));
+        }
+
+        for status in ["queued", "running", "Completed"] {
+            let event = line(lines.next().await);
+            assert_eq!(event["id"], "41");
+            assert_eq!(event["repo"], "https://github.com/example/app");
+            assert_eq!(event["status"], status);
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_readers_get_a_gap_marker() {
+        let (tx, rx) = broadcast::channel(2);
+        let lines = event_lines(rx, None);
+        futures::pin_mut!(lines);
+
+        for id in ["1", "2", "3", "4"] {
+            tx.send(BuildEvent::new(id, "app", "queued")).unwrap();
+        }
+
+        assert_eq!(line(lines.next().await)["gap"], 2);
+        assert_eq!(line(lines.next().await)["id"], "3");
+        assert_eq!(line(lines.next().await)["id"], "4");
+    }
+
+    #[tokio::test]
+    async fn build_finished_waits_for_a_terminal_status() {
+        let (tx, rx) = broadcast::channel(16);
+        let finished = tokio::spawn(build_finished(rx, "42".to_string()));
+
+        tx.send(BuildEvent::new("42", "app", "running")).unwrap();
+        tx.send(BuildEvent::new("41", "app", "Completed")).unwrap();
+        tokio::task::yield_now().await;
+        assert!(!finished.is_finished());
+
+        tx.send(BuildEvent::new("42", "app", "Failed")).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), finished).await.unwrap().unwrap();
+    }
+}
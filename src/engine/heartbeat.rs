@@ -0,0 +1,138 @@
+use chrono::Utc;
+use sqlx::PgPool;
+use tokio::sync::watch;
+use tracing::warn;
+
+use std::future::Future;
+use std::time::Duration;
+
+/// Keeps a running build's `last_heartbeat` and `phase` current, so a build
+/// whose worker died shows as stale long before `ORPHAN_THRESHOLD_SECS`.
+/// Stops once dropped.
+pub struct Heartbeat {
+    /* none with heartbeats off */
+    phase: Option<watch::Sender<&'static str>>,
+}
+
+impl Heartbeat {
+    /// Starts beating for `build_id` every `interval`, off when it's zero.
+    pub fn start(pool: PgPool, build_id: String, interval: Duration, phase: &'static str) -> Self {
+        Self::beating(interval, phase, move |phase| {
+            let pool = pool.clone();
+            let build_id = build_id.clone();
+            async move {
+                let beat = sqlx::query("UPDATE build_data SET last_heartbeat = $1, phase = $2 WHERE id = $3")
+                    .bind(Utc::now().to_rfc3339())
+                    .bind(phase)
+                    .bind(&build_id)
+                    .execute(&pool)
+                    .await;
+                if let Err(e) = beat {
+                    warn!("Failed to record the heartbeat of build {}: {}", build_id, e);
+                }
+            }
+        })
+    }
+
+    fn beating<F, Fut>(interval: Duration, phase: &'static str, mut beat: F) -> Self
+    where
+        F: FnMut(&'static str) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        if interval.is_zero() {
+            return Heartbeat { phase: None };
+        }
+
+        let (tx, mut rx) = watch::channel(phase);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                /* a new phase is written right away, not at the next beat */
+                tokio::select! {
+                    _ = ticker.tick() => {},
+                    changed = rx.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                    },
+                }
+
+                let phase = *rx.borrow();
+                beat(phase).await;
+            }
+        });
+
+        Heartbeat { phase: Some(tx) }
+    }
+
+    /// Records that the build moved on to `phase`, e.g. `pushing`.
+    pub fn phase(&self, phase: &'static str) {
+        if let Some(tx) = &self.phase {
+            let _ = tx.send(phase);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    const INTERVAL: Duration = Duration::from_secs(30);
+
+    fn recorded(interval: Duration) -> (Heartbeat, Arc<Mutex<Vec<&'static str>>>) {
+        let beats = Arc::new(Mutex::new(Vec::new()));
+        let recorder = beats.clone();
+        let heartbeat = Heartbeat::beating(interval, "cloning", move |phase| {
+            recorder.lock().unwrap().push(phase);
+            async {}
+        });
+        (heartbeat, beats)
+    }
+
+    /* moves the paused clock on by `intervals` beats, letting the beating task run after each */
+    async fn wait(intervals: u32) {
+        for _ in 0..intervals {
+            tokio::time::advance(INTERVAL).await;
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_advance_during_a_long_build() {
+        let (heartbeat, beats) = recorded(INTERVAL);
+        /* the first beat is right away */
+        tokio::task::yield_now().await;
+        assert_eq!(*beats.lock().unwrap(), ["cloning"]);
+
+        /* a long build: many intervals pass while it's cloning, then building */
+        wait(3).await;
+        assert_eq!(*beats.lock().unwrap(), ["cloning"; 4]);
+
+        heartbeat.phase("building");
+        tokio::task::yield_now().await;
+        wait(2).await;
+        assert_eq!(*beats.lock().unwrap(), ["cloning", "cloning", "cloning", "cloning", "building", "building", "building"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn beating_stops_once_dropped() {
+        let (heartbeat, beats) = recorded(INTERVAL);
+        wait(2).await;
+        let beaten = beats.lock().unwrap().len();
+        assert!(beaten > 0);
+
+        drop(heartbeat);
+        wait(5).await;
+        assert_eq!(beats.lock().unwrap().len(), beaten);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_zero_interval_never_beats() {
+        let (heartbeat, beats) = recorded(Duration::ZERO);
+        heartbeat.phase("building");
+        wait(3).await;
+        assert!(beats.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+use serde::Serialize;
+use sqlx::Row;
+
+use crate::engine::state::AppState;
+
+use std::borrow::Cow;
+
+/// A build as `GET /builds` lists it.
+#[derive(Serialize, Debug)]
+pub struct BuildSummary {
+    pub id: String,
+    pub repo: Option<String>,
+    pub status: String,
+    pub start_time: String,
+    pub end_time: Option<String>,
+    pub commit: Option<String>,
+    pub image: Option<String>,
+    pub message: Option<String>,
+}
+
+/// The newest `limit` builds, of `repo` and with `status` when given.
+pub async fn list_builds(state: &AppState, repo: Option<&str>, status: Option<&str>, limit: i64) -> Result<Vec<BuildSummary>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT id, repo, status, start_time, end_time, commit_sha, image, message FROM build_data \
+        WHERE ($1::STRING IS NULL OR repo = $1) AND ($2::STRING IS NULL OR status = $2) \
+        ORDER BY start_time DESC LIMIT $3",
+    )
+        .bind(repo)
+        .bind(status)
+        .bind(limit)
+        .fetch_all(&state.db_pool)
+        .await?;
+
+    Ok(rows.into_iter()
+        .map(|row| BuildSummary {
+            id: row.get("id"),
+            repo: row.get("repo"),
+            status: row.get("status"),
+            start_time: row.get("start_time"),
+            end_time: row.get("end_time"),
+            commit: row.get("commit_sha"),
+            image: row.get("image"),
+            message: row.get("message"),
+        })
+        .collect())
+}
+
+pub const CSV_HEADER: &str = "id,repo,status,start_time,end_time,commit,image,message";
+
+/// `value` as a CSV field, quoted (with quotes doubled) when it has a comma,
+/// quote or line break in it.
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// `builds` as CSV with a header row, unset fields left empty.
+pub fn to_csv(builds: &[BuildSummary]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push_str("\r\n");
+
+    for build in builds {
+        let fields = [
+            Some(build.id.as_str()),
+            build.repo.as_deref(),
+            Some(build.status.as_str()),
+            Some(build.start_time.as_str()),
+            build.end_time.as_deref(),
+            build.commit.as_deref(),
+            build.image.as_deref(),
+            build.message.as_deref(),
+        ];
+        let row: Vec<Cow<str>> = fields.iter().map(|field| csv_field(field.unwrap_or_default())).collect();
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(message: Option<&str>) -> BuildSummary {
+        BuildSummary {
+            id: "b1".to_string(),
+            repo: Some("acme/web".to_string()),
+            status: "failed".to_string(),
+            start_time: "2024-01-01T00:00:00Z".to_string(),
+            end_time: None,
+            commit: Some("abc123".to_string()),
+            image: None,
+            message: message.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn csv_has_a_header_and_one_row_per_build() {
+        let csv = to_csv(&[build(Some("exit code 1"))]);
+        let lines: Vec<&str> = csv.split_terminator("\r\n").collect();
+
+        assert_eq!(lines, [CSV_HEADER, "b1,acme/web,failed,2024-01-01T00:00:00Z,,abc123,,exit code 1"]);
+        assert_eq!(lines[1].split(',').count(), CSV_HEADER.split(',').count());
+    }
+
+    #[test]
+    fn fields_with_commas_quotes_or_line_breaks_are_quoted() {
+        let csv = to_csv(&[build(Some("npm said \"no\",\nthen quit"))]);
+        assert!(csv.ends_with(",\"npm said \"\"no\"\",\nthen quit\"\r\n"));
+    }
+
+    #[test]
+    fn no_builds_is_just_the_header() {
+        assert_eq!(to_csv(&[]), format!("{}\r\n", CSV_HEADER));
+    }
+
+    #[test]
+    fn plain_fields_are_left_alone() {
+        assert!(matches!(csv_field("acme/web"), Cow::Borrowed("acme/web")));
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+    }
+}
@@ -0,0 +1,113 @@
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::Row;
+
+use crate::engine::events::is_terminal;
+use crate::engine::pipeline::{build_info_from_value, new_build_id, validate, BuildError, BuildInfo, TAG_EXISTS};
+use crate::engine::profiles::layer;
+use crate::engine::queue::submit_to_matrix;
+use crate::engine::state::AppState;
+
+/// A `/build/matrix` body: one build per variant, each layered over `base`
+/// the way a request is layered over its profile.
+#[derive(Deserialize)]
+pub struct MatrixRequest {
+    pub base: Value,
+    pub variants: Vec<Value>,
+}
+
+/// One build of a matrix as it's reported on the parent.
+#[derive(Serialize, Debug, Clone)]
+pub struct MatrixBuild {
+    pub id: String,
+    pub status: String,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MatrixStatus {
+    pub id: String,
+    /* running until every build finished, then Completed or Failed */
+    pub status: &'static str,
+    pub succeeded: bool,
+    pub builds: Vec<MatrixBuild>,
+}
+
+/// The build of every variant. Nothing is queued unless all of them are
+/// valid, a bad variant is reported by its index.
+pub fn expand(request: MatrixRequest, state: &AppState) -> Result<Vec<BuildInfo>, BuildError> {
+    let config = state.config();
+
+    if !request.base.is_object() {
+        return Err(BuildError::new(StatusCode::BAD_REQUEST, "base must be an object"));
+    }
+    if request.variants.is_empty() {
+        return Err(BuildError::new(StatusCode::BAD_REQUEST, "variants must not be empty"));
+    }
+
+    request.variants.into_iter().enumerate().map(|(index, variant)| {
+        let in_variant = |e: BuildError| BuildError::new(e.status, format!("variant {}: {}", index, e.message));
+
+        if !variant.is_object() {
+            return Err(in_variant(BuildError::new(StatusCode::BAD_REQUEST, "must be an object")));
+        }
+
+        let build_info = build_info_from_value(layer(&request.base, variant), &config).map_err(in_variant)?;
+        validate(state, &build_info).map_err(in_variant)?;
+        Ok(build_info)
+    })
+    .collect()
+}
+
+/// Queues every build of the matrix under a new parent id. Builds that fail
+/// to queue (e.g. the server started draining) are listed with their error.
+pub async fn submit_matrix(state: &AppState, builds: Vec<BuildInfo>) -> (String, Vec<Value>) {
+    let matrix_id = new_build_id();
+    let mut results = Vec::with_capacity(builds.len());
+
+    for (index, build_info) in builds.into_iter().enumerate() {
+        results.push(match submit_to_matrix(state, build_info, &matrix_id, index).await {
+            Ok(id) => json!({ "status": StatusCode::ACCEPTED.as_u16(), "id": id }),
+            Err(e) => json!({ "status": e.status.as_u16(), "error": e.message }),
+        });
+    }
+
+    (matrix_id, results)
+}
+
+/// The builds of matrix `id` in variant order, `None` if there is no such
+/// matrix. It succeeded only once every build did.
+pub async fn matrix_status(state: &AppState, id: &str) -> Result<Option<MatrixStatus>, sqlx::Error> {
+    let rows = sqlx::query("SELECT id, status, message FROM build_data WHERE matrix_id = $1 ORDER BY matrix_index")
+        .bind(id)
+        .fetch_all(&state.db_pool)
+        .await?;
+
+    if rows.is_empty() {
+        return Ok(None);
+    }
+
+    let builds: Vec<MatrixBuild> = rows.iter().map(|row| MatrixBuild {
+        id: row.get("id"),
+        status: row.get("status"),
+        message: row.get("message"),
+    })
+    .collect();
+
+    let succeeded = builds.iter().all(|build| build.status == "Completed" || build.status == "Skipped" || build.status == "Warmed" || build.status == TAG_EXISTS);
+    let status = if builds.iter().any(|build| !is_terminal(&build.status)) {
+        "running"
+    } else if succeeded {
+        "Completed"
+    } else {
+        "Failed"
+    };
+
+    Ok(Some(MatrixStatus {
+        id: id.to_string(),
+        status,
+        succeeded,
+        builds,
+    }))
+}
@@ -0,0 +1,13 @@
+pub mod diff;
+pub mod events;
+pub mod heartbeat;
+pub mod history;
+pub mod matrix;
+pub mod pipeline;
+pub mod profiles;
+pub mod queue;
+pub mod reconcile;
+pub mod refs;
+pub mod reload;
+pub mod state;
+pub mod stats;
@@ -0,0 +1,1808 @@
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use nixpacks::nixpacks::builder::docker::DockerBuilderOptions as NixpacksOptions;
+use nixpacks::nixpacks::plan::generator::GeneratePlanOptions;
+use nixpacks::nixpacks::plan::BuildPlan;
+use nixpacks::generate_build_plan;
+
+use shiplift::Docker;
+use sqlx::{PgConnection, Row};
+use tempfile::TempDir;
+use chrono::Utc;
+use uuid::Uuid;
+use tracing::{info_span, Instrument};
+
+use crate::build::archive::{self, ArchiveError};
+use crate::build::artifacts::{self, Artifact};
+use crate::build::build_log::{BuildLog, LogLevel};
+use crate::build::disk::preflight;
+use crate::build::dockerfile;
+use crate::build::generated_files::{self, GeneratedFile};
+use crate::build::git;
+use crate::build::image_config;
+use crate::build::image_size::{self, image_size};
+use crate::build::image_user::{image_user, root_refusal};
+use crate::build::oci;
+use crate::build::paths_filter::any_match;
+use crate::build::platform;
+use crate::build::plan::{dependency_plan, with_test_phase, PlanSummary, NIXPACKS_VERSION, TESTS_FAILED_MARKER};
+use crate::build::plan_cache::PlanCache;
+use crate::build::post_build::run_post_build;
+use crate::build::pull::{base_images, pull_base_images};
+use crate::build::registry::{self, ExistingTagPolicy};
+use crate::build::backend::{Backend, BuildJob};
+use crate::build::cache_scope::{scoped_key, CacheScope};
+use crate::build::sandbox::{limit_hint, SandboxLimits, SandboxRequest};
+use crate::build::sbom;
+use crate::build::secrets::BuildSecrets;
+use crate::build::verify::verify_head_signature;
+use crate::build::workdir;
+use crate::config::config::Config;
+use crate::engine::events::{BuildEvent, PUSH_QUEUED};
+use crate::engine::heartbeat::Heartbeat;
+use crate::engine::profiles::{apply_profile, apply_provider_defaults};
+use crate::engine::state::AppState;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Component, Path};
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BuildInfo {
+    /* named server side profile the rest of the request is layered over */
+    pub profile: Option<String>,
+    pub path: String,
+    pub name: String,
+    /* directory inside the repo to build, e.g. services/api of a monorepo */
+    pub subdir: Option<String>,
+    pub envs: Option<Vec<String>>,
+    #[serde(default)]
+    pub build_options: DockerBuilderOptions,
+    #[serde(default)]
+    pub require_signed_commit: bool,
+    /* refuse local directory builds with uncommitted changes */
+    #[serde(default)]
+    pub require_clean: bool,
+    /* ulimits and tmpfs sizing for the build container */
+    pub sandbox: Option<SandboxRequest>,
+    /* adds a <name>:<build id> tag and a forge-build=<build id> label */
+    #[serde(default)]
+    pub tag_with_build_id: bool,
+    /* replace what nixpacks set as the image's entrypoint / cmd, exec form */
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    /* run as a build step after the install phase, non-zero fails the build before there's an image */
+    pub test_command: Option<String>,
+    /* run in a one-off container of the new image once it built, e.g. migrations */
+    pub post_build_command: Option<String>,
+    pub post_build_timeout_secs: Option<u64>,
+    /* init and update submodules after cloning */
+    #[serde(default)]
+    pub recurse_submodules: bool,
+    /* overrides IMAGE_PREFIX and the registry routes, "" for none */
+    pub image_prefix: Option<String>,
+    /* picks the registry from REGISTRY_ROUTES, e.g. production or staging */
+    pub environment: Option<String>,
+    /* the branch being built, its BRANCH_ENVIRONMENTS entry stands in for environment */
+    pub branch: Option<String>,
+    /* owner/name on GitHub the build's commit status is reported to */
+    pub github_repository: Option<String>,
+    /* push the image to its registry once it built */
+    #[serde(default)]
+    pub push: bool,
+    /* what the push does with tags the registry already has, defaults to ON_EXISTING_TAG */
+    pub on_existing_tag: Option<ExistingTagPolicy>,
+    /* commit to build instead of the remote's HEAD, e.g. a push's after */
+    pub after: Option<String>,
+    /* branch or tag to build instead of the remote's HEAD */
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    /* with paths_filter, only build when before..after touched a matching path */
+    pub before: Option<String>,
+    pub paths_filter: Option<Vec<String>>,
+    /* higher runs first, equal priorities in submission order */
+    pub priority: Option<u8>,
+    /* name -> value, mounted at /run/secrets/<name> in RUN steps, never stored */
+    #[serde(default, skip_serializing)]
+    pub secrets: BuildSecrets,
+    /* lets the image run as root when REQUIRE_NON_ROOT is set */
+    #[serde(default)]
+    pub allow_root: bool,
+    /* fail the build when the image is bigger, defaults to MAX_IMAGE_SIZE_MB, 0 for no limit */
+    pub max_image_size_mb: Option<u64>,
+    /* lets the build apply tags listed in PROTECTED_TAGS */
+    #[serde(default)]
+    pub allow_protected_tags: bool,
+    /* org.opencontainers.image.* source labels, defaults to OCI_LABELS */
+    pub oci_labels: Option<bool>,
+    /* store a software bill of materials of the image, defaults to GENERATE_SBOM */
+    pub sbom: Option<bool>,
+    /* write a build log at all, defaults to CAPTURE_BUILD_LOGS */
+    pub capture_log: Option<bool>,
+    /* how much goes into it, defaults to what build_options.quiet/verbose say */
+    pub log_level: Option<LogLevel>,
+    /* build under forge-build-<id>/<name> and only tag it as name once it went through, defaults to ISOLATE_BUILD_TAGS */
+    pub isolate_tags: Option<bool>,
+    /* tag the image of a build that fails after building as <name>:failed-<build id> */
+    #[serde(default)]
+    pub keep_failed_image: bool,
+    /* only build the dependency phases into the layer cache, set by /build/warm */
+    #[serde(default)]
+    pub warm: bool,
+    /* what path points at, guessed from it when not given */
+    pub source_type: Option<SourceType>,
+    /* docker network RUN steps are attached to, e.g. one with a package mirror */
+    pub build_network: Option<String>,
+    /* the client's own key -> value pairs, stored with the build and never interpreted */
+    pub metadata: Option<BTreeMap<String, String>>,
+    /* refuse the build unless forge's nixpacks is this version, for pinned builds */
+    pub nixpacks_version: Option<String>,
+    /* written into the clone before it's planned, {sha}, {branch} and metadata keys filled in */
+    pub generated_files: Option<Vec<GeneratedFile>>,
+}
+
+/// Where a remote build's source comes from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceType {
+    Git,
+    /* a zip archive downloaded over http(s), e.g. a codeload url */
+    Zip,
+}
+
+impl BuildInfo {
+    /// Whether `path` is a zip archive to download rather than a repo, by
+    /// `source_type` or else a path ending in `.zip`.
+    pub fn is_zip(&self) -> bool {
+        match self.source_type {
+            Some(source_type) => source_type == SourceType::Zip,
+            None => self.path.split(['?', '#']).next().unwrap_or_default().ends_with(".zip"),
+        }
+    }
+
+    pub fn log_level(&self) -> LogLevel {
+        self.log_level.unwrap_or_else(|| LogLevel::from_options(self.build_options.quiet, self.build_options.verbose))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct DockerBuilderOptions {
+    pub name: Option<String>,
+    pub out_dir: Option<String>,
+    pub print_dockerfile: bool,
+    pub tags: Vec<String>,
+    pub labels: Vec<String>,
+    pub quiet: bool,
+    pub cache_key: Option<String>,
+    pub no_cache: bool,
+    pub inline_cache: bool,
+    pub cache_from: Option<String>,
+    pub platform: Vec<String>,
+    pub current_dir: bool,
+    pub no_error_without_start: bool,
+    pub incremental_cache_image: Option<String>,
+    pub verbose: bool,
+}
+
+pub fn convert_to_nixpacks_options(local_options: &DockerBuilderOptions) -> NixpacksOptions {
+    NixpacksOptions {
+        name: local_options.name.clone(),
+        out_dir: local_options.out_dir.clone(),
+        print_dockerfile: local_options.print_dockerfile,
+        tags: local_options.tags.clone(),
+        labels: local_options.labels.clone(),
+        quiet: local_options.quiet,
+        cache_key: local_options.cache_key.clone(),
+        no_cache: local_options.no_cache,
+        inline_cache: local_options.inline_cache,
+        cache_from: local_options.cache_from.clone(),
+        platform: local_options.platform.clone(),
+        current_dir: local_options.current_dir,
+        no_error_without_start: local_options.no_error_without_start,
+        incremental_cache_image: local_options.incremental_cache_image.clone(),
+        verbose: local_options.verbose,
+        cpu_quota: None,
+        memory: None,
+    }
+}
+
+/// A build that was refused before it started, nothing is recorded for it.
+#[derive(Debug)]
+pub struct BuildError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl BuildError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        BuildError { status, message: message.into() }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// How long each step of a build took, in milliseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BuildTimings {
+    /* none when building a local path */
+    pub clone_ms: Option<u64>,
+    pub plan_ms: u64,
+    pub build_ms: u64,
+    /* none unless the image was pushed */
+    pub push_ms: Option<u64>,
+}
+
+fn elapsed_ms(start: Instant) -> u64 {
+    start.elapsed().as_millis() as u64
+}
+
+/// A build that ran, successfully or not.
+#[derive(Serialize, Debug, Clone)]
+pub struct BuildOutcome {
+    pub id: String,
+    pub status: String,
+    pub message: String,
+    /* none when the build was skipped before planning */
+    pub plan: Option<PlanSummary>,
+    /* every image reference the build was tagged with */
+    pub tags: Vec<String>,
+    pub timings: BuildTimings,
+    /* providers whose PROVIDER_DEFAULTS_FILE defaults filled in build_options */
+    pub provider_defaults: Vec<String>,
+}
+
+impl BuildOutcome {
+    pub fn succeeded(&self) -> bool {
+        self.status == "Completed" || self.status == "Skipped" || self.status == "Warmed" || self.status == TAG_EXISTS
+    }
+}
+
+/// A `build_data` row.
+#[derive(Serialize, Debug, Clone)]
+pub struct BuildRecord {
+    pub id: String,
+    pub status: String,
+    pub start_time: String,
+    pub end_time: Option<String>,
+    pub message: Option<String>,
+    pub plan: Option<serde_json::Value>,
+    pub timings: Option<BuildTimings>,
+    /* the request as queued, see recorded_options */
+    pub options: Option<serde_json::Value>,
+    pub commit: Option<String>,
+    pub priority: Option<u8>,
+    /* what nixpacks wrote to out_dir, for builds that set one */
+    pub artifacts: Option<Vec<Artifact>>,
+    /* USER of the built image, "" when it runs as root by default */
+    pub image_user: Option<String>,
+    /* bytes */
+    pub image_size: Option<i64>,
+    pub provider_defaults: Option<Vec<String>>,
+    /* the layer cache key the build used, after CACHE_SCOPE */
+    pub cache_key: Option<String>,
+    /* the tag kept for debugging when the build failed after building */
+    pub failed_image: Option<String>,
+    pub metadata: Option<BTreeMap<String, String>>,
+    /* the nixpacks that planned and built it */
+    pub nixpacks_version: Option<String>,
+    /* the last sign of life of a running build, and what it was doing */
+    pub last_heartbeat: Option<String>,
+    pub phase: Option<String>,
+}
+
+/// Parses a build request body, applying the profile it names.
+pub fn parse_build_info(body: &[u8], config: &Config) -> Result<BuildInfo, BuildError> {
+    let request: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|_| BuildError::new(StatusCode::BAD_REQUEST, "Invalid request body"))?;
+
+    build_info_from_value(request, config)
+}
+
+pub fn build_info_from_value(request: serde_json::Value, config: &Config) -> Result<BuildInfo, BuildError> {
+    let request = apply_profile(request, &config.profiles)?;
+
+    let mut build_info: BuildInfo = serde_json::from_value(request).map_err(|_| BuildError::new(StatusCode::BAD_REQUEST, "Invalid request body"))?;
+    build_info.build_options.platform = build_info.build_options.platform.iter().map(|name| platform::normalize(name)).collect();
+    if let Some(envs) = build_info.envs.take() {
+        build_info.envs = Some(config.build_envs.apply(envs).map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?);
+    }
+    Ok(build_info)
+}
+
+/* bounds on metadata, it's stored with every build */
+const MAX_METADATA_ENTRIES: usize = 32;
+const MAX_METADATA_KEY_BYTES: usize = 128;
+const MAX_METADATA_VALUE_BYTES: usize = 1024;
+
+fn check_metadata(metadata: &BTreeMap<String, String>) -> Result<(), String> {
+    if metadata.len() > MAX_METADATA_ENTRIES {
+        return Err(format!("metadata may have at most {} entries", MAX_METADATA_ENTRIES));
+    }
+    for (key, value) in metadata {
+        if key.is_empty() || key.len() > MAX_METADATA_KEY_BYTES {
+            return Err(format!("metadata keys must be 1 to {} bytes, got {:?}", MAX_METADATA_KEY_BYTES, key));
+        }
+        if value.len() > MAX_METADATA_VALUE_BYTES {
+            return Err(format!("metadata value of {} is longer than {} bytes", key, MAX_METADATA_VALUE_BYTES));
+        }
+    }
+    Ok(())
+}
+
+/// The request as it's stored with the build. Env values are dropped since
+/// they may hold secrets, only the names are kept.
+pub fn recorded_options(build_info: &BuildInfo) -> serde_json::Value {
+    let mut options = serde_json::to_value(build_info).unwrap();
+
+    if let Some(envs) = &build_info.envs {
+        let names: Vec<&str> = envs.iter().map(|env| env.split('=').next().unwrap_or_default()).collect();
+        options["envs"] = serde_json::json!(names);
+    }
+    /* it has a column of its own */
+    if let Some(options) = options.as_object_mut() {
+        options.remove("metadata");
+    }
+
+    options
+}
+
+/// `metadata` as it's kept in its column.
+pub fn stored_metadata(build_info: &BuildInfo) -> Option<String> {
+    build_info.metadata.as_ref().map(|metadata| serde_json::to_string(metadata).unwrap())
+}
+
+fn read_metadata(column: Option<String>) -> Option<BTreeMap<String, String>> {
+    column.and_then(|metadata| serde_json::from_str(&metadata).ok())
+}
+
+fn envs_of(build_info: &BuildInfo) -> Vec<&str> {
+    if let Some(inner_vec) = &build_info.envs {
+        inner_vec.iter().map(|inner_str| inner_str.as_ref()).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// A fresh working directory under the configured base, removed on drop.
+fn workdir(state: &AppState) -> std::io::Result<TempDir> {
+    let config = state.config();
+    workdir::create(&config.workdir_base, "clone")
+}
+
+/// The full reference the image is built as, with the registry prefix
+/// applied: the request's, else its environment's, else IMAGE_PREFIX.
+fn image_name(state: &AppState, build_info: &BuildInfo) -> Result<String, BuildError> {
+    let config = state.config();
+    let name = build_info.build_options.name.as_deref().unwrap_or(&build_info.name);
+    let prefix = match build_info.image_prefix.as_deref() {
+        Some(prefix) => Some(prefix),
+        None => config.registry_routes
+            .prefix(build_info.environment.as_deref(), build_info.branch.as_deref(), build_info.push)
+            .map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?
+            .or(config.image_prefix.as_deref()),
+    };
+
+    registry::qualify(name, prefix).map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))
+}
+
+/// Checks a request before it's queued, so bad requests are refused up
+/// front rather than failing once a worker picks them up.
+pub fn validate(state: &AppState, build_info: &BuildInfo) -> Result<SandboxLimits, BuildError> {
+    let config = state.config();
+    if build_info.path.is_empty() || build_info.name.is_empty() {
+        return Err(BuildError::new(StatusCode::BAD_REQUEST, "Missing required fields"));
+    }
+
+    image_name(state, build_info)?;
+
+    if build_info.is_zip() {
+        archive::check_url(&build_info.path).map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+
+        /* these all need the repo's history */
+        let git_only = [
+            ("after", build_info.after.is_some()),
+            ("ref", build_info.git_ref.is_some()),
+            ("paths_filter", build_info.paths_filter.is_some()),
+            ("recurse_submodules", build_info.recurse_submodules),
+            ("require_signed_commit", build_info.require_signed_commit),
+        ];
+        if let Some((option, _)) = git_only.iter().find(|(_, set)| *set) {
+            return Err(BuildError::new(StatusCode::BAD_REQUEST, format!("{} needs a git source, not a zip archive", option)));
+        }
+    }
+
+    if let Some(metadata) = &build_info.metadata {
+        check_metadata(metadata).map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+    }
+
+    if let Some(version) = &build_info.nixpacks_version {
+        if version != NIXPACKS_VERSION {
+            return Err(BuildError::new(StatusCode::BAD_REQUEST, format!("nixpacks {} was requested, this forge builds with nixpacks {}", version, NIXPACKS_VERSION)));
+        }
+    }
+
+    if let Some(files) = &build_info.generated_files {
+        /* a local path is the caller's own working tree */
+        if Path::new(&build_info.path).is_dir() {
+            return Err(BuildError::new(StatusCode::BAD_REQUEST, "generated_files needs a cloned or downloaded source, not a local path"));
+        }
+        generated_files::check(files).map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+    }
+
+    if build_info.after.is_some() && build_info.git_ref.is_some() {
+        return Err(BuildError::new(StatusCode::BAD_REQUEST, "after and ref both pick what to build, set one of them"));
+    }
+
+    if let Some(subdir) = &build_info.subdir {
+        if subdir.is_empty() || !Path::new(subdir).components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return Err(BuildError::new(StatusCode::BAD_REQUEST, format!("subdir must be a relative path inside the repo, got {}", subdir)));
+        }
+    }
+
+    image_config::check("entrypoint", &build_info.entrypoint)
+        .and_then(|_| image_config::check("cmd", &build_info.cmd))
+        .map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+
+    if let Some(out_dir) = &build_info.build_options.out_dir {
+        artifacts::validate_out_dir(out_dir).map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+    }
+
+    platform::check(&build_info.build_options.platform, &config.allowed_platforms)
+        .map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+
+    build_info.secrets.check(&config.allowed_build_secrets)
+        .map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+
+    if let Some(network) = &build_info.build_network {
+        if !config.allowed_build_networks.contains(network) {
+            return Err(BuildError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Build network {} is not allowed, allowed networks are: {}", network, config.allowed_build_networks.join(", ")),
+            ));
+        }
+    }
+
+    SandboxLimits::resolve(
+        build_info.sandbox.as_ref(),
+        &config.build_limits,
+        &config.max_build_ulimits,
+        config.max_build_shm_size_mb,
+    ).map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))
+}
+
+/// Refuses tags on the deny-list, so only builds that ask for it can move
+/// e.g. `latest` or `stable`.
+fn check_protected_tags(tags: &[String], protected: &[String]) -> Result<(), BuildError> {
+    let denied: Vec<&str> = tags.iter()
+        .map(String::as_str)
+        .filter(|tag| protected.iter().any(|name| name == registry::tag_of(tag)))
+        .collect();
+
+    if denied.is_empty() {
+        Ok(())
+    } else {
+        Err(BuildError::new(
+            StatusCode::FORBIDDEN,
+            format!("Protected tags need allow_protected_tags: {}", denied.join(", ")),
+        ))
+    }
+}
+
+/// Where a plan of `build_info` is cached, only clones have a commit that
+/// pins their contents.
+fn plan_cache_key(build_info: &BuildInfo, repo_dir: &str, local: bool) -> Option<String> {
+    /* generated files change what's planned without a commit of their own */
+    if local || build_info.is_zip() || build_info.generated_files.is_some() {
+        return None;
+    }
+    let commit = git::head_commit(repo_dir)?;
+    let repo = match &build_info.subdir {
+        Some(subdir) => format!("{}/{}", build_info.path, subdir),
+        None => build_info.path.clone(),
+    };
+    Some(PlanCache::key(&repo, &commit, &envs_of(build_info)))
+}
+
+/// What a recent /plan showed for the commit checked out at `repo_dir`,
+/// unless the build asks for a fresh start.
+fn cached_plan(state: &AppState, build_info: &BuildInfo, repo_dir: &str, local: bool) -> Option<BuildPlan> {
+    plan_cache_key(build_info, repo_dir, local)
+        .filter(|_| !build_info.build_options.no_cache)
+        .and_then(|key| state.plans.get(&key))
+}
+
+/// The directory `build_info` is planned and built from, its `subdir` of
+/// the checkout at `repo_dir` when it has one.
+fn app_dir(repo_dir: &str, build_info: &BuildInfo) -> Result<String, BuildError> {
+    let subdir = match &build_info.subdir {
+        Some(subdir) => subdir,
+        None => return Ok(repo_dir.to_string()),
+    };
+
+    let dir = Path::new(repo_dir).join(subdir);
+    if !dir.is_dir() {
+        return Err(BuildError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("subdir {} isn't a directory in the repo", subdir)));
+    }
+    Ok(dir.display().to_string())
+}
+
+/* recorded for builds that succeeded but left tags the registry had alone, on_existing_tag skip */
+pub const TAG_EXISTS: &str = "Completed (tag exists, not pushed)";
+
+/* recorded for builds whose test_command failed, no image was made */
+pub const TESTS_FAILED: &str = "TestsFailed";
+
+/* recorded for builds whose planning ran past PLAN_TIMEOUT_SECS */
+pub const PLAN_TIMED_OUT: &str = "Failed (plan timed out)";
+
+/// Plans `dir` on a blocking thread, giving up with a 504 after
+/// `timeout_secs` (0 for no limit).
+async fn generate_plan(dir: &str, build_info: &BuildInfo, options: &GeneratePlanOptions, timeout_secs: u64) -> Result<BuildPlan, BuildError> {
+    let dir = dir.to_string();
+    let envs: Vec<String> = envs_of(build_info).into_iter().map(String::from).collect();
+    let options = options.clone();
+    let span = info_span!("plan");
+    plan_within(timeout_secs, move || {
+        span.in_scope(|| generate_build_plan(&dir, envs.iter().map(String::as_str).collect(), &options))
+    }).await
+}
+
+/// Runs `planner` on a blocking thread for at most `timeout_secs`. A
+/// planner that was given up on keeps its thread until it returns, but not
+/// the build's worker.
+async fn plan_within<E: fmt::Display + Send + 'static>(timeout_secs: u64, planner: impl FnOnce() -> Result<BuildPlan, E> + Send + 'static) -> Result<BuildPlan, BuildError> {
+    let planning = tokio::task::spawn_blocking(planner);
+
+    let planned = if timeout_secs == 0 {
+        Ok(planning.await)
+    } else {
+        tokio::time::timeout(Duration::from_secs(timeout_secs), planning).await
+    };
+
+    match planned {
+        Ok(Ok(Ok(plan))) => Ok(plan),
+        Ok(Ok(Err(e))) => Err(BuildError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate build plan: {}", e))),
+        Ok(Err(e)) => Err(BuildError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Planning failed: {}", e))),
+        Err(_) => Err(BuildError::new(StatusCode::GATEWAY_TIMEOUT, format!("Planning timed out after {}s", timeout_secs))),
+    }
+}
+
+/// Clones `build_info`'s repo and plans it without building, caching the
+/// plan so a /build of the same commit soon after uses it. Returns the
+/// commit that was planned and the plan.
+pub async fn plan(state: &AppState, build_info: &BuildInfo) -> Result<(Option<String>, PlanSummary), BuildError> {
+    validate(state, build_info)?;
+
+    let mut timings = BuildTimings::default();
+    let source = fetch_source(state, build_info, &|_, _| {}, &mut timings).await?;
+
+    let dir = app_dir(&source.dir, build_info)?;
+    let plan = generate_plan(&dir, build_info, &GeneratePlanOptions::default(), state.config().plan_timeout_secs).await?;
+    let summary = PlanSummary::from_plan(&plan);
+
+    if let Some(key) = plan_cache_key(build_info, &source.dir, source.local) {
+        state.plans.insert(key, plan);
+    }
+
+    Ok((git::head_commit(&source.dir), summary))
+}
+
+/// What `{name}` in generated files stands for: the build's `metadata`, then
+/// `sha`, `short_sha`, `branch`, `ref`, `build_id` and `name`.
+fn template_vars(build_info: &BuildInfo, repo_dir: &str, build_id: &str) -> BTreeMap<String, String> {
+    let mut vars = build_info.metadata.clone().unwrap_or_default();
+
+    if let Some(sha) = git::head_commit(repo_dir) {
+        vars.insert("short_sha".to_string(), sha.chars().take(7).collect());
+        vars.insert("sha".to_string(), sha);
+    }
+    if let Some(branch) = build_info.branch.clone().or_else(|| git::head_branch(repo_dir)) {
+        vars.insert("branch".to_string(), branch);
+    }
+    if let Some(git_ref) = &build_info.git_ref {
+        vars.insert("ref".to_string(), git_ref.clone());
+    }
+    vars.insert("build_id".to_string(), build_id.to_string());
+    vars.insert("name".to_string(), build_info.name.clone());
+
+    vars
+}
+
+/// A build's checked out source.
+struct Source {
+    dir: String,
+    /* a directory on this host, built in place */
+    local: bool,
+    /* where a clone or download lives, removed on drop */
+    workdir: Option<TempDir>,
+}
+
+/// Clones, downloads or points at the source `build_info` builds.
+async fn fetch_source(state: &AppState, build_info: &BuildInfo, log: &impl Fn(LogLevel, String), timings: &mut BuildTimings) -> Result<Source, BuildError> {
+    let config = state.config();
+    let local = std::path::Path::new(&build_info.path).is_dir();
+    let (dir, temp_dir) = if local {
+        (build_info.path.clone(), None)
+    } else if build_info.is_zip() {
+        let temp_dir = workdir::create(&config.workdir_base, "zip").map_err(|e| BuildError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create working dir: {}", e)))?;
+        let fetch_start = Instant::now();
+        let max_bytes = config.max_upload_mb * 1024 * 1024;
+        let archive_error = |e: ArchiveError| match e {
+            ArchiveError::TooLarge(message) => BuildError::new(StatusCode::PAYLOAD_TOO_LARGE, message),
+            ArchiveError::Invalid(message) => BuildError::new(StatusCode::BAD_REQUEST, message),
+        };
+
+        let archive = temp_dir.path().join("source.zip");
+        archive::download(&build_info.path, &archive, max_bytes, config.http.body_read_timeout)
+            .instrument(info_span!("download"))
+            .await
+            .map_err(archive_error)?;
+
+        let source_dir = temp_dir.path().join("source");
+        let root = tokio::task::spawn_blocking(move || archive::extract_zip(&archive, &source_dir, max_bytes))
+            .await
+            .map_err(|e| BuildError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Extraction failed: {}", e)))?
+            .map_err(archive_error)?;
+
+        timings.clone_ms = Some(elapsed_ms(fetch_start));
+        log(LogLevel::Normal, format!("Downloaded and unpacked {}", build_info.path));
+        (root.display().to_string(), Some(temp_dir))
+    } else {
+        let temp_dir = workdir(state).map_err(|e| BuildError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create working dir: {}", e)))?;
+        let repo_dir = temp_dir.path().display().to_string();
+        let clone_start = Instant::now();
+        let cloned = match &state.clone_cache {
+            Some(cache) => cache.checkout(&build_info.path, &repo_dir).instrument(info_span!("clone")).await,
+            None => info_span!("clone").in_scope(|| git::clone(&build_info.path, &repo_dir)),
+        };
+        let repo = match cloned {
+            Ok(repo) => repo,
+            Err(e) => return Err(BuildError::new(StatusCode::BAD_REQUEST, format!("Failed to clone repository: {}", e))),
+        };
+        eprintln!("Cloned repo successfully");
+        log(LogLevel::Normal, format!("Cloned {}", build_info.path));
+
+        if build_info.recurse_submodules {
+            info_span!("submodules").in_scope(|| git::update_submodules(&repo))
+                .map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+        }
+        if let Some(after) = &build_info.after {
+            git::checkout_commit(&repo, after).map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+        } else if let Some(git_ref) = &build_info.git_ref {
+            git::checkout_ref(&repo, git_ref).map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+        }
+        timings.clone_ms = Some(elapsed_ms(clone_start));
+        log(LogLevel::Normal, format!("Checked out {}", git::head_commit(&repo_dir).unwrap_or_default()));
+        (repo_dir, Some(temp_dir))
+    };
+
+    Ok(Source { dir, local, workdir: temp_dir })
+}
+
+pub fn new_build_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Clones, plans and builds `build_info` under the `build_data` row `build_id`
+/// that was recorded when it was queued.
+pub async fn run_build(state: &AppState, build_info: BuildInfo, build_id: String) -> Result<BuildOutcome, BuildError> {
+    let span = info_span!("run_build", build_id = %build_id, repo = %build_info.path);
+
+    build(state, build_info, build_id).instrument(span).await
+}
+
+async fn build(state: &AppState, build_info: BuildInfo, build_id: String) -> Result<BuildOutcome, BuildError> {
+    /* one snapshot for the whole build, a reload only affects later builds */
+    let config = state.config();
+    let limits = validate(state, &build_info)?;
+
+    preflight(&config.workdir_base, config.min_free_disk_mb)
+        .map_err(|e| BuildError::new(StatusCode::INSUFFICIENT_STORAGE, e))?;
+
+    let log_level = build_info.log_level();
+    let build_log = BuildLog::new(&config.build_log_dir, &build_id)
+        .with_level(build_info.capture_log.unwrap_or(config.capture_build_logs), log_level)
+        /* build secrets are masked whatever they're called */
+        .with_redactor(config.logs.redact.redactor(envs_of(&build_info)).with_values(build_info.secrets.values().cloned()));
+    let log = |level: LogLevel, text: String| {
+        if let Err(e) = build_log.append(level, &text) {
+            eprintln!("Failed to write build log: {}", e);
+        }
+    };
+
+    let heartbeat = Heartbeat::start(state.db_pool.clone(), build_id.clone(), Duration::from_secs(config.heartbeat_interval_secs), "cloning");
+
+    let mut timings = BuildTimings::default();
+    /* _temp_dir keeps the clone alive until the build is done */
+    let Source { dir: repo_dir, local, workdir: _temp_dir } = fetch_source(state, &build_info, &log, &mut timings).await?;
+
+    /* a clone is clean by definition */
+    if local && build_info.require_clean {
+        let dirty = git::dirty_files(&repo_dir)
+            .map_err(|e| BuildError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("Can't check the working tree: {}", e)))?;
+        if !dirty.is_empty() {
+            return Err(BuildError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Working tree has uncommitted changes: {}", dirty.join(", ")),
+            ));
+        }
+    }
+
+    if build_info.require_signed_commit {
+        let keyring = match &config.trusted_keyring {
+            Some(keyring) => keyring,
+            None => return Err(BuildError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Signed commits required but TRUSTED_GPG_KEYRING is not configured",
+            )),
+        };
+
+        if let Err(e) = verify_head_signature(&repo_dir, keyring) {
+            return Err(BuildError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("Commit verification failed: {}", e)));
+        }
+    }
+
+    if let Some(patterns) = &build_info.paths_filter {
+        if !has_relevant_changes(&build_info, &repo_dir, patterns)? {
+            return Ok(skip(state, build_info, build_id, timings).await);
+        }
+    }
+
+    if let Some(files) = &build_info.generated_files {
+        let vars = template_vars(&build_info, &repo_dir, &build_id);
+        let written = generated_files::write(Path::new(&repo_dir), files, &vars)
+            .map_err(|e| BuildError::new(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+        log(LogLevel::Normal, format!("Generated {}", written.join(", ")));
+    }
+
+    let app_dir = app_dir(&repo_dir, &build_info)?;
+
+    let mut conn = state.db_pool.acquire().await
+        .map_err(|e| BuildError::new(StatusCode::SERVICE_UNAVAILABLE, format!("No database connection available: {}", e)))?;
+    let mut plan_options = GeneratePlanOptions::default(); // Generate default options
+
+    heartbeat.phase("planning");
+    let plan_start = Instant::now();
+    let mut plan = match cached_plan(state, &build_info, &repo_dir, local) {
+        Some(plan) => {
+            log(LogLevel::Normal, "Using the plan cached by /plan".to_string());
+            plan
+        },
+        None => generate_plan(&app_dir, &build_info, &plan_options, config.plan_timeout_secs).await?,
+    };
+    if build_info.warm {
+        plan = dependency_plan(&plan);
+    }
+    /* warming builds no further than install, where the tests would come after */
+    let test_command = build_info.test_command.as_deref().filter(|_| !build_info.warm);
+    if let Some(command) = test_command {
+        plan = with_test_phase(&plan, command);
+    }
+    /* nixpacks builds from the plan that was recorded rather than planning again,
+    which would be outside PLAN_TIMEOUT_SECS */
+    plan_options.plan = Some(plan.clone());
+    timings.plan_ms = elapsed_ms(plan_start);
+
+    let docker = Docker::new();
+    let images = base_images(&plan);
+    let pull = pull_base_images(&docker, &images, config.pull_policy, &state.pull_locks);
+    if let Err(e) = pull.instrument(info_span!("pull")).await {
+        eprintln!("Failed to pull base images, leaving it to the build: {}", e);
+    }
+
+    let plan_summary = PlanSummary::from_plan(&plan);
+    log(LogLevel::Normal, format!("Planned in {} ms", timings.plan_ms));
+    log(LogLevel::Verbose, format!("Plan: {}", serde_json::to_string_pretty(&plan_summary).unwrap()));
+
+    let started = Utc::now();
+
+    let (mut build_options, provider_defaults) = apply_provider_defaults(&build_info.build_options, &plan_summary.providers, &config.provider_defaults);
+    if !provider_defaults.is_empty() {
+        log(LogLevel::Normal, format!("Applied build_options defaults of {}", provider_defaults.join(", ")));
+        /* a default platform gets the same check as a requested one */
+        platform::check(&build_options.platform, &config.allowed_platforms)
+            .map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, e))?;
+    }
+    if build_info.oci_labels.unwrap_or(config.oci_labels) {
+        /* a local checkout is described by where it was cloned from */
+        let source = if local { git::origin_url(&repo_dir) } else { Some(build_info.path.clone()) };
+        let labels = oci::source_labels(&build_options.labels, source.as_deref(), git::head_commit(&repo_dir).as_deref(), started);
+        build_options.labels.extend(labels);
+    }
+
+    /* an explicit log_level decides how chatty nixpacks is too */
+    build_options.quiet = log_level == LogLevel::Quiet;
+    build_options.verbose = log_level == LogLevel::Verbose;
+    let mut nixpack_options = convert_to_nixpacks_options(&build_options);
+    let image_name = image_name(state, &build_info)?;
+
+    if let Some(cache_key) = branch_cache_key(config.cache_scope, &build_info, build_options.cache_key.as_deref(), &image_name, &repo_dir) {
+        nixpack_options.cache_key = Some(cache_key);
+    }
+    if let Some(cache_key) = &nixpack_options.cache_key {
+        log(LogLevel::Verbose, format!("Cache key: {}", cache_key));
+    }
+
+    /* out_dir is kept per build under ARTIFACT_DIR, not wherever the request says */
+    let artifact_dir = build_info.build_options.out_dir.as_ref()
+        .map(|out_dir| artifacts::build_dir(&config.artifact_dir, &build_id).join(out_dir));
+    if let Some(dir) = &artifact_dir {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| BuildError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create artifact dir: {}", e)))?;
+        nixpack_options.out_dir = Some(dir.display().to_string());
+    }
+
+    if build_info.tag_with_build_id {
+        nixpack_options.tags.push(format!("{}:{}", image_name, build_id));
+        nixpack_options.labels.push(format!("forge-build={}", build_id));
+    }
+
+    /* the image of a warm build isn't wanted, only its layers in the cache */
+    let image_name = if build_info.warm {
+        nixpack_options.tags.clear();
+        nixpack_options.no_error_without_start = true;
+        format!("{}:warm-{}", image_name, build_id)
+    } else {
+        image_name
+    };
+    nixpack_options.name = Some(image_name.clone());
+
+    let mut tags = vec![image_name];
+    tags.extend(nixpack_options.tags.iter().cloned());
+
+    if !build_info.allow_protected_tags {
+        check_protected_tags(&tags, &config.protected_tags)?;
+    }
+
+    /* an isolated build works on a tag of its own and only takes its real tags once it went through */
+    let isolated = build_info.isolate_tags.unwrap_or(config.isolate_build_tags) && !build_info.warm;
+    let local_tags = if isolated {
+        let local_tag = isolated_tag(&tags[0], &build_id);
+        nixpack_options.name = Some(local_tag.clone());
+        nixpack_options.tags.clear();
+        vec![local_tag]
+    } else {
+        tags.clone()
+    };
+    /* the built image, whatever it's tagged as */
+    let image = &local_tags[0];
+
+    /* Update build data once build is triggered */
+    let start = BuildStart {
+        start_time: started.to_rfc3339(),
+        plan: &plan_summary,
+        commit_sha: git::head_commit(&repo_dir),
+        image: &tags[0],
+        provider_defaults: &provider_defaults,
+        cache_key: nixpack_options.cache_key.as_deref(),
+    };
+    match record_start(&mut conn, &build_id, &start).await {
+        Ok(_) => eprintln!("DB update success"),
+        Err(e) => eprintln!("DB update error: {}", e), // Or handle the error more properly
+    }
+    state.emit(BuildEvent::new(&build_id, &build_info.path, "running"));
+
+    /* podman and buildah have no daemon to hand the build to */
+    let docker_host = match state.image_builder.backend() {
+        Backend::Docker => platform::docker_host(&build_options.platform, &config.platform_docker_hosts),
+        _ => None,
+    };
+    if let Some(docker_host) = docker_host {
+        log(LogLevel::Normal, format!("Building for {} on {}", build_options.platform.join(", "), docker_host));
+    }
+
+    heartbeat.phase("building");
+    let build_start = Instant::now();
+    let job = BuildJob {
+        repo_dir: &app_dir,
+        envs: envs_of(&build_info),
+        plan_options: &plan_options,
+        nixpack_options,
+        tags: &local_tags,
+        limits: &limits,
+        secrets: &build_info.secrets,
+        network: build_info.build_network.as_deref(),
+        workdir_base: &config.workdir_base,
+        docker_host,
+        step_errors: test_command.is_some(),
+    };
+    let mut result = state.image_builder.build(job).instrument(info_span!("build")).await;
+    if result.is_ok() && !build_info.warm && (build_info.entrypoint.is_some() || build_info.cmd.is_some()) {
+        let overridden = image_config::override_config(
+            state.image_builder.backend().cli(),
+            &local_tags,
+            build_info.entrypoint.as_deref(),
+            build_info.cmd.as_deref(),
+            &config.workdir_base,
+        ).await;
+        if let Err(e) = overridden {
+            result = Err(e);
+        }
+    }
+    timings.build_ms = elapsed_ms(build_start);
+
+    match &result {
+        Ok(_) => log(LogLevel::Normal, format!("Built {} in {} ms", tags.join(", "), timings.build_ms)),
+        Err(e) => log(LogLevel::Quiet, format!("Build failed: {}", e)),
+    }
+
+    if let Ok(Some(dockerfile)) = &result {
+        log(LogLevel::Verbose, format!("Dockerfile:\n{}", dockerfile));
+        if let Err(e) = sqlx::query("UPDATE build_data SET dockerfile = $1 WHERE id = $2")
+            .bind(dockerfile::encode(dockerfile))
+            .bind(&build_id)
+            .execute(&mut conn)
+            .await {
+            eprintln!("DB update error: {}", e);
+        }
+    }
+
+    if build_info.warm && result.is_ok() {
+        if let Err(e) = registry::remove(state.image_builder.backend().cli(), &tags[0]).await {
+            eprintln!("Failed to remove warm image {}: {}", tags[0], e);
+        }
+        return Ok(warmed(state, build_info, build_id, plan_summary, timings).await);
+    }
+
+    /* need to port  registry server from old repo(:
+    let status = match result {
+        Ok(_) => {
+            let client = Client::new();
+            let registry_post_data = json!({
+                "image_name": build_info.name,
+                "image_tag": build_info.build_options.tags.get(0).unwrap_or(&"latest".to_string())
+            });
+
+            let push_result = client.post("http://localhost:8083/push")
+                .json(&registry_post_data)
+                .send()
+                .await;
+
+            match push_result {
+                Ok(_) => "Completed",
+                Err(_) => "Failed"
+            }
+        },
+        Err(_) => "Failed"
+    };
+    */
+
+    let (mut status, mut message) = match &result {
+        Ok(_) => ("Completed", "Image created.".to_string()),
+        Err(e) => build_failure(e),
+    };
+
+    if result.is_ok() {
+        let user = match image_user(&Docker::new(), image).await {
+            Ok(user) => Some(user),
+            Err(e) => {
+                eprintln!("Failed to read the image user: {}", e);
+                None
+            }
+        };
+
+        if let Err(e) = sqlx::query("UPDATE build_data SET image_user = $1 WHERE id = $2")
+            .bind(&user)
+            .bind(&build_id)
+            .execute(&mut conn)
+            .await {
+            eprintln!("DB update error: {}", e);
+        }
+
+        if config.require_non_root && !build_info.allow_root {
+            if let Some(reason) = root_refusal(user.as_deref()) {
+                let message = match keep_failed_image(state, &build_info, image, &tags[0], &build_id).await {
+                    Some(kept) => format!("{}, kept as {}", reason, kept),
+                    None => reason.to_string(),
+                };
+                if isolated {
+                    remove_local_tag(state, image).await;
+                }
+                return Err(BuildError::new(StatusCode::UNPROCESSABLE_ENTITY, message));
+            }
+        }
+
+        let size = match image_size(&Docker::new(), image).await {
+            Ok(size) => Some(size),
+            Err(e) => {
+                eprintln!("Failed to read the image size: {}", e);
+                None
+            }
+        };
+
+        if let Err(e) = sqlx::query("UPDATE build_data SET image_size = $1 WHERE id = $2")
+            .bind(size.map(|size| size as i64))
+            .bind(&build_id)
+            .execute(&mut conn)
+            .await {
+            eprintln!("DB update error: {}", e);
+        }
+
+        /* an image that can't be inspected isn't held to the limit */
+        let max_mb = build_info.max_image_size_mb.unwrap_or(config.max_image_size_mb);
+        if let Err(reason) = size.map_or(Ok(()), |size| image_size::check(size, max_mb)) {
+            let message = match keep_failed_image(state, &build_info, image, &tags[0], &build_id).await {
+                Some(kept) => format!("{}, kept as {}", reason, kept),
+                None => reason,
+            };
+            if isolated {
+                remove_local_tag(state, image).await;
+            }
+            return Err(BuildError::new(StatusCode::UNPROCESSABLE_ENTITY, message));
+        }
+    }
+
+    /* an SBOM is nice to have, a build doesn't fail without one */
+    if result.is_ok() && status == "Completed" && build_info.sbom.unwrap_or(config.sbom.enabled) {
+        match sbom::generate(&config.sbom, image).instrument(info_span!("sbom")).await {
+            Ok(Some(document)) => {
+                log(LogLevel::Normal, format!("Generated the SBOM ({} bytes)", document.len()));
+                if let Err(e) = sqlx::query("UPDATE build_data SET sbom = $1 WHERE id = $2")
+                    .bind(sbom::encode(&document))
+                    .bind(&build_id)
+                    .execute(&mut conn)
+                    .await {
+                    eprintln!("DB update error: {}", e);
+                }
+            },
+            Ok(None) => log(LogLevel::Normal, format!("{} isn't installed, skipping the SBOM", config.sbom.generator)),
+            Err(e) => log(LogLevel::Quiet, format!("Failed to generate the SBOM: {}", e)),
+        }
+    }
+
+    /* references the registry already has, which the push leaves alone */
+    let mut existing: Vec<String> = Vec::new();
+    let tag_policy = build_info.on_existing_tag.unwrap_or(config.on_existing_tag);
+    if result.is_ok() && build_info.push && status == "Completed" {
+        match registry::existing_tags(state.image_builder.backend().cli(), push_refs(&tags), tag_policy).await {
+            Ok(found) => existing = found,
+            Err(e) => {
+                status = "Failed";
+                message = e;
+            }
+        }
+
+        if let Some(reason) = tag_policy.conflict(&existing) {
+            let message = match keep_failed_image(state, &build_info, image, &tags[0], &build_id).await {
+                Some(kept) => format!("{}, kept as {}", reason, kept),
+                None => reason,
+            };
+            if isolated {
+                remove_local_tag(state, image).await;
+            }
+            return Err(BuildError::new(StatusCode::CONFLICT, message));
+        }
+    }
+
+    if isolated && result.is_ok() && status == "Completed" {
+        for tag in &tags {
+            if let Err(e) = registry::tag(state.image_builder.backend().cli(), image, tag).await {
+                status = "Failed";
+                message = e;
+                break;
+            }
+        }
+    }
+
+    if result.is_ok() && build_info.push && status == "Completed" {
+        heartbeat.phase("pushing");
+        let push_start = Instant::now();
+        let _permit = match state.pushes.try_acquire() {
+            Some(permit) => permit,
+            None => {
+                set_status(state, &build_id, &build_info.path, PUSH_QUEUED).await;
+                let permit = state.pushes.acquire().instrument(info_span!("push_queued")).await;
+                set_status(state, &build_id, &build_info.path, "running").await;
+                permit
+            }
+        };
+        let push = async {
+            let references: Vec<&String> = push_refs(&tags).filter(|reference| !existing.contains(reference)).collect();
+            if let Some(credentials) = &config.registry_credentials {
+                let hosts: BTreeSet<&str> = references.iter().map(|reference| registry::registry_host(reference)).collect();
+                for host in hosts {
+                    registry::login(state.image_builder.backend().cli(), host, credentials).await?;
+                }
+            }
+            for reference in references {
+                registry::push(state.image_builder.backend().cli(), reference).await?;
+            }
+            Ok::<_, String>(())
+        };
+
+        match push.instrument(info_span!("push")).await {
+            Ok(_) if !existing.is_empty() => {
+                status = TAG_EXISTS;
+                message = format!("Image created, {} already existed and wasn't pushed.", existing.join(", "));
+            },
+            Ok(_) => message = "Image created and pushed.".to_string(),
+            Err(e) => {
+                status = "Failed";
+                message = e;
+            },
+        }
+        timings.push_ms = Some(elapsed_ms(push_start));
+        if status != "Failed" {
+            log(LogLevel::Normal, format!("Pushed in {} ms", timings.push_ms.unwrap_or_default()));
+        }
+    }
+
+    if let (Ok(_), Some(command), "Completed" | TAG_EXISTS) = (&result, &build_info.post_build_command, status) {
+        heartbeat.phase("post_build");
+        let timeout = Duration::from_secs(build_info.post_build_timeout_secs.unwrap_or(config.post_build_timeout_secs));
+        let output = match run_post_build(&Docker::new(), image, command, timeout).instrument(info_span!("post_build")).await {
+            Ok(post_build) if post_build.exit_code == 0 => post_build.output,
+            Ok(post_build) => {
+                status = "PostBuildFailed";
+                message = format!("Post-build command exited with {}", post_build.exit_code);
+                post_build.output
+            },
+            Err(e) => {
+                status = "PostBuildFailed";
+                message = e.clone();
+                e
+            },
+        };
+
+        /* a failing command's output is what a quiet log is for */
+        let level = if status == "PostBuildFailed" { LogLevel::Quiet } else { LogLevel::Normal };
+        log(level, format!("$ {}\n{}", command, output));
+    }
+
+    /* the image itself built, something after it failed */
+    if result.is_ok() && status != "Completed" && status != TAG_EXISTS {
+        if let Some(kept) = keep_failed_image(state, &build_info, image, &tags[0], &build_id).await {
+            message = format!("{} Image kept as {}.", message, kept);
+        }
+    }
+
+    if isolated && result.is_ok() {
+        remove_local_tag(state, image).await;
+    }
+
+    let produced = artifact_dir.as_ref().map(|_| {
+        artifacts::list(&artifacts::build_dir(&config.artifact_dir, &build_id)).unwrap_or_else(|e| {
+            eprintln!("Failed to list artifacts: {}", e);
+            Vec::new()
+        })
+    });
+
+    let end_time = Utc::now().to_rfc3339();
+
+    match sqlx::query("UPDATE build_data SET status = $1, end_time = $2, message = $3, timings = $4, artifacts = $5 WHERE id = $6")
+        .bind(status)
+        .bind(&end_time)
+        .bind(&message)
+        .bind(serde_json::to_string(&timings).unwrap())
+        .bind(produced.as_ref().map(|produced| serde_json::to_string(produced).unwrap()))
+        .bind(&build_id)
+        .execute(&mut conn)
+        .await {
+        Ok(_) => eprintln!("DB updated"),
+        Err(e) => eprintln!("DB update error: {}", e), // Or handle the error more properly
+    }
+    state.emit(BuildEvent::new(&build_id, &build_info.path, status));
+    log(LogLevel::Quiet, format!("{}: {}", status, message));
+
+    if produced.is_some() {
+        prune_artifacts(state).await;
+    }
+
+    Ok(BuildOutcome {
+        id: build_id,
+        status: status.to_string(),
+        message,
+        plan: Some(plan_summary),
+        tags,
+        timings,
+        provider_defaults,
+    })
+}
+
+/// Whether `before..after` changed anything `patterns` cares about. Without
+/// a `before` (the first push of a branch) everything counts as changed.
+fn has_relevant_changes(build_info: &BuildInfo, repo_dir: &str, patterns: &[String]) -> Result<bool, BuildError> {
+    let (before, after) = match (&build_info.before, &build_info.after) {
+        (Some(before), Some(after)) if !before.chars().all(|c| c == '0') => (before, after),
+        _ => return Ok(true),
+    };
+
+    let repo = git2::Repository::open(repo_dir)
+        .map_err(|e| BuildError::new(StatusCode::BAD_REQUEST, format!("paths_filter needs a git repository: {}", e)))?;
+
+    match git::changed_files(&repo, before, after) {
+        Ok(files) => Ok(any_match(patterns, &files)),
+        /* e.g. before was force-pushed away, build to be safe */
+        Err(e) => {
+            eprintln!("Failed to diff {}..{}, building anyway: {}", before, after, e);
+            Ok(true)
+        }
+    }
+}
+
+/// The status and message of a build whose image couldn't be built.
+fn build_failure(error: &str) -> (&'static str, String) {
+    if error.contains(TESTS_FAILED_MARKER) {
+        return (TESTS_FAILED, format!("Tests failed: {}", error));
+    }
+
+    match limit_hint(error) {
+        Some(hint) => ("Failed", format!("Failed to create image: {} (hint: {})", error, hint)),
+        None => ("Failed", format!("Failed to create image: {}", error)),
+    }
+}
+
+/// Finishes a warm build whose dependency layers are now cached.
+async fn warmed(state: &AppState, build_info: BuildInfo, build_id: String, plan: PlanSummary, timings: BuildTimings) -> BuildOutcome {
+    let message = "Dependency layers cached".to_string();
+
+    match sqlx::query("UPDATE build_data SET status = $1, end_time = $2, message = $3, timings = $4 WHERE id = $5")
+        .bind("Warmed")
+        .bind(Utc::now().to_rfc3339())
+        .bind(&message)
+        .bind(serde_json::to_string(&timings).unwrap())
+        .bind(&build_id)
+        .execute(&state.db_pool)
+        .await {
+        Ok(_) => eprintln!("DB updated"),
+        Err(e) => eprintln!("DB update error: {}", e),
+    }
+    state.emit(BuildEvent::new(&build_id, &build_info.path, "Warmed"));
+
+    BuildOutcome {
+        id: build_id,
+        status: "Warmed".to_string(),
+        message,
+        plan: Some(plan),
+        tags: Vec::new(),
+        timings,
+        provider_defaults: Vec::new(),
+    }
+}
+
+/// Finishes a build that paths_filter ruled out.
+async fn skip(state: &AppState, build_info: BuildInfo, build_id: String, timings: BuildTimings) -> BuildOutcome {
+    let message = "No relevant changes".to_string();
+
+    match sqlx::query("UPDATE build_data SET status = $1, end_time = $2, message = $3, timings = $4 WHERE id = $5")
+        .bind("Skipped")
+        .bind(Utc::now().to_rfc3339())
+        .bind(&message)
+        .bind(serde_json::to_string(&timings).unwrap())
+        .bind(&build_id)
+        .execute(&state.db_pool)
+        .await {
+        Ok(_) => eprintln!("DB updated"),
+        Err(e) => eprintln!("DB update error: {}", e),
+    }
+    state.emit(BuildEvent::new(&build_id, &build_info.path, "Skipped"));
+
+    BuildOutcome {
+        id: build_id,
+        status: "Skipped".to_string(),
+        message,
+        plan: None,
+        tags: Vec::new(),
+        timings,
+        provider_defaults: Vec::new(),
+    }
+}
+
+/// The cache key of a build of `image_name` with `CACHE_SCOPE=branch`,
+/// `base` (or the image's name) scoped to the branch being built. `None` to
+/// keep the key as it is: shared scope, no branch, or a `cache_key` the
+/// request set itself.
+fn branch_cache_key(scope: CacheScope, build_info: &BuildInfo, base: Option<&str>, image_name: &str, repo_dir: &str) -> Option<String> {
+    if scope != CacheScope::Branch || build_info.build_options.cache_key.is_some() {
+        return None;
+    }
+
+    let branch = build_info.branch.clone()
+        .or_else(|| build_info.git_ref.clone())
+        .or_else(|| git::head_branch(repo_dir))?;
+    let base = base.map(String::from)
+        .unwrap_or_else(|| registry::repository_of(image_name).rsplit('/').next().unwrap_or_default().to_string());
+    Some(scoped_key(&base, &branch))
+}
+
+/// The tag build `build_id` of `name` works on while isolated,
+/// `forge-build-<build id>/<name without its registry and tag>`.
+fn isolated_tag(name: &str, build_id: &str) -> String {
+    let repository = registry::repository_of(name);
+    format!("forge-build-{}/{}", build_id, repository.rsplit('/').next().unwrap_or(repository))
+}
+
+/// Drops an isolated build's own tag. The image stays under its real tags,
+/// without any it's removed.
+async fn remove_local_tag(state: &AppState, image: &str) {
+    if let Err(e) = registry::remove(state.image_builder.backend().cli(), image).await {
+        eprintln!("Failed to remove the build's own tag: {}", e);
+    }
+}
+
+/// The references in `tags` that belong to the built image's repository,
+/// other tags are left local.
+fn push_refs(tags: &[String]) -> impl Iterator<Item = &String> {
+    let repository = &tags[0];
+    tags.iter().filter(move |tag| *tag == repository || tag.strip_prefix(repository.as_str()).is_some_and(|rest| rest.starts_with(':')))
+}
+
+/// Tags `image` of a build that failed after it was built as
+/// `<name>:failed-<build id>`, so it can be run to see what went wrong, and
+/// records the tag on the build. Only for builds with `keep_failed_image`.
+async fn keep_failed_image(state: &AppState, build_info: &BuildInfo, image: &str, name: &str, build_id: &str) -> Option<String> {
+    if !build_info.keep_failed_image {
+        return None;
+    }
+
+    let kept = match tag_failed_image(state.image_builder.backend().cli(), image, name, build_id).await {
+        Ok(kept) => kept,
+        Err(e) => {
+            eprintln!("Failed to keep the failed image: {}", e);
+            return None;
+        }
+    };
+
+    if let Err(e) = sqlx::query("UPDATE build_data SET failed_image = $1 WHERE id = $2")
+        .bind(&kept)
+        .bind(build_id)
+        .execute(&state.db_pool)
+        .await {
+        eprintln!("DB update error: {}", e);
+    }
+
+    prune_failed_images(state).await;
+    Some(kept)
+}
+
+/// Tags `image` as `<name>:failed-<build id>` with `cli`, returning the tag.
+async fn tag_failed_image(cli: &str, image: &str, name: &str, build_id: &str) -> Result<String, String> {
+    let kept = format!("{}:failed-{}", registry::repository_of(name), build_id);
+    registry::tag(cli, image, &kept).await?;
+    Ok(kept)
+}
+
+/// Removes the images kept by `keep_failed_image` past the newest
+/// `FAILED_IMAGE_RETENTION`, so they don't pile up.
+async fn prune_failed_images(state: &AppState) {
+    let config = state.config();
+    let rows = match sqlx::query("SELECT id, failed_image FROM build_data WHERE failed_image IS NOT NULL ORDER BY start_time DESC OFFSET $1")
+        .bind(config.failed_image_retention as i64)
+        .fetch_all(&state.db_pool)
+        .await {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("Failed to look up failed images to prune: {}", e);
+            return;
+        }
+    };
+
+    for row in rows {
+        let id: String = row.get("id");
+        let image: String = row.get("failed_image");
+        if let Err(e) = registry::remove(state.image_builder.backend().cli(), &image).await {
+            eprintln!("{}", e);
+            continue;
+        }
+
+        if let Err(e) = sqlx::query("UPDATE build_data SET failed_image = NULL WHERE id = $1")
+            .bind(&id)
+            .execute(&state.db_pool)
+            .await {
+            eprintln!("DB update error: {}", e);
+        }
+    }
+}
+
+/// Keeps the artifacts of the last `ARTIFACT_RETENTION` completed builds and
+/// of builds still in flight, the rest are removed.
+async fn prune_artifacts(state: &AppState) {
+    let config = state.config();
+    let keep: Vec<String> = match sqlx::query("SELECT id FROM build_data WHERE status = 'Completed' AND artifacts IS NOT NULL ORDER BY end_time DESC LIMIT $1")
+        .bind(config.artifact_retention as i64)
+        .fetch_all(&state.db_pool)
+        .await {
+        Ok(rows) => rows.iter().map(|row| row.get("id")).collect(),
+        Err(e) => {
+            /* without the list everything would look prunable */
+            eprintln!("Failed to look up artifacts to keep, not pruning: {}", e);
+            return;
+        }
+    };
+
+    match artifacts::prune(&config.artifact_dir, |id| keep.iter().any(|kept| kept == id) || state.queue.is_active(id)) {
+        Ok(pruned) if !pruned.is_empty() => eprintln!("Pruned artifacts of {} builds", pruned.len()),
+        Ok(_) => {},
+        Err(e) => eprintln!("Failed to prune artifacts: {}", e),
+    }
+}
+
+/* moves a build between in-flight statuses, unlike record_status it isn't done */
+async fn set_status(state: &AppState, build_id: &str, repo: &str, status: &str) {
+    if let Err(e) = sqlx::query("UPDATE build_data SET status = $1 WHERE id = $2")
+        .bind(status)
+        .bind(build_id)
+        .execute(&state.db_pool)
+        .await {
+        eprintln!("DB update error: {}", e);
+    }
+    state.emit(BuildEvent::new(build_id, repo, status));
+}
+
+/// Marks a build that was refused by `run_build` as failed, as
+/// `Failed (plan timed out)` when planning took too long.
+pub async fn record_failure(state: &AppState, build_id: &str, repo: &str, error: &BuildError) {
+    let status = if error.status == StatusCode::GATEWAY_TIMEOUT { PLAN_TIMED_OUT } else { "Failed" };
+    record_status(state, build_id, repo, status, &error.message).await;
+}
+
+/// Finishes a build that never got to (or past) `run_build` with `status`.
+/// What a build's row is updated with once it starts running.
+struct BuildStart<'a> {
+    start_time: String,
+    plan: &'a PlanSummary,
+    commit_sha: Option<String>,
+    /* the first of the tags it's built with */
+    image: &'a str,
+    provider_defaults: &'a [String],
+    cache_key: Option<&'a str>,
+}
+
+/* the nixpacks it's built with is this forge's own */
+async fn record_start(conn: &mut PgConnection, build_id: &str, start: &BuildStart<'_>) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE build_data SET status = $1, start_time = $2, plan = $3, commit_sha = $4, image = $5, provider_defaults = $6, cache_key = $7, nixpacks_version = $8 WHERE id = $9")
+        .bind("running")
+        .bind(&start.start_time)
+        .bind(serde_json::to_string(start.plan).unwrap())
+        .bind(&start.commit_sha)
+        .bind(start.image)
+        .bind(serde_json::to_string(start.provider_defaults).unwrap())
+        .bind(start.cache_key)
+        .bind(NIXPACKS_VERSION)
+        .bind(build_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn record_status(state: &AppState, build_id: &str, repo: &str, status: &str, message: &str) {
+    match sqlx::query("UPDATE build_data SET status = $1, end_time = $2, message = $3 WHERE id = $4")
+        .bind(status)
+        .bind(Utc::now().to_rfc3339())
+        .bind(message)
+        .bind(build_id)
+        .execute(&state.db_pool)
+        .await {
+        Ok(_) => eprintln!("DB updated"),
+        Err(e) => eprintln!("DB update error: {}", e),
+    }
+    state.emit(BuildEvent::new(build_id, repo, status));
+}
+
+/// Looks up a build by id, `None` if there is no such build.
+pub async fn get_status(state: &AppState, id: &str) -> Result<Option<BuildRecord>, sqlx::Error> {
+    let row = sqlx::query("SELECT id, status, start_time, end_time, message, plan, timings, options, commit_sha, artifacts, image_user, image_size, provider_defaults, cache_key, failed_image, metadata, nixpacks_version, last_heartbeat, phase FROM build_data WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&state.db_pool)
+        .await?;
+
+    Ok(row.map(|row| {
+        let options: Option<serde_json::Value> = row.get::<Option<String>, _>("options").and_then(|options| serde_json::from_str(&options).ok());
+        let priority = options.as_ref()
+            .map(|options| options.get("priority").and_then(|priority| priority.as_u64()).unwrap_or(0) as u8);
+
+        BuildRecord {
+            id: row.get("id"),
+            status: row.get("status"),
+            start_time: row.get("start_time"),
+            end_time: row.get("end_time"),
+            message: row.get("message"),
+            plan: row.get::<Option<String>, _>("plan").and_then(|plan| serde_json::from_str(&plan).ok()),
+            timings: row.get::<Option<String>, _>("timings").and_then(|timings| serde_json::from_str(&timings).ok()),
+            options,
+            commit: row.get("commit_sha"),
+            priority,
+            artifacts: row.get::<Option<String>, _>("artifacts").and_then(|artifacts| serde_json::from_str(&artifacts).ok()),
+            image_user: row.get("image_user"),
+            image_size: row.get("image_size"),
+            provider_defaults: row.get::<Option<String>, _>("provider_defaults").and_then(|applied| serde_json::from_str(&applied).ok()),
+            cache_key: row.get("cache_key"),
+            failed_image: row.get("failed_image"),
+            metadata: read_metadata(row.get("metadata")),
+            nixpacks_version: row.get("nixpacks_version"),
+            last_heartbeat: row.get("last_heartbeat"),
+            phase: row.get("phase"),
+        }
+    }))
+}
+
+/// The stored, encoded SBOM of build `id`: `None` if there is no such
+/// build, `Some(None)` if none was generated for it.
+pub async fn get_sbom(state: &AppState, id: &str) -> Result<Option<Option<Vec<u8>>>, sqlx::Error> {
+    let row = sqlx::query("SELECT sbom FROM build_data WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&state.db_pool)
+        .await?;
+
+    Ok(row.map(|row| row.get("sbom")))
+}
+
+/// The stored Dockerfile of build `id`: `None` if there is no such build,
+/// `Some(None)` if nothing was captured for it.
+pub async fn get_dockerfile(state: &AppState, id: &str) -> Result<Option<Option<Vec<u8>>>, sqlx::Error> {
+    let row = sqlx::query("SELECT dockerfile FROM build_data WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&state.db_pool)
+        .await?;
+
+    Ok(row.map(|row| row.get("dockerfile")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::build::git::tests::commit;
+    use crate::config::config::tests::config;
+    use crate::engine::state::tests::state;
+
+    use serde_json::json;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn unprotected_tags_are_allowed() {
+        let protected = strings(&["latest", "stable"]);
+        let tags = strings(&["registry.example.com/app:1.2.3", "localhost:5000/app:feature-x"]);
+
+        assert!(check_protected_tags(&tags, &protected).is_ok());
+    }
+
+    #[test]
+    fn protected_tags_are_denied() {
+        let protected = strings(&["latest", "stable"]);
+        let tags = strings(&["registry.example.com/app:1.2.3", "registry.example.com/app:stable"]);
+
+        let error = check_protected_tags(&tags, &protected).unwrap_err();
+
+        assert_eq!(error.status, StatusCode::FORBIDDEN);
+        assert_eq!(error.message, "Protected tags need allow_protected_tags: registry.example.com/app:stable");
+    }
+
+    #[test]
+    fn untagged_references_are_latest() {
+        let protected = strings(&["latest"]);
+
+        /* the port isn't taken for a tag */
+        assert!(check_protected_tags(&strings(&["localhost:5000/app"]), &protected).is_err());
+        assert!(check_protected_tags(&strings(&["app"]), &protected).is_err());
+        assert!(check_protected_tags(&strings(&["app"]), &[]).is_ok());
+    }
+
+    /* caches `plan` the way /plan does for the checkout at `dir` */
+    fn planned(state: &AppState, build_info: &BuildInfo, dir: &str, plan: BuildPlan) {
+        state.plans.insert(plan_cache_key(build_info, dir, false).unwrap(), plan);
+    }
+
+    #[tokio::test]
+    async fn build_after_plan_reuses_the_cached_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        commit(&repo, &[("package.json", "{}")]);
+        let repo_dir = dir.path().display().to_string();
+
+        let state = state(config());
+        let build_info: BuildInfo = serde_json::from_value(json!({ "path": "https://github.com/example/app", "name": "app" })).unwrap();
+        planned(&state, &build_info, &repo_dir, BuildPlan { build_image: Some("planned".to_string()), ..Default::default() });
+
+        let plan = cached_plan(&state, &build_info, &repo_dir, false).expect("the plan wasn't reused");
+        assert_eq!(plan.build_image.as_deref(), Some("planned"));
+
+        /* no_cache plans again */
+        let mut fresh = build_info.clone();
+        fresh.build_options.no_cache = true;
+        assert!(cached_plan(&state, &fresh, &repo_dir, false).is_none());
+    }
+
+    #[tokio::test]
+    async fn plan_of_another_commit_isnt_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        commit(&repo, &[("package.json", "{}")]);
+        let repo_dir = dir.path().display().to_string();
+
+        let state = state(config());
+        let build_info: BuildInfo = serde_json::from_value(json!({ "path": "https://github.com/example/app", "name": "app" })).unwrap();
+        planned(&state, &build_info, &repo_dir, BuildPlan::default());
+
+        commit(&repo, &[("package.json", r#"{ "name": "app" }"#)]);
+        assert!(cached_plan(&state, &build_info, &repo_dir, false).is_none());
+        /* local paths have no commit pinning them */
+        assert!(plan_cache_key(&build_info, &repo_dir, true).is_none());
+    }
+
+    #[tokio::test]
+    async fn late_failure_tags_the_image() {
+        use std::os::unix::fs::PermissionsExt;
+
+        /* stands in for docker, recording the tag it's asked to add */
+        let dir = tempfile::tempdir().unwrap();
+        let cli = dir.path().join("docker");
+        let record = dir.path().join("record");
+        std::fs::write(&cli, format!("#!/bin/sh\necho \"$@\" > {}\n", record.display())).unwrap();
+        std::fs::set_permissions(&cli, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        /* the image built, then e.g. the push failed */
+        let kept = tag_failed_image(cli.to_str().unwrap(), "forge-build-42/app", "registry.example.com/app:1.0", "42").await.unwrap();
+
+        assert_eq!(kept, "registry.example.com/app:failed-42");
+        assert_eq!(std::fs::read_to_string(&record).unwrap(), "tag forge-build-42/app registry.example.com/app:failed-42\n");
+    }
+
+    #[tokio::test]
+    async fn failed_tagging_is_reported() {
+        assert!(tag_failed_image("false", "app", "app", "42").await.is_err());
+    }
+
+    #[test]
+    fn same_named_builds_get_their_own_tags() {
+        let first = isolated_tag("registry.example.com/team/app:1.0", "41");
+        let second = isolated_tag("registry.example.com/team/app:1.0", "42");
+
+        assert_eq!(first, "forge-build-41/app");
+        assert_eq!(second, "forge-build-42/app");
+        /* nothing about the real name is left to collide on */
+        assert_eq!(isolated_tag("app", "42"), second);
+    }
+
+    #[tokio::test]
+    async fn slow_planning_times_out_and_frees_the_worker() {
+        let started = Instant::now();
+
+        let error = plan_within(1, || {
+            std::thread::sleep(Duration::from_secs(3));
+            Ok::<_, String>(BuildPlan::default())
+        }).await.unwrap_err();
+
+        assert_eq!(error.status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(error.message, "Planning timed out after 1s");
+        /* the worker is back long before the planner returns */
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn planning_within_the_budget_succeeds() {
+        let plan = plan_within(5, || Ok::<_, String>(BuildPlan { build_image: Some("planned".to_string()), ..Default::default() })).await.unwrap();
+        assert_eq!(plan.build_image.as_deref(), Some("planned"));
+
+        let error = plan_within(0, || Err::<BuildPlan, _>("no provider matched")).await.unwrap_err();
+        assert_eq!(error.message, "Failed to generate build plan: no provider matched");
+    }
+
+    #[tokio::test]
+    async fn metadata_round_trips_through_submit_and_status() {
+        let state = state(config());
+        let request = json!({
+            "path": "https://github.com/example/app",
+            "name": "app",
+            "metadata": { "pipeline": "1234", "pr": "56", "triggered_by": "ci, nightly" },
+        });
+        let build_info = build_info_from_value(request.clone(), &state.config()).unwrap();
+        assert!(validate(&state, &build_info).is_ok());
+
+        /* kept in its own column, not with the options */
+        assert!(recorded_options(&build_info).get("metadata").is_none());
+        let metadata = read_metadata(stored_metadata(&build_info)).unwrap();
+        assert_eq!(json!(metadata), request["metadata"]);
+    }
+
+    #[test]
+    fn metadata_is_bounded() {
+        let metadata = |key: &str, value: &str| BTreeMap::from([(key.to_string(), value.to_string())]);
+
+        assert!(check_metadata(&metadata("pr", "56")).is_ok());
+        assert!(check_metadata(&metadata("", "56")).is_err());
+        assert!(check_metadata(&metadata(&"k".repeat(MAX_METADATA_KEY_BYTES + 1), "56")).is_err());
+        assert_eq!(
+            check_metadata(&metadata("pr", &"v".repeat(MAX_METADATA_VALUE_BYTES + 1))).unwrap_err(),
+            "metadata value of pr is longer than 1024 bytes",
+        );
+
+        let many: BTreeMap<String, String> = (0..=MAX_METADATA_ENTRIES).map(|i| (i.to_string(), String::new())).collect();
+        assert!(check_metadata(&many).is_err());
+    }
+
+    #[test]
+    fn branches_get_their_own_cache_keys() {
+        let build_info = |branch: &str| -> BuildInfo {
+            serde_json::from_value(json!({ "path": "https://github.com/example/app", "name": "app", "branch": branch })).unwrap()
+        };
+        let key = |branch: &str| branch_cache_key(CacheScope::Branch, &build_info(branch), None, "registry.example.com/team/app:1.0", "/nonexistent");
+
+        assert_eq!(key("main").as_deref(), Some("app-main"));
+        assert_eq!(key("feature/login").as_deref(), Some("app-feature-login"));
+        assert_ne!(key("main"), key("feature/login"));
+
+        /* a profile's key is scoped too */
+        let scoped = branch_cache_key(CacheScope::Branch, &build_info("main"), Some("deps"), "app", "/nonexistent");
+        assert_eq!(scoped.as_deref(), Some("deps-main"));
+    }
+
+    #[test]
+    fn shared_scope_and_requested_keys_are_left_alone() {
+        let build_info: BuildInfo = serde_json::from_value(json!({ "path": "https://github.com/example/app", "name": "app", "branch": "main" })).unwrap();
+        assert!(branch_cache_key(CacheScope::Shared, &build_info, None, "app", "/nonexistent").is_none());
+
+        let requested: BuildInfo = serde_json::from_value(json!({
+            "path": "https://github.com/example/app",
+            "name": "app",
+            "branch": "main",
+            "build_options": { "cache_key": "mine" },
+        })).unwrap();
+        assert!(branch_cache_key(CacheScope::Branch, &requested, Some("mine"), "app", "/nonexistent").is_none());
+    }
+
+    #[test]
+    fn failing_test_step_is_told_apart() {
+        let error = format!("#12 0.41 1 failing\n#12 0.41 {}\nERROR: failed to solve: process \"/bin/sh -c ( npm test ) || ...\" did not complete successfully: exit code: 1", TESTS_FAILED_MARKER);
+        let (status, message) = build_failure(&error);
+        assert_eq!(status, TESTS_FAILED);
+        assert!(message.starts_with("Tests failed: #12 0.41 1 failing"));
+
+        let (status, message) = build_failure("ERROR: process \"/bin/sh -c npm run build\" did not complete successfully: exit code: 2");
+        assert_eq!(status, "Failed");
+        assert!(message.starts_with("Failed to create image: "));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database at TEST_DATABASE_URL"]
+    async fn started_builds_record_their_nixpacks_version() {
+        let mut config = config();
+        config.db.url = std::env::var("TEST_DATABASE_URL").unwrap();
+        let state = state(config);
+        let build_info: BuildInfo = serde_json::from_value(json!({ "path": "https://github.com/example/app", "name": "app" })).unwrap();
+        let id = crate::engine::queue::submit(&state, build_info).await.unwrap();
+        assert_eq!(get_status(&state, &id).await.unwrap().unwrap().nixpacks_version, None);
+
+        let plan = PlanSummary::from_plan(&BuildPlan::default());
+        let start = BuildStart {
+            start_time: Utc::now().to_rfc3339(),
+            plan: &plan,
+            commit_sha: None,
+            image: "app",
+            provider_defaults: &[],
+            cache_key: None,
+        };
+        let mut conn = state.db_pool.acquire().await.unwrap();
+        record_start(&mut conn, &id, &start).await.unwrap();
+
+        let record = get_status(&state, &id).await.unwrap().unwrap();
+        assert_eq!(record.status, "running");
+        assert_eq!(record.nixpacks_version.as_deref(), Some(NIXPACKS_VERSION));
+        assert_eq!(serde_json::to_value(&record).unwrap()["nixpacks_version"], json!(NIXPACKS_VERSION));
+    }
+
+    #[tokio::test]
+    async fn mismatched_nixpacks_pins_are_refused() {
+        let state = state(config());
+        let pinned = |version: &str| -> BuildInfo {
+            serde_json::from_value(json!({ "path": "https://github.com/example/app", "name": "app", "nixpacks_version": version })).unwrap()
+        };
+
+        assert!(validate(&state, &pinned(NIXPACKS_VERSION)).is_ok());
+        let error = validate(&state, &pinned("0.0.1")).unwrap_err();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.message, format!("nixpacks 0.0.1 was requested, this forge builds with nixpacks {}", NIXPACKS_VERSION));
+    }
+}
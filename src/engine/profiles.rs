@@ -0,0 +1,241 @@
+use hyper::StatusCode;
+use serde_json::{Map, Value};
+
+use crate::engine::pipeline::{BuildError, DockerBuilderOptions};
+
+use std::collections::BTreeMap;
+
+/// Layers the named profile under `request`, both being `BuildInfo` shaped
+/// JSON. Objects are merged key by key with the request winning, `envs` are
+/// merged by variable name, anything else the request sets replaces the
+/// profile's value.
+pub fn apply_profile(mut request: Value, profiles: &BTreeMap<String, Value>) -> Result<Value, BuildError> {
+    let name = match request.get("profile") {
+        None | Some(Value::Null) => return Ok(request),
+        Some(Value::String(name)) => name.clone(),
+        Some(_) => return Err(BuildError::new(StatusCode::BAD_REQUEST, "profile must be a string")),
+    };
+
+    let profile = profiles.get(&name).ok_or_else(|| BuildError::new(
+        StatusCode::BAD_REQUEST,
+        format!("Unknown profile {}, valid profiles are: {}", name, profiles.keys().cloned().collect::<Vec<_>>().join(", ")),
+    ))?;
+
+    Ok(layer(profile, request.take()))
+}
+
+/// `overrides` layered over `base`, by the same rules as a request over its
+/// profile.
+pub fn layer(base: &Value, overrides: Value) -> Value {
+    let envs = merge_envs(base.get("envs"), overrides.get("envs"));
+    let mut merged = base.clone();
+    merge(&mut merged, overrides);
+
+    if let Some(envs) = envs {
+        merged["envs"] = envs;
+    }
+
+    merged
+}
+
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        },
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// `NAME=value` lists merged by name, request entries replacing the profile's.
+fn merge_envs(profile: Option<&Value>, request: Option<&Value>) -> Option<Value> {
+    let profile = profile?.as_array()?;
+    let request = match request {
+        Some(Value::Array(request)) => request.clone(),
+        _ => Vec::new(),
+    };
+
+    let name_of = |env: &Value| env.as_str().map(|env| env.split('=').next().unwrap_or(env).to_string());
+    let overridden: Vec<String> = request.iter().filter_map(name_of).collect();
+
+    let mut envs: Vec<Value> = profile.iter()
+        .filter(|env| name_of(env).is_none_or(|name| !overridden.contains(&name)))
+        .cloned()
+        .collect();
+    envs.extend(request);
+
+    Some(Value::Array(envs))
+}
+
+/// Profiles as read from `BUILD_PROFILES_FILE`, a JSON object of name to
+/// partial build request.
+pub fn parse_profiles(contents: &str) -> Result<BTreeMap<String, Value>, String> {
+    let profiles: Map<String, Value> = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+
+    for (name, profile) in &profiles {
+        if !profile.is_object() {
+            return Err(format!("profile {} must be an object", name));
+        }
+    }
+
+    Ok(profiles.into_iter().collect())
+}
+
+/// Per provider `build_options` defaults as read from
+/// `PROVIDER_DEFAULTS_FILE`, a JSON object of provider name (as nixpacks
+/// detects it, e.g. `node` or `rust`) to partial `build_options`.
+pub fn parse_provider_defaults(contents: &str) -> Result<BTreeMap<String, Value>, String> {
+    let defaults: Map<String, Value> = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+
+    for (provider, options) in &defaults {
+        if !options.is_object() {
+            return Err(format!("defaults for {} must be an object", provider));
+        }
+        /* both are decided per build by forge */
+        if let Some(key) = ["name", "out_dir"].iter().find(|key| options.get(**key).is_some()) {
+            return Err(format!("defaults for {} can't set {}", provider, key));
+        }
+        serde_json::from_value::<DockerBuilderOptions>(options.clone())
+            .map_err(|e| format!("defaults for {} aren't valid build_options: {}", provider, e))?;
+    }
+
+    Ok(defaults.into_iter().collect())
+}
+
+/// Fills the `options` a request left unset (at their default value) from
+/// the defaults of each detected provider, earlier providers winning.
+/// Returns the options and the providers whose defaults were applied.
+pub fn apply_provider_defaults(options: &DockerBuilderOptions, providers: &[String], defaults: &BTreeMap<String, Value>) -> (DockerBuilderOptions, Vec<String>) {
+    let unset = serde_json::to_value(DockerBuilderOptions::default()).unwrap();
+    let mut merged = serde_json::to_value(options).unwrap();
+    let mut applied = Vec::new();
+
+    for provider in providers {
+        let provider_defaults = match defaults.get(provider).and_then(Value::as_object) {
+            Some(provider_defaults) => provider_defaults,
+            None => continue,
+        };
+
+        let mut used = false;
+        for (key, value) in provider_defaults {
+            if merged.get(key) == unset.get(key) {
+                merged[key] = value.clone();
+                used = true;
+            }
+        }
+        if used {
+            applied.push(provider.clone());
+        }
+    }
+
+    /* every default was checked to be valid build_options when loaded */
+    (serde_json::from_value(merged).unwrap(), applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn profiles() -> BTreeMap<String, Value> {
+        parse_profiles(r#"{
+            "staging": {
+                "environment": "staging",
+                "envs": ["NODE_ENV=staging", "LOG_LEVEL=debug"],
+                "build_options": { "tags": ["staging", "latest"], "no_cache": true }
+            },
+            "production": { "environment": "production" }
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn request_overrides_profile_tags() {
+        let request = json!({
+            "profile": "staging",
+            "path": "https://github.com/example/app",
+            "name": "app",
+            "build_options": { "tags": ["v1.2.3"] },
+        });
+
+        let merged = apply_profile(request, &profiles()).unwrap();
+
+        assert_eq!(merged["build_options"]["tags"], json!(["v1.2.3"]));
+        /* what the request left alone still comes from the profile */
+        assert_eq!(merged["build_options"]["no_cache"], json!(true));
+        assert_eq!(merged["environment"], json!("staging"));
+        assert_eq!(merged["name"], json!("app"));
+    }
+
+    #[test]
+    fn envs_are_merged_by_name() {
+        let request = json!({ "profile": "staging", "envs": ["LOG_LEVEL=info", "PORT=8080"] });
+
+        let merged = apply_profile(request, &profiles()).unwrap();
+
+        assert_eq!(merged["envs"], json!(["NODE_ENV=staging", "LOG_LEVEL=info", "PORT=8080"]));
+    }
+
+    #[test]
+    fn unknown_profile_lists_valid_ones() {
+        let error = apply_profile(json!({ "profile": "qa" }), &profiles()).unwrap_err();
+
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert!(error.message.contains("production, staging"), "{}", error.message);
+    }
+
+    #[test]
+    fn request_without_profile_is_unchanged() {
+        let request = json!({ "path": ".", "name": "app" });
+        assert_eq!(apply_profile(request.clone(), &profiles()).unwrap(), request);
+    }
+
+    #[test]
+    fn profiles_must_be_objects() {
+        assert!(parse_profiles(r#"{ "staging": ["not", "an", "object"] }"#).is_err());
+    }
+
+    fn provider_defaults() -> BTreeMap<String, Value> {
+        parse_provider_defaults(r#"{
+            "node": { "cache_key": "node-modules", "inline_cache": true },
+            "rust": { "cache_key": "cargo-target", "platform": ["linux/amd64"] }
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn node_defaults_are_applied() {
+        let (options, applied) = apply_provider_defaults(&DockerBuilderOptions::default(), &["node".to_string()], &provider_defaults());
+
+        assert_eq!(options.cache_key.as_deref(), Some("node-modules"));
+        assert!(options.inline_cache);
+        assert_eq!(applied, ["node"]);
+    }
+
+    #[test]
+    fn rust_defaults_dont_override_the_request() {
+        let requested = DockerBuilderOptions { cache_key: Some("mine".to_string()), ..Default::default() };
+        let (options, applied) = apply_provider_defaults(&requested, &["rust".to_string()], &provider_defaults());
+
+        assert_eq!(options.cache_key.as_deref(), Some("mine"));
+        assert_eq!(options.platform, ["linux/amd64"]);
+        assert_eq!(applied, ["rust"]);
+    }
+
+    #[test]
+    fn earlier_providers_win_and_unused_ones_arent_listed() {
+        let providers = ["node".to_string(), "rust".to_string(), "python".to_string()];
+        let (options, applied) = apply_provider_defaults(&DockerBuilderOptions::default(), &providers, &provider_defaults());
+
+        assert_eq!(options.cache_key.as_deref(), Some("node-modules"));
+        assert_eq!(applied, ["node", "rust"]);
+    }
+
+    #[test]
+    fn provider_defaults_cant_set_per_build_options() {
+        assert_eq!(parse_provider_defaults(r#"{ "node": { "name": "app" } }"#), Err("defaults for node can't set name".to_string()));
+        assert!(parse_provider_defaults(r#"{ "node": { "no_cache": "yes" } }"#).unwrap_err().contains("aren't valid build_options"));
+        assert!(parse_provider_defaults(r#"{ "node": true }"#).is_err());
+    }
+}
@@ -0,0 +1,610 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::{oneshot, Notify};
+use tracing::{error, info};
+
+use crate::engine::events::BuildEvent;
+use crate::engine::pipeline::{new_build_id, record_failure, record_status, recorded_options, run_build, stored_metadata, validate, BuildError, BuildInfo, BuildOutcome};
+use crate::engine::state::AppState;
+
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+type BuildResult = Result<BuildOutcome, BuildError>;
+
+pub struct QueuedBuild {
+    pub id: String,
+    pub build_info: BuildInfo,
+    pub queued_at: DateTime<Utc>,
+    pub priority: u8,
+    /* submission order, breaks ties between equal priorities */
+    seq: u64,
+    /* set by /admin/queue/{id}/promote, later promotions run first */
+    promoted: u64,
+    /* set when the submitter waits on the result */
+    done: Option<oneshot::Sender<BuildResult>>,
+    stop: Arc<StopHandle>,
+}
+
+/// Why a build was stopped before it finished.
+#[derive(Clone, Copy, Debug)]
+pub struct StopReason {
+    /* recorded as the build's status */
+    pub status: &'static str,
+    pub message: &'static str,
+    /* what a submitter still waiting is told */
+    pub error: &'static str,
+}
+
+pub const DRAINED: StopReason = StopReason {
+    status: INTERRUPTED,
+    message: "Aborted by /admin/drain",
+    error: "Build was interrupted, the server is draining",
+};
+
+pub const CLIENT_GONE: StopReason = StopReason {
+    status: "Cancelled (client gone)",
+    message: "The client disconnected before the build finished",
+    error: "Build was cancelled, the client disconnected",
+};
+
+/* lets a build be stopped from outside, whether it's still queued or running */
+struct StopHandle {
+    notify: Notify,
+    reason: Mutex<Option<StopReason>>,
+}
+
+impl StopHandle {
+    fn new() -> Self {
+        StopHandle {
+            notify: Notify::new(),
+            reason: Mutex::new(None),
+        }
+    }
+
+    fn stop(&self, reason: StopReason) {
+        self.reason.lock().unwrap().get_or_insert(reason);
+        self.notify.notify_one();
+    }
+
+    fn reason(&self) -> Option<StopReason> {
+        *self.reason.lock().unwrap()
+    }
+}
+
+/* promoted builds first, then higher priority, then first come first served */
+impl Ord for QueuedBuild {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.promoted.cmp(&other.promoted)
+            .then_with(|| self.priority.cmp(&other.priority))
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for QueuedBuild {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedBuild {
+    fn eq(&self, other: &Self) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for QueuedBuild {}
+
+/// A queued build as /admin/queue lists it.
+#[derive(Serialize, Debug)]
+pub struct QueueEntry {
+    pub id: String,
+    pub repo: String,
+    pub priority: u8,
+    pub promoted: bool,
+    pub queued_at: DateTime<Utc>,
+}
+
+/* what the queue keeps about a build while a worker is on it */
+struct RunningBuild {
+    repo: String,
+    stop: Arc<StopHandle>,
+}
+
+/// Builds waiting for a worker, highest priority first and in submission
+/// order within a priority. Only one build per repo runs at a time, a build
+/// whose repo is already running waits in the queue without taking a worker.
+pub struct BuildQueue {
+    entries: Mutex<BinaryHeap<QueuedBuild>>,
+    next_seq: AtomicU64,
+    next_promotion: AtomicU64,
+    notify: Notify,
+    /* builds a worker is on right now, stopped through their handle.
+    lock entries before running when both are needed */
+    running: Mutex<HashMap<String, RunningBuild>>,
+    /* cleared by /admin/drain until /admin/resume */
+    accepting: AtomicBool,
+}
+
+impl BuildQueue {
+    pub fn new() -> Self {
+        BuildQueue {
+            entries: Mutex::new(BinaryHeap::new()),
+            next_seq: AtomicU64::new(0),
+            next_promotion: AtomicU64::new(1),
+            notify: Notify::new(),
+            running: Mutex::new(HashMap::new()),
+            accepting: AtomicBool::new(true),
+        }
+    }
+
+    fn push(&self, mut build: QueuedBuild) {
+        build.seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        self.entries.lock().unwrap().push(build);
+        self.notify.notify_one();
+    }
+
+    /* the next build whose repo isn't running, marked running as it leaves the
+    queue so stop always finds it in one or the other */
+    fn take_next(&self) -> Option<QueuedBuild> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut running = self.running.lock().unwrap();
+
+        let mut waiting = Vec::new();
+        let mut next = None;
+        while let Some(build) = entries.pop() {
+            if running.values().any(|other| other.repo == build.build_info.path) {
+                waiting.push(build);
+            } else {
+                next = Some(build);
+                break;
+            }
+        }
+        entries.extend(waiting);
+
+        let build = next?;
+        running.insert(build.id.clone(), RunningBuild {
+            repo: build.build_info.path.clone(),
+            stop: Arc::clone(&build.stop),
+        });
+
+        /* another idle worker may be able to take one of the rest */
+        if !entries.is_empty() {
+            self.notify.notify_one();
+        }
+        Some(build)
+    }
+
+    async fn pop(&self) -> QueuedBuild {
+        loop {
+            if let Some(build) = self.take_next() {
+                return build;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /* a worker let go of the build, queued builds of its repo can run now */
+    fn finish(&self, id: &str) {
+        self.running.lock().unwrap().remove(id);
+        self.notify.notify_one();
+    }
+
+    /// Stops a queued or running build, the worker records `reason` once it
+    /// lets go of it. Does nothing for builds this process doesn't own.
+    pub fn stop(&self, id: &str, reason: StopReason) {
+        let entries = self.entries.lock().unwrap();
+        let running = self.running.lock().unwrap();
+
+        if let Some(build) = running.get(id) {
+            build.stop.stop(reason);
+        } else if let Some(build) = entries.iter().find(|build| build.id == id) {
+            build.stop.stop(reason);
+        }
+    }
+
+    /// Whether this process still owns the build, queued or running.
+    pub fn is_active(&self, id: &str) -> bool {
+        let queued = self.entries.lock().unwrap().iter().any(|build| build.id == id);
+        queued || self.running.lock().unwrap().contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// The queued builds in the order workers will pick them up.
+    pub fn entries(&self) -> Vec<QueueEntry> {
+        let entries = self.entries.lock().unwrap();
+        let mut builds: Vec<&QueuedBuild> = entries.iter().collect();
+        builds.sort_by(|a, b| b.cmp(a));
+
+        builds.into_iter()
+            .map(|build| QueueEntry {
+                id: build.id.clone(),
+                repo: build.build_info.path.clone(),
+                priority: build.priority,
+                promoted: build.promoted > 0,
+                queued_at: build.queued_at,
+            })
+            .collect()
+    }
+
+    /// Moves a queued build to the front, ahead of builds promoted before
+    /// it. `false` when it isn't queued (anymore).
+    pub fn promote(&self, id: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.iter().any(|build| build.id == id) {
+            return false;
+        }
+
+        /* a heap can't reorder one entry in place, rebuild it */
+        let mut builds = std::mem::take(&mut *entries).into_vec();
+        for build in builds.iter_mut().filter(|build| build.id == id) {
+            build.promoted = self.next_promotion.fetch_add(1, Ordering::SeqCst);
+        }
+        *entries = BinaryHeap::from(builds);
+        true
+    }
+
+    /// Takes a build out of the queue before a worker gets to it.
+    fn remove(&self, id: &str) -> Option<QueuedBuild> {
+        let mut entries = self.entries.lock().unwrap();
+        let (removed, kept): (Vec<QueuedBuild>, Vec<QueuedBuild>) = std::mem::take(&mut *entries)
+            .into_vec()
+            .into_iter()
+            .partition(|build| build.id == id);
+        *entries = BinaryHeap::from(kept);
+        removed.into_iter().next()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for BuildQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* matrix is the parent id and the variant's position in it */
+async fn enqueue(state: &AppState, build_info: BuildInfo, done: Option<oneshot::Sender<BuildResult>>, matrix: Option<(&str, usize)>) -> Result<String, BuildError> {
+    if !state.queue.is_accepting() {
+        return Err(BuildError::new(hyper::StatusCode::SERVICE_UNAVAILABLE, "Not accepting new builds, the server is draining"));
+    }
+
+    validate(state, &build_info)?;
+
+    let id = new_build_id();
+    let queued_at = Utc::now();
+
+    match sqlx::query("INSERT into build_data (id, repo, start_time, status, options, matrix_id, matrix_index, metadata) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
+        .bind(&id)
+        .bind(&build_info.path)
+        .bind(queued_at.to_rfc3339())
+        .bind("queued")
+        .bind(recorded_options(&build_info).to_string())
+        .bind(matrix.map(|(matrix_id, _)| matrix_id))
+        .bind(matrix.map(|(_, index)| index as i64))
+        .bind(stored_metadata(&build_info))
+        .execute(&state.db_pool)
+        .await {
+        Ok(_) => eprintln!("DB insert success"),
+        Err(e) => eprintln!("DB insert error: {}", e),
+    }
+    state.emit(BuildEvent::new(&id, &build_info.path, "queued"));
+
+    state.queue.push(QueuedBuild {
+        id: id.clone(),
+        priority: build_info.priority.unwrap_or(0),
+        build_info,
+        queued_at,
+        seq: 0,
+        promoted: 0,
+        done,
+        stop: Arc::new(StopHandle::new()),
+    });
+
+    Ok(id)
+}
+
+/// Queues a build and returns its id straight away.
+pub async fn submit(state: &AppState, build_info: BuildInfo) -> Result<String, BuildError> {
+    enqueue(state, build_info, None, None).await
+}
+
+/// Queues variant `index` of matrix `matrix_id` and returns its id.
+pub async fn submit_to_matrix(state: &AppState, build_info: BuildInfo, matrix_id: &str, index: usize) -> Result<String, BuildError> {
+    enqueue(state, build_info, None, Some((matrix_id, index))).await
+}
+
+/* stops the build if the waiting future is dropped, i.e. the client hung up */
+struct StopOnDrop<'a> {
+    queue: &'a BuildQueue,
+    id: String,
+    armed: bool,
+}
+
+impl Drop for StopOnDrop<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.queue.stop(&self.id, CLIENT_GONE);
+        }
+    }
+}
+
+/// Queues a build and waits for it to finish. With `cancel_on_disconnect`
+/// the build is cancelled if the caller stops waiting.
+pub async fn submit_and_wait(state: &AppState, build_info: BuildInfo) -> BuildResult {
+    let (tx, rx) = oneshot::channel();
+    let id = enqueue(state, build_info, Some(tx), None).await?;
+
+    let mut guard = StopOnDrop {
+        queue: &state.queue,
+        id,
+        armed: state.config().cancel_on_disconnect,
+    };
+    let result = rx.await.unwrap_or_else(|_| Err(BuildError::new(hyper::StatusCode::INTERNAL_SERVER_ERROR, "Build worker went away")));
+    guard.armed = false;
+
+    result
+}
+
+pub const CANCELLED: &str = "Cancelled";
+pub const INTERRUPTED: &str = "Interrupted";
+
+/// Stops taking new builds and cancels everything still queued, and with
+/// `abort_running` the builds workers are on too. Returns how many were
+/// cancelled and interrupted.
+pub async fn drain(state: &AppState, abort_running: bool) -> (usize, usize) {
+    state.queue.accepting.store(false, Ordering::SeqCst);
+
+    let queued: Vec<QueuedBuild> = state.queue.entries.lock().unwrap().drain().collect();
+    let cancelled = queued.len();
+
+    for build in queued {
+        record_status(state, &build.id, &build.build_info.path, CANCELLED, "Cancelled by /admin/drain").await;
+        if let Some(done) = build.done {
+            let _ = done.send(Err(BuildError::new(hyper::StatusCode::SERVICE_UNAVAILABLE, "Build was cancelled, the server is draining")));
+        }
+    }
+
+    let interrupted = if abort_running {
+        let running = state.queue.running.lock().unwrap();
+        for build in running.values() {
+            /* the worker records the build as interrupted once it stops */
+            build.stop.stop(DRAINED);
+        }
+        running.len()
+    } else {
+        0
+    };
+
+    (cancelled, interrupted)
+}
+
+/// Removes a build from the queue and records it as cancelled. `false`
+/// when it isn't queued, e.g. because a worker already started it.
+pub async fn dequeue(state: &AppState, id: &str) -> bool {
+    let build = match state.queue.remove(id) {
+        Some(build) => build,
+        None => return false,
+    };
+
+    record_status(state, &build.id, &build.build_info.path, CANCELLED, "Removed from the queue by /admin/queue").await;
+    if let Some(done) = build.done {
+        let _ = done.send(Err(BuildError::new(hyper::StatusCode::CONFLICT, "Build was removed from the queue")));
+    }
+    true
+}
+
+pub fn resume(state: &AppState) {
+    state.queue.accepting.store(true, Ordering::SeqCst);
+}
+
+async fn stopped(state: &AppState, id: &str, repo: &str, reason: StopReason) -> BuildResult {
+    info!("Build {} stopped: {}", id, reason.message);
+    record_status(state, id, repo, reason.status, reason.message).await;
+    Err(BuildError::new(hyper::StatusCode::SERVICE_UNAVAILABLE, reason.error))
+}
+
+/// Starts `count` workers, which is how many builds run at once.
+pub fn spawn_workers(state: Arc<AppState>, count: usize) {
+    for _ in 0..count {
+        let state = Arc::clone(&state);
+
+        tokio::spawn(async move {
+            loop {
+                let build = state.queue.pop().await;
+                let repo = build.build_info.path.clone();
+                let stop = Arc::clone(&build.stop);
+
+                let result = match stop.reason() {
+                    /* stopped while it was still queued */
+                    Some(reason) => stopped(&state, &build.id, &repo, reason).await,
+                    None => tokio::select! {
+                        result = run_build(&state, build.build_info, build.id.clone()) => {
+                            if let Err(e) = &result {
+                                error!("Build {} failed before it started: {}", build.id, e);
+                                record_failure(&state, &build.id, &repo, e).await;
+                            }
+                            result
+                        },
+                        _ = stop.notify.notified() => {
+                            let reason = stop.reason().unwrap_or(DRAINED);
+                            stopped(&state, &build.id, &repo, reason).await
+                        },
+                    },
+                };
+
+                state.queue.finish(&build.id);
+
+                if let Some(done) = build.done {
+                    let _ = done.send(result);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn build(id: &str, repo: &str, priority: u8) -> QueuedBuild {
+        let build_info: BuildInfo = serde_json::from_value(serde_json::json!({ "path": repo, "name": id })).unwrap();
+        QueuedBuild {
+            id: id.to_string(),
+            build_info,
+            queued_at: Utc::now(),
+            priority,
+            seq: 0,
+            promoted: 0,
+            done: None,
+            stop: Arc::new(StopHandle::new()),
+        }
+    }
+
+    fn next_id(queue: &BuildQueue) -> Option<String> {
+        queue.take_next().map(|build| build.id)
+    }
+
+    #[test]
+    fn same_repo_builds_do_not_overlap() {
+        let queue = BuildQueue::new();
+        queue.push(build("first", "repo", 0));
+        queue.push(build("second", "repo", 0));
+        queue.push(build("other", "other-repo", 0));
+
+        assert_eq!(next_id(&queue).as_deref(), Some("first"));
+        /* "second" waits for "first", a different repo still runs */
+        assert_eq!(next_id(&queue).as_deref(), Some("other"));
+        assert_eq!(next_id(&queue), None);
+        assert_eq!(queue.len(), 1);
+
+        queue.finish("first");
+        assert_eq!(next_id(&queue).as_deref(), Some("second"));
+    }
+
+    #[tokio::test]
+    async fn waiting_worker_takes_build_once_repo_is_free() {
+        let queue = Arc::new(BuildQueue::new());
+        queue.push(build("first", "repo", 0));
+        queue.push(build("second", "repo", 0));
+        assert_eq!(queue.pop().await.id, "first");
+
+        let waiting = {
+            let queue = Arc::clone(&queue);
+            tokio::spawn(async move { queue.pop().await.id })
+        };
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        queue.finish("first");
+        let id = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert_eq!(id, "second");
+    }
+
+    #[test]
+    fn higher_priority_first_then_submission_order() {
+        let queue = BuildQueue::new();
+        queue.push(build("a", "repo-a", 0));
+        queue.push(build("b", "repo-b", 5));
+        queue.push(build("c", "repo-c", 0));
+        queue.push(build("d", "repo-d", 5));
+
+        let order: Vec<String> = queue.entries().into_iter().map(|entry| entry.id).collect();
+        assert_eq!(order, ["b", "d", "a", "c"]);
+        assert_eq!(next_id(&queue).as_deref(), Some("b"));
+        assert_eq!(next_id(&queue).as_deref(), Some("d"));
+        assert_eq!(next_id(&queue).as_deref(), Some("a"));
+        assert_eq!(next_id(&queue).as_deref(), Some("c"));
+        assert_eq!(next_id(&queue), None);
+    }
+
+    fn stop_reason(queue: &BuildQueue, id: &str) -> Option<&'static str> {
+        let entries = queue.entries.lock().unwrap();
+        let running = queue.running.lock().unwrap();
+        let stop = entries.iter().find(|build| build.id == id).map(|build| &build.stop)
+            .or_else(|| running.get(id).map(|build| &build.stop));
+        stop.and_then(|stop| stop.reason()).map(|reason| reason.status)
+    }
+
+    #[test]
+    fn dropped_connection_stops_the_build() {
+        let queue = BuildQueue::new();
+        queue.push(build("running", "repo-a", 0));
+        queue.push(build("queued", "repo-b", 0));
+        assert_eq!(next_id(&queue).as_deref(), Some("running"));
+
+        /* the waiting future of each is dropped, as hyper does when the client hangs up */
+        drop(StopOnDrop { queue: &queue, id: "queued".to_string(), armed: true });
+        drop(StopOnDrop { queue: &queue, id: "running".to_string(), armed: true });
+
+        assert_eq!(stop_reason(&queue, "queued"), Some(CLIENT_GONE.status));
+        assert_eq!(stop_reason(&queue, "running"), Some(CLIENT_GONE.status));
+    }
+
+    #[test]
+    fn build_continues_without_cancel_on_disconnect() {
+        let queue = BuildQueue::new();
+        queue.push(build("running", "repo", 0));
+        queue.take_next();
+
+        drop(StopOnDrop { queue: &queue, id: "running".to_string(), armed: false });
+
+        assert_eq!(stop_reason(&queue, "running"), None);
+    }
+
+    #[test]
+    fn promoted_builds_run_first() {
+        let queue = BuildQueue::new();
+        queue.push(build("a", "repo-a", 9));
+        queue.push(build("b", "repo-b", 0));
+        queue.push(build("c", "repo-c", 0));
+
+        assert!(queue.promote("c"));
+        assert!(queue.promote("b"));
+        assert!(!queue.promote("missing"));
+
+        assert_eq!(next_id(&queue).as_deref(), Some("b"));
+        assert_eq!(next_id(&queue).as_deref(), Some("c"));
+        assert_eq!(next_id(&queue).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn remove_and_stop_queued_build() {
+        let queue = BuildQueue::new();
+        queue.push(build("a", "repo", 0));
+        queue.push(build("b", "repo", 0));
+
+        queue.stop("b", CLIENT_GONE);
+        assert!(queue.remove("a").is_some());
+        assert!(!queue.is_active("a"));
+
+        let stopped = queue.take_next().unwrap();
+        assert_eq!(stopped.id, "b");
+        assert_eq!(stopped.stop.reason().map(|reason| reason.status), Some(CLIENT_GONE.status));
+        assert!(queue.is_active("b"));
+    }
+
+    #[test]
+    fn running_builds_cant_be_removed() {
+        let queue = BuildQueue::new();
+        queue.push(build("a", "repo", 0));
+        queue.take_next();
+
+        assert!(queue.remove("a").is_none());
+        assert!(!queue.promote("a"));
+        assert!(queue.is_active("a"));
+    }
+}
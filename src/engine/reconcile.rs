@@ -0,0 +1,210 @@
+use chrono::{DateTime, Duration, Utc};
+use hyper::StatusCode;
+use serde::Serialize;
+use shiplift::Docker;
+use sqlx::Row;
+use tracing::{error, info, warn};
+
+use crate::build::registry;
+use crate::build::workdir;
+use crate::engine::events::BuildEvent;
+use crate::engine::state::AppState;
+
+use std::path::Path;
+use std::sync::Arc;
+
+pub const ORPHANED: &str = "Failed (orphaned)";
+
+/* heartbeats missed before a running build counts as dead */
+const MISSED_HEARTBEATS: i64 = 3;
+
+/// Marks builds stuck in a non-terminal state for longer than `older_than`
+/// that no worker of ours owns as orphaned, returning how many there were.
+/// A build that records heartbeats is stale once it missed a few of them,
+/// even if that's sooner.
+pub async fn reconcile_orphans(state: &AppState, older_than: Duration) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query("SELECT id, repo, start_time, last_heartbeat FROM build_data WHERE status IN ('queued', 'running', 'Pushing (queued)')")
+        .fetch_all(&state.db_pool)
+        .await?;
+
+    let cutoff = Utc::now() - older_than;
+    let heartbeat_interval = state.config().heartbeat_interval_secs as i64;
+    let heartbeat_cutoff = match heartbeat_interval {
+        0 => cutoff,
+        interval => cutoff.max(Utc::now() - Duration::seconds(interval * MISSED_HEARTBEATS)),
+    };
+    let mut orphaned = 0;
+
+    for row in rows {
+        let id: String = row.get("id");
+        let repo: Option<String> = row.get("repo");
+        let start_time: String = row.get("start_time");
+        let last_heartbeat: Option<String> = row.get("last_heartbeat");
+
+        /* rows with an unreadable start time can't be from a live build either */
+        let stale = match last_heartbeat.and_then(|beat| beat.parse::<DateTime<Utc>>().ok()) {
+            Some(last_heartbeat) => last_heartbeat < heartbeat_cutoff,
+            None => start_time.parse::<DateTime<Utc>>().map_or(true, |start_time| start_time < cutoff),
+        };
+        if !stale || state.queue.is_active(&id) {
+            continue;
+        }
+
+        let updated = sqlx::query("UPDATE build_data SET status = $1, end_time = $2, message = $3 WHERE id = $4 AND status IN ('queued', 'running', 'Pushing (queued)')")
+            .bind(ORPHANED)
+            .bind(Utc::now().to_rfc3339())
+            .bind("No worker owned this build anymore")
+            .bind(&id)
+            .execute(&state.db_pool)
+            .await?;
+
+        if updated.rows_affected() > 0 {
+            warn!("Marked build {} as orphaned", id);
+            state.emit(BuildEvent::new(&id, repo.as_deref().unwrap_or_default(), ORPHANED));
+            orphaned += 1;
+        }
+    }
+
+    info!("Reconciled {} orphaned builds", orphaned);
+    Ok(orphaned)
+}
+
+pub const EVICTED: &str = "Evicted";
+
+/// Where the image reconciler looks for built images.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageCheck {
+    /* the docker host forge builds on */
+    Local,
+    /* the registry the image was pushed to */
+    Registry,
+}
+
+impl ImageCheck {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "local" => Some(ImageCheck::Local),
+            "registry" => Some(ImageCheck::Registry),
+            _ => None,
+        }
+    }
+}
+
+async fn image_exists(state: &AppState, docker: &Docker, image: &str) -> Result<bool, String> {
+    match state.config().image_reconcile_check {
+        ImageCheck::Local => match docker.images().get(image).inspect().await {
+            Ok(_) => Ok(true),
+            Err(shiplift::Error::Fault { code, .. }) if code == StatusCode::NOT_FOUND => Ok(false),
+            Err(e) => Err(e.to_string()),
+        },
+        ImageCheck::Registry => registry::exists(state.image_builder.backend().cli(), image).await,
+    }
+}
+
+/// Marks completed builds whose image is gone (pruned locally, or deleted
+/// from the registry) as evicted. Returns how many were checked and evicted,
+/// images that couldn't be checked are left alone.
+pub async fn reconcile_images(state: &AppState) -> Result<(u64, u64), sqlx::Error> {
+    let rows = sqlx::query("SELECT id, repo, image FROM build_data WHERE status = 'Completed' AND image IS NOT NULL")
+        .fetch_all(&state.db_pool)
+        .await?;
+
+    let docker = Docker::new();
+    let (mut checked, mut evicted) = (0, 0);
+
+    for row in rows {
+        let id: String = row.get("id");
+        let repo: Option<String> = row.get("repo");
+        let image: String = row.get("image");
+
+        match image_exists(state, &docker, &image).await {
+            Ok(true) => {},
+            Ok(false) => {
+                let updated = sqlx::query("UPDATE build_data SET status = $1, message = $2 WHERE id = $3 AND status = 'Completed'")
+                    .bind(EVICTED)
+                    .bind(format!("Image {} no longer exists", image))
+                    .bind(&id)
+                    .execute(&state.db_pool)
+                    .await?;
+
+                if updated.rows_affected() > 0 {
+                    info!("Marked build {} as evicted, {} is gone", id, image);
+                    state.emit(BuildEvent::new(&id, repo.as_deref().unwrap_or_default(), EVICTED));
+                    evicted += 1;
+                }
+            },
+            Err(e) => {
+                warn!("Couldn't check image {} of build {}: {}", image, id, e);
+                continue;
+            },
+        }
+        checked += 1;
+    }
+
+    info!("Reconciled images: {} checked, {} evicted", checked, evicted);
+    Ok((checked, evicted))
+}
+
+/// Runs `reconcile_images` every `interval` for as long as forge runs.
+pub fn spawn_image_reconciler(state: Arc<AppState>, interval: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = reconcile_images(&state).await {
+                error!("Image reconciliation failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Removes working dirs crashed runs left under `WORKDIR_BASE`, and secret
+/// dirs they left on `/dev/shm`. Returns the bytes reclaimed.
+pub async fn sweep_workdirs(state: &AppState) -> u64 {
+    let config = state.config();
+    let min_age = std::time::Duration::from_secs(config.workdir_sweep_min_age_secs);
+    let mut bases = vec![config.workdir_base.clone()];
+    if Path::new("/dev/shm").is_dir() {
+        bases.push("/dev/shm".into());
+    }
+
+    let swept = tokio::task::spawn_blocking(move || {
+        let (mut removed, mut reclaimed) = (0, 0);
+        for base in bases {
+            match workdir::sweep(&base, min_age) {
+                Ok((dirs, bytes)) => {
+                    removed += dirs;
+                    reclaimed += bytes;
+                },
+                Err(e) => warn!("Failed to sweep {}: {}", base.display(), e),
+            }
+        }
+        (removed, reclaimed)
+    })
+    .await;
+
+    match swept {
+        Ok((removed, reclaimed)) => {
+            info!("Swept {} leftover working dirs, reclaimed {} MB", removed, reclaimed / 1024 / 1024);
+            reclaimed
+        },
+        Err(e) => {
+            error!("Working dir sweep failed: {}", e);
+            0
+        }
+    }
+}
+
+/// Runs `sweep_workdirs` every `interval` for as long as forge runs.
+pub fn spawn_workdir_sweeper(state: Arc<AppState>, interval: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        /* the first tick is immediate, startup already swept */
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            sweep_workdirs(&state).await;
+        }
+    });
+}
@@ -0,0 +1,155 @@
+use futures::future::join_all;
+use hyper::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::build::registry;
+use crate::engine::pipeline::{build_info_from_value, validate, BuildError, BuildInfo};
+use crate::engine::profiles::layer;
+use crate::engine::queue::submit_and_wait;
+use crate::engine::state::AppState;
+
+/// A `/build/refs` body: `base` built once per branch or tag in `refs`.
+#[derive(Deserialize)]
+pub struct RefsRequest {
+    pub base: Value,
+    pub refs: Vec<String>,
+}
+
+/// `git_ref` as an image tag, characters a tag can't have become `-`.
+fn ref_tag(git_ref: &str) -> String {
+    let tag: String = git_ref.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') { c } else { '-' })
+        .take(128)
+        .collect();
+
+    /* a tag can't start with a separator */
+    match tag.strip_prefix(|c: char| c == '.' || c == '-') {
+        Some(rest) => format!("_{}", rest),
+        None => tag,
+    }
+}
+
+/// The build of every ref, each tagged with its ref instead of the tag of
+/// `base`'s name. Nothing is built unless all of them are valid, a bad ref
+/// is reported by name.
+pub fn expand(request: RefsRequest, state: &AppState) -> Result<Vec<(String, BuildInfo)>, BuildError> {
+    let config = state.config();
+
+    let name = match request.base.get("name").and_then(Value::as_str) {
+        Some(name) => registry::repository_of(name).to_string(),
+        None => return Err(BuildError::new(StatusCode::BAD_REQUEST, "base must be an object with a name")),
+    };
+    if request.refs.is_empty() {
+        return Err(BuildError::new(StatusCode::BAD_REQUEST, "refs must not be empty"));
+    }
+
+    let mut builds: Vec<(String, BuildInfo)> = Vec::with_capacity(request.refs.len());
+    for git_ref in request.refs {
+        let in_ref = |e: BuildError| BuildError::new(e.status, format!("ref {}: {}", git_ref, e.message));
+
+        if git_ref.is_empty() || builds.iter().any(|(built, _)| *built == git_ref) {
+            return Err(in_ref(BuildError::new(StatusCode::BAD_REQUEST, "refs must be unique and not empty")));
+        }
+
+        let overrides = json!({ "ref": git_ref, "name": format!("{}:{}", name, ref_tag(&git_ref)) });
+        let build_info = build_info_from_value(layer(&request.base, overrides), &config).map_err(in_ref)?;
+        validate(state, &build_info).map_err(in_ref)?;
+        builds.push((git_ref, build_info));
+    }
+
+    Ok(builds)
+}
+
+/// Builds every ref side by side and waits for all of them. A failing ref
+/// doesn't stop the others, each is reported under its name along with
+/// whether they all succeeded.
+pub async fn build_refs(state: &AppState, builds: Vec<(String, BuildInfo)>) -> (bool, Map<String, Value>) {
+    let (refs, builds): (Vec<String>, Vec<BuildInfo>) = builds.into_iter().unzip();
+    let outcomes = join_all(builds.into_iter().map(|build_info| submit_and_wait(state, build_info))).await;
+
+    let mut succeeded = true;
+    let mut results = Map::new();
+    for (git_ref, outcome) in refs.into_iter().zip(outcomes) {
+        let result = match outcome {
+            Ok(outcome) => {
+                succeeded &= outcome.succeeded();
+                json!(outcome)
+            },
+            Err(e) => {
+                succeeded = false;
+                json!({ "status": e.status.as_u16(), "error": e.message })
+            }
+        };
+        results.insert(git_ref, result);
+    }
+
+    (succeeded, results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::build::git::{self, tests::commit};
+    use crate::config::config::tests::config;
+    use crate::engine::state::tests::state;
+
+    use git2::Repository;
+
+    /* a repo with app.txt "1" tagged v1 and app.txt "2" on branch release */
+    fn fixture(dir: &std::path::Path) -> String {
+        let repo = Repository::init(dir.join("origin")).unwrap();
+        let first = commit(&repo, &[("app.txt", "1")]);
+        repo.tag_lightweight("v1", &repo.find_object(first, None).unwrap(), false).unwrap();
+        let second = commit(&repo, &[("app.txt", "2")]);
+        repo.branch("release", &repo.find_commit(second).unwrap(), false).unwrap();
+
+        format!("file://{}", dir.join("origin").display())
+    }
+
+    #[tokio::test]
+    async fn every_ref_is_built_under_its_own_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = fixture(dir.path());
+        let state = state(config());
+
+        let request = RefsRequest {
+            base: json!({ "path": url, "name": "registry.example.com/app:latest" }),
+            refs: vec!["v1".to_string(), "release".to_string()],
+        };
+        let builds = expand(request, &state).unwrap();
+
+        let names: Vec<(&str, &str)> = builds.iter().map(|(git_ref, build_info)| (git_ref.as_str(), build_info.name.as_str())).collect();
+        assert_eq!(names, [("v1", "registry.example.com/app:v1"), ("release", "registry.example.com/app:release")]);
+
+        /* each build checks out its own ref of the one repo */
+        for ((git_ref, build_info), contents) in builds.iter().zip(["1", "2"]) {
+            assert_eq!(build_info.git_ref.as_deref(), Some(git_ref.as_str()));
+
+            let checkout = dir.path().join(git_ref);
+            let repo = git::clone(&build_info.path, checkout.to_str().unwrap()).unwrap();
+            git::checkout_ref(&repo, git_ref).unwrap();
+            assert_eq!(std::fs::read_to_string(checkout.join("app.txt")).unwrap(), contents);
+        }
+    }
+
+    #[tokio::test]
+    async fn bad_refs_are_reported_by_name() {
+        let state = state(config());
+        let request = |refs: &[&str]| RefsRequest {
+            base: json!({ "path": "https://github.com/example/app", "name": "app" }),
+            refs: refs.iter().map(|git_ref| git_ref.to_string()).collect(),
+        };
+
+        assert_eq!(expand(request(&[]), &state).unwrap_err().message, "refs must not be empty");
+        assert_eq!(expand(request(&["main", "main"]), &state).unwrap_err().message, "ref main: refs must be unique and not empty");
+    }
+
+    #[test]
+    fn refs_are_made_into_tags() {
+        assert_eq!(ref_tag("feature/login"), "feature-login");
+        assert_eq!(ref_tag("v1.2.3"), "v1.2.3");
+        assert_eq!(ref_tag("-rc"), "_rc");
+    }
+}
@@ -0,0 +1,105 @@
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+use crate::config::config::Config;
+use crate::engine::queue::spawn_workers;
+use crate::engine::state::AppState;
+
+use std::sync::Arc;
+
+/// Re-reads `.env` and the environment and swaps the new config in for
+/// requests and builds from now on, those in flight keep the one they
+/// started with. Settings only read at startup keep their running values.
+pub fn reload(state: &Arc<AppState>) -> Result<(), String> {
+    /* dotenv() never overrides, values that changed in .env have to be set again.
+    dotenv_iter is deprecated, but it's still the only way dotenv 0.15 has to read
+    .env without leaving the values already set alone */
+    #[allow(deprecated)]
+    let vars = dotenv::dotenv_iter();
+    if let Ok(vars) = vars {
+        for (key, value) in vars.flatten() {
+            std::env::set_var(key, value);
+        }
+    }
+
+    let running = state.config();
+    let mut config = Config::from_env()?;
+
+    for field in config.keep_startup_settings(&running) {
+        warn!("{} can't change at runtime, restart forge to apply it", field);
+    }
+
+    /* more workers can be started, stopping some needs a restart */
+    if config.max_concurrent_builds > running.max_concurrent_builds {
+        spawn_workers(Arc::clone(state), config.max_concurrent_builds - running.max_concurrent_builds);
+    } else if config.max_concurrent_builds < running.max_concurrent_builds {
+        warn!("max_concurrent_builds can only grow at runtime, restart forge to lower it");
+        config.max_concurrent_builds = running.max_concurrent_builds;
+    }
+
+    state.set_config(config);
+    Ok(())
+}
+
+/// Reloads the config on every SIGHUP.
+pub fn reload_on_sighup(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => return error!("Can't listen for SIGHUP, config reload is off: {}", e),
+        };
+
+        while hangups.recv().await.is_some() {
+            match reload(&state) {
+                Ok(()) => info!("Reloaded config"),
+                Err(e) => error!("Config reload failed, keeping the running config: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::config::tests::{config, lock_env};
+    use crate::engine::state::tests::state;
+
+    use std::time::Duration;
+
+    /* the lock keeps other tests from reading the environment while it's changed, they run on their own threads */
+    #[allow(clippy::await_holding_lock)]
+    #[tokio::test]
+    async fn sighup_mutates_config_and_signals_reload() {
+        let state = state(config());
+        let _env = lock_env();
+        std::env::set_var("MAX_UPLOAD_MB", "100");
+        reload(&state).unwrap();
+        assert_eq!(state.config().max_upload_mb, 100);
+
+        reload_on_sighup(Arc::clone(&state));
+        /* let the listener register before the signal is sent */
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        std::env::set_var("MAX_UPLOAD_MB", "200");
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.config().max_upload_mb != 200 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("config wasn't reloaded");
+
+        /* a config that doesn't load leaves the running one in place */
+        std::env::set_var("MAX_UPLOAD_MB", "lots");
+        assert_eq!(reload(&state), Err("MAX_UPLOAD_MB must be a number (got lots)".to_string()));
+        assert_eq!(state.config().max_upload_mb, 200);
+        std::env::remove_var("MAX_UPLOAD_MB");
+    }
+}
@@ -0,0 +1,88 @@
+use sqlx::PgPool;
+use tokio::sync::broadcast;
+
+use crate::build::backend::{image_builder, ImageBuilder};
+use crate::build::clone_cache::CloneCache;
+use crate::build::lock::KeyedLock;
+use crate::build::plan_cache::PlanCache;
+use crate::build::registry::PushLimiter;
+use crate::config::config::Config;
+use crate::engine::events::BuildEvent;
+use crate::engine::queue::BuildQueue;
+use crate::logs::checkpoint::Checkpoints;
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// State shared by every request handler, HTTP or gRPC.
+pub struct AppState {
+    /* swapped wholesale on reload, read through config() */
+    config: RwLock<Arc<Config>>,
+    pub db_pool: PgPool,
+    /* shared across builds so each base image is only pulled once at a time */
+    pub pull_locks: KeyedLock,
+    /* MAX_CONCURRENT_PUSHES, fixed at startup */
+    pub pushes: PushLimiter,
+    /* set when CLONE_CACHE_DIR is */
+    pub clone_cache: Option<CloneCache>,
+    /* plans /plan generated, for a /build of the same commit */
+    pub plans: PlanCache,
+    /* set when LOG_RESUME is, fixed at startup */
+    pub log_checkpoints: Option<Checkpoints>,
+    /* picked by BUILD_BACKEND */
+    pub image_builder: Box<dyn ImageBuilder>,
+    pub events: broadcast::Sender<BuildEvent>,
+    pub queue: BuildQueue,
+}
+
+impl AppState {
+    pub fn new(config: Config, db_pool: PgPool) -> Self {
+        let (events, _) = broadcast::channel(100);
+        let clone_cache = config.clone_cache_dir.clone()
+            .map(|dir| CloneCache::new(dir, config.clone_cache_max_mb));
+        let image_builder = image_builder(config.build_backend);
+        let pushes = PushLimiter::new(config.max_concurrent_pushes);
+        let plans = PlanCache::new(Duration::from_secs(config.plan_cache_ttl_secs), config.plan_cache_max_entries);
+        let log_checkpoints = config.log_resume.then(|| Checkpoints::new(db_pool.clone()));
+
+        AppState {
+            config: RwLock::new(Arc::new(config)),
+            db_pool,
+            pull_locks: KeyedLock::new(),
+            pushes,
+            clone_cache,
+            plans,
+            log_checkpoints,
+            image_builder,
+            events,
+            queue: BuildQueue::new(),
+        }
+    }
+
+    /// The current config. Hold on to it for as long as one request or build
+    /// should see the same settings.
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    pub fn set_config(&self, config: Config) {
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    pub fn emit(&self, event: BuildEvent) {
+        /* nobody listening is fine */
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// State around `config` whose database is never connected to unless a
+    /// test queries it.
+    pub(crate) fn state(config: Config) -> Arc<AppState> {
+        let pool = config.db.pool_options().connect_lazy(&config.db.url).unwrap();
+        Arc::new(AppState::new(config, pool))
+    }
+}
@@ -0,0 +1,73 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::Row;
+
+use crate::engine::events::is_terminal;
+use crate::engine::pipeline::TAG_EXISTS;
+use crate::engine::state::AppState;
+
+use std::collections::BTreeMap;
+
+/// Aggregates over the builds started since `since`.
+#[derive(Serialize, Debug)]
+pub struct BuildStats {
+    pub since: DateTime<Utc>,
+    pub total: i64,
+    pub by_status: BTreeMap<String, i64>,
+    /* successful share of the finished builds, none while nothing finished */
+    pub success_rate: Option<f64>,
+    /* seconds, over completed builds */
+    pub duration: DurationStats,
+    /* yyyy-mm-dd -> builds started that day */
+    pub per_day: BTreeMap<String, i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DurationStats {
+    pub avg: Option<f64>,
+    pub p50: Option<f64>,
+    pub p90: Option<f64>,
+    pub p99: Option<f64>,
+}
+
+/* start_time is rfc3339 in utc, so comparing and slicing the strings is index friendly */
+pub async fn build_stats(state: &AppState, since: DateTime<Utc>) -> Result<BuildStats, sqlx::Error> {
+    let since_str = since.to_rfc3339();
+
+    let rows = sqlx::query("SELECT status, count(*) AS builds FROM build_data WHERE start_time >= $1 GROUP BY status")
+        .bind(&since_str)
+        .fetch_all(&state.db_pool)
+        .await?;
+    let by_status: BTreeMap<String, i64> = rows.iter().map(|row| (row.get("status"), row.get("builds"))).collect();
+
+    let total = by_status.values().sum();
+    let finished: i64 = by_status.iter().filter(|(status, _)| is_terminal(status)).map(|(_, builds)| builds).sum();
+    let succeeded: i64 = ["Completed", "Skipped", "Warmed", TAG_EXISTS].iter().filter_map(|status| by_status.get(*status)).sum();
+    let success_rate = (finished > 0).then(|| succeeded as f64 / finished as f64);
+
+    let row = sqlx::query(
+        "SELECT avg(secs) AS avg, \
+            percentile_cont(0.5) WITHIN GROUP (ORDER BY secs) AS p50, \
+            percentile_cont(0.9) WITHIN GROUP (ORDER BY secs) AS p90, \
+            percentile_cont(0.99) WITHIN GROUP (ORDER BY secs) AS p99 \
+        FROM (SELECT EXTRACT(EPOCH FROM (end_time::TIMESTAMPTZ - start_time::TIMESTAMPTZ))::FLOAT8 AS secs \
+            FROM build_data WHERE status = 'Completed' AND start_time >= $1 AND end_time IS NOT NULL) AS durations",
+    )
+        .bind(&since_str)
+        .fetch_one(&state.db_pool)
+        .await?;
+    let duration = DurationStats {
+        avg: row.get::<Option<f64>, _>("avg"),
+        p50: row.get::<Option<f64>, _>("p50"),
+        p90: row.get::<Option<f64>, _>("p90"),
+        p99: row.get::<Option<f64>, _>("p99"),
+    };
+
+    let rows = sqlx::query("SELECT substr(start_time, 1, 10) AS day, count(*) AS builds FROM build_data WHERE start_time >= $1 GROUP BY day")
+        .bind(&since_str)
+        .fetch_all(&state.db_pool)
+        .await?;
+    let per_day = rows.iter().map(|row| (row.get("day"), row.get("builds"))).collect();
+
+    Ok(BuildStats { since, total, by_status, success_rate, duration, per_day })
+}
@@ -0,0 +1,156 @@
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use chrono::{DateTime, Utc};
+use hyper::StatusCode;
+use tracing::error;
+
+use crate::engine::pipeline::{get_status, parse_build_info, BuildError};
+use crate::engine::queue::submit_and_wait;
+use crate::engine::state::AppState;
+use crate::logs::logs::{get_logs, resolve_container, ContainerError, LogFilter};
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub mod proto {
+    tonic::include_proto!("forge");
+}
+
+use proto::forge_server::{Forge, ForgeServer};
+use proto::{
+    BuildEvent, BuildStatus, GetStatusRequest, LogLine, StreamBuildEventsRequest, StreamLogsRequest,
+    SubmitBuildRequest, SubmitBuildResponse,
+};
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+pub struct ForgeService {
+    state: Arc<AppState>,
+}
+
+fn to_status(e: BuildError) -> Status {
+    match e.status {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(e.message),
+        StatusCode::UNPROCESSABLE_ENTITY => Status::failed_precondition(e.message),
+        _ => Status::internal(e.message),
+    }
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>, Status> {
+    value.parse::<DateTime<Utc>>().map_err(|e| Status::invalid_argument(format!("Invalid time {}: {}", value, e)))
+}
+
+#[tonic::async_trait]
+impl Forge for ForgeService {
+    async fn submit_build(&self, request: Request<SubmitBuildRequest>) -> Result<Response<SubmitBuildResponse>, Status> {
+        let build_info = parse_build_info(request.into_inner().build_info_json.as_bytes(), &self.state.config())
+            .map_err(to_status)?;
+
+        let outcome = submit_and_wait(&self.state, build_info).await.map_err(to_status)?;
+
+        Ok(Response::new(SubmitBuildResponse {
+            plan_json: serde_json::to_string(&outcome.plan).unwrap(),
+            timings_json: serde_json::to_string(&outcome.timings).unwrap(),
+            id: outcome.id,
+            status: outcome.status,
+            message: outcome.message,
+        }))
+    }
+
+    async fn get_status(&self, request: Request<GetStatusRequest>) -> Result<Response<BuildStatus>, Status> {
+        let id = request.into_inner().id;
+
+        match get_status(&self.state, &id).await {
+            Ok(Some(record)) => Ok(Response::new(BuildStatus {
+                id: record.id,
+                status: record.status,
+                start_time: record.start_time,
+                end_time: record.end_time,
+                plan_json: record.plan.map(|plan| plan.to_string()),
+                timings_json: record.timings.map(|timings| serde_json::to_string(&timings).unwrap()),
+                priority: record.priority.map(u32::from),
+                last_heartbeat: record.last_heartbeat,
+                phase: record.phase,
+                nixpacks_version: record.nixpacks_version,
+            })),
+            Ok(None) => Err(Status::not_found(format!("No build with id {}", id))),
+            Err(e) => {
+                error!("Error looking up build {}: {}", id, e);
+                Err(Status::unavailable("Failed to look up build"))
+            }
+        }
+    }
+
+    type StreamLogsStream = ResponseStream<LogLine>;
+
+    async fn stream_logs(&self, request: Request<StreamLogsRequest>) -> Result<Response<Self::StreamLogsStream>, Status> {
+        let request = request.into_inner();
+        let filter = LogFilter {
+            start_time: parse_time(&request.start_time)?,
+            end_time: parse_time(&request.end_time)?,
+            follow: request.follow,
+            tail: request.tail.clone(),
+            min_level: None,
+        };
+
+        let container_id = match resolve_container(&request.container_id).await {
+            Ok(container_id) => container_id,
+            Err(ContainerError::NotFound) => return Err(Status::not_found(format!("No such container: {}", request.container_id))),
+            Err(ContainerError::Ambiguous(candidates)) => return Err(Status::not_found(format!(
+                "Ambiguous container {}, candidates: {}", request.container_id, candidates.join(", "),
+            ))),
+            Err(e) => {
+                error!("Can't collect logs for {}: {}", request.container_id, e);
+                return Err(Status::unavailable("Docker is unavailable, logs can't be collected"));
+            }
+        };
+
+        let (tx, rx) = broadcast::channel(100);
+        let log_config = self.state.config().logs.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = get_logs(&container_id, filter, tx, &log_config, futures::future::pending(), None).await {
+                error!("Error getting logs for {}: {}", container_id, e);
+            }
+        });
+
+        /* lagging receivers skip what they missed rather than ending the stream */
+        let stream = BroadcastStream::new(rx).filter_map(|message| message.ok()).map(|message| Ok(LogLine {
+            source: message.source,
+            timestamp: message.timestamp.to_rfc3339(),
+            text: message.text,
+            marker: message.marker,
+        }));
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type StreamBuildEventsStream = ResponseStream<BuildEvent>;
+
+    async fn stream_build_events(&self, request: Request<StreamBuildEventsRequest>) -> Result<Response<Self::StreamBuildEventsStream>, Status> {
+        let repo = request.into_inner().repo;
+
+        let stream = BroadcastStream::new(self.state.events.subscribe())
+            .filter_map(|event| event.ok())
+            .filter(move |event| repo.as_ref().map_or(true, |repo| &event.repo == repo))
+            .map(|event| Ok(BuildEvent {
+                id: event.id,
+                repo: event.repo,
+                status: event.status,
+                timestamp: event.timestamp.to_rfc3339(),
+            }));
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+pub async fn serve(state: Arc<AppState>, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+    Server::builder()
+        .add_service(ForgeServer::new(ForgeService { state }))
+        .serve(addr)
+        .await
+}
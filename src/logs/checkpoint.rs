@@ -0,0 +1,211 @@
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Row};
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+use crate::engine::events::{build_finished, is_terminal};
+use crate::engine::pipeline::get_status;
+use crate::engine::state::AppState;
+use crate::logs::level::Level;
+use crate::logs::logs::{get_logs, LogFilter};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/* how often a collection's progress is written, a restart replays at most this much */
+pub const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A followed collection as kept in `log_collections`, one per container.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub container_id: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    /* the last line sent on, none before the first */
+    pub last_seen: Option<DateTime<Utc>>,
+    /* the build the collection stops with */
+    pub build_id: Option<String>,
+    pub log_topic: String,
+    pub log_table: String,
+    pub min_level: Option<Level>,
+}
+
+/// Where followed collections record how far they got, so they pick up from
+/// there after a restart. Only with `LOG_RESUME`.
+#[derive(Clone)]
+pub struct Checkpoints {
+    pool: PgPool,
+}
+
+impl Checkpoints {
+    pub fn new(pool: PgPool) -> Self {
+        Checkpoints { pool }
+    }
+
+    /// Records a collection that's starting, replacing an earlier one of the
+    /// same container.
+    pub async fn start(&self, checkpoint: &Checkpoint) {
+        let stored = sqlx::query("UPSERT INTO log_collections (container_id, start_time, end_time, last_seen, build_id, log_topic, log_table, min_level) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
+            .bind(&checkpoint.container_id)
+            .bind(checkpoint.start_time.to_rfc3339())
+            .bind(checkpoint.end_time.to_rfc3339())
+            .bind(checkpoint.last_seen.map(|last_seen| last_seen.to_rfc3339()))
+            .bind(&checkpoint.build_id)
+            .bind(&checkpoint.log_topic)
+            .bind(&checkpoint.log_table)
+            .bind(checkpoint.min_level.map(|level| level.name()))
+            .execute(&self.pool)
+            .await;
+
+        if let Err(e) = stored {
+            warn!("Failed to record the log collection of {}, it won't resume after a restart: {}", checkpoint.container_id, e);
+        }
+    }
+
+    pub async fn advance(&self, container_id: &str, last_seen: DateTime<Utc>) {
+        let stored = sqlx::query("UPDATE log_collections SET last_seen = $1 WHERE container_id = $2")
+            .bind(last_seen.to_rfc3339())
+            .bind(container_id)
+            .execute(&self.pool)
+            .await;
+
+        if let Err(e) = stored {
+            warn!("Failed to record how far the logs of {} were collected: {}", container_id, e);
+        }
+    }
+
+    /// Forgets a collection that's done, whichever way it ended.
+    pub async fn finish(&self, container_id: &str) {
+        if let Err(e) = sqlx::query("DELETE FROM log_collections WHERE container_id = $1").bind(container_id).execute(&self.pool).await {
+            warn!("Failed to remove the log collection of {}: {}", container_id, e);
+        }
+    }
+
+    async fn active(&self) -> Result<Vec<Checkpoint>, sqlx::Error> {
+        let rows = sqlx::query("SELECT container_id, start_time, end_time, last_seen, build_id, log_topic, log_table, min_level FROM log_collections")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let parse = |value: String| DateTime::parse_from_rfc3339(&value).map(|time| time.with_timezone(&Utc)).ok();
+        Ok(rows.into_iter()
+            .filter_map(|row| Some(Checkpoint {
+                container_id: row.get("container_id"),
+                start_time: parse(row.get("start_time"))?,
+                end_time: parse(row.get("end_time"))?,
+                last_seen: row.get::<Option<String>, _>("last_seen").and_then(parse),
+                build_id: row.get("build_id"),
+                log_topic: row.get("log_topic"),
+                log_table: row.get("log_table"),
+                min_level: row.get::<Option<String>, _>("min_level").and_then(|level| Level::parse(&level)),
+            }))
+            .collect())
+    }
+}
+
+/// The filter a collection resumes with, following from just after the last
+/// line it sent on.
+fn resume_filter(checkpoint: &Checkpoint) -> LogFilter {
+    LogFilter {
+        /* the line at last_seen was already sent */
+        start_time: checkpoint.last_seen.map_or(checkpoint.start_time, |last_seen| last_seen + chrono::Duration::nanoseconds(1)),
+        end_time: checkpoint.end_time,
+        follow: true,
+        tail: None,
+        min_level: checkpoint.min_level,
+    }
+}
+
+/// Restarts the collections that were running when forge stopped, each from
+/// just after the last line it sent on. There's no live subscriber anymore,
+/// so the lines only go to the sinks.
+pub async fn resume_collections(state: Arc<AppState>) {
+    let checkpoints = match &state.log_checkpoints {
+        Some(checkpoints) => checkpoints.clone(),
+        None => return,
+    };
+
+    let active = match checkpoints.active().await {
+        Ok(active) => active,
+        Err(e) => {
+            error!("Failed to read the log collections to resume: {}", e);
+            return;
+        }
+    };
+
+    for checkpoint in active {
+        if checkpoint.end_time <= Utc::now() {
+            checkpoints.finish(&checkpoint.container_id).await;
+            continue;
+        }
+
+        let config = state.config();
+        let log_config = config.logs.with_destinations(Some(&checkpoint.log_topic), Some(&checkpoint.log_table))
+            .unwrap_or_else(|e| {
+                warn!("Resuming the logs of {} with the default destinations: {}", checkpoint.container_id, e);
+                config.logs.clone()
+            });
+
+        let mut filter = resume_filter(&checkpoint);
+
+        /* subscribed before the status is read, as /logs does */
+        let events = state.events.subscribe();
+        if let Some(build_id) = &checkpoint.build_id {
+            if let Ok(Some(record)) = get_status(&state, build_id).await {
+                if is_terminal(&record.status) {
+                    filter.follow = false;
+                }
+            }
+        }
+        let build_id = checkpoint.build_id.clone();
+        let stop = async move {
+            match build_id {
+                Some(build_id) => build_finished(events, build_id).await,
+                None => futures::future::pending().await,
+            }
+        };
+
+        info!("Resuming log collection of {} from {}", checkpoint.container_id, filter.start_time);
+        let checkpoints = checkpoints.clone();
+        tokio::spawn(async move {
+            let (tx, _) = broadcast::channel(100);
+            if let Err(e) = get_logs(&checkpoint.container_id, filter, tx, &log_config, stop, Some(&checkpoints)).await {
+                error!("Error getting logs for {}: {}", checkpoint.container_id, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(last_seen: Option<DateTime<Utc>>) -> Checkpoint {
+        Checkpoint {
+            container_id: "abc123".to_string(),
+            start_time: "2026-10-15T10:00:00Z".parse().unwrap(),
+            end_time: "2026-10-15T12:00:00Z".parse().unwrap(),
+            last_seen,
+            build_id: None,
+            log_topic: "logs".to_string(),
+            log_table: "logs".to_string(),
+            min_level: Some(Level::Warn),
+        }
+    }
+
+    #[test]
+    fn collection_resumes_after_the_stored_timestamp() {
+        let last_seen: DateTime<Utc> = "2026-10-15T11:30:00Z".parse().unwrap();
+        let filter = resume_filter(&checkpoint(Some(last_seen)));
+
+        assert_eq!(filter.start_time, last_seen + chrono::Duration::nanoseconds(1));
+        assert_eq!(filter.end_time, checkpoint(None).end_time);
+        assert!(filter.follow);
+        assert_eq!(filter.min_level, Some(Level::Warn));
+    }
+
+    #[test]
+    fn collection_without_lines_resumes_from_its_start() {
+        let filter = resume_filter(&checkpoint(None));
+        assert_eq!(filter.start_time, checkpoint(None).start_time);
+    }
+}
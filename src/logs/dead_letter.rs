@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::build::disk::dir_size;
+use crate::engine::state::AppState;
+use crate::logs::logs::{LogConfig, LogMessage};
+use crate::logs::sink::{new_sink, SinkKind};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where log batches a sink failed to write are kept until it's back.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DeadLetterConfig {
+    /* off when unset, failed batches are only logged */
+    pub dir: Option<PathBuf>,
+    /* batches that would grow the dir past this are dropped */
+    pub max_mb: u64,
+    pub retry_interval_secs: u64,
+}
+
+/// A batch one sink failed to write, with what it takes to write it again.
+#[derive(Serialize, Deserialize, Debug)]
+struct DeadLetter {
+    sink: String,
+    container_id: String,
+    log_topic: String,
+    log_table: String,
+    messages: Vec<LogMessage>,
+}
+
+/* set once a batch was dropped for lack of room, so it's logged once per fill */
+static FULL: AtomicBool = AtomicBool::new(false);
+
+/// Writes `messages` that `kind` failed to write to the dead-letter dir.
+/// Returns whether they were kept.
+pub fn store(config: &LogConfig, kind: SinkKind, container_id: &str, messages: &[LogMessage]) -> bool {
+    let dir = match &config.dead_letter.dir {
+        Some(dir) => dir,
+        None => return false,
+    };
+
+    let letter = DeadLetter {
+        sink: kind.name().to_string(),
+        container_id: container_id.to_string(),
+        log_topic: config.kafka.topic.clone(),
+        log_table: config.clickhouse.table.clone(),
+        messages: messages.to_vec(),
+    };
+    /* LogMessage always serializes */
+    let contents = serde_json::to_vec(&letter).unwrap();
+
+    if dir_size(dir) + contents.len() as u64 > config.dead_letter.max_mb * 1024 * 1024 {
+        if !FULL.swap(true, Ordering::Relaxed) {
+            error!("Log dead-letter dir {} is full ({} MB), dropping log lines until it drains", dir.display(), config.dead_letter.max_mb);
+        }
+        return false;
+    }
+
+    /* named so they sort oldest first, replayed in that order */
+    let path = dir.join(format!("{}-{}-{}.json", chrono::Utc::now().timestamp_millis(), kind.name(), Uuid::new_v4()));
+    match fs::create_dir_all(dir).and_then(|_| fs::write(&path, contents)) {
+        Ok(_) => true,
+        Err(e) => {
+            error!("Failed to write dead-lettered log lines to {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+/// Dead-lettered batches, oldest first.
+fn letters(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .collect())
+        .unwrap_or_default();
+    paths.sort();
+    paths
+}
+
+fn read(path: &Path) -> Result<DeadLetter, String> {
+    let contents = fs::read(path).map_err(|e| e.to_string())?;
+    serde_json::from_slice(&contents).map_err(|e| e.to_string())
+}
+
+/// Writes one dead-lettered batch to its sink again.
+async fn replay(config: &LogConfig, letter: &DeadLetter) -> Result<(), String> {
+    let kind = SinkKind::parse(&letter.sink).ok_or_else(|| format!("unknown sink {}", letter.sink))?;
+    let config = config.with_destinations(Some(&letter.log_topic), Some(&letter.log_table))?;
+    let sink = new_sink(kind, &config, &letter.container_id).map_err(|e| e.to_string())?;
+
+    sink.write_batch(&letter.messages).await.map_err(|e| e.to_string())?;
+    sink.flush().await.map_err(|e| e.to_string())
+}
+
+/// Replays every dead-lettered batch. A sink that fails again is left
+/// alone until the next round, its later batches stay where they are so
+/// they're written in order.
+pub async fn replay_all(state: &AppState) {
+    let config = state.config();
+    let dir = match &config.logs.dead_letter.dir {
+        Some(dir) => dir,
+        None => return,
+    };
+
+    let mut down: Vec<String> = Vec::new();
+    let mut replayed = 0;
+    for path in letters(dir) {
+        let letter = match read(&path) {
+            Ok(letter) => letter,
+            Err(e) => {
+                /* it would never replay, keeping it only takes up room */
+                error!("Dropping unreadable dead-lettered log batch {}: {}", path.display(), e);
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+        };
+        if down.contains(&letter.sink) {
+            continue;
+        }
+
+        match replay(&config.logs, &letter).await {
+            Ok(_) => {
+                replayed += 1;
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("Failed to remove replayed log batch {}: {}", path.display(), e);
+                }
+            },
+            Err(e) => {
+                warn!("Failed to replay dead-lettered log batch {} to {}: {}", path.display(), letter.sink, e);
+                down.push(letter.sink);
+            }
+        }
+    }
+
+    if replayed > 0 {
+        info!("Replayed {} dead-lettered log batches", replayed);
+        FULL.store(false, Ordering::Relaxed);
+    }
+}
+
+pub fn spawn_replayer(state: Arc<AppState>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            replay_all(&state).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::logs::logs::tests::log_config;
+    use crate::logs::sink::tests::message;
+
+    #[test]
+    fn stored_batches_are_read_back_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = log_config();
+        config.dead_letter.dir = Some(dir.path().to_path_buf());
+        config.dead_letter.max_mb = 1;
+
+        assert!(store(&config, SinkKind::Kafka, "container", &[message("container", "first")]));
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(store(&config, SinkKind::Kafka, "container", &[message("container", "second")]));
+
+        let stored: Vec<String> = letters(dir.path()).iter()
+            .map(|path| read(path).unwrap())
+            .map(|letter| format!("{} {} {}", letter.sink, letter.log_topic, letter.messages[0].text))
+            .collect();
+        assert_eq!(stored, ["kafka logs first", "kafka logs second"]);
+    }
+
+    #[test]
+    fn batches_past_the_limit_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = log_config();
+        config.dead_letter.dir = Some(dir.path().to_path_buf());
+        config.dead_letter.max_mb = 0;
+
+        assert!(!store(&config, SinkKind::Kafka, "container", &[message("container", "line")]));
+        assert!(letters(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn nothing_is_kept_without_a_dir() {
+        assert!(!store(&log_config(), SinkKind::Kafka, "container", &[message("container", "line")]));
+    }
+}
@@ -0,0 +1,166 @@
+use async_trait::async_trait;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::config::ClientConfig;
+use rdkafka::util::Timeout;
+use tokio::sync::Mutex;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::logs::logs::LogMessage;
+use crate::logs::sink::{LogSink, SinkError};
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct KafkaConfig {
+    pub brokers: String,
+    pub topic: String,
+    /* lines held while kafka is unreachable, the oldest are dropped past this */
+    pub buffer_size: usize,
+    /* wait between reconnect / resend attempts, doubling up to the max */
+    pub retry_initial: Duration,
+    pub retry_max: Duration,
+}
+
+/// Produces each line to a Kafka (or Redpanda) topic. While the brokers are
+/// unreachable lines are buffered and the producer retried with backoff, so
+/// log collection carries on without it.
+pub struct KafkaSink {
+    config: KafkaConfig,
+    state: Mutex<KafkaState>,
+}
+
+struct KafkaState {
+    producer: Option<FutureProducer>,
+    backlog: VecDeque<String>,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+
+impl KafkaSink {
+    pub fn new(config: &KafkaConfig) -> Self {
+        KafkaSink {
+            state: Mutex::new(KafkaState {
+                producer: None,
+                backlog: VecDeque::new(),
+                retry_at: None,
+                backoff: config.retry_initial,
+            }),
+            config: config.clone(),
+        }
+    }
+
+    fn create_producer(&self) -> Result<FutureProducer, SinkError> {
+        let duration_in_millis = Duration::from_secs(5).as_millis().to_string();
+
+        Ok(ClientConfig::new()
+            .set("bootstrap.servers", &self.config.brokers)
+            .set("message.timeout.ms", &duration_in_millis)
+            .create()?)
+    }
+
+    fn back_off(&self, state: &mut KafkaState, error: &dyn std::fmt::Display) {
+        warn!("Kafka unavailable, buffering {} lines and retrying in {:?}: {}", state.backlog.len(), state.backoff, error);
+        state.retry_at = Some(Instant::now() + state.backoff);
+        state.backoff = (state.backoff * 2).min(self.config.retry_max);
+    }
+
+    /// Sends as much of the backlog as kafka takes, backing off on the first
+    /// failure.
+    async fn deliver(&self, state: &mut KafkaState, force: bool) {
+        if !force && state.retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+            return;
+        }
+
+        if state.producer.is_none() {
+            match self.create_producer() {
+                Ok(producer) => state.producer = Some(producer),
+                Err(e) => return self.back_off(state, &e),
+            }
+        }
+
+        while let Some(payload) = state.backlog.front() {
+            let record = FutureRecord::to(&self.config.topic).payload(payload).key("");
+            let sent = state.producer.as_ref().unwrap().send(record, Timeout::Never).await;
+
+            match sent {
+                Ok(_) => {
+                    state.backlog.pop_front();
+                    state.retry_at = None;
+                    state.backoff = self.config.retry_initial;
+                },
+                Err((e, _)) => return self.back_off(state, &e),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LogSink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn write(&self, message: &LogMessage) -> Result<(), SinkError> {
+        let mut state = self.state.lock().await;
+
+        state.backlog.push_back(format!("{:?}", message));
+        if state.backlog.len() > self.config.buffer_size {
+            state.backlog.pop_front();
+            warn!("Kafka buffer full, dropped the oldest line");
+        }
+
+        self.deliver(&mut state, false).await;
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        let mut state = self.state.lock().await;
+        self.deliver(&mut state, true).await;
+
+        match state.backlog.len() {
+            0 => Ok(()),
+            undelivered => Err(format!("{} lines were never delivered to kafka", undelivered).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::logs::logs::tests::log_config;
+    use crate::logs::sink::{build_sinks, SinkKind};
+
+    #[tokio::test]
+    async fn no_kafka_sink_unless_configured() {
+        let mut config = log_config();
+        config.sinks = Vec::new();
+        assert!(build_sinks(&config, "container").unwrap().is_empty());
+
+        config.sinks = vec![SinkKind::Kafka];
+        let names: Vec<&str> = build_sinks(&config, "container").unwrap().iter().map(|sink| sink.name()).collect();
+        assert_eq!(names, ["kafka"]);
+    }
+
+    #[tokio::test]
+    async fn backoff_doubles_up_to_the_max() {
+        let config = KafkaConfig {
+            retry_initial: Duration::from_secs(1),
+            retry_max: Duration::from_secs(5),
+            ..log_config().kafka
+        };
+        let sink = KafkaSink::new(&config);
+        let mut state = sink.state.lock().await;
+
+        let mut waits = Vec::new();
+        for _ in 0..4 {
+            waits.push(state.backoff);
+            sink.back_off(&mut state, &"unreachable");
+        }
+
+        assert_eq!(waits, [1, 2, 4, 5].map(Duration::from_secs));
+        assert!(state.retry_at.is_some());
+    }
+}
@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// How severe a collected line is, as far as it says.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    /// The usual spellings, `WARNING`, `ERR`, `FATAL` and the like included.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "trace" | "trc" => Some(Level::Trace),
+            "debug" | "dbg" => Some(Level::Debug),
+            "info" | "inf" | "notice" => Some(Level::Info),
+            "warn" | "warning" | "wrn" => Some(Level::Warn),
+            "error" | "err" | "fatal" | "critical" | "crit" | "panic" => Some(Level::Error),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+
+    /* pino and bunyan write levels as numbers */
+    fn from_number(value: u64) -> Self {
+        match value {
+            0..=10 => Level::Trace,
+            11..=20 => Level::Debug,
+            21..=30 => Level::Info,
+            31..=40 => Level::Warn,
+            _ => Level::Error,
+        }
+    }
+}
+
+/// Finds the level of a line, `None` when it doesn't say.
+pub trait LevelDetector: Send + Sync {
+    fn detect(&self, text: &str) -> Option<Level>;
+}
+
+/// Structured lines: a JSON object's `level`, `severity` or `lvl` field.
+pub struct JsonLevel;
+
+impl LevelDetector for JsonLevel {
+    fn detect(&self, text: &str) -> Option<Level> {
+        let text = text.trim();
+        if !text.starts_with('{') {
+            return None;
+        }
+
+        let line: Value = serde_json::from_str(text).ok()?;
+        ["level", "severity", "lvl", "log.level"].iter()
+            .find_map(|field| line.get(field))
+            .and_then(|level| match level {
+                Value::String(level) => Level::parse(level),
+                Value::Number(level) => level.as_u64().map(Level::from_number),
+                _ => None,
+            })
+    }
+}
+
+/// Plain lines starting with their level, `[INFO] ...`, `WARN: ...` or the
+/// same after a leading timestamp.
+pub struct PrefixLevel;
+
+impl LevelDetector for PrefixLevel {
+    fn detect(&self, text: &str) -> Option<Level> {
+        let token = |word: &str| Level::parse(word.trim_matches(|c: char| !c.is_ascii_alphabetic()));
+
+        let mut words = text.split_whitespace();
+        let first = words.next()?;
+        match token(first) {
+            Some(level) => Some(level),
+            /* e.g. 2024-05-01T10:00:00Z ERROR ... */
+            None if first.starts_with(|c: char| c.is_ascii_digit()) => words.next().and_then(token),
+            None => None,
+        }
+    }
+}
+
+/// The detectors `LOG_LEVEL_DETECTORS` can name, tried in its order.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectorKind {
+    Json,
+    Prefix,
+}
+
+impl DetectorKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "json" => Some(DetectorKind::Json),
+            "prefix" => Some(DetectorKind::Prefix),
+            _ => None,
+        }
+    }
+
+    fn detector(&self) -> Box<dyn LevelDetector> {
+        match self {
+            DetectorKind::Json => Box::new(JsonLevel),
+            DetectorKind::Prefix => Box::new(PrefixLevel),
+        }
+    }
+}
+
+/// Drops lines below a minimum level. The first detector that finds a level
+/// decides, a line none of them can read is kept.
+pub struct LevelFilter {
+    min: Option<Level>,
+    detectors: Vec<Box<dyn LevelDetector>>,
+}
+
+impl LevelFilter {
+    pub fn new(min: Option<Level>, kinds: &[DetectorKind]) -> Self {
+        LevelFilter {
+            min,
+            detectors: kinds.iter().map(DetectorKind::detector).collect(),
+        }
+    }
+
+    pub fn keeps(&self, text: &str) -> bool {
+        let min = match self.min {
+            Some(min) => min,
+            None => return true,
+        };
+
+        self.detectors.iter()
+            .find_map(|detector| detector.detect(text))
+            .is_none_or(|level| level >= min)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(min: Level) -> LevelFilter {
+        LevelFilter::new(Some(min), &[DetectorKind::Json, DetectorKind::Prefix])
+    }
+
+    #[test]
+    fn info_lines_are_dropped_at_warn() {
+        let filter = filter(Level::Warn);
+
+        assert!(!filter.keeps("[INFO] listening on :8080"));
+        assert!(!filter.keeps(r#"{"level":"info","msg":"request served"}"#));
+        assert!(!filter.keeps("2026-10-15T10:00:00Z DEBUG cache hit"));
+        assert!(filter.keeps("WARN: slow query"));
+        assert!(filter.keeps(r#"{"level":50,"msg":"crashed"}"#));
+        assert!(filter.keeps("2026-10-15T10:00:00Z ERROR connection lost"));
+    }
+
+    #[test]
+    fn lines_without_a_level_are_kept() {
+        let filter = filter(Level::Error);
+
+        assert!(filter.keeps("listening on :8080"));
+        assert!(filter.keeps(r#"{"msg":"no level here"}"#));
+        assert!(filter.keeps(""));
+    }
+
+    #[test]
+    fn no_threshold_keeps_everything() {
+        assert!(LevelFilter::new(None, &[DetectorKind::Prefix]).keeps("[TRACE] entering loop"));
+    }
+
+    #[test]
+    fn only_the_configured_detectors_are_used() {
+        let prefix_only = LevelFilter::new(Some(Level::Warn), &[DetectorKind::Prefix]);
+        assert!(prefix_only.keeps(r#"{"level":"info","msg":"request served"}"#));
+    }
+}
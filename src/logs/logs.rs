@@ -1,113 +1,786 @@
 use shiplift::Docker;
-use shiplift::LogsOptions;
+use shiplift::{ContainerListOptions, LogsOptions};
+use shiplift::rep::ContainerDetails;
+use hyper::StatusCode;
 use tokio::sync::broadcast;
+use tokio::time::Instant;
 
-use clickhouse_rs::Pool;
-use clickhouse_rs::types::{Block, Value};
-
-use rdkafka::producer::{FutureProducer, FutureRecord};
-use rdkafka::config::ClientConfig;
-use rdkafka::util::Timeout;
-
-use chrono::prelude::*;
 use chrono::{DateTime, Utc};
-use chrono_tz::Tz;
-use futures::StreamExt;
-use tracing::error;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::logs::checkpoint::{Checkpoints, CHECKPOINT_INTERVAL};
+use crate::logs::clickhouse::ClickHouseConfig;
+use crate::logs::dead_letter::DeadLetterConfig;
+use crate::logs::kafka::KafkaConfig;
+use crate::logs::level::{DetectorKind, Level, LevelFilter};
+use crate::logs::redact::{RedactConfig, Redactor};
+use crate::logs::s3::S3Config;
+use crate::logs::sink::{build_sinks, dispatch, flush, LogSink, SinkKind};
 
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::future::Future;
 use std::str;
 use std::time::Duration;
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LogMessage {
     pub source: String,
     pub timestamp: DateTime<Utc>,
     pub text: String,
+    /* set on notices from forge itself (e.g. a reconnect gap), these are only broadcast */
+    pub marker: bool,
+    /* the line's length in bytes when it was cut down to max_line_bytes */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated_from: Option<usize>,
+}
+
+impl LogMessage {
+    pub fn marker(source: &str, text: String) -> Self {
+        LogMessage {
+            source: source.to_string(),
+            timestamp: Utc::now(),
+            text,
+            marker: true,
+            truncated_from: None,
+        }
+    }
+}
+
+const TRUNCATED: &str = "…(truncated)";
+
+/// Cuts `text` down to at most `max_bytes` (0 for no limit) plus a
+/// `…(truncated)` marker, returning the original length in bytes when it
+/// did. The cut falls on a char boundary.
+pub fn truncate_line(text: String, max_bytes: usize) -> (String, Option<usize>) {
+    if max_bytes == 0 || text.len() <= max_bytes {
+        return (text, None);
+    }
+
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let original = text.len();
+    let mut text = text;
+    text.truncate(end);
+    text.push_str(TRUNCATED);
+    (text, Some(original))
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LogConfig {
+    /* how many times a dropped docker log stream is re-established before giving up */
+    pub reconnect_attempts: u32,
+    pub reconnect_delay: Duration,
+    /* upper bound on one collection, whatever end_time says */
+    pub collection_timeout: Duration,
+    /* longer lines are cut down before they're broadcast or sent to a sink, 0 for no limit */
+    pub max_line_bytes: usize,
+    /* masked in every collected line and build log before it goes anywhere */
+    pub redact: RedactConfig,
+    /* where lines go besides the live broadcast, none means broadcast only */
+    pub sinks: Vec<SinkKind>,
+    /* containers writing to one kind of sink at once, fixed once logs were first collected */
+    pub sink_concurrency: usize,
+    /* how long a sink that failed a write is skipped before it's tried again */
+    pub sink_retry_interval: Duration,
+    pub clickhouse: ClickHouseConfig,
+    pub kafka: KafkaConfig,
+    pub s3: Option<S3Config>,
+    /* where batches a sink failed to write wait for it to come back */
+    pub dead_letter: DeadLetterConfig,
+    /* how a line's level is found for min_level, the first that finds one wins */
+    pub level_detectors: Vec<DetectorKind>,
+    /* topics and tables a /logs request may pick instead of the defaults */
+    pub allowed_topics: Vec<String>,
+    pub allowed_tables: Vec<String>,
+}
+
+impl LogConfig {
+    /// This config with the kafka topic and clickhouse table a request asked
+    /// for. Each has to be the configured default or on its allowlist.
+    pub fn with_destinations(&self, topic: Option<&str>, table: Option<&str>) -> Result<LogConfig, String> {
+        let mut config = self.clone();
+
+        if let Some(topic) = topic {
+            if topic != self.kafka.topic && !self.allowed_topics.iter().any(|allowed| allowed == topic) {
+                return Err(format!("Kafka topic {} is not allowed", topic));
+            }
+            config.kafka.topic = topic.to_string();
+        }
+
+        if let Some(table) = table {
+            if table != self.clickhouse.table && !self.allowed_tables.iter().any(|allowed| allowed == table) {
+                return Err(format!("ClickHouse table {} is not allowed", table));
+            }
+            config.clickhouse = ClickHouseConfig {
+                collapse_repeats: self.clickhouse.collapse_repeats,
+                ..ClickHouseConfig::new(self.clickhouse.url.clone(), table.to_string())?
+            };
+        }
+
+        Ok(config)
+    }
 }
 
 pub struct LogFilter {
     pub start_time: DateTime<Utc>,
     pub end_time: DateTime<Utc>,
+    /* keep streaming new output until end_time instead of stopping at what's there now */
+    pub follow: bool,
+    /* only the last n lines, as docker logs --tail */
+    pub tail: Option<String>,
+    /* lines below this are dropped, ones without a detectable level are kept */
+    pub min_level: Option<Level>,
 }
 
 impl LogFilter {
     pub fn matches(&self, message: &LogMessage) -> bool {
         message.timestamp >= self.start_time && message.timestamp <= self.end_time
     }
+
+    /// Docker side options, so only the requested window is sent over. The
+    /// window is still applied precisely with `matches` since docker's since
+    /// filter only has second precision.
+    pub fn logs_options(&self, last_seen: Option<DateTime<Utc>>) -> LogsOptions {
+        let mut options = LogsOptions::builder();
+        options.stdout(true).stderr(true).timestamps(true).follow(self.follow);
+
+        match last_seen {
+            /* pick up where a dropped stream left off */
+            Some(last_seen) => {
+                options.since(&last_seen.max(self.start_time));
+            },
+            None => {
+                options.since(&self.start_time);
+                if let Some(tail) = &self.tail {
+                    options.tail(tail);
+                }
+            },
+        }
+
+        options.build()
+    }
+}
+
+
+#[derive(Debug)]
+pub enum ContainerError {
+    NotFound,
+    /* a short id or name matching more than one container, their full ids */
+    Ambiguous(Vec<String>),
+    /* docker couldn't be asked */
+    Unavailable(shiplift::Error),
+}
+
+impl std::fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ContainerError::NotFound => write!(f, "No such container"),
+            ContainerError::Ambiguous(candidates) => write!(f, "Ambiguous container, it could be any of {}", candidates.join(", ")),
+            ContainerError::Unavailable(e) => write!(f, "Docker is unavailable: {}", e),
+        }
+    }
+}
+
+/// Looks the container up, so log collection is only started for containers
+/// that are actually there.
+pub async fn inspect_container(container_id: &str) -> Result<ContainerDetails, ContainerError> {
+    match Docker::new().containers().get(container_id).inspect().await {
+        Ok(details) => Ok(details),
+        Err(shiplift::Error::Fault { code, .. }) if code == StatusCode::NOT_FOUND => Err(ContainerError::NotFound),
+        Err(e) => Err(ContainerError::Unavailable(e)),
+    }
+}
+
+/// The full id of the container `id_or_name` refers to, which like with
+/// `docker logs` can be a full or short id, or a name.
+pub async fn resolve_container(id_or_name: &str) -> Result<String, ContainerError> {
+    let options = ContainerListOptions::builder().all().build();
+    let containers: Vec<(String, Vec<String>)> = Docker::new().containers().list(&options).await
+        .map_err(ContainerError::Unavailable)?
+        .into_iter()
+        .map(|container| (container.id, container.names))
+        .collect();
+
+    match_container(id_or_name, &containers)
+}
+
+/// Picks the container `id_or_name` refers to out of `(id, names)` pairs,
+/// names being listed with docker's leading `/`.
+fn match_container(id_or_name: &str, containers: &[(String, Vec<String>)]) -> Result<String, ContainerError> {
+    /* exact matches win over prefixes, as an id prefix can also be a whole name */
+    let name = format!("/{}", id_or_name);
+    if let Some((id, _)) = containers.iter().find(|(id, names)| id == id_or_name || names.contains(&name)) {
+        return Ok(id.clone());
+    }
+
+    let candidates: Vec<String> = containers.iter()
+        .filter(|(id, _)| !id_or_name.is_empty() && id.starts_with(id_or_name))
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    match candidates.len() {
+        0 => Err(ContainerError::NotFound),
+        1 => Ok(candidates.into_iter().next().unwrap()),
+        _ => Err(ContainerError::Ambiguous(candidates)),
+    }
+}
+
+/// The messages sent on `rx` as a stream, ending once collection stops.
+/// Messages a slow reader missed are skipped.
+pub fn messages(rx: broadcast::Receiver<LogMessage>) -> impl Stream<Item = LogMessage> {
+    futures::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(message) => return Some((message, rx)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// The messages of several collections as one stream in timestamp order,
+/// ending once all of them stopped. Lines are held back for `delay` so a
+/// line another source sends a little later can still go before them, a
+/// quiet source holds nobody up.
+pub fn merged(receivers: Vec<broadcast::Receiver<LogMessage>>, delay: Duration) -> impl Stream<Item = LogMessage> {
+    let sources = futures::stream::select_all(receivers.into_iter().map(|rx| Box::pin(messages(rx))));
+    /* keyed by timestamp then arrival, so equal timestamps keep their order */
+    let pending: BTreeMap<(DateTime<Utc>, u64), (Instant, LogMessage)> = BTreeMap::new();
+
+    futures::stream::unfold((sources, pending, 0u64, false), move |(mut sources, mut pending, mut arrivals, mut ended)| async move {
+        loop {
+            let release_at = pending.values().next().map(|(arrived, _)| *arrived + delay);
+            match release_at {
+                Some(release_at) if ended || release_at <= Instant::now() => {
+                    let (_, (_, message)) = pending.pop_first().unwrap();
+                    return Some((message, (sources, pending, arrivals, ended)));
+                },
+                None if ended => return None,
+                _ => {},
+            }
+
+            tokio::select! {
+                message = sources.next() => match message {
+                    Some(message) => {
+                        pending.insert((message.timestamp, arrivals), (Instant::now(), message));
+                        arrivals += 1;
+                    },
+                    None => ended = true,
+                },
+                _ = tokio::time::sleep_until(release_at.unwrap_or_else(Instant::now)), if release_at.is_some() => {},
+            }
+        }
+    })
+}
+
+/* how often a container that hasn't started yet is checked on */
+const START_POLL: Duration = Duration::from_secs(1);
+
+/// Waits for a created container to be started, e.g. a sidecar that comes
+/// up after the main service. False if it still hadn't by `until`.
+pub async fn wait_for_start(container_id: &str, until: DateTime<Utc>) -> Result<bool, ContainerError> {
+    loop {
+        if inspect_container(container_id).await?.state.status != "created" {
+            return Ok(true);
+        }
+        if Utc::now() + chrono::Duration::from_std(START_POLL).unwrap() > until {
+            return Ok(false);
+        }
+        tokio::time::sleep(START_POLL).await;
+    }
+}
+
+/* how long a stopped collection keeps reading, so the last lines still make it */
+const STOP_GRACE: Duration = Duration::from_secs(2);
+
+/// Collects `container_id`'s logs into `tx` and the configured sinks until
+/// the window, the collection timeout or `stop` ends it, then flushes the
+/// sinks. With `checkpoints`, how far it got is recorded as it goes and the
+/// record removed once it's done.
+pub async fn get_logs(container_id: &str, filter: LogFilter, tx: broadcast::Sender<LogMessage>, config: &LogConfig, stop: impl Future<Output = ()>, checkpoints: Option<&Checkpoints>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let result = collect_window(container_id, filter, tx, config, stop, checkpoints).await;
+    if let Some(checkpoints) = checkpoints {
+        checkpoints.finish(container_id).await;
+    }
+    result
+}
+
+/* how a collection starts, given the container's state */
+#[derive(Debug, PartialEq)]
+enum Start {
+    /* nothing to collect, said why */
+    Nothing(String),
+    Collect { follow: bool, notice: Option<String> },
+}
+
+fn start(status: &str, running: bool, follow: bool) -> Start {
+    /* a container that never started has no output and docker may never end its stream */
+    if status == "created" {
+        return Start::Nothing("container hasn't started, there are no logs to collect".to_string());
+    }
+    if follow && !running {
+        /* nothing new is coming, read what's there */
+        return Start::Collect { follow: false, notice: Some(format!("container is {}, not following", status)) };
+    }
+    Start::Collect { follow, notice: None }
+}
+
+/* a followed stream is done at end_time even if docker goes quiet */
+fn budget(filter: &LogFilter, timeout: Duration, now: DateTime<Utc>) -> Duration {
+    if filter.follow {
+        let until_end = (filter.end_time - now).to_std().unwrap_or_default();
+        return timeout.min(until_end);
+    }
+    timeout
+}
+
+async fn collect_window(container_id: &str, mut filter: LogFilter, tx: broadcast::Sender<LogMessage>, config: &LogConfig, stop: impl Future<Output = ()>, checkpoints: Option<&Checkpoints>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let details = inspect_container(container_id).await.map_err(|e| e.to_string())?;
+    match start(&details.state.status, details.state.running, filter.follow) {
+        Start::Nothing(reason) => {
+            let _ = tx.send(LogMessage::marker(container_id, reason));
+            return Ok(());
+        },
+        Start::Collect { follow, notice } => {
+            filter.follow = follow;
+            if let Some(notice) = notice {
+                let _ = tx.send(LogMessage::marker(container_id, notice));
+            }
+        },
+    }
+
+    let sinks = build_sinks(config, container_id)?;
+    /* the container's own secret envs are the likeliest to show up in its output */
+    let redactor = config.redact.redactor(details.config.env.iter().flatten().map(String::as_str));
+
+    let budget = budget(&filter, config.collection_timeout, Utc::now());
+    let collection = tokio::time::timeout(budget, collect(container_id, &filter, &tx, config, &sinks, &redactor, checkpoints));
+    let outcome = until_stopped(collection, stop, &tx, container_id).await;
+
+    let result = match outcome {
+        Some(Ok(result)) => result,
+        Some(Err(_)) => {
+            let _ = tx.send(LogMessage::marker(container_id, "log collection stopped, end_time or the collection timeout was reached".to_string()));
+            Ok(())
+        },
+        /* stopped with the build */
+        None => Ok(()),
+    };
+    flush(&sinks).await;
+
+    result
 }
 
+/* runs `collection` until it ends or `stop` fires, then gives it STOP_GRACE
+to read the last lines. None when it was cut off */
+async fn until_stopped<T>(collection: impl Future<Output = T>, stop: impl Future<Output = ()>, tx: &broadcast::Sender<LogMessage>, container_id: &str) -> Option<T> {
+    tokio::pin!(collection);
+
+    tokio::select! {
+        outcome = &mut collection => Some(outcome),
+        _ = stop => {
+            let _ = tx.send(LogMessage::marker(container_id, "build finished, stopping log collection".to_string()));
+            tokio::time::timeout(STOP_GRACE, &mut collection).await.ok()
+        },
+    }
+}
 
-pub async fn get_logs(container_id: &str, filter: LogFilter, tx: broadcast::Sender<LogMessage>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn collect(container_id: &str, filter: &LogFilter, tx: &broadcast::Sender<LogMessage>, config: &LogConfig, sinks: &[Box<dyn LogSink>], redactor: &Redactor, checkpoints: Option<&Checkpoints>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let docker = Docker::new();
 
     let container = docker.containers().get(container_id);
-    let options = LogsOptions::builder().stdout(true).stderr(true).build();
-    let mut logs_stream = container.logs(&options);
-
-    let pool = Pool::new("tcp://clickhouse:8123");
-
-    let duration_in_millis = Duration::from_secs(5).as_millis().to_string();
-
-    let producer: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", "redpanda:18081")
-        .set("message.timeout.ms", &duration_in_millis)
-        .create()?;
-
-    while let Some(log_result) = logs_stream.next().await {
-        match log_result {
-            Ok(log_output) => {
-                let log_data = str::from_utf8(&log_output)?;
-                let parts: Vec<&str> = log_data.splitn(2, ' ').collect();
-                let timestamp = parts[0].parse::<DateTime<Utc>>()?;
-                let text = parts[1].to_string();
-                
-                let message = LogMessage {
-                    source: container_id.to_string(),
-                    timestamp,
-                    text,
-                };
-
-                if filter.matches(&message) {
-                    let topic = "logs_topic";
-                    let payload = format!("{:?}", message);
-                    let record = FutureRecord::to(topic).payload(&payload).key("");
-
-                    match producer.send(record, Timeout::Never).await {
-                        Ok(_) => {}
-                        Err(e) => error!("Error sending message to Kafka: {:?}", e),
+    let open = |options: LogsOptions| container.logs(&options).map(|chunk| chunk.map(|chunk| chunk.to_vec()));
+
+    read_logs(container_id, open, filter, tx, config, sinks, redactor, checkpoints).await
+}
+
+/* reads the streams `open` gives, opening another where a dropped one left off */
+#[allow(clippy::too_many_arguments)]
+async fn read_logs<S, E>(container_id: &str, mut open: impl FnMut(LogsOptions) -> S, filter: &LogFilter, tx: &broadcast::Sender<LogMessage>, config: &LogConfig, sinks: &[Box<dyn LogSink>], redactor: &Redactor, checkpoints: Option<&Checkpoints>) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: Stream<Item = Result<Vec<u8>, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut last_seen: Option<DateTime<Utc>> = None;
+    let mut reconnects = 0;
+    let mut checkpointed = Instant::now();
+    let levels = LevelFilter::new(filter.min_level, &config.level_detectors);
+
+    loop {
+        let mut logs_stream = open(filter.logs_options(last_seen));
+        let mut disconnected = false;
+
+        while let Some(log_result) = logs_stream.next().await {
+            match log_result {
+                Ok(log_output) => {
+                    let log_data = str::from_utf8(&log_output)?;
+                    let parts: Vec<&str> = log_data.splitn(2, ' ').collect();
+                    let timestamp = parts[0].parse::<DateTime<Utc>>()?;
+                    let (text, truncated_from) = truncate_line(redactor.redact(parts[1]).into_owned(), config.max_line_bytes);
+
+                    /* since only has second precision, so a reconnect replays part of the last second */
+                    if last_seen.is_some_and(|last_seen| timestamp <= last_seen) {
+                        continue;
                     }
-                }
+                    /* docker has no until, so a followed stream is cut off here */
+                    if timestamp > filter.end_time {
+                        break;
+                    }
+                    last_seen = Some(timestamp);
+                    reconnects = 0;
 
-                let mut block = Block::new();
+                    let message = LogMessage {
+                        source: container_id.to_string(),
+                        timestamp,
+                        text,
+                        marker: false,
+                        truncated_from,
+                    };
 
-                let timestamp: DateTime<Utc> = message.timestamp;
-                let timestamp_seconds = timestamp.timestamp(); // timestamp() returns i64, cast it to u32
-                let timezone_offset_seconds = Local::now().offset().fix().local_minus_utc() as u32;
+                    if filter.matches(&message) && levels.keeps(&message.text) {
+                        /* no live subscribers is fine */
+                        let _ = tx.send(message.clone());
 
-                let row = vec![
-                    ("source".to_string(), Value::String(Arc::new(message.source.into_bytes()))),
-                    ("timestamp".to_string(), Value::DateTime64(timestamp_seconds, (timezone_offset_seconds, Tz::UTC))),
-                    ("text".to_string(), Value::String(Arc::new(message.text.into_bytes()))),
-                ];
-                
-                if let Err(e) = block.push(row) {
-                    error!("Error pushing row to block: {}", e);
+                        dispatch(sinks, &message).await;
+                    }
+
+                    if let Some(checkpoints) = checkpoints {
+                        if checkpointed.elapsed() >= CHECKPOINT_INTERVAL {
+                            checkpoints.advance(container_id, timestamp).await;
+                            checkpointed = Instant::now();
+                        }
+                    }
+                },
+                Err(e) => {
+                    error!("Error reading logs: {}", e);
+                    disconnected = true;
+                    break;
                 }
+            }
+        }
 
-                let mut client = pool.get_handle().await?;
-            
-                let ddl = r"
-                INSERT INTO logs (source, timestamp, text) VALUES
-                ";
+        if !disconnected {
+            break;
+        }
 
-                client.insert(ddl, block).await?;
-            },
-            Err(e) => {
-                error!("Error reading logs: {}", e);
-            }
+        if reconnects >= config.reconnect_attempts {
+            return Err(format!("Lost log stream for {} after {} reconnect attempts", container_id, reconnects).into());
         }
+        reconnects += 1;
+
+        tokio::time::sleep(config.reconnect_delay).await;
+
+        warn!("Reconnecting log stream for {} (attempt {}/{})", container_id, reconnects, config.reconnect_attempts);
+        let _ = tx.send(LogMessage::marker(
+            container_id,
+            format!("log stream interrupted, reconnecting (attempt {}/{}), lines may be missing", reconnects, config.reconnect_attempts),
+        ));
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::logs::redact::RedactConfig;
+    use crate::logs::sink::tests::{queued, MockSink};
+
+    use std::collections::VecDeque;
+
+    pub(crate) fn log_config() -> LogConfig {
+        LogConfig {
+            reconnect_attempts: 2,
+            reconnect_delay: Duration::ZERO,
+            collection_timeout: Duration::from_secs(60),
+            max_line_bytes: 0,
+            redact: RedactConfig::default(),
+            sinks: Vec::new(),
+            sink_concurrency: 1,
+            sink_retry_interval: Duration::from_secs(30),
+            clickhouse: ClickHouseConfig::new("tcp://localhost:9000".to_string(), "logs".to_string()).unwrap(),
+            kafka: KafkaConfig {
+                brokers: "localhost:9092".to_string(),
+                topic: "logs".to_string(),
+                buffer_size: 100,
+                retry_initial: Duration::from_secs(1),
+                retry_max: Duration::from_secs(1),
+            },
+            s3: None,
+            dead_letter: DeadLetterConfig {
+                dir: None,
+                max_mb: 0,
+                retry_interval_secs: 60,
+            },
+            level_detectors: Vec::new(),
+            allowed_topics: Vec::new(),
+            allowed_tables: Vec::new(),
+        }
+    }
+
+    pub(crate) fn filter() -> LogFilter {
+        LogFilter {
+            start_time: "2024-01-01T00:00:00Z".parse().unwrap(),
+            end_time: "2024-01-01T01:00:00Z".parse().unwrap(),
+            follow: false,
+            tail: None,
+            min_level: None,
+        }
+    }
+
+    type Chunks = Vec<Result<Vec<u8>, String>>;
+
+    fn line(timestamp: &str, text: &str) -> Result<Vec<u8>, String> {
+        Ok(format!("{} {}", timestamp, text).into_bytes())
+    }
+
+    /* runs read_logs over one canned stream per (re)connect, returning what was broadcast */
+    async fn read(streams: Vec<Chunks>, filter: &LogFilter, config: &LogConfig) -> (Result<(), String>, Vec<LogMessage>) {
+        let (tx, mut rx) = broadcast::channel(100);
+        let mut streams: VecDeque<Chunks> = streams.into();
+        let open = |_| futures::stream::iter(streams.pop_front().unwrap_or_default());
+
+        let result = read_logs("container", open, filter, &tx, config, &[], &Redactor::default(), None).await;
+
+        let mut messages = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            messages.push(message);
+        }
+        (result.map_err(|e| e.to_string()), messages)
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_stream_error() {
+        let streams = vec![
+            vec![line("2024-01-01T00:00:01Z", "first"), Err("connection reset".to_string())],
+            /* the since filter replays the last second */
+            vec![line("2024-01-01T00:00:01Z", "first"), line("2024-01-01T00:00:02Z", "second")],
+        ];
+
+        let (result, messages) = read(streams, &filter(), &log_config()).await;
+
+        assert!(result.is_ok());
+        let texts: Vec<(&str, bool)> = messages.iter().map(|message| (message.text.as_str(), message.marker)).collect();
+        assert_eq!(texts.len(), 3);
+        assert_eq!(texts[0], ("first", false));
+        assert!(texts[1].1 && texts[1].0.contains("reconnecting (attempt 1/2)"));
+        assert_eq!(texts[2], ("second", false));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_reconnect_attempts() {
+        let broken = || vec![Err("connection reset".to_string())];
+        let streams = vec![broken(), broken(), broken()];
+
+        let (result, messages) = read(streams, &filter(), &log_config()).await;
+
+        assert!(result.unwrap_err().contains("after 2 reconnect attempts"));
+        assert_eq!(messages.iter().filter(|message| message.marker).count(), 2);
+    }
+
+    fn query(options: LogsOptions) -> String {
+        options.serialize().unwrap_or_default()
+    }
+
+    #[test]
+    fn logs_options_apply_since_and_tail() {
+        let filter = LogFilter {
+            follow: true,
+            tail: Some("10".to_string()),
+            ..filter()
+        };
+
+        let query = query(filter.logs_options(None));
+        assert!(query.contains("since=1704067200"), "{}", query);
+        assert!(query.contains("tail=10"), "{}", query);
+        assert!(query.contains("follow=true"), "{}", query);
+    }
+
+    #[test]
+    fn logs_options_resume_from_last_seen() {
+        let filter = LogFilter {
+            tail: Some("10".to_string()),
+            ..filter()
+        };
+        let last_seen = "2024-01-01T00:10:00Z".parse().unwrap();
+
+        let query = query(filter.logs_options(Some(last_seen)));
+        assert!(query.contains("since=1704067800"), "{}", query);
+        /* the tail was already applied to the first stream */
+        assert!(!query.contains("tail="), "{}", query);
+    }
+
+    #[test]
+    fn container_that_never_started_has_nothing_to_collect() {
+        assert!(matches!(start("created", false, true), Start::Nothing(reason) if reason.contains("hasn't started")));
+    }
+
+    #[test]
+    fn stopped_container_isnt_followed() {
+        assert_eq!(start("exited", false, true), Start::Collect {
+            follow: false,
+            notice: Some("container is exited, not following".to_string()),
+        });
+        assert_eq!(start("exited", false, false), Start::Collect { follow: false, notice: None });
+        assert_eq!(start("running", true, true), Start::Collect { follow: true, notice: None });
+    }
+
+    #[test]
+    fn followed_collection_ends_at_end_time() {
+        let now = "2024-01-01T00:59:00Z".parse().unwrap();
+        let followed = LogFilter { follow: true, ..filter() };
+
+        assert_eq!(budget(&followed, Duration::from_secs(3600), now), Duration::from_secs(60));
+        assert_eq!(budget(&followed, Duration::from_secs(10), now), Duration::from_secs(10));
+        /* a window that's already over */
+        assert_eq!(budget(&followed, Duration::from_secs(3600), "2024-01-01T02:00:00Z".parse().unwrap()), Duration::ZERO);
+        assert_eq!(budget(&filter(), Duration::from_secs(3600), now), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn destination_overrides_are_applied() {
+        let config = LogConfig {
+            allowed_topics: vec!["tenant-a-logs".to_string()],
+            allowed_tables: vec!["tenant_a.logs".to_string()],
+            ..log_config()
+        };
+
+        let overridden = config.with_destinations(Some("tenant-a-logs"), Some("tenant_a.logs")).unwrap();
+        assert_eq!(overridden.kafka.topic, "tenant-a-logs");
+        assert_eq!(overridden.clickhouse.table, "tenant_a.logs");
+
+        let defaults = config.with_destinations(None, None).unwrap();
+        assert_eq!(defaults.kafka.topic, "logs");
+        assert_eq!(defaults.clickhouse.table, "logs");
+    }
+
+    #[test]
+    fn destinations_off_the_allowlist_are_rejected() {
+        let config = LogConfig {
+            allowed_tables: vec!["logs; DROP TABLE logs".to_string()],
+            ..log_config()
+        };
+
+        assert!(config.with_destinations(Some("other-topic"), None).is_err());
+        assert!(config.with_destinations(None, Some("other_table")).is_err());
+        /* even an allowlisted table has to be a valid name */
+        assert!(config.with_destinations(None, Some("logs; DROP TABLE logs")).is_err());
+    }
+
+    #[tokio::test]
+    async fn finished_build_stops_its_collector() {
+        let (tx, mut rx) = broadcast::channel(10);
+        let started = Instant::now();
+
+        /* a followed stream that would go on forever */
+        let outcome = until_stopped(futures::future::pending::<()>(), async {}, &tx, "container").await;
+
+        assert!(outcome.is_none());
+        assert!(started.elapsed() < STOP_GRACE + Duration::from_secs(1));
+        assert!(rx.try_recv().unwrap().text.contains("stopping log collection"));
+    }
+
+    #[tokio::test]
+    async fn stopped_collector_gets_to_finish_reading() {
+        let (tx, _rx) = broadcast::channel(10);
+        let collection = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            "flushed"
+        };
+
+        assert_eq!(until_stopped(collection, async {}, &tx, "container").await, Some("flushed"));
+    }
+
+    fn containers() -> Vec<(String, Vec<String>)> {
+        vec![
+            ("4f2a9c01aa".to_string(), vec!["/web".to_string()]),
+            ("4f2b7d33bb".to_string(), vec!["/worker".to_string()]),
+            ("9e11c0ffee".to_string(), vec!["/4f2a".to_string()]),
+        ]
+    }
+
+    #[test]
+    fn containers_resolve_by_name() {
+        assert_eq!(match_container("worker", &containers()).unwrap(), "4f2b7d33bb");
+        /* a name wins over an id it's a prefix of */
+        assert_eq!(match_container("4f2a", &containers()).unwrap(), "9e11c0ffee");
+    }
+
+    #[test]
+    fn containers_resolve_by_short_id() {
+        assert_eq!(match_container("4f2b", &containers()).unwrap(), "4f2b7d33bb");
+        assert_eq!(match_container("9e11c0ffee", &containers()).unwrap(), "9e11c0ffee");
+    }
+
+    #[test]
+    fn ambiguous_or_unknown_containers_arent_resolved() {
+        match match_container("4f2", &containers()) {
+            Err(ContainerError::Ambiguous(candidates)) => assert_eq!(candidates, ["4f2a9c01aa", "4f2b7d33bb"]),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(match_container("db", &containers()), Err(ContainerError::NotFound)));
+        assert!(matches!(match_container("", &containers()), Err(ContainerError::NotFound)));
+    }
+
+    #[test]
+    fn short_lines_arent_truncated() {
+        assert_eq!(truncate_line("hello".to_string(), 5), ("hello".to_string(), None));
+        /* 0 turns the limit off */
+        assert_eq!(truncate_line("x".repeat(100_000), 0).1, None);
+    }
+
+    #[test]
+    fn oversized_line_is_truncated_on_a_char_boundary() {
+        let (text, original) = truncate_line("abcdé".to_string(), 5);
+        /* é is two bytes, cutting at 5 would split it */
+        assert_eq!(text, format!("abcd{}", TRUNCATED));
+        assert_eq!(original, Some(6));
+    }
+
+    #[tokio::test]
+    async fn oversized_lines_are_flagged_when_collected() {
+        let config = LogConfig { max_line_bytes: 16, ..log_config() };
+        let bundle = "x".repeat(1024 * 1024);
+        let streams = vec![vec![line("2024-01-01T00:00:01Z", &bundle), line("2024-01-01T00:00:02Z", "short")]];
+
+        let (result, messages) = read(streams, &filter(), &config).await;
+
+        assert!(result.is_ok());
+        assert_eq!(messages[0].text, format!("{}{}", "x".repeat(16), TRUNCATED));
+        assert_eq!(messages[0].truncated_from, Some(bundle.len()));
+        assert_eq!(messages[1].text, "short");
+        assert_eq!(messages[1].truncated_from, None);
+    }
+
+    #[tokio::test]
+    async fn logs_still_broadcast_while_clickhouse_is_down() {
+        let clickhouse = MockSink::failing();
+        let kafka = MockSink::default();
+        let sinks = [
+            queued(clickhouse.clone(), SinkKind::ClickHouse, log_config()),
+            queued(kafka.clone(), SinkKind::Kafka, log_config()),
+        ];
+        let (tx, mut rx) = broadcast::channel(100);
+        let lines = ["first", "second", "third"];
+        let stream: Chunks = lines.iter().enumerate().map(|(i, text)| line(&format!("2024-01-01T00:00:0{}Z", i + 1), text)).collect();
+
+        let result = read_logs("container", |_| futures::stream::iter(stream.clone()), &filter(), &tx, &log_config(), &sinks, &Redactor::default(), None).await;
+        assert!(result.is_ok());
+        flush(&sinks).await;
+
+        let mut broadcasted = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            broadcasted.push(message.text);
+        }
+        assert_eq!(broadcasted, lines);
+        assert_eq!(kafka.lines(), lines);
+        assert!(clickhouse.lines().is_empty());
+    }
+}
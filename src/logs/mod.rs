@@ -1 +1,9 @@
-pub mod logs;
\ No newline at end of file
+pub mod checkpoint;
+pub mod clickhouse;
+pub mod dead_letter;
+pub mod kafka;
+pub mod level;
+pub mod logs;
+pub mod redact;
+pub mod s3;
+pub mod sink;
@@ -0,0 +1,152 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{Client, Url};
+use sha2::{Digest, Sha256};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::config::redact;
+use crate::logs::logs::LogMessage;
+use crate::logs::sink::{LogSink, SinkError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct S3Config {
+    /* e.g. https://s3.eu-west-1.amazonaws.com or a minio url, buckets are addressed path style */
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    #[serde(serialize_with = "redact::secret")]
+    pub access_key_id: String,
+    #[serde(serialize_with = "redact::secret")]
+    pub secret_access_key: String,
+    /* lines per object */
+    pub batch_lines: usize,
+}
+
+/// Batches lines into newline delimited JSON objects keyed
+/// `<container>/<date>/<first timestamp>.ndjson`.
+pub struct S3Sink {
+    config: S3Config,
+    container: String,
+    client: Client,
+    batch: Mutex<Vec<LogMessage>>,
+}
+
+impl S3Sink {
+    pub fn new(config: S3Config, container_id: &str) -> Self {
+        /* container ids come from the request, keep them to one key segment */
+        let container = container_id.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+            .collect();
+
+        S3Sink {
+            config,
+            container,
+            client: Client::new(),
+            batch: Mutex::new(Vec::new()),
+        }
+    }
+
+    async fn upload(&self, batch: Vec<LogMessage>) -> Result<(), SinkError> {
+        let first = match batch.first() {
+            Some(first) => first.timestamp,
+            None => return Ok(()),
+        };
+
+        let mut body = String::new();
+        for message in &batch {
+            body.push_str(&serde_json::json!({
+                "source": message.source,
+                "timestamp": message.timestamp.to_rfc3339(),
+                "text": message.text,
+            }).to_string());
+            body.push('\n');
+        }
+
+        let key = format!("{}/{}/{}.ndjson", self.container, first.format("%Y-%m-%d"), first.timestamp_nanos());
+        self.put_object(&key, body.into_bytes()).await
+    }
+
+    /// A SigV4 signed PUT, which is all the object store API we need.
+    async fn put_object(&self, key: &str, body: Vec<u8>) -> Result<(), SinkError> {
+        let url = Url::parse(&format!("{}/{}/{}", self.config.endpoint.trim_end_matches('/'), self.config.bucket, key))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err("S3_ENDPOINT has no host".into()),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+        let canonical_request = format!(
+            "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            url.path(), host, payload_hash, amz_date, signed_headers, payload_hash,
+        );
+        let scope = format!("{}/{}/s3/aws4_request", now.format("%Y%m%d"), self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+
+        let signature = hex::encode(hmac(&signing_key(&self.config, now), &string_to_sign));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.config.access_key_id, scope, signed_headers, signature,
+        );
+
+        self.client.put(url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(config: &S3Config, now: DateTime<Utc>) -> Vec<u8> {
+    let date = hmac(format!("AWS4{}", config.secret_access_key).as_bytes(), &now.format("%Y%m%d").to_string());
+    let region = hmac(&date, &config.region);
+    let service = hmac(&region, "s3");
+    hmac(&service, "aws4_request")
+}
+
+#[async_trait]
+impl LogSink for S3Sink {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn write(&self, message: &LogMessage) -> Result<(), SinkError> {
+        let full = {
+            let mut batch = self.batch.lock().await;
+            batch.push(message.clone());
+            if batch.len() < self.config.batch_lines {
+                return Ok(());
+            }
+            std::mem::take(&mut *batch)
+        };
+
+        self.upload(full).await
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        let rest = std::mem::take(&mut *self.batch.lock().await);
+        self.upload(rest).await
+    }
+}
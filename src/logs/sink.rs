@@ -0,0 +1,467 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::sync::{mpsc, Mutex, Semaphore, SemaphorePermit};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::logs::clickhouse::ClickHouseSink;
+use crate::logs::dead_letter;
+use crate::logs::kafka::KafkaSink;
+use crate::logs::logs::{LogConfig, LogMessage};
+use crate::logs::s3::S3Sink;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+pub type SinkError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Somewhere collected log lines are forwarded to, besides the live
+/// broadcast. One sink is created per container whose logs are collected.
+#[async_trait]
+pub trait LogSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn write(&self, message: &LogMessage) -> Result<(), SinkError>;
+
+    /// Writes lines that queued up together, in order. Sinks that can send
+    /// several lines at once override this.
+    async fn write_batch(&self, messages: &[LogMessage]) -> Result<(), SinkError> {
+        for message in messages {
+            self.write(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Called once collection stops, for sinks that batch.
+    async fn flush(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    ClickHouse,
+    Kafka,
+    S3,
+}
+
+impl SinkKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "clickhouse" => Some(SinkKind::ClickHouse),
+            "kafka" => Some(SinkKind::Kafka),
+            "s3" => Some(SinkKind::S3),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SinkKind::ClickHouse => "clickhouse",
+            SinkKind::Kafka => "kafka",
+            SinkKind::S3 => "s3",
+        }
+    }
+}
+
+/* in declaration order, so a kind's index is `kind as usize` */
+const KINDS: [SinkKind; 3] = [SinkKind::ClickHouse, SinkKind::Kafka, SinkKind::S3];
+
+/* lines a worker takes off its queue and writes in one go */
+const BATCH_LINES: usize = 500;
+/* lines queued per container and sink before collection waits on the sink */
+const QUEUE_LINES: usize = 10_000;
+
+/// Bounds how many containers' lines are being written to one kind of sink
+/// at once.
+struct FlushLimiter {
+    permits: Semaphore,
+    in_flight: AtomicUsize,
+}
+
+/* counted as in flight while held */
+struct Flush<'a> {
+    _permit: SemaphorePermit<'a>,
+    in_flight: &'a AtomicUsize,
+}
+
+impl Drop for Flush<'_> {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl FlushLimiter {
+    async fn begin(&self) -> Flush<'_> {
+        let permit = self.permits.acquire().await.expect("flush semaphore closed");
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Flush { _permit: permit, in_flight: &self.in_flight }
+    }
+}
+
+/* one per sink kind, sized by the first config that collects logs, so LOG_SINK_CONCURRENCY is kept on reload */
+static LIMITERS: OnceLock<Vec<FlushLimiter>> = OnceLock::new();
+
+fn limiter(kind: SinkKind, concurrency: usize) -> &'static FlushLimiter {
+    let limiters = LIMITERS.get_or_init(|| KINDS.iter()
+        .map(|_| FlushLimiter { permits: Semaphore::new(concurrency.max(1)), in_flight: AtomicUsize::new(0) })
+        .collect());
+    &limiters[kind as usize]
+}
+
+/// How many writes each kind of sink has in flight right now.
+pub fn in_flight() -> BTreeMap<&'static str, usize> {
+    KINDS.iter()
+        .map(|kind| {
+            let in_flight = LIMITERS.get().map_or(0, |limiters| limiters[*kind as usize].in_flight.load(Ordering::Relaxed));
+            (kind.name(), in_flight)
+        })
+        .collect()
+}
+
+/// Feeds a sink from a queue on its own task, so collection doesn't wait on
+/// every insert. One worker per container keeps its lines in order, while
+/// up to `LOG_SINK_CONCURRENCY` containers write to each kind of sink in
+/// parallel. Batches the sink fails to write go to the dead-letter dir.
+/// After a failure the sink isn't tried again for `sink_retry_interval`, so
+/// a sink that's down doesn't back the queue up into the collection and
+/// stall the broadcast and the other sinks.
+struct QueuedSink {
+    name: &'static str,
+    tx: Mutex<Option<mpsc::Sender<LogMessage>>>,
+    worker: Mutex<Option<JoinHandle<Box<dyn LogSink>>>>,
+    limiter: &'static FlushLimiter,
+}
+
+impl QueuedSink {
+    fn spawn(sink: Box<dyn LogSink>, kind: SinkKind, config: LogConfig, container_id: String, limiter: &'static FlushLimiter) -> Self {
+        let name = sink.name();
+        let (tx, mut rx) = mpsc::channel::<LogMessage>(QUEUE_LINES);
+
+        let worker = tokio::spawn(async move {
+            /* set while the sink is skipped after a failed write */
+            let mut retry_at: Option<Instant> = None;
+
+            while let Some(first) = rx.recv().await {
+                let mut batch = vec![first];
+                while batch.len() < BATCH_LINES {
+                    match rx.try_recv() {
+                        Ok(message) => batch.push(message),
+                        Err(_) => break,
+                    }
+                }
+
+                if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+                    dead_letter::store(&config, kind, &container_id, &batch);
+                    continue;
+                }
+
+                let _flush = limiter.begin().await;
+                match sink.write_batch(&batch).await {
+                    Ok(_) => {
+                        if retry_at.take().is_some() {
+                            info!("{} is writable again, sending it the logs of {}", sink.name(), container_id);
+                        }
+                    },
+                    Err(e) => {
+                        error!("Error writing {} log lines to {}: {}", batch.len(), sink.name(), e);
+                        dead_letter::store(&config, kind, &container_id, &batch);
+                        if retry_at.is_none() {
+                            let kept = if config.dead_letter.dir.is_some() { "dead-lettered" } else { "dropped" };
+                            warn!(
+                                "Skipping {} for the logs of {}, their lines are {} until it's retried every {}s",
+                                sink.name(), container_id, kept, config.sink_retry_interval.as_secs(),
+                            );
+                        }
+                        retry_at = Some(Instant::now() + config.sink_retry_interval);
+                    }
+                }
+            }
+            sink
+        });
+
+        QueuedSink {
+            name,
+            tx: Mutex::new(Some(tx)),
+            worker: Mutex::new(Some(worker)),
+            limiter,
+        }
+    }
+}
+
+#[async_trait]
+impl LogSink for QueuedSink {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn write(&self, message: &LogMessage) -> Result<(), SinkError> {
+        let tx = self.tx.lock().await.clone().ok_or("sink was already flushed")?;
+        tx.send(message.clone()).await.map_err(|_| "sink worker stopped".into())
+    }
+
+    /// Waits for the queue to drain, then flushes the sink itself.
+    async fn flush(&self) -> Result<(), SinkError> {
+        self.tx.lock().await.take();
+        let worker = self.worker.lock().await.take();
+        let sink = match worker {
+            Some(worker) => worker.await?,
+            None => return Ok(()),
+        };
+
+        let _flush = self.limiter.begin().await;
+        sink.flush().await
+    }
+}
+
+/// A sink of `kind` writing straight through, for the logs of `container_id`.
+pub fn new_sink(kind: SinkKind, config: &LogConfig, container_id: &str) -> Result<Box<dyn LogSink>, SinkError> {
+    Ok(match kind {
+        SinkKind::ClickHouse => Box::new(ClickHouseSink::new(&config.clickhouse)),
+        SinkKind::Kafka => Box::new(KafkaSink::new(&config.kafka)),
+        SinkKind::S3 => {
+            let s3 = config.s3.clone().ok_or("the s3 log sink needs S3_ENDPOINT and S3_BUCKET")?;
+            Box::new(S3Sink::new(s3, container_id))
+        }
+    })
+}
+
+/// Sets up the sinks `config` turns on, for the logs of `container_id`.
+pub fn build_sinks(config: &LogConfig, container_id: &str) -> Result<Vec<Box<dyn LogSink>>, SinkError> {
+    config.sinks.iter().map(|kind| -> Result<Box<dyn LogSink>, SinkError> {
+        let sink = new_sink(*kind, config, container_id)?;
+        Ok(Box::new(QueuedSink::spawn(sink, *kind, config.clone(), container_id.to_string(), limiter(*kind, config.sink_concurrency))))
+    }).collect()
+}
+
+/// Hands `message` to every sink. A failing sink is logged and skipped so
+/// it doesn't hold up the others.
+pub async fn dispatch(sinks: &[Box<dyn LogSink>], message: &LogMessage) {
+    for sink in sinks {
+        if let Err(e) = sink.write(message).await {
+            error!("Error writing log line to {}: {}", sink.name(), e);
+        }
+    }
+}
+
+pub async fn flush(sinks: &[Box<dyn LogSink>]) {
+    for sink in sinks {
+        if let Err(e) = sink.flush().await {
+            error!("Error flushing logs to {}: {}", sink.name(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use chrono::Utc;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    /// Keeps what it's sent, or fails every write while `failing` is set.
+    #[derive(Clone, Default)]
+    pub(crate) struct MockSink {
+        pub lines: Arc<std::sync::Mutex<Vec<String>>>,
+        pub failing: Arc<AtomicBool>,
+        pub attempts: Arc<AtomicUsize>,
+    }
+
+    impl MockSink {
+        pub fn failing() -> Self {
+            let sink = MockSink::default();
+            sink.failing.store(true, Ordering::SeqCst);
+            sink
+        }
+
+        pub fn lines(&self) -> Vec<String> {
+            self.lines.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl LogSink for MockSink {
+        fn name(&self) -> &'static str {
+            "mock"
+        }
+
+        async fn write(&self, message: &LogMessage) -> Result<(), SinkError> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            if self.failing.load(Ordering::SeqCst) {
+                return Err("sink is down".into());
+            }
+            self.lines.lock().unwrap().push(message.text.clone());
+            Ok(())
+        }
+    }
+
+    pub(crate) fn message(source: &str, text: &str) -> LogMessage {
+        LogMessage {
+            source: source.to_string(),
+            timestamp: Utc::now(),
+            text: text.to_string(),
+            marker: false,
+            truncated_from: None,
+        }
+    }
+
+    /// `sink` behind a queue of its own, as `build_sinks` sets up `kind`.
+    pub(crate) fn queued(sink: MockSink, kind: SinkKind, config: LogConfig) -> Box<dyn LogSink> {
+        let limiter: &'static FlushLimiter = Box::leak(Box::new(FlushLimiter { permits: Semaphore::new(1), in_flight: AtomicUsize::new(0) }));
+        Box::new(QueuedSink::spawn(Box::new(sink), kind, config, "container".to_string(), limiter))
+    }
+
+    #[tokio::test]
+    async fn dispatch_writes_to_every_sink() {
+        let first = MockSink::default();
+        let second = MockSink::default();
+        let sinks: Vec<Box<dyn LogSink>> = vec![Box::new(first.clone()), Box::new(second.clone())];
+
+        dispatch(&sinks, &message("container", "hello")).await;
+
+        assert_eq!(first.lines(), ["hello"]);
+        assert_eq!(second.lines(), ["hello"]);
+    }
+
+    #[tokio::test]
+    async fn failing_sink_doesnt_hold_up_the_others() {
+        let broken = MockSink::failing();
+        let working = MockSink::default();
+        let sinks: Vec<Box<dyn LogSink>> = vec![Box::new(broken.clone()), Box::new(working.clone())];
+
+        dispatch(&sinks, &message("container", "first")).await;
+        dispatch(&sinks, &message("container", "second")).await;
+
+        assert_eq!(broken.attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(working.lines(), ["first", "second"]);
+    }
+
+    #[test]
+    fn sink_kinds_parse() {
+        assert_eq!(SinkKind::parse("ClickHouse"), Some(SinkKind::ClickHouse));
+        assert_eq!(SinkKind::parse("kafka"), Some(SinkKind::Kafka));
+        assert_eq!(SinkKind::parse("s3"), Some(SinkKind::S3));
+        assert_eq!(SinkKind::parse("elasticsearch"), None);
+    }
+
+    #[test]
+    fn s3_sink_needs_its_config() {
+        let config = crate::logs::logs::tests::log_config();
+        assert!(new_sink(SinkKind::S3, &config, "container").is_err());
+    }
+
+    /// Records each batch's lines as (source, text), slowly enough for
+    /// batches of several containers to overlap, and the most it was
+    /// writing at once.
+    #[derive(Clone, Default)]
+    struct SlowSink {
+        rows: Arc<std::sync::Mutex<Vec<(String, String)>>>,
+        writing: Arc<AtomicUsize>,
+        most: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl LogSink for SlowSink {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        async fn write(&self, message: &LogMessage) -> Result<(), SinkError> {
+            self.write_batch(std::slice::from_ref(message)).await
+        }
+
+        async fn write_batch(&self, messages: &[LogMessage]) -> Result<(), SinkError> {
+            let now = self.writing.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+            self.rows.lock().unwrap().extend(messages.iter().map(|message| (message.source.clone(), message.text.clone())));
+            self.writing.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_flushes_keep_each_containers_lines_in_order() {
+        let config = crate::logs::logs::tests::log_config();
+        let limiter: &'static FlushLimiter = Box::leak(Box::new(FlushLimiter { permits: Semaphore::new(2), in_flight: AtomicUsize::new(0) }));
+        /* one table every container's lines go to */
+        let table = SlowSink::default();
+        let containers = ["a", "b", "c"];
+
+        let sinks: Vec<QueuedSink> = containers.iter()
+            .map(|container| QueuedSink::spawn(Box::new(table.clone()), SinkKind::ClickHouse, config.clone(), container.to_string(), limiter))
+            .collect();
+        let writers = containers.iter().zip(&sinks).map(|(container, sink)| async move {
+            for line in 0..300 {
+                sink.write(&message(container, &line.to_string())).await.unwrap();
+                if line % 50 == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+            sink.flush().await.unwrap();
+        });
+        futures::future::join_all(writers).await;
+
+        let rows = table.rows.lock().unwrap().clone();
+        assert_eq!(rows.len(), 900);
+        for container in containers {
+            let lines: Vec<usize> = rows.iter().filter(|(source, _)| source == container).map(|(_, text)| text.parse().unwrap()).collect();
+            assert_eq!(lines, (0..300).collect::<Vec<_>>(), "lines of {} out of order", container);
+        }
+        assert!(table.most.load(Ordering::SeqCst) <= 2);
+        assert_eq!(limiter.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn lines_a_failing_sink_refuses_are_dead_lettered() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = crate::logs::logs::tests::log_config();
+        config.dead_letter.dir = Some(dir.path().to_path_buf());
+        config.dead_letter.max_mb = 1;
+        let limiter: &'static FlushLimiter = Box::leak(Box::new(FlushLimiter { permits: Semaphore::new(1), in_flight: AtomicUsize::new(0) }));
+
+        let sink = QueuedSink::spawn(Box::new(MockSink::failing()), SinkKind::ClickHouse, config, "container".to_string(), limiter);
+        for line in ["first", "second", "third"] {
+            sink.write(&message("container", line)).await.unwrap();
+        }
+        sink.flush().await.unwrap();
+
+        let mut lines = Vec::new();
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let letter: serde_json::Value = serde_json::from_slice(&std::fs::read(entry.unwrap().path()).unwrap()).unwrap();
+            assert_eq!(letter["sink"], "clickhouse");
+            assert_eq!(letter["container_id"], "container");
+            lines.extend(letter["messages"].as_array().unwrap().iter().map(|message| message["text"].as_str().unwrap().to_string()));
+        }
+        lines.sort();
+        assert_eq!(lines, ["first", "second", "third"]);
+    }
+
+    #[tokio::test]
+    async fn failed_sink_is_skipped_until_its_retried() {
+        let config = LogConfig { sink_retry_interval: std::time::Duration::from_millis(200), ..crate::logs::logs::tests::log_config() };
+        let clickhouse = MockSink::failing();
+        let sink = queued(clickhouse.clone(), SinkKind::ClickHouse, config);
+        let settle = || tokio::time::sleep(std::time::Duration::from_millis(20));
+
+        sink.write(&message("container", "first")).await.unwrap();
+        settle().await;
+        sink.write(&message("container", "second")).await.unwrap();
+        settle().await;
+        /* the second line wasn't even tried */
+        assert_eq!(clickhouse.attempts.load(Ordering::SeqCst), 1);
+
+        clickhouse.failing.store(false, Ordering::SeqCst);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        sink.write(&message("container", "third")).await.unwrap();
+        sink.flush().await.unwrap();
+
+        assert_eq!(clickhouse.attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(clickhouse.lines(), ["third"]);
+    }
+}
@@ -1,94 +1,274 @@
+/* each module keeps its main code in a file of the same name, e.g. logs/logs.rs */
+#![allow(clippy::module_inception)]
+
+pub mod admin;
+pub mod build;
+pub mod config;
+pub mod doctor;
+pub mod engine;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod logs;
+pub mod server;
+pub mod telemetry;
 pub mod webhook;
 
-use hyper::body::to_bytes;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, StatusCode, Method, Error};
 use hyper::Server;
-use reqwest::{Client, Url};
+use hyper::header::CONTENT_TYPE;
+use reqwest::Url;
 
+use webhook::commit_status::spawn_commit_status_reporter;
 use webhook::webhook::handle_request as handle_webhook;
+use admin::admin::handle_request as handle_admin;
 
-use nixpacks::nixpacks::builder::docker::DockerBuilderOptions as NixpacksOptions;
-use nixpacks::nixpacks::plan::generator::GeneratePlanOptions;
-use nixpacks::{create_docker_image, generate_build_plan};
-
-use logs::logs::get_logs;
-use logs::logs::LogFilter;
+use build::artifacts::build_dir;
+use build::build_log::BuildLog;
+use build::disk::free_space_mb;
+use build::workdir;
+use engine::diff::diff_builds;
+use engine::events::{build_finished, event_lines, is_terminal};
+use engine::matrix::{expand, matrix_status, submit_matrix, MatrixRequest};
+use engine::pipeline::{build_info_from_value, get_dockerfile, get_sbom, get_status, parse_build_info, plan};
+use build::dockerfile;
+use build::sbom;
+use engine::refs::{build_refs, RefsRequest};
+use engine::queue::{spawn_workers, submit, submit_and_wait};
+use engine::reconcile::{reconcile_orphans, spawn_image_reconciler, spawn_workdir_sweeper, sweep_workdirs};
+use engine::reload::reload_on_sighup;
+use engine::state::AppState;
+use engine::history::{list_builds, to_csv};
+use engine::stats::build_stats;
+use config::config::Config;
+use logs::checkpoint::{resume_collections, Checkpoint};
+use logs::dead_letter::spawn_replayer;
+use logs::logs::{get_logs, merged, messages, wait_for_start};
+use logs::level::Level;
+use logs::logs::{LogFilter, LogMessage};
+use logs::logs::{resolve_container, ContainerError};
+use server::compression;
+use server::cors;
+use server::server::read_body;
+use server::upload::{extract_tarball, is_tarball, UploadParams};
+use telemetry::telemetry::request_span;
 use dotenv::dotenv;
 use serde::Deserialize;
 use serde_json::json;
-use sqlx::PgPool;
-use sqlx::postgres::PgPoolOptions;
 
-use git2::Repository;
-use tempfile::tempdir;
 use colored::*;
 use std::sync::Arc;
 use chrono::{Utc, DateTime};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, Semaphore};
+use tracing::{error, Instrument};
+use futures::StreamExt;
 
 extern crate chrono;
 extern crate chrono_tz;
+
 #[derive(Deserialize)]
-struct BuildInfo {
-	pub path: String,
-	pub name: String,
-	pub envs: Option<Vec<String>>,
-	pub build_options: DockerBuilderOptions,
+struct LogParams {
+	pub container_id: String,
+	pub start_time: DateTime<Utc>,
+	pub end_time: DateTime<Utc>,
+	#[serde(default)]
+	pub follow: bool,
+	pub tail: Option<String>,
+	/* json or ndjson to get the logs back in the response */
+	pub format: Option<String>,
+	/* instead of KAFKA_LOGS_TOPIC / CLICKHOUSE_LOGS_TABLE, from their allowlists */
+	pub log_topic: Option<String>,
+	pub log_table: Option<String>,
+	/* stop collecting once this build finishes */
+	pub build_id: Option<String>,
+	/* drop lines below this level, e.g. warn */
+	pub min_level: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct LogParams {
+struct LogStreamParams {
+	/* comma separated, each one as /logs takes it */
 	pub container_id: String,
 	pub start_time: DateTime<Utc>,
 	pub end_time: DateTime<Utc>,
+	pub tail: Option<String>,
+	pub min_level: Option<String>,
+}
+
+/* how long merged lines wait for earlier ones from slower containers */
+const MERGE_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+#[derive(Deserialize)]
+struct EventParams {
+	/* only events of builds of this repo, as the build's path */
+	pub repo: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BuildLogParams {
+	#[serde(default)]
+	pub offset: u64,
+}
+
+#[derive(Deserialize)]
+struct StatsParams {
+	/* defaults to STATS_WINDOW_DAYS ago */
+	pub since: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct HistoryParams {
+	pub repo: Option<String>,
+	pub status: Option<String>,
+	/* newest first, at most MAX_HISTORY_LIMIT */
+	pub limit: Option<i64>,
+	/* json (default) or csv */
+	pub format: Option<String>,
+}
+
+const DEFAULT_HISTORY_LIMIT: i64 = 100;
+const MAX_HISTORY_LIMIT: i64 = 1000;
+
+/// Whether `GET /builds` answers in CSV, by `?format=` or else `Accept`.
+/// `None` for a format it doesn't know.
+fn wants_csv(params: &HistoryParams, req: &Request<Body>) -> Option<bool> {
+	match params.format.as_deref() {
+		Some("csv") => return Some(true),
+		Some("json") => return Some(false),
+		Some(_) => return None,
+		None => {},
+	}
+
+	let accept = req.headers().get("Accept").and_then(|value| value.to_str().ok()).unwrap_or("");
+	Some(accept.contains("text/csv"))
+}
+
+#[derive(PartialEq)]
+enum LogFormat {
+	/* collect in the background and answer 202 right away */
+	Background,
+	Json,
+	Ndjson,
+}
+
+fn log_format(params: &LogParams, req: &Request<Body>) -> Option<LogFormat> {
+	match params.format.as_deref() {
+		Some("json") => return Some(LogFormat::Json),
+		Some("ndjson") => return Some(LogFormat::Ndjson),
+		Some(_) => return None,
+		None => {},
+	}
+
+	let accept = req.headers().get("Accept").and_then(|value| value.to_str().ok()).unwrap_or("");
+	if accept.contains("application/x-ndjson") {
+		Some(LogFormat::Ndjson)
+	} else {
+		Some(LogFormat::Background)
+	}
+}
+
+/// The two ids in `/builds/{a}/diff/{b}`.
+fn diff_ids(path: &str) -> Option<(String, String)> {
+	let (a, b) = path.strip_prefix("/builds/")?.split_once("/diff/")?;
+	if a.is_empty() || b.is_empty() || a.contains('/') || b.contains('/') {
+		return None;
+	}
+	Some((a.to_string(), b.to_string()))
+}
+
+/// The id in `/build/matrix/{id}`.
+fn matrix_id(path: &str) -> Option<String> {
+	let id = path.strip_prefix("/build/matrix/")?;
+	if id.is_empty() || id.contains('/') {
+		return None;
+	}
+	Some(id.to_string())
+}
+
+/// The id in `/build/{id}<suffix>`.
+fn build_id_in(path: &str, suffix: &str) -> Option<String> {
+	let id = path.strip_prefix("/build/")?.strip_suffix(suffix)?;
+	if id.is_empty() || id.contains('/') {
+		return None;
+	}
+	Some(id.to_string())
+}
+
+fn artifacts_id(path: &str) -> Option<String> {
+	build_id_in(path, "/artifacts")
+}
+
+fn dockerfile_id(path: &str) -> Option<String> {
+	build_id_in(path, "/dockerfile")
 }
 
-#[derive(Deserialize, Clone, Default, Debug)]
-pub struct DockerBuilderOptions {
-    pub name: Option<String>,
-    pub out_dir: Option<String>,
-    pub print_dockerfile: bool,
-    pub tags: Vec<String>,
-    pub labels: Vec<String>,
-    pub quiet: bool,
-    pub cache_key: Option<String>,
-    pub no_cache: bool,
-    pub inline_cache: bool,
-    pub cache_from: Option<String>,
-    pub platform: Vec<String>,
-    pub current_dir: bool,
-    pub no_error_without_start: bool,
-    pub incremental_cache_image: Option<String>,
-    pub verbose: bool,
+fn sbom_id(path: &str) -> Option<String> {
+	build_id_in(path, "/sbom")
 }
 
-fn convert_to_nixpacks_options(local_options: &DockerBuilderOptions) -> NixpacksOptions {
-	NixpacksOptions {
-        name: local_options.name.clone(),
-        out_dir: local_options.out_dir.clone(),
-        print_dockerfile: local_options.print_dockerfile,
-        tags: local_options.tags.clone(),
-        labels: local_options.labels.clone(),
-        quiet: local_options.quiet,
-        cache_key: local_options.cache_key.clone(),
-        no_cache: local_options.no_cache,
-        inline_cache: local_options.inline_cache,
-        cache_from: local_options.cache_from.clone(),
-        platform: local_options.platform.clone(),
-        current_dir: local_options.current_dir,
-        no_error_without_start: local_options.no_error_without_start,
-        incremental_cache_image: local_options.incremental_cache_image.clone(),
-        verbose: local_options.verbose,
-    	cpu_quota: todo!(),
-    	memory: todo!(),
-    }
+fn log_id(path: &str) -> Option<String> {
+	build_id_in(path, "/log")
 }
 
-async fn handle(req: Request<Body>, db_pool: Arc<PgPool>) -> Result<Response<Body>, Error> {
+/// The answer to GET /build/{id}/sbom for what's `stored` on build `id`.
+fn sbom_response(id: &str, stored: Option<Option<Vec<u8>>>) -> Response<Body> {
+	let stored = match stored {
+		Some(Some(stored)) => stored,
+		Some(None) => {
+			return Response::builder()
+			.status(StatusCode::NOT_FOUND)
+			.body(Body::from(format!("No SBOM was generated for build {}", id)))
+			.unwrap();
+		},
+		None => {
+			return Response::builder()
+			.status(StatusCode::NOT_FOUND)
+			.body(Body::from(format!("No build with id {}", id)))
+			.unwrap();
+		},
+	};
+
+	match sbom::decode(&stored) {
+		Ok(document) => Response::builder()
+			.status(StatusCode::OK)
+			.header("Content-Type", "application/json")
+			.body(Body::from(document))
+			.unwrap(),
+		Err(e) => {
+			error!("Stored SBOM of build {} is unreadable: {}", id, e);
+			Response::builder()
+				.status(StatusCode::INTERNAL_SERVER_ERROR)
+				.body(Body::from("Stored SBOM is unreadable"))
+				.unwrap()
+		}
+	}
+}
+
+async fn handle(req: Request<Body>, state: Arc<AppState>) -> Result<Response<Body>, Error> {
+	let config = state.config();
+	if let Some(response) = cors::preflight(&config.cors, &req) {
+		return Ok(response);
+	}
+
+	let origin = cors::origin(&req);
+	let accepts_gzip = compression::accepts_gzip(&req);
+	let mut response = route(req, state).await?;
+	cors::apply(&config.cors, origin.as_deref(), &mut response);
+	compression::compress(&config.compression, accepts_gzip, response).await
+}
+
+async fn route(req: Request<Body>, state: Arc<AppState>) -> Result<Response<Body>, Error> {
+	/* a reload mid-request doesn't change what this request sees */
+	let config = state.config();
+
 	match (req.method(), req.uri().path()) {
 
+		(&Method::GET, "/") if !config.enable_landing_page => {
+			Ok(Response::builder()
+				.status(StatusCode::NOT_FOUND)
+				.body(Body::from("Not found"))
+				.unwrap())
+		},
 		(&Method::GET, "/") => {
 			let html = r#"<!DOCTYPE html>
 			<html>
@@ -134,141 +314,651 @@ async fn handle(req: Request<Body>, db_pool: Arc<PgPool>) -> Result<Response<Bod
 
 			Ok(response)
 		},
+		(&Method::GET, "/readyz") => {
+			let (status_code, body) = if state.queue.is_accepting() {
+				(StatusCode::OK, "ready")
+			} else {
+				(StatusCode::SERVICE_UNAVAILABLE, "draining")
+			};
+
+			Ok(Response::builder()
+				.status(status_code)
+				.body(Body::from(body))
+				.unwrap())
+		},
 		(&Method::POST, "/webhook") => {
-			handle_webhook(req).await
+			handle_webhook(req, state).await
+		}
+		(_, path) if path.starts_with("/admin/") => {
+			handle_admin(req, state).await
 		}
 
-		(&Method::POST, "/build") => {				
-			let whole_body = to_bytes(req.into_body()).await?;
+		(&Method::POST, "/build") => {
+			/* keeps an uploaded source tree around until its build is done */
+			let mut _upload_dir = None;
 
-			let repo_dir;
+			let content_type = req.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok());
+			let parsed = if is_tarball(content_type) {
+				let params: UploadParams = match serde_urlencoded::from_str(req.uri().query().unwrap_or("")) {
+					Ok(params) => params,
+					Err(_) => {
+						return Ok(Response::builder()
+							.status(StatusCode::BAD_REQUEST)
+							.body(Body::from("Tarball builds need ?name=<image name>"))
+							.unwrap());
+					}
+				};
 
-			let build_info: BuildInfo = match serde_json::from_slice(&whole_body) {
+				let dir = match workdir::create(&config.workdir_base, "upload") {
+					Ok(dir) => dir,
+					Err(e) => {
+						return Ok(Response::builder()
+							.status(StatusCode::INTERNAL_SERVER_ERROR)
+							.body(Body::from(format!("Failed to create working dir: {}", e)))
+							.unwrap());
+					}
+				};
+
+				let max_bytes = config.max_upload_mb * 1024 * 1024;
+				if let Err(response) = extract_tarball(req.into_body(), dir.path(), max_bytes, config.http.body_read_timeout).await {
+					return Ok(response);
+				}
+
+				let request = json!({ "path": dir.path().display().to_string(), "name": params.name, "profile": params.profile });
+				_upload_dir = Some(dir);
+				build_info_from_value(request, &config)
+			} else {
+				let whole_body = match read_body(req.into_body(), config.http.body_read_timeout).await? {
+					Ok(body) => body,
+					Err(response) => return Ok(response),
+				};
+				parse_build_info(&whole_body, &config)
+			};
+
+			let build_info = match parsed {
 				Ok(info) => info,
-				Err(_) => {
+				Err(e) => {
 				let response = Response::builder()
-					.status(StatusCode::BAD_REQUEST)
-					.body(Body::from("Invalid request body"))
+					.status(e.status)
+					.body(Body::from(e.message))
 					.unwrap();
 				return Ok(response);
 				}
 			};
 
-			if std::path::Path::new(&build_info.path).is_dir() {
-				repo_dir = build_info.path.clone();
-			} else {
-				let temp_dir = tempdir().expect("Failed to create temp dir");
-				repo_dir = temp_dir.path().	display().to_string();
-				match Repository::clone(&build_info.path, &repo_dir) {
-					Ok(_) => eprintln!("Cloned repo successfully"),
+			let outcome = match submit_and_wait(&state, build_info).await {
+				Ok(outcome) => outcome,
+				Err(e) => {
+					let response = Response::builder()
+						.status(e.status)
+						.body(Body::from(e.message))
+						.unwrap();
+					return Ok(response);
+				}
+			};
+
+			let status_code = if outcome.succeeded() { StatusCode::OK } else { StatusCode::INTERNAL_SERVER_ERROR };
+
+			let response = Response::builder()
+				.status(status_code)
+				.header("Content-Type", "application/json")
+				.body(Body::from(json!(outcome).to_string()))
+				.unwrap();
+
+			Ok(response)
+		},
+		(&Method::GET, "/stats") => {
+			let params: StatsParams = match serde_urlencoded::from_str(req.uri().query().unwrap_or("")) {
+				Ok(params) => params,
+				Err(_) => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("since must be an RFC3339 timestamp"))
+					.unwrap());
+				}
+			};
+			let since = params.since.unwrap_or_else(|| Utc::now() - chrono::Duration::days(config.stats_window_days));
+
+			match build_stats(&state, since).await {
+				Ok(stats) => Ok(Response::builder()
+					.status(StatusCode::OK)
+					.header("Content-Type", "application/json")
+					.body(Body::from(json!(stats).to_string()))
+					.unwrap()),
+				Err(e) => {
+					error!("Failed to aggregate build stats: {}", e);
+					Ok(Response::builder()
+						.status(StatusCode::SERVICE_UNAVAILABLE)
+						.body(Body::from("Failed to aggregate build stats"))
+						.unwrap())
+				}
+			}
+		},
+		(&Method::GET, "/builds") => {
+			let params: HistoryParams = match serde_urlencoded::from_str(req.uri().query().unwrap_or("")) {
+				Ok(params) => params,
+				Err(_) => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("limit must be a number"))
+					.unwrap());
+				}
+			};
+			let csv = match wants_csv(&params, &req) {
+				Some(csv) => csv,
+				None => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("format must be json or csv"))
+					.unwrap());
+				}
+			};
+			let limit = params.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).clamp(1, MAX_HISTORY_LIMIT);
+
+			match list_builds(&state, params.repo.as_deref(), params.status.as_deref(), limit).await {
+				Ok(builds) if csv => Ok(Response::builder()
+					.status(StatusCode::OK)
+					.header("Content-Type", "text/csv; charset=utf-8")
+					.body(Body::from(to_csv(&builds)))
+					.unwrap()),
+				Ok(builds) => Ok(Response::builder()
+					.status(StatusCode::OK)
+					.header("Content-Type", "application/json")
+					.body(Body::from(json!(builds).to_string()))
+					.unwrap()),
+				Err(e) => {
+					error!("Failed to list builds: {}", e);
+					Ok(Response::builder()
+						.status(StatusCode::SERVICE_UNAVAILABLE)
+						.body(Body::from("Failed to list builds"))
+						.unwrap())
+				}
+			}
+		},
+		(&Method::POST, "/build/warm") => {
+			let whole_body = match read_body(req.into_body(), config.http.body_read_timeout).await? {
+				Ok(body) => body,
+				Err(response) => return Ok(response),
+			};
+
+			let mut build_info = match parse_build_info(&whole_body, &config) {
+				Ok(info) => info,
+				Err(e) => {
+					return Ok(Response::builder()
+						.status(e.status)
+						.body(Body::from(e.message))
+						.unwrap());
+				}
+			};
+			build_info.warm = true;
+
+			let outcome = match submit_and_wait(&state, build_info).await {
+				Ok(outcome) => outcome,
+				Err(e) => {
+					return Ok(Response::builder()
+						.status(e.status)
+						.body(Body::from(e.message))
+						.unwrap());
+				}
+			};
+
+			let status_code = if outcome.succeeded() { StatusCode::OK } else { StatusCode::INTERNAL_SERVER_ERROR };
+
+			Ok(Response::builder()
+				.status(status_code)
+				.header("Content-Type", "application/json")
+				.body(Body::from(json!(outcome).to_string()))
+				.unwrap())
+		},
+		(&Method::POST, "/plan") => {
+			let whole_body = match read_body(req.into_body(), config.http.body_read_timeout).await? {
+				Ok(body) => body,
+				Err(response) => return Ok(response),
+			};
+
+			let planned = match parse_build_info(&whole_body, &config) {
+				Ok(build_info) => plan(&state, &build_info).await,
+				Err(e) => Err(e),
+			};
+
+			match planned {
+				Ok((commit, plan)) => Ok(Response::builder()
+					.status(StatusCode::OK)
+					.header("Content-Type", "application/json")
+					.body(Body::from(json!({ "commit": commit, "plan": plan }).to_string()))
+					.unwrap()),
+				Err(e) => Ok(Response::builder()
+					.status(e.status)
+					.body(Body::from(e.message))
+					.unwrap()),
+			}
+		},
+		(&Method::POST, "/builds/batch") => {
+			let whole_body = match read_body(req.into_body(), config.http.body_read_timeout).await? {
+				Ok(body) => body,
+				Err(response) => return Ok(response),
+			};
+
+			let requests: Vec<serde_json::Value> = match serde_json::from_slice(&whole_body) {
+				Ok(requests) => requests,
+				Err(_) => {
+					let response = Response::builder()
+						.status(StatusCode::BAD_REQUEST)
+						.body(Body::from("Invalid request body, expected an array of builds"))
+						.unwrap();
+					return Ok(response);
+				}
+			};
+
+			/* each build is queued on its own, so one bad entry doesn't sink the batch */
+			let mut results = Vec::with_capacity(requests.len());
+			let mut failed = false;
+
+			for request in requests {
+				let queued = match build_info_from_value(request, &config) {
+					Ok(build_info) => submit(&state, build_info).await,
+					Err(e) => Err(e),
+				};
+
+				results.push(match queued {
+					Ok(id) => json!({ "status": StatusCode::ACCEPTED.as_u16(), "id": id }),
 					Err(e) => {
-						let response = Response::builder()
-							.status(StatusCode::BAD_REQUEST)
-							.body(Body::from(format!("Failed to clone repository: {}", e)))
-							.unwrap();
-						return Ok(response);
+						failed = true;
+						json!({ "status": e.status.as_u16(), "error": e.message })
 					}
-				}
+				});
 			}
 
-			if build_info.path.is_empty() || build_info.name.is_empty() {
-				let response = Response::builder()
+			let status_code = if failed { StatusCode::MULTI_STATUS } else { StatusCode::ACCEPTED };
+
+			let response = Response::builder()
+				.status(status_code)
+				.header("Content-Type", "application/json")
+				.body(Body::from(json!(results).to_string()))
+				.unwrap();
+
+			Ok(response)
+		},
+		(&Method::POST, "/build/matrix") => {
+			let whole_body = match read_body(req.into_body(), config.http.body_read_timeout).await? {
+				Ok(body) => body,
+				Err(response) => return Ok(response),
+			};
+
+			let request: MatrixRequest = match serde_json::from_slice(&whole_body) {
+				Ok(request) => request,
+				Err(_) => {
+					return Ok(Response::builder()
 					.status(StatusCode::BAD_REQUEST)
-					.body(Body::from("Missing required fields"))
-					.unwrap();
-				return Ok(response)
+					.body(Body::from("Invalid request body, expected a base build and a list of variants"))
+					.unwrap());
+				}
+			};
+
+			let builds = match expand(request, &state) {
+				Ok(builds) => builds,
+				Err(e) => {
+					return Ok(Response::builder()
+					.status(e.status)
+					.body(Body::from(e.message))
+					.unwrap());
+				}
+			};
+
+			let (id, results) = submit_matrix(&state, builds).await;
+
+			Ok(Response::builder()
+				.status(StatusCode::ACCEPTED)
+				.header("Content-Type", "application/json")
+				.body(Body::from(json!({ "id": id, "builds": results }).to_string()))
+				.unwrap())
+		},
+		(&Method::POST, "/build/refs") => {
+			let whole_body = match read_body(req.into_body(), config.http.body_read_timeout).await? {
+				Ok(body) => body,
+				Err(response) => return Ok(response),
+			};
+
+			let request: RefsRequest = match serde_json::from_slice(&whole_body) {
+				Ok(request) => request,
+				Err(_) => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("Invalid request body, expected a base build and a list of refs"))
+					.unwrap());
+				}
+			};
+
+			let builds = match engine::refs::expand(request, &state) {
+				Ok(builds) => builds,
+				Err(e) => {
+					return Ok(Response::builder()
+					.status(e.status)
+					.body(Body::from(e.message))
+					.unwrap());
+				}
+			};
+
+			let (succeeded, results) = build_refs(&state, builds).await;
+			let status_code = if succeeded { StatusCode::OK } else { StatusCode::MULTI_STATUS };
+
+			Ok(Response::builder()
+				.status(status_code)
+				.header("Content-Type", "application/json")
+				.body(Body::from(json!({ "refs": results }).to_string()))
+				.unwrap())
+		},
+		(&Method::GET, path) if matrix_id(path).is_some() => {
+			let id = matrix_id(path).unwrap();
+
+			match matrix_status(&state, &id).await {
+				Ok(Some(status)) => Ok(Response::builder()
+					.status(StatusCode::OK)
+					.header("Content-Type", "application/json")
+					.body(Body::from(json!(status).to_string()))
+					.unwrap()),
+				Ok(None) => Ok(Response::builder()
+					.status(StatusCode::NOT_FOUND)
+					.body(Body::from(format!("No matrix with id {}", id)))
+					.unwrap()),
+				Err(e) => {
+					error!("Error looking up matrix {}: {}", id, e);
+					Ok(Response::builder()
+						.status(StatusCode::SERVICE_UNAVAILABLE)
+						.body(Body::from("Failed to look up matrix"))
+						.unwrap())
+				}
 			}
+		},
+		(&Method::GET, path) if artifacts_id(path).is_some() => {
+			let id = artifacts_id(path).unwrap();
 
-			let mut conn = db_pool.acquire().await.unwrap();
-			let plan_options = GeneratePlanOptions::default(); // Generate default options
-			
-			
-			let envs: Vec<&str> = if let Some(inner_vec) = &build_info.envs {
-				inner_vec.iter().map(|inner_str| inner_str.as_ref()).collect()
-			} else {
-				Vec::new()
+			let record = match get_status(&state, &id).await {
+				Ok(Some(record)) => record,
+				Ok(None) => {
+					return Ok(Response::builder()
+					.status(StatusCode::NOT_FOUND)
+					.body(Body::from(format!("No build with id {}", id)))
+					.unwrap());
+				},
+				Err(e) => {
+					error!("Error looking up build {}: {}", id, e);
+					return Ok(Response::builder()
+					.status(StatusCode::SERVICE_UNAVAILABLE)
+					.body(Body::from("Failed to look up build"))
+					.unwrap());
+				}
 			};
 
-			let plan = generate_build_plan(
-				&build_info.path,
-				envs,
-				&plan_options
-			);
+			let dir = build_dir(&config.artifact_dir, &id);
+			/* the listing is kept after retention removed the files */
+			let pruned = record.artifacts.is_some() && !dir.is_dir();
 
-			let nixpack_options = convert_to_nixpacks_options(&build_info.build_options);
+			Ok(Response::builder()
+				.status(StatusCode::OK)
+				.header("Content-Type", "application/json")
+				.body(Body::from(json!({ "id": id, "dir": dir, "artifacts": record.artifacts.unwrap_or_default(), "pruned": pruned }).to_string()))
+				.unwrap())
+		},
+		(&Method::GET, path) if log_id(path).is_some() => {
+			let id = log_id(path).unwrap();
 
-			let start_time = Utc::now().to_rfc3339();
-			let build_if = format!("{}:{}", &build_info.path, &start_time);
+			let params: BuildLogParams = match serde_urlencoded::from_str(req.uri().query().unwrap_or("")) {
+				Ok(params) => params,
+				Err(_) => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("offset must be a number"))
+					.unwrap());
+				}
+			};
+
+			let record = match get_status(&state, &id).await {
+				Ok(Some(record)) => record,
+				Ok(None) => {
+					return Ok(Response::builder()
+					.status(StatusCode::NOT_FOUND)
+					.body(Body::from(format!("No build with id {}", id)))
+					.unwrap());
+				},
+				Err(e) => {
+					error!("Error looking up build {}: {}", id, e);
+					return Ok(Response::builder()
+					.status(StatusCode::SERVICE_UNAVAILABLE)
+					.body(Body::from("Failed to look up build"))
+					.unwrap());
+				}
+			};
 
-			/* Insert build data once build is triggered */
-			match sqlx::query("INSERT into build_data (id, start_time, status) VALUES ($1, $2, $3)")
-				.bind(&build_if)
-				.bind(&start_time)
-				.bind("running")
-				.execute(&mut conn)
-				.await {
-				Ok(_) => eprintln!("DB insert success"),
-				Err(e) => eprintln!("DB insert error: {}", e), // Or handle the error more properly
+			/* X-Log-Length is where the next poll picks up, X-Build-Status says whether more can come */
+			let log = BuildLog::new(&config.build_log_dir, &id);
+			match log.read_from(params.offset) {
+				Ok(Some((bytes, length))) => Ok(Response::builder()
+					.status(StatusCode::OK)
+					.header("Content-Type", "text/plain; charset=utf-8")
+					.header("X-Log-Offset", params.offset)
+					.header("X-Log-Length", length)
+					.header("X-Build-Status", record.status)
+					.body(Body::from(bytes))
+					.unwrap()),
+				Ok(None) => {
+					let length = log.length().unwrap_or_default();
+					Ok(Response::builder()
+						.status(StatusCode::RANGE_NOT_SATISFIABLE)
+						.header("Content-Range", format!("bytes */{}", length))
+						.header("X-Log-Length", length)
+						.body(Body::from(format!("offset {} is past the end of the log ({} bytes)", params.offset, length)))
+						.unwrap())
+				},
+				Err(e) => {
+					error!("Failed to read the log of build {}: {}", id, e);
+					Ok(Response::builder()
+						.status(StatusCode::INTERNAL_SERVER_ERROR)
+						.body(Body::from("Failed to read the build log"))
+						.unwrap())
+				}
 			}
-			
-			let envs: Vec<&str> = if let Some(inner_vec) = &build_info.envs {
-				inner_vec.iter().map(|inner_str| inner_str.as_ref()).collect()
-			} else {
-				Vec::new()
-			};
-
-			let result = create_docker_image(
-				&repo_dir,
-				envs,
-				&plan_options,
-				&nixpack_options,
-			).await;
-
-            /* need to port  registry server from old repo(: 
-			let status = match result {
-				Ok(_) => {
-					let client = Client::new();
-					let registry_post_data = json!({
-						"image_name": build_info.name,
-						"image_tag": build_info.build_options.tags.get(0).unwrap_or(&"latest".to_string())
-					});
-
-					let push_result = client.post("http://localhost:8083/push")
-						.json(&registry_post_data)
-						.send()
-						.await;
-
-					match push_result {
-						Ok(_) => "Completed",
-						Err(_) => "Failed"
-					}
+		},
+		(&Method::GET, path) if dockerfile_id(path).is_some() => {
+			let id = dockerfile_id(path).unwrap();
+
+			let stored = match get_dockerfile(&state, &id).await {
+				Ok(Some(Some(stored))) => stored,
+				Ok(Some(None)) => {
+					return Ok(Response::builder()
+					.status(StatusCode::NOT_FOUND)
+					.body(Body::from(format!("No Dockerfile was captured for build {}", id)))
+					.unwrap());
+				},
+				Ok(None) => {
+					return Ok(Response::builder()
+					.status(StatusCode::NOT_FOUND)
+					.body(Body::from(format!("No build with id {}", id)))
+					.unwrap());
 				},
-				Err(_) => "Failed"
-			};
-            */
-
-			let end_time = Utc::now().to_rfc3339();
-			
-			match sqlx::query("UPDATE build_data SET status = $1, end_time = $2 WHERE id = $3")
-				.bind(status)
-				.bind(&end_time)
-				.bind(&build_if)
-				.execute(&mut conn)
-				.await {
-				Ok(_) => eprintln!("DB updated"),
-				Err(e) => eprintln!("DB update error: {}", e), // Or handle the error more properly
+				Err(e) => {
+					error!("Error looking up build {}: {}", id, e);
+					return Ok(Response::builder()
+					.status(StatusCode::SERVICE_UNAVAILABLE)
+					.body(Body::from("Failed to look up build"))
+					.unwrap());
+				}
+			};
+
+			match dockerfile::decode(&stored) {
+				Ok(contents) => Ok(Response::builder()
+					.status(StatusCode::OK)
+					.header("Content-Type", "text/plain; charset=utf-8")
+					.body(Body::from(contents))
+					.unwrap()),
+				Err(e) => {
+					error!("Stored Dockerfile of build {} is unreadable: {}", id, e);
+					Ok(Response::builder()
+						.status(StatusCode::INTERNAL_SERVER_ERROR)
+						.body(Body::from("Stored Dockerfile is unreadable"))
+						.unwrap())
+				}
 			}
+		},
+		(&Method::GET, path) if sbom_id(path).is_some() => {
+			let id = sbom_id(path).unwrap();
 
-			let _ = match result {
-				Ok(_) => Ok(Response::new(Body::from("Image created."))),
-				Err(e) => Err({
-					let mut response = Response::new(Body::from(format!("Failed to create image: {}", e)));
-					*response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-					response
-				})
+			match get_sbom(&state, &id).await {
+				Ok(stored) => Ok(sbom_response(&id, stored)),
+				Err(e) => {
+					error!("Error looking up build {}: {}", id, e);
+					Ok(Response::builder()
+					.status(StatusCode::SERVICE_UNAVAILABLE)
+					.body(Body::from("Failed to look up build"))
+					.unwrap())
+				}
+			}
+		},
+		(&Method::GET, path) if diff_ids(path).is_some() => {
+			let (a, b) = diff_ids(path).unwrap();
+
+			let mut records = Vec::with_capacity(2);
+			for id in [&a, &b] {
+				match get_status(&state, id).await {
+					Ok(Some(record)) => records.push(record),
+					Ok(None) => {
+						return Ok(Response::builder()
+						.status(StatusCode::NOT_FOUND)
+						.body(Body::from(format!("No build with id {}", id)))
+						.unwrap());
+					},
+					Err(e) => {
+						error!("Error looking up build {}: {}", id, e);
+						return Ok(Response::builder()
+						.status(StatusCode::SERVICE_UNAVAILABLE)
+						.body(Body::from("Failed to look up build"))
+						.unwrap());
+					}
+				}
+			}
+
+			let changes = diff_builds(&records[0], &records[1]);
+
+			Ok(Response::builder()
+				.status(StatusCode::OK)
+				.header("Content-Type", "application/json")
+				.body(Body::from(json!({ "a": a, "b": b, "changes": changes }).to_string()))
+				.unwrap())
+		},
+		(&Method::GET, "/events") => {
+			let params: EventParams = match serde_urlencoded::from_str(req.uri().query().unwrap_or("")) {
+				Ok(params) => params,
+				Err(_) => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("Invalid request paramaters"))
+					.unwrap());
+				}
 			};
 
-			Ok(Response::new(Body::from("Image created.")))
+			let lines = event_lines(state.events.subscribe(), params.repo).map(Ok::<_, std::convert::Infallible>);
+
+			Ok(Response::builder()
+				.status(StatusCode::OK)
+				.header("Content-Type", "application/x-ndjson")
+				.body(Body::wrap_stream(lines))
+				.unwrap())
+		},
+		(&Method::GET, "/logs/stream") => {
+			let params: LogStreamParams = match serde_urlencoded::from_str(req.uri().query().unwrap_or("")) {
+				Ok(params) => params,
+				Err(_) => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("Invalid request paramaters"))
+					.unwrap());
+				}
+			};
+			let min_level = match params.min_level.as_deref().map(Level::parse) {
+				None => None,
+				Some(Some(level)) => Some(level),
+				Some(None) => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("min_level must be trace, debug, info, warn or error"))
+					.unwrap());
+				}
+			};
+
+			let mut container_ids = Vec::new();
+			for id_or_name in params.container_id.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+				match resolve_container(id_or_name).await {
+					Ok(container_id) if !container_ids.contains(&container_id) => container_ids.push(container_id),
+					Ok(_) => {},
+					Err(ContainerError::NotFound) => {
+						return Ok(Response::builder()
+						.status(StatusCode::NOT_FOUND)
+						.body(Body::from(format!("No such container: {}", id_or_name)))
+						.unwrap());
+					},
+					Err(ContainerError::Ambiguous(candidates)) => {
+						return Ok(Response::builder()
+						.status(StatusCode::NOT_FOUND)
+						.header("Content-Type", "application/json")
+						.body(Body::from(json!({ "error": format!("Ambiguous container: {}", id_or_name), "candidates": candidates }).to_string()))
+						.unwrap());
+					},
+					Err(e) => {
+						error!("Can't collect logs for {}: {}", id_or_name, e);
+						return Ok(Response::builder()
+						.status(StatusCode::SERVICE_UNAVAILABLE)
+						.body(Body::from("Docker is unavailable, logs can't be collected"))
+						.unwrap());
+					}
+				}
+			}
+			if container_ids.is_empty() {
+				return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("container_id must name at least one container"))
+					.unwrap());
+			}
+
+			/* one collection per container as /logs would run it, merged on the way out */
+			let mut receivers = Vec::new();
+			for container_id in container_ids {
+				let (tx, rx) = broadcast::channel(100);
+				receivers.push(rx);
+
+				let filter = LogFilter {
+					start_time: params.start_time,
+					end_time: params.end_time,
+					follow: true,
+					tail: params.tail.clone(),
+					min_level,
+				};
+				let log_config = config.logs.clone();
+				tokio::spawn(async move {
+					/* sidecars may come up after the rest, their lines join the stream once they do */
+					match wait_for_start(&container_id, filter.end_time).await {
+						Ok(true) => {},
+						Ok(false) => {
+							let _ = tx.send(LogMessage::marker(&container_id, "container didn't start before end_time".to_string()));
+							return;
+						},
+						Err(e) => {
+							error!("Error getting logs for {}: {}", container_id, e);
+							return;
+						}
+					}
+					if let Err(e) = get_logs(&container_id, filter, tx, &log_config, futures::future::pending(), None).await {
+						error!("Error getting logs for {}: {}", container_id, e);
+					}
+				});
+			}
+
+			let lines = merged(receivers, MERGE_DELAY).map(|message| Ok::<_, std::convert::Infallible>(json!(message).to_string() + "\n"));
+
+			Ok(Response::builder()
+				.status(StatusCode::OK)
+				.header("Content-Type", "application/x-ndjson")
+				.body(Body::wrap_stream(lines))
+				.unwrap())
 		},
 		(&Method::GET, "/logs") => {
 			let url = Url::parse(&("http://localhost".to_string() + req.uri().path_and_query().map(|x| x.as_str()).unwrap_or(""))).unwrap();
@@ -283,16 +973,148 @@ async fn handle(req: Request<Body>, db_pool: Arc<PgPool>) -> Result<Response<Bod
 				}
 			};
 
-			let (tx, _) = broadcast::channel(100);
-			let filter = LogFilter { start_time: params.start_time, end_time: params.end_time };
+			let format = match log_format(&params, &req) {
+				Some(format) => format,
+				None => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("format must be json or ndjson"))
+					.unwrap());
+				}
+			};
+			let min_level = match params.min_level.as_deref().map(Level::parse) {
+				None => None,
+				Some(Some(level)) => Some(level),
+				Some(None) => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from("min_level must be trace, debug, info, warn or error"))
+					.unwrap());
+				}
+			};
+
+			/* short ids and names are resolved, lines are collected and labelled under the full id */
+			let container_id = match resolve_container(&params.container_id).await {
+				Ok(container_id) => container_id,
+				Err(ContainerError::NotFound) => {
+					return Ok(Response::builder()
+					.status(StatusCode::NOT_FOUND)
+					.body(Body::from(format!("No such container: {}", params.container_id)))
+					.unwrap());
+				},
+				Err(ContainerError::Ambiguous(candidates)) => {
+					return Ok(Response::builder()
+					.status(StatusCode::NOT_FOUND)
+					.header("Content-Type", "application/json")
+					.body(Body::from(json!({ "error": format!("Ambiguous container: {}", params.container_id), "candidates": candidates }).to_string()))
+					.unwrap());
+				},
+				Err(e) => {
+					error!("Can't collect logs for {}: {}", params.container_id, e);
+					return Ok(Response::builder()
+					.status(StatusCode::SERVICE_UNAVAILABLE)
+					.body(Body::from("Docker is unavailable, logs can't be collected"))
+					.unwrap());
+				}
+			};
+
+			let (tx, rx) = broadcast::channel(100);
+			let mut filter = LogFilter {
+				start_time: params.start_time,
+				end_time: params.end_time,
+				follow: params.follow,
+				tail: params.tail.clone(),
+				min_level,
+			};
+
+			/* subscribed before the status is read, so the build finishing in between isn't missed */
+			let events = state.events.subscribe();
+			if let Some(build_id) = &params.build_id {
+				match get_status(&state, build_id).await {
+					Ok(Some(record)) => {
+						if is_terminal(&record.status) {
+							/* nothing more is coming, read what's there */
+							filter.follow = false;
+						}
+					},
+					Ok(None) => {
+						return Ok(Response::builder()
+						.status(StatusCode::NOT_FOUND)
+						.body(Body::from(format!("No such build: {}", build_id)))
+						.unwrap());
+					},
+					Err(e) => {
+						error!("Failed to look up build {}: {}", build_id, e);
+						return Ok(Response::builder()
+						.status(StatusCode::SERVICE_UNAVAILABLE)
+						.body(Body::from("Failed to look up build"))
+						.unwrap());
+					}
+				}
+			}
+			let build_id = params.build_id.clone();
+			let stop = async move {
+				match build_id {
+					Some(build_id) => build_finished(events, build_id).await,
+					None => futures::future::pending().await,
+				}
+			};
+			let log_config = match config.logs.with_destinations(params.log_topic.as_deref(), params.log_table.as_deref()) {
+				Ok(log_config) => log_config,
+				Err(e) => {
+					return Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.body(Body::from(e))
+					.unwrap());
+				}
+			};
+
+			/* only what goes to a sink is worth resuming, a live reader is gone after a restart */
+			let checkpoints = state.log_checkpoints.clone().filter(|_| filter.follow && !log_config.sinks.is_empty());
+			if let Some(checkpoints) = &checkpoints {
+				checkpoints.start(&Checkpoint {
+					container_id: container_id.clone(),
+					start_time: filter.start_time,
+					end_time: filter.end_time,
+					last_seen: None,
+					build_id: params.build_id.clone(),
+					log_topic: log_config.kafka.topic.clone(),
+					log_table: log_config.clickhouse.table.clone(),
+					min_level: filter.min_level,
+				}).await;
+			}
 
 			tokio::spawn(async move {
-				if let Err(e) = get_logs(&params.container_id, filter, tx).await {
-					format!("Error getting logs: {}", e);
+				if let Err(e) = get_logs(&container_id, filter, tx, &log_config, stop, checkpoints.as_ref()).await {
+					error!("Error getting logs for {}: {}", container_id, e);
 				}
 			});
-			
-			Ok(Response::new(Body::from("Logs are being collected.")))
+
+			match format {
+				LogFormat::Background => Ok(Response::builder()
+					.status(StatusCode::ACCEPTED)
+					.body(Body::from("Logs are being collected."))
+					.unwrap()),
+				LogFormat::Json => {
+					let collected: Vec<_> = messages(rx).collect().await;
+
+					Ok(Response::builder()
+						.status(StatusCode::OK)
+						.header("Content-Type", "application/json")
+						.body(Body::from(json!(collected).to_string()))
+						.unwrap())
+				},
+				/* one chunk per line, so each is sent as soon as it's collected */
+				LogFormat::Ndjson => {
+					let lines = messages(rx).map(|message| Ok::<_, std::convert::Infallible>(json!(message).to_string() + "\n"));
+
+					Ok(Response::builder()
+						.status(StatusCode::OK)
+						.header("Content-Type", "application/x-ndjson")
+						.body(Body::wrap_stream(lines))
+						.unwrap())
+				},
+			}
 
 		}
 		
@@ -310,34 +1132,176 @@ async fn handle(req: Request<Body>, db_pool: Arc<PgPool>) -> Result<Response<Bod
 async fn main() {	
 	dotenv().ok();
 
-	let db_url = std::env::var("COCKROACH_DB_URL")
-		.expect("COCKROACH_DB_URL must be set");
+	/* forge doctor / --preflight checks the deployment and exits without serving */
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	if args.first().is_some_and(|arg| arg == "doctor" || arg == "--preflight") {
+		let skip: Vec<String> = args.iter()
+			.filter_map(|arg| arg.strip_prefix("--skip="))
+			.flat_map(|names| names.split(','))
+			.map(|name| name.trim().to_string())
+			.collect();
+		std::process::exit(doctor::doctor::run(&skip).await);
+	}
+
+	telemetry::telemetry::init();
+
+	let config = Config::from_env().expect("Invalid configuration");
 
-	let db_pool = Arc::new(
-		PgPoolOptions::new()
-			.max_connections(5)
-			.connect(&db_url)
-			.await
-			.expect("Failed to connect to DB")
+	println!(
+		"DB pool: max {} connections, acquire timeout {:?}, idle timeout {:?}, max lifetime {:?}",
+		config.db.max_connections, config.db.acquire_timeout, config.db.idle_timeout, config.db.max_lifetime,
 	);
 
+	let db_pool = config.db.pool_options()
+		.connect(&config.db.url)
+		.await
+		.expect("Failed to connect to DB");
+
+	match free_space_mb(&config.workdir_base) {
+		Ok(free_mb) => println!("{} MB free in working directory {}", free_mb, config.workdir_base.display()),
+		Err(e) => eprintln!("Failed to check free space on {}: {}", config.workdir_base.display(), e),
+	}
+
+	let state = Arc::new(AppState::new(config, db_pool));
+
+	/* nothing can be running yet, so whatever is still marked running was left by a previous run */
+	if let Err(e) = reconcile_orphans(&state, chrono::Duration::zero()).await {
+		eprintln!("Failed to reconcile orphaned builds: {}", e);
+	}
+	sweep_workdirs(&state).await;
+	if state.config().workdir_sweep_interval_secs > 0 {
+		spawn_workdir_sweeper(Arc::clone(&state), std::time::Duration::from_secs(state.config().workdir_sweep_interval_secs));
+	}
+	spawn_workers(Arc::clone(&state), state.config().max_concurrent_builds);
+	spawn_commit_status_reporter(Arc::clone(&state));
+	resume_collections(Arc::clone(&state)).await;
+	/* always running, LOG_DEAD_LETTER_DIR may be set by a reload */
+	spawn_replayer(Arc::clone(&state), std::time::Duration::from_secs(state.config().logs.dead_letter.retry_interval_secs.max(1)));
+	reload_on_sighup(Arc::clone(&state));
+	if state.config().image_reconcile_interval_secs > 0 {
+		spawn_image_reconciler(Arc::clone(&state), std::time::Duration::from_secs(state.config().image_reconcile_interval_secs));
+	}
+
+	#[cfg(feature = "grpc")]
+	{
+		let state = Arc::clone(&state);
+		let grpc_addr = state.config().grpc_addr;
+		tokio::spawn(async move {
+			println!("gRPC Server listening on {}", grpc_addr.to_string().bright_blue());
+			if let Err(e) = grpc::grpc::serve(state, grpc_addr).await {
+				eprintln!("grpc server error: {}", e);
+			}
+		});
+	}
+
 	let addr = ([0, 0, 0 ,0], 8084).into();
-	
+	let http = state.config().http.clone();
+
+	println!(
+		"HTTP: keep-alive {}, tcp keep-alive {:?}, header read timeout {:?}, body read timeout {:?}, max {} connections",
+		http.keepalive, http.tcp_keepalive, http.header_read_timeout, http.body_read_timeout, http.max_connections,
+	);
+
+	/* each connection holds a permit until it closes */
+	let connections = Arc::new(Semaphore::new(http.max_connections));
+
 	let make_svc = make_service_fn(move |_conn| {
-		let db_pool = Arc::clone(&db_pool);
+		let state = Arc::clone(&state);
+		let connections = Arc::clone(&connections);
 		async move {
+			let permit = connections.acquire_owned().await.expect("connection semaphore closed");
 			Ok::<_, Error>(service_fn(move |req| {
-				let db_pool = db_pool.clone();
-				handle(req, db_pool)
+				let _permit = &permit;
+				let state = state.clone();
+				let span = request_span(&req);
+				handle(req, state).instrument(span)
 			}))
 		}
 	});
 
-	let server = Server::bind(&addr).serve(make_svc);
+	let server = Server::bind(&addr)
+		.http1_keepalive(http.keepalive)
+		.http1_header_read_timeout(http.header_read_timeout)
+		.tcp_keepalive(http.tcp_keepalive)
+		.serve(make_svc);
 	
 	println!("Builder Server listening on {}", addr.to_string().bright_blue());
 
 	if let Err(e) = server.await {
 		eprintln!("server error: {}", e);
 	}
-}
\ No newline at end of file
+
+	telemetry::telemetry::shutdown();
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::config::config::tests::config;
+	use crate::engine::state::tests::state;
+
+	fn get(path: &str) -> Request<Body> {
+		Request::builder().method(Method::GET).uri(path).body(Body::empty()).unwrap()
+	}
+
+	#[tokio::test]
+	async fn landing_page_is_served_by_default() {
+		let response = route(get("/"), state(config())).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn landing_page_is_absent_when_disabled() {
+		let mut config = config();
+		config.enable_landing_page = false;
+
+		let response = route(get("/"), state(config)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		assert!(!String::from_utf8_lossy(&body).contains("/webhook"));
+	}
+
+	#[tokio::test]
+	async fn routes_stay_listed_behind_admin_auth() {
+		let mut config = config();
+		config.enable_landing_page = false;
+		config.admin_token = Some("token".to_string());
+		let state = state(config);
+
+		let response = route(get("/admin/routes"), Arc::clone(&state)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		let mut req = get("/admin/routes");
+		req.headers_mut().insert("Authorization", "Bearer token".parse().unwrap());
+		let response = route(req, state).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let body: serde_json::Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+		assert_eq!(body["landing_page"], json!(false));
+		assert!(body["routes"].as_array().unwrap().contains(&json!("POST /webhook")));
+	}
+
+	async fn body_of(response: Response<Body>) -> String {
+		String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap()
+	}
+
+	#[tokio::test]
+	async fn sbom_endpoint_returns_the_stored_document() {
+		let document = json!({ "spdxVersion": "SPDX-2.3", "packages": [{ "name": "openssl" }] }).to_string();
+		assert_eq!(sbom_id("/build/42/sbom").as_deref(), Some("42"));
+
+		let response = sbom_response("42", Some(Some(sbom::encode(document.as_bytes()))));
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()["Content-Type"], "application/json");
+		assert_eq!(body_of(response).await, document);
+	}
+
+	#[tokio::test]
+	async fn sbom_endpoint_without_a_document() {
+		let response = sbom_response("42", Some(None));
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		assert_eq!(body_of(response).await, "No SBOM was generated for build 42");
+
+		assert_eq!(sbom_response("42", None).status(), StatusCode::NOT_FOUND);
+		assert_eq!(sbom_response("42", Some(Some(b"not gzip".to_vec()))).status(), StatusCode::INTERNAL_SERVER_ERROR);
+	}
+}
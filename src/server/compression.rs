@@ -0,0 +1,132 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::body::{to_bytes, HttpBody};
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+
+use std::io::Write;
+
+/// Gzipping JSON responses for clients that accept it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /* smaller bodies are sent as they are, gzip would barely shrink them */
+    pub min_bytes: u64,
+}
+
+/// Whether `Accept-Encoding` allows gzip, by name or `*`, with a non-zero q.
+pub fn accepts_gzip(req: &Request<Body>) -> bool {
+    req.headers().get_all(ACCEPT_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let q = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && q > 0.0
+        })
+}
+
+/// Gzips `response` when the client accepts it and it's a JSON body of at
+/// least `min_bytes`. Streamed bodies (ndjson, logs) have no known size and
+/// are left alone, so their lines aren't held back.
+pub async fn compress(config: &CompressionConfig, accepts_gzip: bool, response: Response<Body>) -> Result<Response<Body>, hyper::Error> {
+    let is_json = response.headers().get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    let size = response.body().size_hint().exact();
+
+    if !config.enabled || !is_json || response.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(response);
+    }
+    if size.is_none_or(|size| size < config.min_bytes) {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    /* whether it's compressed depends on the request, caches have to know */
+    parts.headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    if !accepts_gzip {
+        return Ok(Response::from_parts(parts, body));
+    }
+
+    let bytes = to_bytes(body).await?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    /* writes to a Vec don't fail */
+    encoder.write_all(&bytes).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, Body::from(compressed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::read::GzDecoder;
+
+    use std::io::Read;
+
+    const CONFIG: CompressionConfig = CompressionConfig { enabled: true, min_bytes: 1024 };
+
+    fn request(accept_encoding: &str) -> Request<Body> {
+        Request::builder().header(ACCEPT_ENCODING, accept_encoding).body(Body::empty()).unwrap()
+    }
+
+    fn json(body: String) -> Response<Body> {
+        Response::builder().header(CONTENT_TYPE, "application/json").body(Body::from(body)).unwrap()
+    }
+
+    fn large_body() -> String {
+        serde_json::json!({ "builds": vec!["https://github.com/example/app"; 100] }).to_string()
+    }
+
+    #[test]
+    fn gzip_is_negotiated() {
+        assert!(accepts_gzip(&request("gzip, deflate, br")));
+        assert!(accepts_gzip(&request("*")));
+        assert!(!accepts_gzip(&request("gzip;q=0, br")));
+        assert!(!accepts_gzip(&request("identity")));
+    }
+
+    #[tokio::test]
+    async fn large_json_is_gzipped_when_accepted() {
+        let body = large_body();
+
+        let response = compress(&CONFIG, true, json(body.clone())).await.unwrap();
+
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "Accept-Encoding");
+        let compressed = to_bytes(response.into_body()).await.unwrap();
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[tokio::test]
+    async fn large_json_is_plain_when_not_accepted() {
+        let body = large_body();
+
+        let response = compress(&CONFIG, false, json(body.clone())).await.unwrap();
+
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(response.headers()[VARY], "Accept-Encoding");
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn small_and_streamed_bodies_are_plain() {
+        let small = compress(&CONFIG, true, json("{}".to_string())).await.unwrap();
+        assert!(!small.headers().contains_key(CONTENT_ENCODING));
+
+        let (_sender, body) = Body::channel();
+        let streamed = Response::builder().header(CONTENT_TYPE, "application/json").body(body).unwrap();
+        assert!(!compress(&CONFIG, true, streamed).await.unwrap().headers().contains_key(CONTENT_ENCODING));
+    }
+}
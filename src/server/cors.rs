@@ -0,0 +1,153 @@
+use hyper::header::{HeaderValue, ORIGIN, VARY};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+
+/* headers of our own a browser client may read, e.g. to follow a build log */
+const EXPOSED_HEADERS: &str = "X-Log-Offset, X-Log-Length, X-Build-Status";
+
+/// Which browser origins may call the API, none by default.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CorsConfig {
+    /* exact origins, e.g. https://dashboard.example.com, or * for any */
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /* how long a browser may cache a preflight answer */
+    pub max_age_secs: u64,
+}
+
+impl CorsConfig {
+    /// The `Access-Control-Allow-Origin` value for a request from `origin`,
+    /// `None` when that origin isn't allowed.
+    fn allow_origin<'a>(&'a self, origin: &'a str) -> Option<&'a str> {
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some("*")
+        } else {
+            self.allowed_origins.iter().find(|allowed| allowed.as_str() == origin).map(|_| origin)
+        }
+    }
+}
+
+pub fn origin(req: &Request<Body>) -> Option<String> {
+    req.headers().get(ORIGIN).and_then(|value| value.to_str().ok()).map(String::from)
+}
+
+/// Answers a CORS preflight (an `OPTIONS` with
+/// `Access-Control-Request-Method`) from an allowed origin. Anything else,
+/// including every preflight when no origins are configured, is `None` and
+/// routed as usual.
+pub fn preflight(config: &CorsConfig, req: &Request<Body>) -> Option<Response<Body>> {
+    if req.method() != Method::OPTIONS {
+        return None;
+    }
+    let requested = req.headers().get("Access-Control-Request-Method")?.to_str().ok()?;
+    let origin = origin(req)?;
+    let allow_origin = config.allow_origin(&origin)?;
+
+    /* a disallowed method gets no allow headers, which the browser takes as a refusal */
+    let mut response = Response::builder().status(StatusCode::NO_CONTENT);
+    if config.allowed_methods.iter().any(|method| method.eq_ignore_ascii_case(requested)) {
+        response = response
+            .header("Access-Control-Allow-Origin", allow_origin)
+            .header("Access-Control-Allow-Methods", config.allowed_methods.join(", "))
+            .header("Access-Control-Max-Age", config.max_age_secs.to_string());
+        if !config.allowed_headers.is_empty() {
+            response = response.header("Access-Control-Allow-Headers", config.allowed_headers.join(", "));
+        }
+    }
+
+    Some(response.header(VARY, "Origin").body(Body::empty()).unwrap())
+}
+
+/// Adds the CORS headers to the response of a request from `origin` when
+/// it's allowed.
+pub fn apply(config: &CorsConfig, origin: Option<&str>, response: &mut Response<Body>) {
+    let allow_origin = match origin.and_then(|origin| config.allow_origin(origin)) {
+        Some(allow_origin) => allow_origin,
+        None => return,
+    };
+
+    if let Ok(value) = HeaderValue::from_str(allow_origin) {
+        let headers = response.headers_mut();
+        headers.insert("Access-Control-Allow-Origin", value);
+        headers.insert("Access-Control-Expose-Headers", HeaderValue::from_static(EXPOSED_HEADERS));
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DASHBOARD: &str = "https://dashboard.example.com";
+
+    fn config(origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec!["Authorization".to_string(), "Content-Type".to_string()],
+            max_age_secs: 600,
+        }
+    }
+
+    fn preflight_request(origin: &str, method: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/builds")
+            .header(ORIGIN, origin)
+            .header("Access-Control-Request-Method", method)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn header<'a>(response: &'a Response<Body>, name: &str) -> Option<&'a str> {
+        response.headers().get(name).map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn preflight_from_an_allowed_origin() {
+        let response = preflight(&config(&[DASHBOARD]), &preflight_request(DASHBOARD, "post")).unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some(DASHBOARD));
+        assert_eq!(header(&response, "Access-Control-Allow-Methods"), Some("GET, POST"));
+        assert_eq!(header(&response, "Access-Control-Allow-Headers"), Some("Authorization, Content-Type"));
+        assert_eq!(header(&response, "Access-Control-Max-Age"), Some("600"));
+    }
+
+    #[test]
+    fn preflight_for_a_disallowed_method_allows_nothing() {
+        let response = preflight(&config(&[DASHBOARD]), &preflight_request(DASHBOARD, "DELETE")).unwrap();
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+    }
+
+    #[test]
+    fn preflights_are_routed_as_usual_without_cors() {
+        assert!(preflight(&config(&[]), &preflight_request(DASHBOARD, "GET")).is_none());
+        assert!(preflight(&config(&[DASHBOARD]), &preflight_request("https://evil.example.com", "GET")).is_none());
+
+        let options = Request::builder().method(Method::OPTIONS).uri("/builds").header(ORIGIN, DASHBOARD).body(Body::empty()).unwrap();
+        assert!(preflight(&config(&[DASHBOARD]), &options).is_none());
+    }
+
+    #[test]
+    fn actual_requests_get_the_allow_headers() {
+        let mut response = Response::new(Body::empty());
+        apply(&config(&[DASHBOARD]), Some(DASHBOARD), &mut response);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some(DASHBOARD));
+        assert_eq!(header(&response, "Access-Control-Expose-Headers"), Some(EXPOSED_HEADERS));
+        assert_eq!(header(&response, "Vary"), Some("Origin"));
+
+        let mut any = Response::new(Body::empty());
+        apply(&config(&["*"]), Some("https://other.example.com"), &mut any);
+        assert_eq!(header(&any, "Access-Control-Allow-Origin"), Some("*"));
+    }
+
+    #[test]
+    fn disallowed_or_same_origin_requests_are_left_alone() {
+        let mut response = Response::new(Body::empty());
+        apply(&config(&[DASHBOARD]), Some("https://evil.example.com"), &mut response);
+        apply(&config(&[DASHBOARD]), None, &mut response);
+        assert!(response.headers().is_empty());
+    }
+}
@@ -0,0 +1,4 @@
+pub mod compression;
+pub mod cors;
+pub mod server;
+pub mod upload;
@@ -0,0 +1,30 @@
+use hyper::body::{to_bytes, Bytes};
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+use std::time::Duration;
+
+/// Knobs for the HTTP listener.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    pub keepalive: bool,
+    /* none leaves tcp keepalive off */
+    pub tcp_keepalive: Option<Duration>,
+    /* also bounds how long an idle keep-alive connection waits for its next request */
+    pub header_read_timeout: Duration,
+    pub body_read_timeout: Duration,
+    /* connections past this wait until one closes */
+    pub max_connections: usize,
+}
+
+/// Reads the whole body, answering 408 instead when the client takes longer
+/// than `timeout` to send it.
+pub async fn read_body(body: Body, timeout: Duration) -> Result<Result<Bytes, Response<Body>>, hyper::Error> {
+    match tokio::time::timeout(timeout, to_bytes(body)).await {
+        Ok(bytes) => bytes.map(Ok),
+        Err(_) => Ok(Err(Response::builder()
+            .status(StatusCode::REQUEST_TIMEOUT)
+            .body(Body::from("Timed out reading the request body"))
+            .unwrap())),
+    }
+}
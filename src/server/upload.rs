@@ -0,0 +1,220 @@
+use flate2::read::GzDecoder;
+use futures::StreamExt;
+use hyper::body::Bytes;
+use hyper::{Body, Response, StatusCode};
+use serde::Deserialize;
+use tar::{Archive, EntryType};
+use tokio::sync::mpsc;
+
+use std::io::Read;
+use std::path::{Component, Path};
+use std::time::Duration;
+
+/// Query parameters of a tarball `/build`, the rest comes from the profile.
+#[derive(Deserialize)]
+pub struct UploadParams {
+    pub name: String,
+    pub profile: Option<String>,
+}
+
+pub fn is_tarball(content_type: Option<&str>) -> bool {
+    matches!(content_type.map(|value| value.split(';').next().unwrap_or_default().trim()), Some("application/gzip" | "application/x-gzip"))
+}
+
+/* the body chunks, read from the blocking extraction */
+struct BodyReader {
+    rx: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.into()))
+        .unwrap()
+}
+
+/// Extracts the gzipped tarball in `body` into `dir` as it arrives. Entries
+/// escaping `dir`, links and special files are refused, and so is anything
+/// past `max_bytes` sent or unpacked. `idle_timeout` bounds the wait for
+/// each chunk.
+pub async fn extract_tarball(mut body: Body, dir: &Path, max_bytes: u64, idle_timeout: Duration) -> Result<(), Response<Body>> {
+    let (tx, rx) = mpsc::channel(16);
+    let dir = dir.to_path_buf();
+    let extraction = tokio::task::spawn_blocking(move || unpack(BodyReader { rx, chunk: Bytes::new() }, &dir, max_bytes));
+
+    let mut received: u64 = 0;
+    loop {
+        let chunk = match tokio::time::timeout(idle_timeout, body.next()).await {
+            Ok(Some(Ok(chunk))) => chunk,
+            Ok(Some(Err(e))) => return Err(error(StatusCode::BAD_REQUEST, format!("Failed to read the upload: {}", e))),
+            Ok(None) => break,
+            Err(_) => return Err(error(StatusCode::REQUEST_TIMEOUT, "Timed out reading the request body")),
+        };
+
+        received += chunk.len() as u64;
+        if received > max_bytes {
+            return Err(error(StatusCode::PAYLOAD_TOO_LARGE, format!("Upload is larger than {} bytes", max_bytes)));
+        }
+        /* extraction stopped early, its error says why */
+        if tx.send(chunk).await.is_err() {
+            break;
+        }
+    }
+    drop(tx);
+
+    match extraction.await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err((status, message))) => Err(error(status, message)),
+        Err(e) => Err(error(StatusCode::INTERNAL_SERVER_ERROR, format!("Extraction failed: {}", e))),
+    }
+}
+
+fn unpack(reader: impl Read, dir: &Path, max_bytes: u64) -> Result<(), (StatusCode, String)> {
+    let invalid = |message: String| (StatusCode::BAD_REQUEST, message);
+    let mut archive = Archive::new(GzDecoder::new(reader));
+    let mut unpacked: u64 = 0;
+
+    for entry in archive.entries().map_err(|e| invalid(format!("Invalid tarball: {}", e)))? {
+        let mut entry = entry.map_err(|e| invalid(format!("Invalid tarball: {}", e)))?;
+        let path = entry.path().map_err(|e| invalid(format!("Invalid entry path: {}", e)))?.into_owned();
+
+        if !is_contained(&path) {
+            return Err(invalid(format!("Entry {} escapes the upload directory", path.display())));
+        }
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Directory => {},
+            /* pax and gnu headers describing the next entry */
+            EntryType::XHeader | EntryType::XGlobalHeader | EntryType::GNULongName => continue,
+            other => return Err(invalid(format!("Entry {} has unsupported type {:?}", path.display(), other))),
+        }
+
+        unpacked += entry.size();
+        if unpacked > max_bytes {
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, format!("Upload unpacks to more than {} bytes", max_bytes)));
+        }
+
+        entry.unpack_in(dir).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to unpack {}: {}", path.display(), e)))?;
+    }
+
+    Ok(())
+}
+
+/* relative, with no .. that could climb out */
+fn is_contained(path: &Path) -> bool {
+    path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::{Builder, Header};
+
+    fn header(entry_type: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header
+    }
+
+    /* a gzipped tarball of (path, contents), paths written as is so bad ones can be tested */
+    fn tarball(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, contents) in files {
+            let mut header = header(EntryType::Regular, contents.len() as u64);
+            let name = &mut header.as_old_mut().name;
+            name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    async fn extract(body: Vec<u8>, dir: &Path, max_bytes: u64) -> Result<(), (StatusCode, String)> {
+        match extract_tarball(Body::from(body), dir, max_bytes, Duration::from_secs(5)).await {
+            Ok(()) => Ok(()),
+            Err(response) => {
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                Err((status, String::from_utf8_lossy(&body).into_owned()))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn small_tarball_is_extracted() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = tarball(&[("package.json", "{}"), ("src/index.js", "console.log(1)")]);
+
+        extract(body, dir.path(), 1024 * 1024).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(dir.path().join("package.json")).unwrap(), "{}");
+        assert_eq!(std::fs::read_to_string(dir.path().join("src/index.js")).unwrap(), "console.log(1)");
+    }
+
+    #[tokio::test]
+    async fn entries_escaping_the_dir_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = tarball(&[("../escaped", "nope")]);
+
+        let (status, message) = extract(body, &dir.path().join("upload"), 1024 * 1024).await.unwrap_err();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message.contains("escapes the upload directory"), "{}", message);
+        assert!(!dir.path().join("escaped").exists());
+    }
+
+    #[tokio::test]
+    async fn symlinks_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let mut link = header(EntryType::Symlink, 0);
+        builder.append_link(&mut link, "passwd", "/etc/passwd").unwrap();
+        let body = builder.into_inner().unwrap().finish().unwrap();
+
+        let (status, message) = extract(body, dir.path(), 1024 * 1024).await.unwrap_err();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message.contains("unsupported type"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn size_limit_applies_while_unpacking() {
+        let dir = tempfile::tempdir().unwrap();
+        /* compresses far below the limit, unpacks well past it */
+        let big = "a".repeat(64 * 1024);
+        let body = tarball(&[("big.txt", &big)]);
+        assert!((body.len() as u64) < 16 * 1024);
+
+        let (status, _) = extract(body, dir.path(), 16 * 1024).await.unwrap_err();
+
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn gzip_content_types_are_tarballs() {
+        assert!(is_tarball(Some("application/gzip")));
+        assert!(is_tarball(Some("application/x-gzip; charset=binary")));
+        assert!(!is_tarball(Some("application/json")));
+        assert!(!is_tarball(None));
+    }
+}
@@ -0,0 +1,85 @@
+use hyper::{Body, Request};
+use tracing::{info_span, Span};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+#[cfg(feature = "otel")]
+use hyper::HeaderMap;
+#[cfg(feature = "otel")]
+use opentelemetry::global;
+#[cfg(feature = "otel")]
+use opentelemetry::propagation::Extractor;
+#[cfg(feature = "otel")]
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Sets up logging, and with the otel feature exports spans over OTLP when
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init() {
+    let registry = tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer());
+
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel_layer());
+
+    registry.init();
+}
+
+/// Flushes spans that haven't been exported yet.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    global::shutdown_tracer_provider();
+}
+
+#[cfg(feature = "otel")]
+fn otel_layer<S>() -> Option<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry::sdk::trace::Tracer>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|endpoint| !endpoint.is_empty())?;
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", "forge")])))
+        .install_batch(opentelemetry::runtime::Tokio)
+        .expect("Failed to install OTLP exporter");
+
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+#[cfg(feature = "otel")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "otel")]
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Span for handling `req`, continuing the caller's trace when it sent a
+/// traceparent header.
+pub fn request_span(req: &Request<Body>) -> Span {
+    let span = info_span!("request", method = %req.method(), path = %req.uri().path());
+
+    #[cfg(feature = "otel")]
+    {
+        let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(req.headers())));
+        span.set_parent(parent);
+    }
+
+    span
+}
@@ -0,0 +1,106 @@
+use serde_json::{json, Map, Value};
+
+use crate::engine::profiles::layer;
+
+use std::collections::{BTreeMap, HashSet};
+
+/// Apps as read from `WEBHOOK_APPS_FILE`, a JSON object of repository
+/// (owner/name) to the partial build requests of the apps it holds.
+pub fn parse_apps(contents: &str) -> Result<BTreeMap<String, Vec<Value>>, String> {
+    let repositories: Map<String, Value> = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+
+    let mut apps = BTreeMap::new();
+    for (repository, list) in repositories {
+        let list = match list {
+            Value::Array(list) if !list.is_empty() => list,
+            _ => return Err(format!("apps of {} must be a non-empty list", repository)),
+        };
+
+        /* each app is its own image, two with one name would overwrite each other */
+        let mut names = HashSet::new();
+        for app in &list {
+            let name = app.get("name").and_then(Value::as_str)
+                .ok_or_else(|| format!("every app of {} needs a name", repository))?;
+            if !names.insert(name) {
+                return Err(format!("{} has more than one app named {}", repository, name));
+            }
+        }
+
+        apps.insert(repository, list);
+    }
+
+    Ok(apps)
+}
+
+/// The build request of every app, each layered over `base`, a push's
+/// request. An app with a subdir and no paths_filter of its own only builds
+/// when the push touched its subdir.
+pub fn app_requests(base: &Value, apps: &[Value]) -> Vec<Value> {
+    apps.iter()
+        .map(|app| {
+            let mut app = app.clone();
+            if app.get("paths_filter").is_none() {
+                if let Some(subdir) = app.get("subdir").cloned() {
+                    app["paths_filter"] = json!([subdir]);
+                }
+            }
+            layer(base, app)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apps() -> BTreeMap<String, Vec<Value>> {
+        parse_apps(r#"{
+            "example/monorepo": [
+                { "name": "api", "subdir": "services/api", "envs": ["PORT=8080"] },
+                { "name": "web", "subdir": "services/web", "paths_filter": ["services/web", "libs/ui/**"] }
+            ]
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn push_to_a_repo_with_two_apps_builds_both() {
+        let base = json!({
+            "path": "https://github.com/example/monorepo",
+            "name": "monorepo",
+            "branch": "main",
+            "before": "1111111111111111111111111111111111111111",
+            "after": "2222222222222222222222222222222222222222",
+        });
+
+        let requests = app_requests(&base, &apps()["example/monorepo"]);
+
+        assert_eq!(requests.len(), 2);
+        for request in &requests {
+            assert_eq!(request["path"], json!("https://github.com/example/monorepo"));
+            assert_eq!(request["after"], json!("2222222222222222222222222222222222222222"));
+        }
+        assert_eq!(requests[0]["name"], json!("api"));
+        assert_eq!(requests[0]["subdir"], json!("services/api"));
+        assert_eq!(requests[0]["envs"], json!(["PORT=8080"]));
+        assert_eq!(requests[1]["name"], json!("web"));
+    }
+
+    #[test]
+    fn apps_only_build_for_their_own_changes() {
+        let requests = app_requests(&json!({}), &apps()["example/monorepo"]);
+
+        /* defaulted to the subdir, unless the app has a filter of its own */
+        assert_eq!(requests[0]["paths_filter"], json!(["services/api"]));
+        assert_eq!(requests[1]["paths_filter"], json!(["services/web", "libs/ui/**"]));
+    }
+
+    #[test]
+    fn invalid_app_lists_are_refused() {
+        assert_eq!(parse_apps(r#"{ "example/app": [] }"#), Err("apps of example/app must be a non-empty list".to_string()));
+        assert_eq!(parse_apps(r#"{ "example/app": [{ "subdir": "api" }] }"#), Err("every app of example/app needs a name".to_string()));
+        assert_eq!(
+            parse_apps(r#"{ "example/app": [{ "name": "api" }, { "name": "api" }] }"#),
+            Err("example/app has more than one app named api".to_string()),
+        );
+    }
+}
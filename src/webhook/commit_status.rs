@@ -0,0 +1,249 @@
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::config::redact;
+use crate::engine::events::{is_terminal, BuildEvent};
+use crate::engine::pipeline::{get_status, TAG_EXISTS};
+use crate::engine::state::AppState;
+
+use std::sync::Arc;
+
+/// Reporting build results back to GitHub as commit statuses.
+#[derive(Serialize, Debug, Clone)]
+pub struct CommitStatusConfig {
+    /* statuses are only reported with a token */
+    #[serde(serialize_with = "redact::optional_secret")]
+    pub token: Option<String>,
+    /* the name the status is shown under on the commit */
+    pub context: String,
+    pub api_url: String,
+    /* linked from the status, {id} is replaced with the build id */
+    pub target_url: Option<String>,
+}
+
+/// The GitHub state a build in `status` is reported as, `None` for
+/// transitions that don't change it.
+pub fn github_state(status: &str) -> Option<&'static str> {
+    match status {
+        "queued" | "running" => Some("pending"),
+        "Completed" | "Skipped" | "Warmed" | TAG_EXISTS => Some("success"),
+        /* a build that was stopped rather than one that failed */
+        status if status.starts_with("Cancelled") => Some("error"),
+        status if is_terminal(status) => Some("failure"),
+        _ => None,
+    }
+}
+
+fn description(status: &str) -> String {
+    match status {
+        "queued" => "Waiting for a build worker".to_string(),
+        "running" => "Building".to_string(),
+        status => format!("Build {}", status),
+    }
+}
+
+/// Sets the `config.context` status of `sha` in `repository` (owner/name).
+pub async fn post_status(client: &Client, config: &CommitStatusConfig, repository: &str, sha: &str, state: &str, description: &str, build_id: &str) -> Result<(), String> {
+    let token = config.token.as_deref().ok_or("GITHUB_TOKEN isn't set")?;
+    let url = format!("{}/repos/{}/statuses/{}", config.api_url.trim_end_matches('/'), repository, sha);
+    let mut body = json!({
+        "state": state,
+        "context": config.context,
+        /* github refuses longer descriptions */
+        "description": description.chars().take(140).collect::<String>(),
+    });
+    if let Some(target_url) = &config.target_url {
+        body["target_url"] = json!(target_url.replace("{id}", build_id));
+    }
+
+    let response = client.post(&url)
+        .bearer_auth(token)
+        .header("Accept", "application/vnd.github+json")
+        .header("User-Agent", "forge")
+        .json(&body)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to report status to {}: {}", url, e))?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("GitHub answered {} for {}: {}", response.status(), url, response.text().await.unwrap_or_default()))
+    }
+}
+
+/// Reports the status of build `event.id` when it was requested with a
+/// `github_repository`, e.g. by a push webhook.
+async fn report(state: &AppState, client: &Client, event: &BuildEvent) {
+    let github_state = match github_state(&event.status) {
+        Some(github_state) => github_state,
+        None => return,
+    };
+
+    let config = state.config();
+    if config.commit_status.token.is_none() {
+        return;
+    }
+
+    let record = match get_status(state, &event.id).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Failed to look up build {} to report its status: {}", event.id, e);
+            return;
+        }
+    };
+    let options = record.options.unwrap_or_default();
+    let repository = match options.get("github_repository").and_then(|repository| repository.as_str()) {
+        Some(repository) => repository,
+        None => return,
+    };
+    /* the commit that was checked out, or the one asked for before that */
+    let sha = match record.commit.as_deref().or_else(|| options.get("after").and_then(|after| after.as_str())) {
+        Some(sha) => sha,
+        None => return,
+    };
+
+    /* the apps of a monorepo build the same commit, each gets its own status */
+    let mut commit_status = config.commit_status.clone();
+    if let (Some(_), Some(name)) = (options.get("subdir").and_then(|subdir| subdir.as_str()), options.get("name").and_then(|name| name.as_str())) {
+        commit_status.context = format!("{}/{}", commit_status.context, name);
+    }
+
+    if let Err(e) = post_status(client, &commit_status, repository, sha, github_state, &description(&event.status), &event.id).await {
+        warn!("{}", e);
+    }
+}
+
+/// Follows build events for as long as forge runs and reports them to
+/// GitHub. Events are handled one at a time so statuses arrive in order.
+pub fn spawn_commit_status_reporter(state: Arc<AppState>) {
+    if state.config().commit_status.token.is_none() {
+        info!("GITHUB_TOKEN isn't set, build statuses won't be reported to GitHub");
+    }
+
+    let mut events = state.events.subscribe();
+    tokio::spawn(async move {
+        let client = Client::new();
+        loop {
+            match events.recv().await {
+                Ok(event) => report(&state, &client, &event).await,
+                Err(broadcast::error::RecvError::Lagged(missed)) => warn!("Commit status reporting missed {} build events", missed),
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+
+    /* (method, path, authorization, body) of each call the mock got */
+    type Calls = Arc<Mutex<Vec<(String, String, String, serde_json::Value)>>>;
+
+    /// A GitHub API stand-in answering `status` to everything, returning its
+    /// url and the calls it gets.
+    fn mock_github(status: u16) -> (String, Calls) {
+        let calls: Calls = Arc::default();
+        let recorded = Arc::clone(&calls);
+        let make_svc = make_service_fn(move |_conn| {
+            let recorded = Arc::clone(&recorded);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let recorded = Arc::clone(&recorded);
+                    async move {
+                        let method = req.method().to_string();
+                        let path = req.uri().path().to_string();
+                        let authorization = req.headers().get("Authorization").map(|value| value.to_str().unwrap().to_string()).unwrap_or_default();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        recorded.lock().unwrap().push((method, path, authorization, serde_json::from_slice(&body).unwrap()));
+                        Ok::<_, Infallible>(Response::builder().status(status).body(Body::from("{}")).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (url, calls)
+    }
+
+    fn config(api_url: &str) -> CommitStatusConfig {
+        CommitStatusConfig {
+            token: Some("ghp_token".to_string()),
+            context: "forge".to_string(),
+            api_url: api_url.to_string(),
+            target_url: Some("https://forge.example.com/build/{id}".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_then_success_is_reported() {
+        let (url, calls) = mock_github(201);
+        let client = Client::new();
+
+        for status in ["running", "Completed"] {
+            let state = github_state(status).unwrap();
+            post_status(&client, &config(&url), "example/app", "abc123", state, &description(status), "42").await.unwrap();
+        }
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        for (method, path, authorization, _) in calls.iter() {
+            assert_eq!(method, "POST");
+            assert_eq!(path, "/repos/example/app/statuses/abc123");
+            assert_eq!(authorization, "Bearer ghp_token");
+        }
+        assert_eq!(calls[0].3, json!({
+            "state": "pending",
+            "context": "forge",
+            "description": "Building",
+            "target_url": "https://forge.example.com/build/42",
+        }));
+        assert_eq!(calls[1].3["state"], json!("success"));
+        assert_eq!(calls[1].3["description"], json!("Build Completed"));
+    }
+
+    #[tokio::test]
+    async fn rejected_status_is_an_error() {
+        let (url, _) = mock_github(422);
+        let error = post_status(&Client::new(), &config(&url), "example/app", "abc123", "failure", "Build Failed", "42").await.unwrap_err();
+        assert!(error.starts_with("GitHub answered 422"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_sent_without_a_token() {
+        let (url, calls) = mock_github(201);
+        let config = CommitStatusConfig { token: None, ..config(&url) };
+
+        let result = post_status(&Client::new(), &config, "example/app", "abc123", "pending", "Building", "42").await;
+        assert_eq!(result, Err("GITHUB_TOKEN isn't set".to_string()));
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn long_descriptions_are_cut_to_what_github_takes() {
+        let (url, calls) = mock_github(201);
+        post_status(&Client::new(), &config(&url), "example/app", "abc123", "failure", &"x".repeat(500), "42").await.unwrap();
+        assert_eq!(calls.lock().unwrap()[0].3["description"].as_str().unwrap().len(), 140);
+    }
+
+    #[test]
+    fn build_statuses_map_to_github_states() {
+        assert_eq!(github_state("queued"), Some("pending"));
+        assert_eq!(github_state("Completed"), Some("success"));
+        assert_eq!(github_state(TAG_EXISTS), Some("success"));
+        assert_eq!(github_state("Failed"), Some("failure"));
+        assert_eq!(github_state("Cancelled (timed out)"), Some("error"));
+    }
+}
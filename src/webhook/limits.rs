@@ -0,0 +1,82 @@
+use serde::Serialize;
+
+/// How much structure an untrusted JSON body may have.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct JsonLimits {
+    pub max_depth: usize,
+    /* object keys across the whole document */
+    pub max_fields: usize,
+}
+
+/// Scans `body` without parsing it, rejecting documents nested deeper or
+/// with more fields than `limits` allow before serde gets to them.
+pub fn check_json_limits(body: &[u8], limits: &JsonLimits) -> Result<(), String> {
+    let mut depth = 0;
+    let mut fields = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for &byte in body {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > limits.max_depth {
+                    return Err(format!("JSON nested deeper than {} levels", limits.max_depth));
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            /* every key is followed by exactly one colon outside a string */
+            b':' => {
+                fields += 1;
+                if fields > limits.max_fields {
+                    return Err(format!("JSON has more than {} fields", limits.max_fields));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: JsonLimits = JsonLimits { max_depth: 8, max_fields: 4 };
+
+    #[test]
+    fn deeply_nested_body_is_rejected() {
+        let body = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+        assert_eq!(check_json_limits(body.as_bytes(), &LIMITS), Err("JSON nested deeper than 8 levels".to_string()));
+    }
+
+    #[test]
+    fn body_within_limits_passes() {
+        let body = br#"{"ref": "refs/heads/main", "repository": {"name": "app", "tags": [[1], [2]]}}"#;
+        assert_eq!(check_json_limits(body, &LIMITS), Ok(()));
+    }
+
+    #[test]
+    fn too_many_fields_are_rejected() {
+        let body = br#"{"a": 1, "b": 2, "c": 3, "d": 4, "e": 5}"#;
+        assert_eq!(check_json_limits(body, &LIMITS), Err("JSON has more than 4 fields".to_string()));
+    }
+
+    #[test]
+    fn brackets_and_colons_in_strings_dont_count() {
+        let body = format!(r#"{{"message": "{}", "url": "https://a:b@example.com \"{{:}}\""}}"#, "[{:".repeat(100));
+        assert_eq!(check_json_limits(body.as_bytes(), &LIMITS), Ok(()));
+    }
+}
@@ -1 +1,4 @@
-pub mod webhook;
\ No newline at end of file
+pub mod apps;
+pub mod commit_status;
+pub mod limits;
+pub mod webhook;
@@ -1,14 +1,24 @@
 use hyper::{Body, Request, Response, StatusCode, Method};
 use serde::Deserialize;
 use hmac::{Hmac, Mac};
+use hmac::digest::KeyInit;
+use sha1::Sha1;
 use sha2::Sha256;
-use reqwest::Client;
-use dotenv_codegen::dotenv;
+use serde_json::json;
+use tracing::info;
 
-type HmacSha256 = Hmac<Sha256>;
+use crate::engine::pipeline::{build_info_from_value, BuildError};
+use crate::engine::profiles::layer;
+use crate::engine::queue::submit;
+use crate::webhook::apps::app_requests;
+use crate::engine::state::AppState;
+use crate::server::server::read_body;
+use crate::webhook::limits::check_json_limits;
+
+use std::sync::Arc;
 
-const WEBHOOK_SECRET: &str = dotenv!("GITHUB_WEBHOOK_SECRET");
-const BUILDER_ENDPOINT: &str = "http://localhost:8084/build";
+type HmacSha256 = Hmac<Sha256>;
+type HmacSha1 = Hmac<Sha1>;
 
 #[derive(Debug, Deserialize)]
 pub struct WebhookPayload {
@@ -18,12 +28,17 @@ pub struct WebhookPayload {
   pub after: Option<String>,
   pub repository: Option<Repository>,
   pub commits: Option<Vec<Commit>>,
+  /* only on ping events */
+  pub zen: Option<String>,
+  pub hook_id: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Repository {
     pub name: String,
     pub url: String,
+    /* owner/name */
+    pub full_name: Option<String>,
 }
 #[derive(Debug, Deserialize)]
 pub struct Commit {
@@ -33,59 +48,281 @@ pub struct Commit {
     pub distinct: bool,
 }
 
-async fn handle_webhook(payload: WebhookPayload) {
-    if let Some(ref_field) = payload.ref_field {
+/// The /build request for a push, building its `after` commit. before/after
+/// are passed along so a profile's paths_filter can skip unrelated pushes.
+fn build_request(payload: &WebhookPayload, repository: &Repository, profile: Option<&str>) -> serde_json::Value {
+    let mut request = serde_json::json!({
+        "path": repository.url,
+        "name": repository.name.to_lowercase(),
+        "before": payload.before,
+        "after": payload.after,
+        "branch": payload.ref_field.as_deref().and_then(|ref_field| ref_field.strip_prefix("refs/heads/")),
+        "github_repository": repository.full_name,
+    });
+
+    if let Some(profile) = profile {
+        request["profile"] = serde_json::Value::from(profile);
+    }
+
+    request
+}
+
+/// Queues a build of the pushed commit and answers with its id, or why it
+/// was refused.
+async fn handle_push(state: &AppState, payload: &WebhookPayload, repository: &Repository, profile: Option<&str>) -> Response<Body> {
+    if let Some(ref_field) = &payload.ref_field {
         println!("Ref: {}", ref_field);
     }
+    println!("Repository: {}", repository.name);
+    println!("Repository URL: {}", repository.url);
+    for commit in payload.commits.iter().flatten() {
+        println!("Commit: {} - {}", commit.id, commit.message);
+    }
+
+    let config = state.config();
+    let queued = match build_info_from_value(build_request(payload, repository, profile), &config) {
+        Ok(build_info) => submit(state, build_info).await,
+        Err(e) => Err(e),
+    };
 
-    if let Some(repository) = payload.repository {
-        println!("Repository: {}", repository.name);
-        println!("Repository URL: {}", repository.url);
+    match queued {
+        Ok(id) => json_response(StatusCode::ACCEPTED, json!({ "id": id })),
+        Err(e) => json_response(e.status, json!({ "error": e.message })),
     }
+}
 
-    if let Some(commits) = payload.commits {
-        for commit in commits {
-            println!("Commit: {} - {}", commit.id, commit.message);
-        }
+/// Queues a build of every app in the pushed monorepo and answers with
+/// each one's id, or why it was refused, shaped like a /builds/batch
+/// response.
+async fn handle_apps(state: &AppState, payload: &WebhookPayload, repository: &Repository, apps: &[serde_json::Value], profile: Option<&str>) -> Response<Body> {
+    let config = state.config();
+    let base = build_request(payload, repository, profile);
+
+    /* each app is queued on its own, one bad app config doesn't hold the others back */
+    let mut results = Vec::with_capacity(apps.len());
+    let mut failed = false;
+    for request in app_requests(&base, apps) {
+        let queued = match build_info_from_value(request, &config) {
+            Ok(build_info) => submit(state, build_info).await,
+            Err(e) => Err(e),
+        };
+
+        results.push(match queued {
+            Ok(id) => json!({ "status": StatusCode::ACCEPTED.as_u16(), "id": id }),
+            Err(e) => {
+                failed = true;
+                json!({ "status": e.status.as_u16(), "error": e.message })
+            }
+        });
+    }
 
-        let client = Client::new();
-        let _ = client.get(BUILDER_ENDPOINT).send().await;
+    let status = if failed { StatusCode::MULTI_STATUS } else { StatusCode::ACCEPTED };
+    json_response(status, json!(results))
+}
+
+#[derive(Debug, Deserialize)]
+struct DispatchPayload {
+    action: Option<String>,
+    client_payload: Option<serde_json::Value>,
+    repository: Option<DispatchRepository>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DispatchRepository {
+    name: String,
+    full_name: Option<String>,
+    /* url is the api url on anything but push events */
+    clone_url: String,
+}
+
+/// The /build request of a repository_dispatch event: its `client_payload`,
+/// BuildInfo shaped, over the dispatching repo and `profile`.
+fn dispatch_request(body: &[u8], profile: Option<&str>) -> Result<serde_json::Value, String> {
+    let payload: DispatchPayload = serde_json::from_slice(body).map_err(|e| format!("Invalid repository_dispatch payload: {}", e))?;
+
+    let client_payload = match payload.client_payload {
+        None | Some(serde_json::Value::Null) => json!({}),
+        Some(client_payload) if client_payload.is_object() => client_payload,
+        Some(_) => return Err("client_payload must be an object".to_string()),
+    };
+
+    let mut defaults = json!({});
+    if let Some(repository) = &payload.repository {
+        defaults["path"] = json!(repository.clone_url);
+        defaults["name"] = json!(repository.name.to_lowercase());
+        defaults["github_repository"] = json!(repository.full_name);
     }
+    if let Some(profile) = profile {
+        defaults["profile"] = json!(profile);
+    }
+
+    info!(
+        "repository_dispatch {} for {}",
+        payload.action.as_deref().unwrap_or_default(),
+        payload.repository.as_ref().and_then(|repository| repository.full_name.as_deref()).unwrap_or("the client_payload's repo"),
+    );
+    Ok(layer(&defaults, client_payload))
 }
 
-pub async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-        let signature = req.headers().get("X-Hub-Signature-256").map(|value| value.to_str().unwrap().to_owned());
+/// Queues the build a repository_dispatch event asks for and answers with
+/// its id, or why it was refused.
+async fn handle_dispatch(state: &AppState, body: &[u8], profile: Option<&str>) -> Response<Body> {
+    let config = state.config();
+    let queued = match dispatch_request(body, profile) {
+        Ok(request) => match build_info_from_value(request, &config) {
+            Ok(build_info) => submit(state, build_info).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(BuildError::new(StatusCode::BAD_REQUEST, e)),
+    };
+
+    match queued {
+        Ok(id) => json_response(StatusCode::ACCEPTED, json!({ "id": id })),
+        Err(e) => json_response(e.status, json!({ "error": e.message })),
+    }
+}
+
+/// Checks `signature` (hex, without the `sha256=`/`sha1=` prefix) against the
+/// HMAC of `body`, for whichever digest `M` is.
+fn verify_signature<M: Mac + KeyInit>(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let signature_bytes = match hex::decode(signature) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+
+    let mut mac = <M as Mac>::new_from_slice(secret).expect("Invalid HMAC key");
+    mac.update(body);
+    mac.verify_slice(&signature_bytes).is_ok()
+}
+
+/// The signature header that verified `body`, or why none did. sha256 is
+/// used whenever it's sent, sha1 only as an opt-in fallback.
+fn check_signature(secret: &str, allow_sha1: bool, body: &[u8], signature_256: Option<&str>, signature_1: Option<&str>) -> Result<&'static str, &'static str> {
+    let secret = secret.as_bytes();
+
+    match (signature_256, signature_1) {
+        (Some(signature), _) => match signature.strip_prefix("sha256=") {
+            Some(signature) if verify_signature::<HmacSha256>(secret, body, signature) => Ok("X-Hub-Signature-256"),
+            _ => Err("X-Hub-Signature-256 doesn't match the body signed with GITHUB_WEBHOOK_SECRET"),
+        },
+        (None, Some(signature)) if allow_sha1 => match signature.strip_prefix("sha1=") {
+            Some(signature) if verify_signature::<HmacSha1>(secret, body, signature) => Ok("X-Hub-Signature"),
+            _ => Err("X-Hub-Signature doesn't match the body signed with GITHUB_WEBHOOK_SECRET"),
+        },
+        (None, Some(_)) => Err("only X-Hub-Signature (sha1) was sent, it's accepted with WEBHOOK_ALLOW_SHA1=true"),
+        (None, None) => Err("no X-Hub-Signature-256 header"),
+    }
+}
+
+/* pushes to branches build, anything else (tags, pings) is only acknowledged */
+fn builds(payload: &WebhookPayload) -> bool {
+    payload.commits.is_some() && payload.ref_field.as_ref().is_some_and(|s| s.starts_with("refs/heads/"))
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn header(req: &Request<Body>, name: &str) -> Option<String> {
+    req.headers().get(name).and_then(|value| value.to_str().ok()).map(|value| value.to_owned())
+}
+
+pub async fn handle_request(req: Request<Body>, state: Arc<AppState>) -> Result<Response<Body>, hyper::Error> {
+        let config = state.config();
+        let signature_256 = header(&req, "X-Hub-Signature-256");
+        let signature_1 = header(&req, "X-Hub-Signature");
+        let event = header(&req, "X-GitHub-Event");
+        /* ?validate=1 checks a delivery end to end without building, for setting a webhook up */
+        let validate = req.uri().query()
+            .is_some_and(|query| query.split('&').any(|param| param == "validate=1" || param == "validate=true"));
     
         match (req.method(), req.uri().path()) {
             (&Method::POST, "/webhook") => {
-                let whole_body = hyper::body::to_bytes(req.into_body()).await?;
-                
-                let mut mac = HmacSha256::new_from_slice(WEBHOOK_SECRET.as_bytes()).expect("Invalid HMAC key");
-    
-                mac.update(&whole_body);
-                let result = mac.finalize();
-                let code_bytes = result.into_bytes();
-    
-                if let Some(signature) = signature {
-                    let (_, hex_signature) = signature.split_at(7);
-                    let signature_bytes = hex::decode(hex_signature).unwrap();
-                    if code_bytes.as_slice() != signature_bytes.as_slice() {
+                let whole_body = match read_body(req.into_body(), config.http.body_read_timeout).await? {
+                    Ok(body) => body,
+                    Err(response) => return Ok(response),
+                };
+
+                /* an empty secret would accept anything signed with it, so there's no default */
+                let secret = match &config.webhook_secret {
+                    Some(secret) => secret,
+                    None => {
+                        return Ok(Response::builder()
+                            .status(StatusCode::SERVICE_UNAVAILABLE)
+                            .body(Body::from("webhooks disabled, GITHUB_WEBHOOK_SECRET unset"))
+                            .unwrap());
+                    }
+                };
+
+                let verified_by = match check_signature(secret, config.webhook_allow_sha1, &whole_body, signature_256.as_deref(), signature_1.as_deref()) {
+                    Ok(verified_by) => verified_by,
+                    Err(reason) if validate => {
+                        return Ok(json_response(StatusCode::FORBIDDEN, json!({ "valid": false, "reason": reason })));
+                    },
+                    Err(_) => {
                         return Ok(Response::builder()
                             .status(StatusCode::FORBIDDEN)
                             .body(Body::from("Invalid signature"))
                             .unwrap());
                     }
-                } else {
+                };
+    
+                /* a delivery without the header is taken as a push, ping is always answered */
+                let event_name = event.as_deref().unwrap_or("push");
+                let allowed = event_name == "ping" || config.webhook_events.iter().any(|allowed| allowed == event_name);
+                if !allowed && !validate {
+                    println!("Skipping webhook event {}, it isn't in WEBHOOK_EVENTS", event_name);
+                    return Ok(json_response(StatusCode::OK, json!({ "event": event_name, "skipped": true })));
+                }
+
+                if let Err(e) = check_json_limits(&whole_body, &config.webhook_json_limits) {
                     return Ok(Response::builder()
-                        .status(StatusCode::FORBIDDEN)
-                        .body(Body::from("Invalid signature"))
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from(e))
                         .unwrap());
                 }
-    
-                let payload: WebhookPayload = serde_json::from_slice(&whole_body).unwrap();
+
+                let payload: WebhookPayload = match serde_json::from_slice(&whole_body) {
+                    Ok(payload) => payload,
+                    Err(_) => return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from("Invalid payload"))
+                        .unwrap()),
+                };
                 
-                if payload.commits.is_some() && payload.ref_field.as_ref().map_or(false, |s| s.starts_with("refs/heads/")) {
-                    handle_webhook(payload).await;
+                if validate {
+                    return Ok(json_response(StatusCode::OK, json!({
+                        "valid": true,
+                        "signature": verified_by,
+                        "event": event,
+                        "branch": payload.ref_field.as_deref().and_then(|ref_field| ref_field.strip_prefix("refs/heads/")),
+                        "repository": payload.repository.as_ref().map(|repository| &repository.url),
+                        "would_build": allowed && (builds(&payload) || event.as_deref() == Some("repository_dispatch")),
+                    })));
+                }
+
+                /* sent once when the webhook is created */
+                if event.as_deref() == Some("ping") {
+                    return Ok(json_response(StatusCode::OK, json!({ "event": "ping", "hook_id": payload.hook_id, "zen": payload.zen })));
+                }
+
+                /* builds with whatever options the sender put in client_payload */
+                if event.as_deref() == Some("repository_dispatch") {
+                    return Ok(handle_dispatch(&state, &whole_body, config.webhook_profile.as_deref()).await);
+                }
+
+                if let (true, Some(repository)) = (builds(&payload), &payload.repository) {
+                    /* a monorepo builds each of its apps instead of the repo as a whole */
+                    let apps = repository.full_name.as_ref().and_then(|full_name| config.webhook_apps.get(full_name));
+                    if let Some(apps) = apps {
+                        return Ok(handle_apps(&state, &payload, repository, apps, config.webhook_profile.as_deref()).await);
+                    }
+
+                    return Ok(handle_push(&state, &payload, repository, config.webhook_profile.as_deref()).await);
                 }
     
                 Ok(Response::new(Body::from("Webhook receiver")))
@@ -99,4 +336,224 @@ pub async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper:
                     .unwrap())
             }        
         }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::config::tests::config;
+    use crate::config::config::Config;
+    use crate::engine::profiles::parse_profiles;
+    use crate::engine::state::tests::state;
+
+    const SECRET: &str = "It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+
+    fn sign<M: Mac + KeyInit>(body: &[u8]) -> String {
+        let mut mac = <M as Mac>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Delivers `body` as a `event` to /webhook, signed with SECRET.
+    async fn deliver(state: Arc<AppState>, query: &str, event: &str, body: &serde_json::Value) -> (StatusCode, String) {
+        let body = body.to_string();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("/webhook{}", query))
+            .header("X-GitHub-Event", event)
+            .header("X-Hub-Signature-256", format!("sha256={}", sign::<HmacSha256>(body.as_bytes())))
+            .body(Body::from(body))
+            .unwrap();
+
+        let response = handle_request(req, state).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn sha256_signature() {
+        let signature = format!("sha256={}", sign::<HmacSha256>(BODY));
+        assert_eq!(check_signature(SECRET, false, BODY, Some(&signature), None), Ok("X-Hub-Signature-256"));
+        assert!(check_signature(SECRET, false, b"tampered", Some(&signature), None).is_err());
+        assert!(check_signature("another secret", false, BODY, Some(&signature), None).is_err());
+    }
+
+    #[test]
+    fn sha1_signature_only_when_allowed() {
+        let signature = format!("sha1={}", sign::<HmacSha1>(BODY));
+        assert_eq!(check_signature(SECRET, true, BODY, None, Some(&signature)), Ok("X-Hub-Signature"));
+        assert!(check_signature(SECRET, false, BODY, None, Some(&signature)).unwrap_err().contains("WEBHOOK_ALLOW_SHA1"));
+        assert!(check_signature(SECRET, true, b"tampered", None, Some(&signature)).is_err());
+    }
+
+    #[test]
+    fn sha256_preferred_when_both_are_sent() {
+        let sha1 = format!("sha1={}", sign::<HmacSha1>(BODY));
+        let wrong_sha256 = format!("sha256={}", sign::<HmacSha256>(b"tampered"));
+        assert!(check_signature(SECRET, true, BODY, Some(&wrong_sha256), Some(&sha1)).is_err());
+
+        let sha256 = format!("sha256={}", sign::<HmacSha256>(BODY));
+        assert_eq!(check_signature(SECRET, true, BODY, Some(&sha256), Some(&sha1)), Ok("X-Hub-Signature-256"));
+    }
+
+    #[test]
+    fn missing_or_malformed_signature() {
+        assert!(check_signature(SECRET, true, BODY, None, None).is_err());
+        assert!(check_signature(SECRET, true, BODY, Some("sha256=not-hex"), None).is_err());
+        /* the digest prefix is required */
+        assert!(check_signature(SECRET, true, BODY, Some(&sign::<HmacSha256>(BODY)), None).is_err());
+    }
+
+    #[test]
+    fn push_builds_the_pushed_commit() {
+        let payload: WebhookPayload = serde_json::from_value(json!({
+            "ref": "refs/heads/main",
+            "before": "1111111111111111111111111111111111111111",
+            "after": "2222222222222222222222222222222222222222",
+            "repository": { "name": "App", "url": "https://github.com/example/app", "full_name": "example/app" },
+            "commits": [],
+        })).unwrap();
+        let repository = payload.repository.as_ref().unwrap();
+
+        let request = build_request(&payload, repository, Some("ci"));
+
+        assert!(builds(&payload));
+        assert_eq!(request["path"], json!("https://github.com/example/app"));
+        assert_eq!(request["name"], json!("app"));
+        assert_eq!(request["branch"], json!("main"));
+        assert_eq!(request["before"], json!("1111111111111111111111111111111111111111"));
+        assert_eq!(request["after"], json!("2222222222222222222222222222222222222222"));
+        assert_eq!(request["github_repository"], json!("example/app"));
+        assert_eq!(request["profile"], json!("ci"));
+    }
+
+    #[test]
+    fn tag_pushes_dont_build() {
+        let payload: WebhookPayload = serde_json::from_value(json!({ "ref": "refs/tags/v1", "commits": [] })).unwrap();
+        assert!(!builds(&payload));
+    }
+
+    #[tokio::test]
+    async fn webhooks_are_off_without_a_secret() {
+        let mut config = config();
+        config.webhook_secret = None;
+
+        let (status, body) = deliver(state(config), "", "ping", &json!({ "zen": "Keep it logically awesome." })).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "webhooks disabled, GITHUB_WEBHOOK_SECRET unset");
+    }
+
+    fn push() -> serde_json::Value {
+        json!({
+            "ref": "refs/heads/main",
+            "after": "2222222222222222222222222222222222222222",
+            "repository": { "name": "App", "url": "https://github.com/example/app", "full_name": "example/app" },
+            "commits": [],
+        })
+    }
+
+    fn signed_config() -> Config {
+        let mut config = config();
+        config.webhook_secret = Some(SECRET.to_string());
+        config
+    }
+
+    #[tokio::test]
+    async fn validate_checks_a_delivery_without_building() {
+        let state = state(signed_config());
+
+        let (status, body) = deliver(Arc::clone(&state), "?validate=1", "push", &push()).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["valid"], json!(true));
+        assert_eq!(body["signature"], json!("X-Hub-Signature-256"));
+        assert_eq!(body["event"], json!("push"));
+        assert_eq!(body["branch"], json!("main"));
+        assert_eq!(body["would_build"], json!(true));
+        assert!(state.queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn validate_reports_a_bad_signature() {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/webhook?validate=1")
+            .header("X-GitHub-Event", "push")
+            .header("X-Hub-Signature-256", format!("sha256={}", sign::<HmacSha256>(b"something else")))
+            .body(Body::from(push().to_string()))
+            .unwrap();
+
+        let response = handle_request(req, state(signed_config())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["valid"], json!(false));
+    }
+
+    #[tokio::test]
+    async fn ping_is_answered() {
+        let (status, body) = deliver(state(signed_config()), "", "ping", &json!({ "hook_id": 7, "zen": "Design for failure." })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), json!({ "event": "ping", "hook_id": 7, "zen": "Design for failure." }));
+    }
+
+    #[tokio::test]
+    async fn events_not_allowlisted_are_skipped() {
+        let state = state(signed_config());
+        assert_eq!(state.config().webhook_events, ["push"]);
+
+        let (status, body) = deliver(Arc::clone(&state), "", "pull_request", &json!({ "action": "opened", "number": 56 })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), json!({ "event": "pull_request", "skipped": true }));
+
+        /* dispatches build only once they're listed */
+        let (status, body) = deliver(Arc::clone(&state), "", "repository_dispatch", &json!({ "action": "deploy", "client_payload": {} })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), json!({ "event": "repository_dispatch", "skipped": true }));
+        assert!(state.queue.is_empty());
+    }
+
+    #[test]
+    fn dispatch_builds_with_the_client_payload() {
+        let mut config = config();
+        config.profiles = parse_profiles(r#"{ "ci": { "build_options": { "labels": ["ci=true"] } } }"#).unwrap();
+        let body = json!({
+            "action": "deploy",
+            "client_payload": { "branch": "release", "build_options": { "tags": ["app:release"], "no_cache": true } },
+            "repository": { "name": "App", "full_name": "example/app", "clone_url": "https://github.com/example/app.git" },
+        });
+
+        let request = dispatch_request(body.to_string().as_bytes(), Some("ci")).unwrap();
+        let build_info = build_info_from_value(request, &config).unwrap();
+        assert_eq!(build_info.path, "https://github.com/example/app.git");
+        assert_eq!(build_info.name, "app");
+        assert_eq!(build_info.github_repository.as_deref(), Some("example/app"));
+        assert_eq!(build_info.branch.as_deref(), Some("release"));
+        assert_eq!(build_info.build_options.tags, ["app:release"]);
+        assert!(build_info.build_options.no_cache);
+        /* layered over the profile, not in place of it */
+        assert_eq!(build_info.build_options.labels, ["ci=true"]);
+    }
+
+    #[test]
+    fn dispatch_payload_overrides_the_repository() {
+        let body = json!({
+            "client_payload": { "path": "https://github.com/example/other", "name": "other" },
+            "repository": { "name": "App", "full_name": "example/app", "clone_url": "https://github.com/example/app.git" },
+        });
+
+        let request = dispatch_request(body.to_string().as_bytes(), None).unwrap();
+        assert_eq!(request["path"], json!("https://github.com/example/other"));
+        assert_eq!(request["name"], json!("other"));
+        assert!(request.get("profile").is_none());
+    }
+
+    #[test]
+    fn dispatch_client_payload_must_be_an_object() {
+        let body = json!({ "client_payload": ["not", "an", "object"] });
+        assert_eq!(dispatch_request(body.to_string().as_bytes(), None), Err("client_payload must be an object".to_string()));
+        assert!(dispatch_request(b"not json", None).is_err());
+    }
+}